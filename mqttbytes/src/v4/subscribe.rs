@@ -123,6 +123,12 @@ impl SubscribeFilter {
     }
 }
 
+impl<S: Into<String>> From<(S, QoS)> for SubscribeFilter {
+    fn from((path, qos): (S, QoS)) -> SubscribeFilter {
+        SubscribeFilter::new(path.into(), qos)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RetainForwardRule {
     OnEverySubscribe,
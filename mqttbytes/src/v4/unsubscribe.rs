@@ -17,6 +17,17 @@ impl Unsubscribe {
         Unsubscribe { pkid: 0, topics }
     }
 
+    pub fn new_many<T, S>(topics: T) -> Unsubscribe
+    where
+        T: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Unsubscribe {
+            pkid: 0,
+            topics: topics.into_iter().map(|topic| topic.into()).collect(),
+        }
+    }
+
     pub fn read(fixed_header: FixedHeader, mut bytes: Bytes) -> Result<Self, Error> {
         let variable_header_index = fixed_header.fixed_header_len;
         bytes.advance(variable_header_index);
@@ -31,6 +31,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 println!("Incoming = {:?}", i);
             }
             Ok(Event::Outgoing(o)) => println!("Outgoing = {:?}", o),
+            Ok(Event::Reconnected(attempts)) => println!("Reconnected after {} attempts", attempts),
+            Ok(Event::SessionResumed(report)) => println!("Resumed = {:?}", report),
+            Ok(Event::Stats(stats)) => println!("Stats = {:?}", stats),
+            Ok(Event::PingRtt(rtt)) => println!("Ping rtt = {:?}", rtt),
+            Ok(Event::Subscribed(result)) => println!("Subscribed = {:?}", result),
+            Ok(Event::Expired(publish)) => println!("Expired = {:?}", publish),
+            Ok(Event::SlowConsumer(backpressure)) => println!("Slow = {:?}", backpressure),
+            Ok(Event::Paused) => println!("Paused"),
+            Ok(Event::SessionExpired) => println!("Session expired"),
+            Ok(Event::StreamEnd) => return Ok(()),
             Err(e) => {
                 println!("Error = {:?}", e);
             }
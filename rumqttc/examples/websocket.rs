@@ -0,0 +1,43 @@
+use tokio::{task, time};
+
+use rumqttc::{self, AsyncClient, MqttOptions, QoS, Transport};
+use std::error::Error;
+use std::time::Duration;
+
+#[tokio::main(worker_threads = 1)]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::init();
+
+    // Connects to ws://localhost:8083/mqtt
+    let mut mqttoptions = MqttOptions::new("test-1", "localhost", 8083);
+    mqttoptions.set_transport(Transport::ws());
+    mqttoptions.set_ws_path("/mqtt");
+    mqttoptions.set_keep_alive(5);
+
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    task::spawn(async move {
+        requests(client).await;
+        time::sleep(Duration::from_secs(3)).await;
+    });
+
+    loop {
+        let event = eventloop.poll().await;
+        println!("{:?}", event.unwrap());
+    }
+}
+
+async fn requests(client: AsyncClient) {
+    client
+        .subscribe("hello/world", QoS::AtMostOnce)
+        .await
+        .unwrap();
+
+    for i in 1..=10 {
+        client
+            .publish("hello/world", QoS::AtLeastOnce, false, vec![1; i])
+            .await
+            .unwrap();
+
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
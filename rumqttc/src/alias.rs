@@ -0,0 +1,93 @@
+//! Topic prefix aliases which shrink topics on constrained links
+
+/// Maps long topic prefixes to short aliases which a cooperating broker (or a
+/// bridge in front of it) expands back. Topics of outgoing publishes and
+/// subscription filters are compressed on the wire while topics of incoming
+/// publishes are expanded, so the application only sees full topics. Longest
+/// matching prefix wins.
+///
+/// Aliases should start with something no real topic starts with (e.g `$a/`)
+/// as incoming topics starting with an alias are always expanded.
+///
+/// This is a pre MQTT 5 scheme. With MQTT 5, aliased prefixes map to topic
+/// alias ids negotiated with the broker instead
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicAliases {
+    /// Prefixes and their aliases
+    aliases: Vec<(String, String)>,
+}
+
+impl TopicAliases {
+    pub fn new() -> TopicAliases {
+        TopicAliases::default()
+    }
+
+    /// Adds an alias of a topic prefix. E.g `devices/d1234/telemetry/` to `$a/1/`
+    pub fn add<P: Into<String>, A: Into<String>>(&mut self, prefix: P, alias: A) -> &mut Self {
+        let (prefix, alias) = (prefix.into(), alias.into());
+        if prefix.is_empty() || alias.is_empty() {
+            panic!("Empty topic prefixes and aliases are not allowed");
+        }
+
+        if self
+            .aliases
+            .iter()
+            .any(|(p, a)| *p == prefix || *a == alias)
+        {
+            panic!("Duplicate topic prefix or alias = {} -> {}", prefix, alias);
+        }
+
+        self.aliases.push((prefix, alias));
+        self
+    }
+
+    /// Prefixes and their aliases
+    pub fn aliases(&self) -> &[(String, String)] {
+        &self.aliases
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// Topic with its longest aliased prefix replaced by the alias
+    pub fn compress(&self, topic: &str) -> Option<String> {
+        replace(self.aliases.iter().map(|(p, a)| (p, a)), topic)
+    }
+
+    /// Topic with its alias replaced by the prefix
+    pub fn expand(&self, topic: &str) -> Option<String> {
+        replace(self.aliases.iter().map(|(p, a)| (a, p)), topic)
+    }
+}
+
+fn replace<'a, I>(pairs: I, topic: &str) -> Option<String>
+where
+    I: Iterator<Item = (&'a String, &'a String)>,
+{
+    let (from, to) = pairs
+        .filter(|(from, _)| topic.starts_with(from.as_str()))
+        .max_by_key(|(from, _)| from.len())?;
+
+    Some(format!("{}{}", to, &topic[from.len()..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn longest_prefixes_are_compressed_and_expanded() {
+        let mut aliases = TopicAliases::new();
+        aliases
+            .add("devices/d1234/", "$a/1/")
+            .add("devices/d1234/telemetry/", "$a/2/");
+
+        let topic = "devices/d1234/telemetry/temperature";
+        assert_eq!(aliases.compress(topic).unwrap(), "$a/2/temperature");
+        assert_eq!(aliases.expand("$a/2/temperature").unwrap(), topic);
+        assert_eq!(aliases.compress("devices/d1234/cmd").unwrap(), "$a/1/cmd");
+        assert_eq!(aliases.compress("devices/d5678/cmd"), None);
+        assert_eq!(aliases.expand("devices/d1234/cmd"), None);
+    }
+}
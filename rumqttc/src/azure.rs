@@ -0,0 +1,85 @@
+//! Azure IoT Hub connection options
+use crate::{url_encode, MqttOptions, Transport};
+
+use ring::hmac;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Validity of generated sas tokens. A new token is generated for every connection
+const SAS_TOKEN_VALIDITY: Duration = Duration::from_secs(3600);
+
+impl MqttOptions {
+    /// Options to connect `device_id` to azure iot `hub` (hub name without
+    /// `.azure-devices.net`) with the device's base64 encoded symmetric `sas_key`.
+    /// Sets the hub hostname, mqtts port, azure username format and tls with
+    /// bundled roots. Sas token password is regenerated before every connection
+    pub fn azure<S: Into<String>>(
+        hub: S,
+        device_id: S,
+        sas_key: &str,
+    ) -> Result<MqttOptions, base64::DecodeError> {
+        let host = format!("{}.azure-devices.net", hub.into());
+        let device_id = device_id.into();
+        let key = base64::decode(sas_key)?;
+
+        let mut options = MqttOptions::new(device_id.clone(), host.clone(), 8883);
+        options.set_transport(Transport::tls(Vec::new(), None, None));
+
+        let username = format!("{}/{}/?api-version=2018-06-30", host, device_id);
+        let resource = format!("{}/devices/{}", host, device_id);
+        options.set_credential_provider(move || {
+            let expiry = SystemTime::now() + SAS_TOKEN_VALIDITY;
+            let expiry = expiry.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            (username.clone(), sas_token(&resource, &key, expiry))
+        });
+
+        Ok(options)
+    }
+}
+
+/// Shared access signature of `resource` valid till `expiry` (unix time in secs)
+fn sas_token(resource: &str, key: &[u8], expiry: u64) -> String {
+    let resource = url_encode(resource);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let to_sign = format!("{}\n{}", resource, expiry);
+    let signature = hmac::sign(&key, to_sign.as_bytes());
+    let signature = url_encode(&base64::encode(signature.as_ref()));
+
+    format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource, signature, expiry
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sas_token_is_signed_with_device_key() {
+        let key = base64::decode("c2VjcmV0").unwrap();
+        let token = sas_token("myhub.azure-devices.net/devices/dev1", &key, 1600000000);
+        assert_eq!(
+            token,
+            "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fdev1\
+             &sig=qFjgo98bjsuNq14xos4qgNsRUk8RlBSqW3Mmo1oT%2FRw%3D&se=1600000000"
+        );
+    }
+
+    #[test]
+    fn azure_options_use_hub_conventions() {
+        let options = MqttOptions::azure("myhub", "dev1", "c2VjcmV0").unwrap();
+        assert_eq!(
+            options.broker_address(),
+            ("myhub.azure-devices.net".to_owned(), 8883)
+        );
+        assert_eq!(options.client_id(), "dev1");
+
+        let (username, password) = options.connect_credentials().unwrap();
+        assert_eq!(
+            username,
+            "myhub.azure-devices.net/dev1/?api-version=2018-06-30"
+        );
+        assert!(password.starts_with("SharedAccessSignature sr="));
+        assert!(MqttOptions::azure("myhub", "dev1", "not base64!").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Application level fragmentation of payloads larger than what brokers accept
+use crate::Request;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use mqttbytes::matches;
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Formatter};
+
+/// Message id, chunk index and chunk count prefixed to every chunk
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// Partially received payloads held at once. Oldest is dropped beyond this
+const MAX_PARTIALS: usize = 32;
+
+/// Errors while reassembling chunks
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ChunkError {
+    Header(usize),
+    Index(u16, u16),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ChunkError::Header(len) => write!(f, "Chunk of {} bytes is too short", len),
+            ChunkError::Index(index, count) => write!(f, "Chunk {} of {}", index, count),
+        }
+    }
+}
+
+/// Payload being reassembled
+struct Partial {
+    topic: String,
+    id: u32,
+    chunks: Vec<Option<Bytes>>,
+    received: usize,
+    /// Acks of the received chunks held till the payload is acked
+    acks: Vec<Request>,
+}
+
+/// Splits payloads of outgoing publishes on matching topics into chunks with
+/// a header and joins chunks of incoming publishes back. All the payloads on
+/// chunked topics carry the header, small ones as a single chunk
+#[derive(Default)]
+pub(crate) struct Chunking {
+    /// Topic filters and maximum chunk sizes (header included)
+    filters: Vec<(String, usize)>,
+    /// Id of the next split payload
+    next_id: u32,
+    /// Payloads waiting for their remaining chunks
+    partials: VecDeque<Partial>,
+}
+
+impl Clone for Chunking {
+    /// Copies the filters. Ids and partial payloads are per state
+    fn clone(&self) -> Chunking {
+        Chunking {
+            filters: self.filters.clone(),
+            ..Chunking::default()
+        }
+    }
+}
+
+impl Chunking {
+    pub fn add(&mut self, filter: String, size: usize) {
+        self.filters.push((filter, size));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Maximum chunk size of the topic. First matching filter wins
+    fn size(&self, topic: &str) -> Option<usize> {
+        self.filters
+            .iter()
+            .find(|(filter, _)| matches(topic, filter))
+            .map(|(_, size)| *size)
+    }
+
+    /// Checks if payloads on the topic are chunked
+    pub fn applies(&self, topic: &str) -> bool {
+        self.size(topic).is_some()
+    }
+
+    /// Chunks of the payload with their headers. `None` for topics which aren't chunked
+    pub fn split(&mut self, topic: &str, payload: Bytes) -> Option<Vec<Bytes>> {
+        let mut size = self.size(topic)? - HEADER_LEN;
+        let max = u16::MAX as usize;
+        if payload.len() > size * max {
+            warn!("Payload of {} bytes needs oversized chunks", payload.len());
+            size = (payload.len() + max - 1) / max;
+        }
+
+        let count = match payload.len() {
+            0 => 1,
+            len => (len + size - 1) / size,
+        };
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut chunks = Vec::with_capacity(count);
+        for index in 0..count {
+            let start = index * size;
+            let end = usize::min(start + size, payload.len());
+            let mut chunk = BytesMut::with_capacity(HEADER_LEN + end - start);
+            chunk.put_u32(id);
+            chunk.put_u16(index as u16);
+            chunk.put_u16(count as u16);
+            chunk.put_slice(&payload[start..end]);
+            chunks.push(chunk.freeze());
+        }
+
+        Some(chunks)
+    }
+
+    /// Adds a chunk of an incoming publish and holds its ack if any. Returns
+    /// the payload once all of its chunks are received, along with the held
+    /// acks of the other chunks
+    pub fn join(
+        &mut self,
+        topic: &str,
+        mut chunk: Bytes,
+        ack: Option<Request>,
+    ) -> Result<Option<(Bytes, Vec<Request>)>, ChunkError> {
+        if chunk.len() < HEADER_LEN {
+            return Err(ChunkError::Header(chunk.len()));
+        }
+
+        let id = chunk.get_u32();
+        let index = chunk.get_u16();
+        let count = chunk.get_u16();
+        if index >= count {
+            return Err(ChunkError::Index(index, count));
+        }
+
+        if count == 1 {
+            return Ok(Some((chunk, Vec::new())));
+        }
+
+        let position = self
+            .partials
+            .iter()
+            .position(|p| p.id == id && p.topic == topic);
+
+        let partial = match position {
+            Some(position) => &mut self.partials[position],
+            None => {
+                if self.partials.len() >= MAX_PARTIALS {
+                    let partial = self.partials.pop_front().unwrap();
+                    warn!("Dropping partial payload of {}", partial.topic);
+                }
+
+                self.partials.push_back(Partial {
+                    topic: topic.to_owned(),
+                    id,
+                    chunks: vec![None; count as usize],
+                    received: 0,
+                    acks: Vec::new(),
+                });
+
+                self.partials.back_mut().unwrap()
+            }
+        };
+
+        if partial.chunks.len() != count as usize {
+            return Err(ChunkError::Index(index, count));
+        }
+
+        // Redelivered chunks replace the previous copy
+        if partial.chunks[index as usize].replace(chunk).is_none() {
+            partial.received += 1;
+        }
+
+        if partial.received < partial.chunks.len() {
+            partial.acks.extend(ack);
+            return Ok(None);
+        }
+
+        let position = self
+            .partials
+            .iter()
+            .position(|p| p.id == id && p.topic == topic)
+            .unwrap();
+
+        let partial = self.partials.remove(position).unwrap();
+        let mut payload = BytesMut::new();
+        for chunk in partial.chunks.into_iter().flatten() {
+            payload.put(chunk);
+        }
+
+        Ok(Some((payload.freeze(), partial.acks)))
+    }
+}
+
+impl Debug for Chunking {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Chunking")
+            .field("filters", &self.filters)
+            .field("partials", &self.partials.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn payloads_are_split_and_joined_on_matching_topics() {
+        let mut chunking = Chunking::default();
+        chunking.add("firmware/+".to_owned(), HEADER_LEN + 4);
+
+        let payload = Bytes::from((0..10).collect::<Vec<u8>>());
+        let chunks = chunking.split("firmware/v1", payload.clone()).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= HEADER_LEN + 4));
+        assert!(chunking.split("hello/world", payload.clone()).is_none());
+
+        // chunks can arrive out of order
+        let mut receiver = chunking.clone();
+        let topic = "firmware/v1";
+        assert_eq!(receiver.join(topic, chunks[2].clone(), None), Ok(None));
+        assert_eq!(receiver.join(topic, chunks[0].clone(), None), Ok(None));
+        assert_eq!(receiver.join(topic, chunks[0].clone(), None), Ok(None));
+        assert_eq!(
+            receiver.join(topic, chunks[1].clone(), None),
+            Ok(Some((payload, vec![])))
+        );
+        assert!(receiver.partials.is_empty());
+
+        let o = receiver.join(topic, Bytes::from(vec![1, 2]), None);
+        assert_eq!(o, Err(ChunkError::Header(2)));
+    }
+
+    #[test]
+    fn duplicate_chunks_are_counted_once() {
+        let mut chunking = Chunking::default();
+        chunking.add("firmware/+".to_owned(), HEADER_LEN + 2);
+        let payload = Bytes::from(vec![1, 2, 3, 4, 5, 6]);
+        let chunks = chunking.split("firmware/v1", payload.clone()).unwrap();
+
+        let mut receiver = chunking.clone();
+        let topic = "firmware/v1";
+        for index in [1, 1, 0, 0] {
+            assert_eq!(receiver.join(topic, chunks[index].clone(), None), Ok(None));
+        }
+
+        assert_eq!(receiver.partials[0].received, 2);
+        assert_eq!(
+            receiver.join(topic, chunks[2].clone(), None),
+            Ok(Some((payload, vec![])))
+        );
+
+        // Chunks of the same message with a different count are rejected
+        let mut other = chunking.clone();
+        other.filters[0].1 = HEADER_LEN + 3;
+        let mismatched = other.split("firmware/v1", Bytes::from(vec![1; 6])).unwrap();
+        assert_eq!(receiver.join(topic, chunks[0].clone(), None), Ok(None));
+        assert_eq!(
+            receiver.join(topic, mismatched[1].clone(), None),
+            Err(ChunkError::Index(1, 2))
+        );
+
+        let o = receiver.join(topic, Bytes::from(vec![0, 0, 0, 0, 0, 2, 0, 2]), None);
+        assert_eq!(o, Err(ChunkError::Index(2, 2)));
+    }
+
+    #[test]
+    fn oldest_partial_payloads_are_dropped_beyond_the_limit() {
+        let mut chunking = Chunking::default();
+        chunking.add("firmware/+".to_owned(), HEADER_LEN + 1);
+        let payloads: Vec<Vec<Bytes>> = (0..=MAX_PARTIALS)
+            .map(|i| {
+                let payload = Bytes::from(vec![i as u8; 2]);
+                chunking.split("firmware/v1", payload).unwrap()
+            })
+            .collect();
+
+        let mut receiver = chunking.clone();
+        let topic = "firmware/v1";
+        for chunks in payloads.iter() {
+            assert_eq!(receiver.join(topic, chunks[0].clone(), None), Ok(None));
+        }
+
+        assert_eq!(receiver.partials.len(), MAX_PARTIALS);
+
+        // First payload is dropped. Its last chunk starts a new partial
+        let last = payloads.last().unwrap()[1].clone();
+        let payload = Bytes::from(vec![MAX_PARTIALS as u8; 2]);
+        assert_eq!(
+            receiver.join(topic, last, None),
+            Ok(Some((payload, vec![])))
+        );
+        assert_eq!(receiver.join(topic, payloads[0][1].clone(), None), Ok(None));
+        assert_eq!(receiver.partials.len(), MAX_PARTIALS);
+    }
+}
@@ -1,34 +1,125 @@
 //! This module offers a high level synchronous and asynchronous abstraction to
 //! async eventloop.
-use crate::{ConnectionError, Event, EventLoop, MqttOptions, Request};
+use crate::notifications::{notifications, Notifications, SlowConsumerPolicy};
+use crate::{token, ConnectionError, Event, EventLoop, MqttOptions, PublishToken, Request};
 
-use async_channel::{SendError, Sender, TrySendError};
+use async_channel::{bounded, SendError, Sender, TrySendError};
 use bytes::Bytes;
 use mqttbytes::v4::*;
 use mqttbytes::*;
-use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::runtime;
 use tokio::runtime::Runtime;
+#[cfg(feature = "tower")]
+use tokio::sync::Notify;
 
 /// Client Error
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
     #[error("Failed to send cancel request to eventloop")]
     Cancel(#[from] SendError<()>),
+    #[error("Failed to send shutdown request to eventloop")]
+    Shutdown(#[from] SendError<Duration>),
+    #[error("Failed to send pause request to eventloop")]
+    Pause(#[from] SendError<bool>),
     #[error("Failed to send mqtt requests to eventloop")]
     Request(#[from] SendError<Request>),
     #[error("Failed to send mqtt requests to eventloop")]
     TryRequest(#[from] TrySendError<Request>),
     #[error("Serialization error")]
     Mqtt4(mqttbytes::Error),
+    #[error("Inflight window is full")]
+    InflightFull,
+}
+
+/// Publish with all its flags (qos, retain, dup). Defaults to a QoS 0
+/// publish. Sent with `client.publish_with(..)`
+#[derive(Debug, Clone)]
+pub struct PublishBuilder {
+    publish: Publish,
+    expiry: Option<Duration>,
+    priority: bool,
+}
+
+impl PublishBuilder {
+    pub fn new<S, V>(topic: S, payload: V) -> PublishBuilder
+    where
+        S: Into<String>,
+        V: Into<Bytes>,
+    {
+        let publish = Publish::from_bytes(topic, QoS::AtMostOnce, payload.into());
+        PublishBuilder {
+            publish,
+            expiry: None,
+            priority: false,
+        }
+    }
+
+    pub fn set_qos(&mut self, qos: QoS) -> &mut Self {
+        self.publish.qos = qos;
+        self
+    }
+
+    /// Broker retains the publish for future subscribers of the topic
+    pub fn set_retain(&mut self, retain: bool) -> &mut Self {
+        self.publish.retain = retain;
+        self
+    }
+
+    /// Marks the publish as a redelivery
+    pub fn set_dup(&mut self, dup: bool) -> &mut Self {
+        self.publish.dup = dup;
+        self
+    }
+
+    /// Drops the publish with `Event::Expired` if it isn't sent within `ttl`.
+    /// Overrides `MqttOptions::set_request_expiry`
+    pub fn set_expiry(&mut self, ttl: Duration) -> &mut Self {
+        self.expiry = Some(ttl);
+        self
+    }
+
+    /// Sends the publish through the priority channel of the eventloop. It
+    /// jumps ahead of normal requests queued in the requests channel and the
+    /// offline buffer (but not the replay of unacked packets)
+    pub fn set_priority(&mut self, priority: bool) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Publish packet with the flags. Packet id is allocated by the eventloop
+    pub fn build(&self) -> Publish {
+        self.publish.clone()
+    }
+}
+
+impl From<PublishBuilder> for Publish {
+    fn from(builder: PublishBuilder) -> Publish {
+        builder.publish
+    }
 }
 
 /// `AsyncClient` to communicate with MQTT `Eventloop`
 /// This is cloneable and can be used to asynchronously Publish, Subscribe.
 #[derive(Clone, Debug)]
 pub struct AsyncClient {
-    request_tx: Sender<Request>,
+    pub(crate) request_tx: Sender<Request>,
+    priority_tx: Sender<Request>,
     cancel_tx: Sender<()>,
+    shutdown_tx: Sender<Duration>,
+    pause_tx: Sender<bool>,
+    /// Notified when eventloop pulls a request out of the requests channel
+    #[cfg(feature = "tower")]
+    pub(crate) capacity: Arc<Notify>,
+    /// Unacked outgoing publishes. Updated by the eventloop
+    inflight: Arc<AtomicUsize>,
+    /// Maximum number of allowed inflight
+    max_inflight: usize,
+    /// Time to live of publishes
+    expiry: Option<Duration>,
 }
 
 impl AsyncClient {
@@ -36,11 +127,22 @@ impl AsyncClient {
     pub fn new(options: MqttOptions, cap: usize) -> (AsyncClient, EventLoop) {
         let mut eventloop = EventLoop::new(options, cap);
         let request_tx = eventloop.handle();
+        let priority_tx = eventloop.priority_handle();
         let cancel_tx = eventloop.cancel_handle();
+        let shutdown_tx = eventloop.shutdown_handle();
+        let pause_tx = eventloop.pause_handle();
 
         let client = AsyncClient {
             request_tx,
+            priority_tx,
             cancel_tx,
+            shutdown_tx,
+            pause_tx,
+            #[cfg(feature = "tower")]
+            capacity: eventloop.capacity.clone(),
+            inflight: eventloop.inflight.clone(),
+            max_inflight: eventloop.options.inflight() as usize,
+            expiry: eventloop.options.request_expiry(),
         };
 
         (client, eventloop)
@@ -50,12 +152,29 @@ impl AsyncClient {
     /// creating a test instance.
     pub fn from_senders(request_tx: Sender<Request>, cancel_tx: Sender<()>) -> AsyncClient {
         AsyncClient {
+            priority_tx: request_tx.clone(),
             request_tx,
             cancel_tx,
+            shutdown_tx: bounded(1).0,
+            pause_tx: bounded(1).0,
+            #[cfg(feature = "tower")]
+            capacity: Arc::new(Notify::new()),
+            inflight: Arc::new(AtomicUsize::new(0)),
+            max_inflight: usize::MAX,
+            expiry: None,
         }
     }
 
-    /// Sends a MQTT Publish to the eventloop
+    /// Publish request which expires after `ttl` when set
+    fn publish_request(&self, publish: Publish, ttl: Option<Duration>) -> Request {
+        match ttl.or(self.expiry) {
+            Some(ttl) => Request::ExpiringPublish(publish, Instant::now() + ttl),
+            None => Request::Publish(publish),
+        }
+    }
+
+    /// Sends a MQTT Publish to the eventloop. Payload (`Vec<u8>`, `Bytes`,
+    /// `String`, `&'static [u8]` etc) is moved to the eventloop without copies
     pub async fn publish<S, V>(
         &self,
         topic: S,
@@ -65,15 +184,36 @@ impl AsyncClient {
     ) -> Result<(), ClientError>
     where
         S: Into<String>,
-        V: Into<Vec<u8>>,
+        V: Into<Bytes>,
     {
-        let mut publish = Publish::new(topic, qos, payload);
+        let mut publish = Publish::from_bytes(topic, qos, payload.into());
         publish.retain = retain;
-        let publish = Request::Publish(publish);
+        let publish = self.publish_request(publish, None);
         self.request_tx.send(publish).await?;
         Ok(())
     }
 
+    /// Sends a MQTT Publish to the eventloop. Returned token resolves when
+    /// the broker acks the publish
+    pub async fn publish_with_token<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<PublishToken, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Bytes>,
+    {
+        let mut publish = Publish::from_bytes(topic, qos, payload.into());
+        publish.retain = retain;
+        let (tx, token) = token::token();
+        let publish = Request::TrackedPublish(publish, tx);
+        self.request_tx.send(publish).await?;
+        Ok(token)
+    }
+
     /// Sends a MQTT Publish to the eventloop
     pub fn try_publish<S, V>(
         &self,
@@ -84,16 +224,32 @@ impl AsyncClient {
     ) -> Result<(), ClientError>
     where
         S: Into<String>,
-        V: Into<Vec<u8>>,
+        V: Into<Bytes>,
     {
-        let mut publish = Publish::new(topic, qos, payload);
+        let mut publish = Publish::from_bytes(topic, qos, payload.into());
         publish.retain = retain;
-        let publish = Request::Publish(publish);
-        self.request_tx.try_send(publish)?;
+        let publish = self.publish_request(publish, None);
+        self.try_request(publish)?;
         Ok(())
     }
 
-    /// Sends a MQTT Publish to the eventloop
+    /// Sends a MQTT Publish with all the flags of `publish` to the eventloop
+    pub async fn publish_with(&self, publish: PublishBuilder) -> Result<(), ClientError> {
+        let tx = self.lane(publish.priority);
+        let publish = self.publish_request(publish.publish, publish.expiry);
+        tx.send(publish).await?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Publish with all the flags of `publish` to the eventloop
+    pub fn try_publish_with(&self, publish: PublishBuilder) -> Result<(), ClientError> {
+        let tx = self.lane(publish.priority);
+        let publish = self.publish_request(publish.publish, publish.expiry);
+        self.try_send(tx, publish)?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Publish with `Bytes` payload to the eventloop. Same as `publish`
     pub async fn publish_bytes<S>(
         &self,
         topic: S,
@@ -106,11 +262,31 @@ impl AsyncClient {
     {
         let mut publish = Publish::from_bytes(topic, qos, payload);
         publish.retain = retain;
-        let publish = Request::Publish(publish);
+        let publish = self.publish_request(publish, None);
         self.request_tx.send(publish).await?;
         Ok(())
     }
 
+    /// Sends a MQTT PubAck (or PubRec for QoS 2) of an incoming publish to the
+    /// eventloop. Only used with manual acks
+    pub async fn ack(&self, publish: &Publish) -> Result<(), ClientError> {
+        if let Some(ack) = get_ack_req(publish) {
+            self.request_tx.send(ack).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a MQTT PubAck (or PubRec for QoS 2) of an incoming publish to the
+    /// eventloop. Only used with manual acks
+    pub fn try_ack(&self, publish: &Publish) -> Result<(), ClientError> {
+        if let Some(ack) = get_ack_req(publish) {
+            self.request_tx.try_send(ack)?;
+        }
+
+        Ok(())
+    }
+
     /// Sends a MQTT Subscribe to the eventloop
     pub async fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
         let subscribe = Subscribe::new(topic.into(), qos);
@@ -123,29 +299,32 @@ impl AsyncClient {
     pub fn try_subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
         let subscribe = Subscribe::new(topic.into(), qos);
         let request = Request::Subscribe(subscribe);
-        self.request_tx.try_send(request)?;
+        self.try_request(request)?;
         Ok(())
     }
 
-    /// Sends a MQTT Subscribe for multiple topics to the eventloop
-    pub async fn subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
+    /// Sends a MQTT Subscribe for multiple topics (`SubscribeFilter`s or
+    /// `(topic, qos)` pairs) to the eventloop in a single packet
+    pub async fn subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
     where
-        T: IntoIterator<Item = SubscribeFilter>,
+        T: IntoIterator,
+        T::Item: Into<SubscribeFilter>,
     {
-        let subscribe = Subscribe::new_many(topics);
+        let subscribe = Subscribe::new_many(topics.into_iter().map(Into::into));
         let request = Request::Subscribe(subscribe);
         self.request_tx.send(request).await?;
         Ok(())
     }
 
     /// Sends a MQTT Subscribe for multiple topics to the eventloop
-    pub fn try_subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
+    pub fn try_subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
     where
-        T: IntoIterator<Item = SubscribeFilter>,
+        T: IntoIterator,
+        T::Item: Into<SubscribeFilter>,
     {
-        let subscribe = Subscribe::new_many(topics);
+        let subscribe = Subscribe::new_many(topics.into_iter().map(Into::into));
         let request = Request::Subscribe(subscribe);
-        self.request_tx.try_send(request)?;
+        self.try_request(request)?;
         Ok(())
     }
 
@@ -161,7 +340,31 @@ impl AsyncClient {
     pub fn try_unsubscribe<S: Into<String>>(&self, topic: S) -> Result<(), ClientError> {
         let unsubscribe = Unsubscribe::new(topic.into());
         let request = Request::Unsubscribe(unsubscribe);
-        self.request_tx.try_send(request)?;
+        self.try_request(request)?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Unsubscribe for multiple topics to the eventloop in a single packet
+    pub async fn unsubscribe_many<T, S>(&self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let unsubscribe = Unsubscribe::new_many(topics);
+        let request = Request::Unsubscribe(unsubscribe);
+        self.request_tx.send(request).await?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Unsubscribe for multiple topics to the eventloop
+    pub fn try_unsubscribe_many<T, S>(&self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let unsubscribe = Unsubscribe::new_many(topics);
+        let request = Request::Unsubscribe(unsubscribe);
+        self.try_request(request)?;
         Ok(())
     }
 
@@ -184,6 +387,69 @@ impl AsyncClient {
         self.cancel_tx.send(()).await?;
         Ok(())
     }
+
+    /// Stops the eventloop gracefully. Queued requests and inflight publishes
+    /// are flushed within `deadline` before disconnecting. See `EventLoop::shutdown`
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), ClientError> {
+        self.shutdown_tx.send(deadline).await?;
+        Ok(())
+    }
+
+    /// Disconnects from the broker till `resume`. Session state is retained
+    /// and new requests are queued while paused
+    pub async fn pause(&self) -> Result<(), ClientError> {
+        self.pause_tx.send(true).await?;
+        Ok(())
+    }
+
+    /// Reconnects a paused eventloop. Unacked packets are replayed
+    pub async fn resume(&self) -> Result<(), ClientError> {
+        self.pause_tx.send(false).await?;
+        Ok(())
+    }
+
+    /// Number of requests queued in the requests and priority channels
+    pub fn queued(&self) -> usize {
+        self.request_tx.len() + self.priority_tx.len()
+    }
+
+    /// Number of unacked outgoing publishes
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    /// Fails fast when eventloop stopped pulling requests due to full inflight
+    /// window or when the requests channel is full
+    fn try_request(&self, request: Request) -> Result<(), ClientError> {
+        self.try_send(&self.request_tx, request)
+    }
+
+    fn try_send(&self, tx: &Sender<Request>, request: Request) -> Result<(), ClientError> {
+        if self.inflight() >= self.max_inflight {
+            return Err(ClientError::InflightFull);
+        }
+
+        tx.try_send(request)?;
+        Ok(())
+    }
+
+    /// Requests channel of the publish
+    fn lane(&self, priority: bool) -> &Sender<Request> {
+        match priority {
+            true => &self.priority_tx,
+            false => &self.request_tx,
+        }
+    }
+}
+
+pub(crate) fn get_ack_req(publish: &Publish) -> Option<Request> {
+    let ack = match publish.qos {
+        QoS::AtMostOnce => return None,
+        QoS::AtLeastOnce => Request::PubAck(PubAck::new(publish.pkid)),
+        QoS::ExactlyOnce => Request::PubRec(PubRec::new(publish.pkid)),
+    };
+
+    Some(ack)
 }
 
 /// `Client` to communicate with MQTT eventloop `Connection`.
@@ -219,12 +485,56 @@ impl Client {
     ) -> Result<(), ClientError>
     where
         S: Into<String>,
-        V: Into<Vec<u8>>,
+        V: Into<Bytes>,
     {
         pollster::block_on(self.client.publish(topic, qos, retain, payload))?;
         Ok(())
     }
 
+    /// Sends a MQTT Publish to the eventloop. Returned token resolves when
+    /// the broker acks the publish (`token.blocking_wait()`)
+    pub fn publish_with_token<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<PublishToken, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Bytes>,
+    {
+        pollster::block_on(self.client.publish_with_token(topic, qos, retain, payload))
+    }
+
+    /// Sends a MQTT Publish with all the flags of `publish` to the eventloop
+    pub fn publish_with(&mut self, publish: PublishBuilder) -> Result<(), ClientError> {
+        pollster::block_on(self.client.publish_with(publish))?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Publish with all the flags of `publish` to the eventloop
+    pub fn try_publish_with(&mut self, publish: PublishBuilder) -> Result<(), ClientError> {
+        self.client.try_publish_with(publish)?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Publish with `Bytes` payload to the eventloop. Same as `publish`
+    pub fn publish_bytes<S>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: Bytes,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+    {
+        pollster::block_on(self.client.publish_bytes(topic, qos, retain, payload))?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Publish to the eventloop
     pub fn try_publish<S, V>(
         &mut self,
         topic: S,
@@ -234,12 +544,26 @@ impl Client {
     ) -> Result<(), ClientError>
     where
         S: Into<String>,
-        V: Into<Vec<u8>>,
+        V: Into<Bytes>,
     {
         self.client.try_publish(topic, qos, retain, payload)?;
         Ok(())
     }
 
+    /// Sends a MQTT PubAck (or PubRec for QoS 2) of an incoming publish to the
+    /// eventloop. Only used with manual acks
+    pub fn ack(&self, publish: &Publish) -> Result<(), ClientError> {
+        pollster::block_on(self.client.ack(publish))?;
+        Ok(())
+    }
+
+    /// Sends a MQTT PubAck (or PubRec for QoS 2) of an incoming publish to the
+    /// eventloop. Only used with manual acks
+    pub fn try_ack(&self, publish: &Publish) -> Result<(), ClientError> {
+        self.client.try_ack(publish)?;
+        Ok(())
+    }
+
     /// Sends a MQTT Subscribe to the eventloop
     pub fn subscribe<S: Into<String>>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError> {
         pollster::block_on(self.client.subscribe(topic, qos))?;
@@ -259,14 +583,16 @@ impl Client {
     /// Sends a MQTT Subscribe for multiple topics to the eventloop
     pub fn subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
     where
-        T: IntoIterator<Item = SubscribeFilter>,
+        T: IntoIterator,
+        T::Item: Into<SubscribeFilter>,
     {
         pollster::block_on(self.client.subscribe_many(topics))
     }
 
     pub fn try_subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
     where
-        T: IntoIterator<Item = SubscribeFilter>,
+        T: IntoIterator,
+        T::Item: Into<SubscribeFilter>,
     {
         self.client.try_subscribe_many(topics)
     }
@@ -283,6 +609,23 @@ impl Client {
         Ok(())
     }
 
+    /// Sends a MQTT Unsubscribe for multiple topics to the eventloop
+    pub fn unsubscribe_many<T, S>(&mut self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        pollster::block_on(self.client.unsubscribe_many(topics))
+    }
+
+    pub fn try_unsubscribe_many<T, S>(&mut self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.client.try_unsubscribe_many(topics)
+    }
+
     /// Sends a MQTT disconnect to the eventloop
     pub fn disconnect(&mut self) -> Result<(), ClientError> {
         pollster::block_on(self.client.disconnect())?;
@@ -300,20 +643,46 @@ impl Client {
         pollster::block_on(self.client.cancel())?;
         Ok(())
     }
+
+    /// Stops the eventloop gracefully. Connection iterator ends after
+    /// `Event::StreamEnd`
+    pub fn shutdown(&mut self, deadline: Duration) -> Result<(), ClientError> {
+        pollster::block_on(self.client.shutdown(deadline))?;
+        Ok(())
+    }
+
+    /// Disconnects from the broker till `resume`
+    pub fn pause(&mut self) -> Result<(), ClientError> {
+        pollster::block_on(self.client.pause())?;
+        Ok(())
+    }
+
+    /// Reconnects a paused eventloop
+    pub fn resume(&mut self) -> Result<(), ClientError> {
+        pollster::block_on(self.client.resume())?;
+        Ok(())
+    }
+
+    /// Number of requests queued in the requests channel
+    pub fn queued(&self) -> usize {
+        self.client.queued()
+    }
+
+    /// Number of unacked outgoing publishes
+    pub fn inflight(&self) -> usize {
+        self.client.inflight()
+    }
 }
 
 ///  MQTT connection. Maintains all the necessary state
 pub struct Connection {
     pub eventloop: EventLoop,
-    runtime: Option<Runtime>,
+    runtime: Runtime,
 }
 
 impl Connection {
     fn new(eventloop: EventLoop, runtime: Runtime) -> Connection {
-        Connection {
-            eventloop,
-            runtime: Some(runtime),
-        }
+        Connection { eventloop, runtime }
     }
 
     /// Returns an iterator over this connection. Iterating over this is all that's
@@ -322,26 +691,57 @@ impl Connection {
     /// **NOTE** Don't block this while iterating
     #[must_use = "Connection should be iterated over a loop to make progress"]
     pub fn iter(&mut self) -> Iter {
-        let runtime = self.runtime.take().unwrap();
-        Iter {
-            connection: self,
-            runtime,
+        Iter { connection: self }
+    }
+
+    /// Blocks the current thread and hands every event to `f` till the eventloop
+    /// stops. Reconnections are automatic with `MqttOptions::set_reconnect_options`.
+    /// Returns `Ok` when the client cancels or shuts down the connection or
+    /// drops all its handles. Otherwise returns the error which stopped the
+    /// eventloop
+    pub fn run<F>(&mut self, mut f: F) -> Result<(), ConnectionError>
+    where
+        F: FnMut(Event),
+    {
+        loop {
+            match self.runtime.block_on(self.eventloop.poll()) {
+                Ok(event) => f(event),
+                Err(ConnectionError::RequestsDone) | Err(ConnectionError::Cancel) => return Ok(()),
+                Err(e) => return Err(e),
+            }
         }
     }
+
+    /// Runs the connection with `run` on a new thread. Final status of the
+    /// connection is returned when the thread is joined
+    pub fn start_in_thread<F>(mut self, f: F) -> thread::JoinHandle<Result<(), ConnectionError>>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        thread::spawn(move || self.run(f))
+    }
+
+    /// Runs the eventloop on a background thread and forwards its events to a
+    /// notifications channel of `capacity`. See `EventLoop::spawn`
+    pub fn spawn(self, capacity: usize, policy: SlowConsumerPolicy) -> Notifications {
+        let Connection { eventloop, runtime } = self;
+        let (notifications, forward) = notifications(capacity);
+        thread::spawn(move || runtime.block_on(forward.run(eventloop, policy)));
+        notifications
+    }
 }
 
 /// Iterator which polls the eventloop for connection progress
 pub struct Iter<'a> {
     connection: &'a mut Connection,
-    runtime: runtime::Runtime,
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = Result<Event, ConnectionError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let f = self.connection.eventloop.poll();
-        match self.runtime.block_on(f) {
+        let Connection { eventloop, runtime } = &mut *self.connection;
+        match runtime.block_on(eventloop.poll()) {
             Ok(v) => Some(Ok(v)),
             // closing of request channel should stop the iterator
             Err(ConnectionError::RequestsDone) => {
@@ -357,10 +757,115 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
-impl<'a> Drop for Iter<'a> {
-    fn drop(&mut self) {
-        // TODO: Don't create new runtime in drop
-        let runtime = runtime::Builder::new_current_thread().build().unwrap();
-        self.connection.runtime = Some(mem::replace(&mut self.runtime, runtime));
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_payloads_are_not_copied() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 10);
+
+        let payload = Bytes::from(vec![1; 1024]);
+        client
+            .publish("hello/world", QoS::AtLeastOnce, false, payload.clone())
+            .await
+            .unwrap();
+
+        match eventloop.requests_rx.recv().await.unwrap() {
+            Request::Publish(publish) => assert_eq!(publish.payload.as_ptr(), payload.as_ptr()),
+            request => panic!("Unexpected request = {:?}", request),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_flags_survive_to_the_wire() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+        let mut publish = PublishBuilder::new("hello/world", vec![1, 2, 3]);
+        publish
+            .set_qos(QoS::AtLeastOnce)
+            .set_retain(true)
+            .set_dup(true);
+        client.publish_with(publish).await.unwrap();
+
+        let request = eventloop.requests_rx.recv().await.unwrap();
+        eventloop.state.handle_outgoing_packet(request).unwrap();
+        assert_eq!(eventloop.state.write[0], 0b0011_1011);
+    }
+
+    #[tokio::test]
+    async fn bulk_subscriptions_are_sent_in_one_packet() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 10);
+
+        let topics = vec![("hello/1", QoS::AtMostOnce), ("hello/2", QoS::AtLeastOnce)];
+        client.subscribe_many(topics).await.unwrap();
+        client
+            .unsubscribe_many(vec!["hello/1", "hello/2"])
+            .await
+            .unwrap();
+
+        match eventloop.requests_rx.recv().await.unwrap() {
+            Request::Subscribe(subscribe) => assert_eq!(subscribe.filters.len(), 2),
+            request => panic!("Unexpected request = {:?}", request),
+        }
+
+        match eventloop.requests_rx.recv().await.unwrap() {
+            Request::Unsubscribe(unsubscribe) => {
+                assert_eq!(unsubscribe.topics, vec!["hello/1", "hello/2"])
+            }
+            request => panic!("Unexpected request = {:?}", request),
+        }
+    }
+
+    #[test]
+    fn sync_publish_bytes_shares_the_payload() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (mut client, connection) = Client::new(options, 10);
+
+        let payload = Bytes::from(vec![1; 1024]);
+        client
+            .publish_bytes("hello/world", QoS::AtLeastOnce, true, payload.clone())
+            .unwrap();
+
+        let requests_rx = &connection.eventloop.requests_rx;
+        match requests_rx.try_recv().unwrap() {
+            Request::Publish(publish) => {
+                assert!(publish.retain);
+                assert_eq!(publish.payload.as_ptr(), payload.as_ptr());
+            }
+            request => panic!("Unexpected request = {:?}", request),
+        }
+    }
+
+    #[test]
+    fn shared_clients_report_full_and_closed_channels() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 1);
+        let client = &client;
+
+        let topics = vec![("hello/1", QoS::AtMostOnce)];
+        client.try_subscribe_many(topics.clone()).unwrap();
+        match client.try_subscribe_many(topics.clone()) {
+            Err(ClientError::TryRequest(TrySendError::Full(_))) => (),
+            result => panic!("Unexpected result = {:?}", result),
+        }
+
+        drop(eventloop);
+        match pollster::block_on(client.subscribe_many(topics)) {
+            Err(ClientError::Request(_)) => (),
+            result => panic!("Unexpected result = {:?}", result),
+        }
+
+        let mut client = Client {
+            client: client.clone(),
+        };
+        let payload = Bytes::from(vec![1, 2, 3]);
+        match client.publish_bytes("hello/world", QoS::AtLeastOnce, false, payload) {
+            Err(ClientError::Request(_)) => (),
+            result => panic!("Unexpected result = {:?}", result),
+        }
     }
 }
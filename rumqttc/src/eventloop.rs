@@ -1,22 +1,42 @@
+use crate::config::check_client_id;
+use crate::health::{ConnectionState, Health, HealthProbe};
+use crate::notifications::Backpressure;
+use crate::persist::Persistence;
 use crate::{framed::Network, Transport};
 use crate::{tls, Incoming, MqttState, Packet, Request, StateError};
-use crate::{MqttOptions, Outgoing};
+use crate::{MqttOptions, OptionError, Outgoing, OverflowPolicy, Stats};
 
 use async_channel::{bounded, Receiver, Sender};
 #[cfg(feature = "websocket")]
 use async_tungstenite::tokio::{connect_async, connect_async_with_tls_connector};
 use mqttbytes::v4::*;
+use mqttbytes::QoS;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::select;
+use tokio::sync::{mpsc, Notify};
 use tokio::time::{self, error::Elapsed, Instant, Sleep};
+use tokio_rustls::rustls::TLSError;
+#[cfg(feature = "socks5")]
+use tokio_socks::tcp::Socks5Stream;
 #[cfg(feature = "websocket")]
 use ws_stream_tungstenite::WsStream;
 
+use std::collections::VecDeque;
 use std::io;
+use std::net::SocketAddr;
+use std::ops::BitOr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::vec::IntoIter;
 
+/// Delay before starting a connection to the next address of the broker
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 /// Critical errors during eventloop polling
 #[derive(Debug, thiserror::Error)]
 pub enum ConnectionError {
@@ -26,6 +46,8 @@ pub enum ConnectionError {
     Timeout(#[from] Elapsed),
     #[error("Packet parsing error: {0}")]
     Mqtt4Bytes(mqttbytes::Error),
+    #[error("Incoming packet of {size} bytes exceeds the limit of {max} bytes")]
+    PacketTooLarge { size: usize, max: usize },
     #[error("Network: {0}")]
     Network(#[from] tls::Error),
     #[error("I/O: {0}")]
@@ -36,6 +58,82 @@ pub enum ConnectionError {
     RequestsDone,
     #[error("Cancel request by the user")]
     Cancel,
+    #[error("Notifications consumer is too slow")]
+    NotificationsFull,
+    #[error("No incoming activity for {0:?}")]
+    Inactive(Duration),
+    #[error("Invalid options: {0}")]
+    Options(#[from] OptionError),
+}
+
+/// Category of a `ConnectionError`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// Transient failures of the connection like resets, timeouts and closed
+    /// streams. Fixed by reconnecting
+    Network,
+    /// Broker or client violated mqtt. Reconnection starts a fresh protocol state
+    Protocol,
+    /// Broker rejected credentials or certificates. Retries fail the same way
+    Authentication,
+    /// Invalid options like bad client ids, certificates or addresses
+    Configuration,
+    /// Eventloop is stopped by the user or a slow consumer
+    Stopped,
+}
+
+impl ConnectionError {
+    /// Category of this error
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            ConnectionError::Io(_)
+            | ConnectionError::Timeout(_)
+            | ConnectionError::StreamDone
+            | ConnectionError::Inactive(_) => ErrorCategory::Network,
+            ConnectionError::Mqtt4Bytes(_) | ConnectionError::PacketTooLarge { .. } => {
+                ErrorCategory::Protocol
+            }
+            ConnectionError::MqttState(e) => {
+                match e {
+                    StateError::Io(_) | StateError::AwaitPingResp => ErrorCategory::Network,
+                    StateError::Connect(code) => match code {
+                        ConnectReturnCode::BadUserNamePassword
+                        | ConnectReturnCode::NotAuthorized => ErrorCategory::Authentication,
+                        ConnectReturnCode::RefusedProtocolVersion
+                        | ConnectReturnCode::BadClientId => ErrorCategory::Configuration,
+                        // broker is temporarily unavailable
+                        _ => ErrorCategory::Network,
+                    },
+                    _ => ErrorCategory::Protocol,
+                }
+            }
+            ConnectionError::Network(e) => match e {
+                tls::Error::Io(_) => ErrorCategory::Network,
+                tls::Error::WebPki(_) => ErrorCategory::Authentication,
+                tls::Error::TLS(e) => match e {
+                    TLSError::WebPKIError(_)
+                    | TLSError::General(_)
+                    | TLSError::NoCertificatesPresented => ErrorCategory::Authentication,
+                    _ => ErrorCategory::Network,
+                },
+                _ => ErrorCategory::Configuration,
+            },
+            ConnectionError::RequestsDone
+            | ConnectionError::Cancel
+            | ConnectionError::NotificationsFull => ErrorCategory::Stopped,
+            ConnectionError::Options(_) => ErrorCategory::Configuration,
+        }
+    }
+
+    /// Checks if a reconnection can fix this error. Network and protocol errors
+    /// are retryable. Authentication and configuration errors need user
+    /// intervention and stops are deliberate
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.category(),
+            ErrorCategory::Network | ErrorCategory::Protocol
+        )
+    }
 }
 
 /// Eventloop with all the state of a connection
@@ -48,16 +146,56 @@ pub struct EventLoop {
     pub requests_rx: Receiver<Request>,
     /// Requests handle to send requests
     pub requests_tx: Sender<Request>,
+    /// Priority request stream. Served before `requests_rx`
+    pub priority_rx: Receiver<Request>,
+    /// Handle to send priority requests
+    pub priority_tx: Sender<Request>,
     /// Pending packets from last session
     pub pending: IntoIter<Request>,
     /// Network connection to the broker
     pub(crate) network: Option<Network>,
     /// Keep alive time
     pub(crate) keepalive_timeout: Option<Pin<Box<Sleep>>>,
+    /// Time of the next stats event
+    pub(crate) stats_timeout: Option<Pin<Box<Sleep>>>,
+    /// Time of the next watchdog check
+    pub(crate) watchdog_timeout: Option<Pin<Box<Sleep>>>,
+    /// Time of the next liveness check
+    pub(crate) liveness_timeout: Option<Pin<Box<Sleep>>>,
     /// Handle to read cancellation requests
     pub(crate) cancel_rx: Receiver<()>,
     /// Handle to send cancellation requests (and drops)
     pub(crate) cancel_tx: Sender<()>,
+    /// Handle to read shutdown requests with their drain deadline
+    pub(crate) shutdown_rx: Receiver<Duration>,
+    /// Handle to send shutdown requests
+    pub(crate) shutdown_tx: Sender<Duration>,
+    /// Deadline of an ongoing graceful shutdown
+    pub(crate) shutdown: Option<Instant>,
+    /// Handle to read pause (true) and resume (false) requests
+    pub(crate) pause_rx: Receiver<bool>,
+    /// Handle to send pause and resume requests
+    pub(crate) pause_tx: Sender<bool>,
+    /// Set while the connection is paused by the user
+    pub(crate) paused: bool,
+    /// Consecutive failed reconnection attempts
+    pub(crate) reconnect_attempts: usize,
+    /// Set when a previous connection is cleaned. Next connection is a resumption
+    pub(crate) resuming: bool,
+    /// Report of the resumed session. Yielded after pending packets are replayed
+    pub(crate) report: Option<SessionReport>,
+    /// Notifies clients waiting for space in the requests channel
+    pub(crate) capacity: Arc<Notify>,
+    /// User supplied stream used for the next connection instead of dialing
+    pub(crate) stream: Option<Network>,
+    /// Requests pulled while (re)connecting. Sent after pending packets
+    pub(crate) offline: VecDeque<Request>,
+    /// Set while new requests wait for acks of retransmitted publishes
+    pub(crate) holding: bool,
+    /// Unacked outgoing publishes shared with clients
+    pub(crate) inflight: Arc<AtomicUsize>,
+    /// Connection status shared with health probes
+    pub(crate) health: Health,
 }
 
 /// Events which can be yielded by the event loop
@@ -65,6 +203,134 @@ pub struct EventLoop {
 pub enum Event {
     Incoming(Incoming),
     Outgoing(Outgoing),
+    /// Connection reestablished automatically after these many attempts
+    Reconnected(usize),
+    /// Summary of a reconnection cycle. Yielded after inflight packets of
+    /// previous connection are replayed
+    SessionResumed(SessionReport),
+    /// Periodic connection counters
+    Stats(Stats),
+    /// Round trip time of a ping. Yielded before its pingresp
+    PingRtt(Duration),
+    /// Result of each topic of a subscribe. Yielded before its suback
+    Subscribed(SubscribeResult),
+    /// Queued publish which wasn't sent before its expiry and is dropped
+    Expired(Publish),
+    /// Consumer of a spawned eventloop's notifications drains slower than
+    /// publishes arrive. See `MqttOptions::set_slow_consumer_window`
+    SlowConsumer(Backpressure),
+    /// Connection is closed by a pause request. State and queued requests
+    /// are retained and the eventloop reconnects on resume
+    Paused,
+    /// Broker didn't resume the session of a `clean_session = false` connection.
+    /// Subscriptions are restored and unacked publishes are retransmitted by
+    /// the eventloop. Applications should replay other session state they keep.
+    /// Yielded after the connack
+    SessionExpired,
+    /// Graceful shutdown is done and disconnect is sent. Eventloop yields
+    /// `ConnectionError::RequestsDone` after this
+    StreamEnd,
+}
+
+/// Kinds of events yielded by the eventloop. Kinds are combined with `|`. Events
+/// which aren't in the filter of `MqttOptions::set_event_filter` are handled
+/// by the eventloop but aren't yielded. Errors are always yielded
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EventFilter(u8);
+
+impl EventFilter {
+    /// Incoming publishes
+    pub const PUBLISHES: EventFilter = EventFilter(1);
+    /// Incoming acks of publishes, subscribes and unsubscribes
+    pub const ACKS: EventFilter = EventFilter(1 << 1);
+    /// Outgoing packets other than pings
+    pub const OUTGOING: EventFilter = EventFilter(1 << 2);
+    /// Pings, ping responses and `Event::PingRtt`
+    pub const PINGS: EventFilter = EventFilter(1 << 3);
+    /// Connacks, reconnections, session reports, pauses and stream ends
+    pub const CONNECTION: EventFilter = EventFilter(1 << 4);
+    /// Stats, subscribe results, expired publishes and slow consumer warnings
+    pub const REPORTS: EventFilter = EventFilter(1 << 5);
+    /// All the events
+    pub const ALL: EventFilter = EventFilter(u8::MAX);
+
+    /// Checks if all the kinds of `other` are in this filter
+    pub fn contains(self, other: EventFilter) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Checks if the event is yielded with this filter
+    pub fn allows(self, event: &Event) -> bool {
+        let kind = match event {
+            Event::Incoming(Packet::Publish(_)) => EventFilter::PUBLISHES,
+            Event::Incoming(Packet::PingResp) => EventFilter::PINGS,
+            Event::Incoming(Packet::PubAck(_))
+            | Event::Incoming(Packet::PubRec(_))
+            | Event::Incoming(Packet::PubRel(_))
+            | Event::Incoming(Packet::PubComp(_))
+            | Event::Incoming(Packet::SubAck(_))
+            | Event::Incoming(Packet::UnsubAck(_)) => EventFilter::ACKS,
+            Event::Incoming(_) => EventFilter::CONNECTION,
+            Event::Outgoing(Outgoing::PingReq) | Event::Outgoing(Outgoing::PingResp) => {
+                EventFilter::PINGS
+            }
+            Event::Outgoing(_) => EventFilter::OUTGOING,
+            Event::PingRtt(_) => EventFilter::PINGS,
+            Event::Reconnected(_)
+            | Event::SessionResumed(_)
+            | Event::SessionExpired
+            | Event::Paused
+            | Event::StreamEnd => EventFilter::CONNECTION,
+            Event::Stats(_) | Event::Subscribed(_) | Event::Expired(_) | Event::SlowConsumer(_) => {
+                EventFilter::REPORTS
+            }
+        };
+
+        self.contains(kind)
+    }
+}
+
+impl BitOr for EventFilter {
+    type Output = EventFilter;
+
+    fn bitor(self, other: EventFilter) -> EventFilter {
+        EventFilter(self.0 | other.0)
+    }
+}
+
+/// Summary of a session after reconnection. Helps to verify that
+/// no data is silently dropped during reconnections
+#[derive(Debug, PartialEq, Clone)]
+pub struct SessionReport {
+    /// Broker resumed previous session
+    pub session_present: bool,
+    /// Unacked publishes and releases of previous connection which are replayed
+    pub inflight_replayed: usize,
+    /// Subscriptions restored by the broker from previous session
+    pub subscriptions_restored: usize,
+    /// Requests buffered while disconnected which are flushed after the replay
+    pub offline_buffered: usize,
+}
+
+/// Return codes of a subscribe matched with its topics. Rejected topics
+/// are removed from the tracked subscriptions of the state and downgraded
+/// topics are resubscribed with the granted qos
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubscribeResult {
+    pub pkid: u16,
+    pub results: Vec<(String, SubscribeReasonCode)>,
+    /// Topics granted a lower qos than requested and the granted qos
+    pub downgrades: Vec<(String, QoS)>,
+}
+
+impl SubscribeResult {
+    /// Topics rejected by the broker
+    pub fn failures(&self) -> impl Iterator<Item = &str> {
+        self.results
+            .iter()
+            .filter(|(_, code)| *code == SubscribeReasonCode::Failure)
+            .map(|(topic, _)| topic.as_str())
+    }
 }
 
 impl EventLoop {
@@ -74,21 +340,67 @@ impl EventLoop {
     /// access and update `options`, `state` and `requests`.
     pub fn new(options: MqttOptions, cap: usize) -> EventLoop {
         let (cancel_tx, cancel_rx) = bounded(5);
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+        let (pause_tx, pause_rx) = bounded(5);
         let (requests_tx, requests_rx) = bounded(cap);
+        let (priority_tx, priority_rx) = bounded(cap);
         let pending = Vec::new();
         let pending = pending.into_iter();
         let max_inflight = options.inflight;
+        let mut state = MqttState::new(max_inflight);
+        let pkid_range = options.pkid_range();
+        state.set_pkid_range(*pkid_range.start(), *pkid_range.end());
+        state.pkid_exhaustion = options.pkid_exhaustion();
+        state.max_subscribe_qos = options.max_subscribe_qos();
+        state.manual_acks = options.manual_acks();
+        state.max_missed_pings = options.max_missed_pings();
+        state.dedup_window = options.dedup_window();
+        state.aliases = options.topic_aliases().clone();
+        state.transforms = options.transforms.clone();
+        state.chunking = options.chunking.clone();
+        if let Some((path, max_bytes)) = options.persistence() {
+            match Persistence::open(path, max_bytes) {
+                Ok((persistence, snapshot)) => {
+                    if let Err(e) = state.restore(snapshot) {
+                        error!("Failed to restore persisted publishes. Error = {:?}", e);
+                    }
+
+                    state.persistence = Some(persistence);
+                }
+                Err(e) => error!("Failed to open persistence {:?}. Error = {:?}", path, e),
+            }
+        }
 
         EventLoop {
             options,
-            state: MqttState::new(max_inflight),
+            state,
             requests_tx,
             requests_rx,
+            priority_tx,
+            priority_rx,
             pending,
             network: None,
             keepalive_timeout: None,
+            stats_timeout: None,
+            watchdog_timeout: None,
+            liveness_timeout: None,
             cancel_rx,
             cancel_tx,
+            shutdown_rx,
+            shutdown_tx,
+            shutdown: None,
+            pause_rx,
+            pause_tx,
+            paused: false,
+            reconnect_attempts: 0,
+            resuming: false,
+            report: None,
+            capacity: Arc::new(Notify::new()),
+            stream: None,
+            offline: VecDeque::new(),
+            holding: false,
+            inflight: Arc::new(AtomicUsize::new(0)),
+            health: Health::new(),
         }
     }
 
@@ -97,6 +409,37 @@ impl EventLoop {
         self.requests_tx.clone()
     }
 
+    /// Feeds requests of a blocking source like `std::sync::mpsc::Receiver` or
+    /// `crossbeam_channel::Receiver` to this eventloop from a new thread. Lets
+    /// synchronous producer threads publish without a tokio bridge. The thread
+    /// exits when the source is done (all its senders are dropped) or the
+    /// eventloop is dropped
+    pub fn forward_requests<R>(&self, requests: R) -> thread::JoinHandle<()>
+    where
+        R: IntoIterator<Item = Request> + Send + 'static,
+    {
+        let requests_tx = self.handle();
+        thread::Builder::new()
+            .name("rumqttc-requests".to_owned())
+            .spawn(move || {
+                for request in requests {
+                    if pollster::block_on(requests_tx.send(request)).is_err() {
+                        warn!("Eventloop is dropped. Stopping request forwarding");
+                        return;
+                    }
+                }
+
+                debug!("Request source is done");
+            })
+            .expect("Failed to spawn request forwarding thread")
+    }
+
+    /// Returns a handle to send requests (e.g alarms) which jump ahead of the
+    /// requests queued in `handle()`'s channel and the offline buffer
+    pub fn priority_handle(&self) -> Sender<Request> {
+        self.priority_tx.clone()
+    }
+
     /// Handle for cancelling the eventloop.
     ///
     /// Can be useful in cases when connection should be halted immediately
@@ -105,11 +448,81 @@ impl EventLoop {
         self.cancel_tx.clone()
     }
 
+    /// Handle for graceful shutdown of the eventloop
+    pub(crate) fn shutdown_handle(&mut self) -> Sender<Duration> {
+        self.shutdown_tx.clone()
+    }
+
+    /// Handle for pausing and resuming the connection
+    pub(crate) fn pause_handle(&mut self) -> Sender<bool> {
+        self.pause_tx.clone()
+    }
+
+    /// Starts a graceful shutdown. New requests are rejected while queued
+    /// requests, pending packets and inflight publishes are flushed and acked
+    /// within `deadline`. Disconnect is sent after that (or when the deadline
+    /// elapses) and polling yields `Event::StreamEnd`
+    pub fn shutdown(&mut self, deadline: Duration) {
+        info!("Shutting down. Deadline = {:?}", deadline);
+        self.requests_rx.close();
+        self.priority_rx.close();
+        self.shutdown = Some(Instant::now() + deadline);
+    }
+
+    /// Uses an already established stream (serial port, quic stream, in memory
+    /// duplex etc) for the next connection instead of dialing the broker with
+    /// the configured transport. Mqtt connection is still made by the eventloop.
+    /// Stream is used for one connection. Set a new one after disconnection or
+    /// the eventloop falls back to the configured transport
+    pub fn set_network<S>(&mut self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let network = Network::new(stream, self.options.max_incoming_packet_size);
+        self.stream = Some(network);
+    }
+
+    /// Handle to the connection status of this eventloop for readiness probes
+    pub fn health(&self) -> HealthProbe {
+        self.health.probe()
+    }
+
+    /// Packet, byte, reconnection and ping counters of this eventloop
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.state.stats.clone();
+        stats.inflight = self.state.inflight;
+        stats
+    }
+
+    /// `poll` for executors other than tokio (async-std, smol, futures). Tokio
+    /// sockets and timers of the eventloop are driven by a shared background
+    /// runtime, so this can be awaited outside of a tokio runtime
+    #[cfg(feature = "compat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "compat")))]
+    pub async fn poll_compat(&mut self) -> Result<Event, ConnectionError> {
+        async_compat::Compat::new(self.poll()).await
+    }
+
     fn clean(&mut self) {
         self.network = None;
         self.keepalive_timeout = None;
-        let pending = self.state.clean();
+        let mut pending = self.state.clean();
+        if !self.options.retransmit_inflight() && !pending.is_empty() {
+            warn!(
+                "Dropping {} unacked packets of previous connection",
+                pending.len()
+            );
+            pending.clear();
+            self.state.tokens.clear();
+            if let Some(persistence) = &self.state.persistence {
+                if let Err(e) = persistence.clear() {
+                    error!("Failed to clear persistence. Error = {:?}", e);
+                }
+            }
+        }
+
         self.pending = pending.into_iter();
+        self.resuming = true;
     }
 
     /// Yields Next notification or outgoing request and periodically pings
@@ -118,12 +531,173 @@ impl EventLoop {
     /// **NOTE** Don't block this while iterating
     #[must_use = "Eventloop should be iterated over a loop to make progress"]
     pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
+        loop {
+            let o = self.poll_once().await;
+            let inflight = self.state.inflight() as usize;
+            self.inflight.store(inflight, Ordering::Relaxed);
+
+            let error = match o {
+                Ok(event) => {
+                    self.update_health(None);
+                    if !self.options.event_filter().allows(&event) {
+                        continue;
+                    }
+
+                    return Ok(event);
+                }
+                Err(e) => e,
+            };
+
+            self.update_health(Some(&error));
+
+            let reconnect = match &self.options.reconnect {
+                Some(reconnect) => reconnect,
+                None => return Err(error),
+            };
+
+            if !reconnect.should_retry(&error, self.reconnect_attempts) {
+                self.reconnect_attempts = 0;
+                return Err(error);
+            }
+
+            let delay = reconnect.delay(self.reconnect_attempts);
+            self.reconnect_attempts += 1;
+            warn!(
+                "Connection error = {:?}. Reconnecting in {:?}. Attempt = {}",
+                error, delay, self.reconnect_attempts
+            );
+            trace_event!(warn, error = %error, ?delay, attempt = self.reconnect_attempts, "reconnecting");
+            self.health
+                .update(|status| status.state = ConnectionState::Reconnecting);
+
+            // sleep here shouldn't block cancellation requests
+            let sleep = time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                select! {
+                    _ = &mut sleep => break,
+                    Ok(request) = self.requests_rx.recv(), if buffering(&self.options, &self.offline) => {
+                        buffer(&self.options, &mut self.offline, request);
+                    }
+                    _ = self.cancel_rx.recv() => return Err(ConnectionError::Cancel),
+                }
+            }
+        }
+    }
+
+    async fn poll_once(&mut self) -> Result<Event, ConnectionError> {
+        // Requests channel is closed by a finished shutdown
+        if self.network.is_none() && self.shutdown.is_none() && self.requests_rx.is_closed() {
+            return Err(ConnectionError::RequestsDone);
+        }
+
+        // Stay disconnected till a resume. Shutdown requests resume to drain
+        while self.paused {
+            select! {
+                Ok(pause) = self.pause_rx.recv() => self.paused = pause,
+                Ok(deadline) = self.shutdown_rx.recv() => {
+                    self.shutdown(deadline);
+                    self.paused = false;
+                }
+                _ = self.cancel_rx.recv() => return Err(ConnectionError::Cancel),
+            }
+        }
+
         if self.network.is_none() {
-            let (network, connack) = connect_or_cancel(&self.options, &self.cancel_rx).await?;
+            // Inflight packets of a restored state snapshot are replayed after connection
+            if self.state.inflight() > 0 && self.pending.len() == 0 {
+                let pending = self.state.clean();
+                self.pending = pending.into_iter();
+            }
+
+            let stream = self.stream.take();
+            let o = connect_or_cancel(
+                &self.options,
+                stream,
+                &self.requests_rx,
+                &mut self.offline,
+                &self.cancel_rx,
+            )
+            .await;
+
+            let (network, connack) = match o {
+                Ok(v) => v,
+                Err(ConnectionError::Cancel) => return Err(ConnectionError::Cancel),
+                Err(e) => {
+                    trace_event!(warn, error = %e, "connection failed");
+                    self.options.rotate_endpoint();
+                    return Err(e);
+                }
+            };
             self.network = Some(network);
+            trace_event!(info, ?connack, "connected");
+
+            if let Some((rate, burst)) = self.options.bandwidth_throttle() {
+                self.network.as_mut().unwrap().set_bandwidth(rate, burst);
+            }
+
+            // Zero keep alive disables pings
+            let keep_alive = self.options.keep_alive;
+            if self.keepalive_timeout.is_none() && keep_alive.as_secs() > 0 {
+                self.keepalive_timeout = Some(Box::pin(time::sleep(keep_alive)));
+            }
+
+            if let (None, Some(interval)) = (&self.stats_timeout, self.options.stats_interval()) {
+                self.stats_timeout = Some(Box::pin(time::sleep(interval)));
+            }
+
+            // Inactivity windows start with the connection
+            if let Some(window) = self.options.watchdog() {
+                self.state.last_activity = Instant::now().into_std();
+                self.watchdog_timeout = Some(Box::pin(time::sleep(window)));
+            }
+
+            if let Some(timeout) = self.options.liveness_timeout() {
+                self.liveness_timeout = Some(Box::pin(time::sleep(timeout)));
+            }
+
+            // Broker doesn't redeliver QoS 2 publishes of a session it didn't resume
+            if let Incoming::ConnAck(ConnAck {
+                session_present: false,
+                ..
+            }) = &connack
+            {
+                self.state.clean_incoming();
+
+                // Persistent session is lost. Replays are adapted to the new session
+                if !self.options.clean_session() {
+                    warn!("Broker didn't resume the session. Restoring subscriptions");
+                    let pending = self.pending.by_ref().collect();
+                    self.pending = self.state.expire_session(pending).into_iter();
+                    self.state.events.push_back(Event::SessionExpired);
+                }
+            }
+
+            if self.resuming {
+                self.resuming = false;
+                self.state.stats.reconnects += 1;
+                self.report = Some(self.session_report(&connack));
+            }
+
+            // Requests queued while connecting are sent after pending packets.
+            // Priority requests go ahead of the offline buffer. With strict
+            // ordering, they are held till the pending packets are acked
+            self.holding = self.options.strict_ordering() && self.pending.len() > 0;
+            if !self.holding && (!self.offline.is_empty() || !self.priority_rx.is_empty()) {
+                let mut pending: Vec<Request> = self.pending.by_ref().collect();
+                while let Ok(request) = self.priority_rx.try_recv() {
+                    pending.push(request);
+                }
 
-            if self.keepalive_timeout.is_none() {
-                self.keepalive_timeout = Some(Box::pin(time::sleep(self.options.keep_alive)));
+                pending.extend(self.offline.drain(..));
+                self.pending = pending.into_iter();
+            }
+
+            // Automatic reconnections are reported before the connack
+            if self.reconnect_attempts > 0 {
+                let attempts = std::mem::replace(&mut self.reconnect_attempts, 0);
+                self.state.events.push_back(Event::Incoming(connack));
+                return Ok(Event::Reconnected(attempts));
             }
 
             return Ok(Event::Incoming(connack));
@@ -132,104 +706,310 @@ impl EventLoop {
         match self.select().await {
             Ok(v) => Ok(v),
             Err(e) => {
+                trace_event!(warn, error = %e, inflight = self.state.inflight, "disconnected");
                 self.clean();
                 Err(e)
             }
         }
     }
 
+    fn update_health(&self, error: Option<&ConnectionError>) {
+        let state = match (&self.network, self.paused) {
+            (Some(_), _) => ConnectionState::Connected,
+            (None, true) => ConnectionState::Paused,
+            (None, false) => ConnectionState::Disconnected,
+        };
+
+        let inflight = self.state.inflight();
+        let reconnects = self.state.stats.reconnects;
+        self.health.update(|status| {
+            status.state = state;
+            status.inflight = inflight;
+            status.reconnects = reconnects;
+            if let Some(error) = error {
+                status.last_error = Some(error.to_string());
+            }
+        });
+    }
+
+    fn session_report(&self, connack: &Incoming) -> SessionReport {
+        let session_present = match connack {
+            Incoming::ConnAck(connack) => connack.session_present,
+            _ => false,
+        };
+
+        // broker drops subscriptions when it doesn't resume the session
+        let subscriptions_restored = match session_present {
+            true => self.state.subscriptions.len(),
+            false => 0,
+        };
+
+        SessionReport {
+            session_present,
+            inflight_replayed: self.pending.len(),
+            subscriptions_restored,
+            offline_buffered: self.requests_rx.len() + self.priority_rx.len() + self.offline.len(),
+        }
+    }
+
     /// Select on network and requests and generate keepalive pings when necessary
     async fn select(&mut self) -> Result<Event, ConnectionError> {
-        let network = self.network.as_mut().unwrap();
-        // let await_acks = self.state.await_acks;
-        let inflight_full = self.state.inflight >= self.options.inflight;
-        let throttle = self.options.pending_throttle;
-        let pending = self.pending.len() > 0;
-        let collision = self.state.collision.is_some();
-
-        // Read buffered events from previous polls before calling a new poll
-        if let Some(event) = self.state.events.pop_front() {
-            return Ok(event);
-        }
+        loop {
+            // Retransmissions are acked. Release the held requests
+            if self.holding && self.pending.len() == 0 && self.state.inflight() == 0 {
+                debug!(
+                    "Retransmissions acked. Releasing {} held requests",
+                    self.offline.len()
+                );
+                self.holding = false;
+                self.pending = self.offline.drain(..).collect::<Vec<_>>().into_iter();
+            }
 
-        // this loop is necessary since self.incoming.pop_front() might return None. In that case,
-        // instead of returning a None event, we try again.
-        select! {
-            // Pull a bunch of packets from network, reply in bunch and yield the first item
-            o = network.readb(&mut self.state) => {
-                o?;
-                // flush all the acks and return first incoming packet
-                network.flush(&mut self.state.write).await?;
-                Ok(self.state.events.pop_front().unwrap())
-            },
-            // Pull next request from user requests channel.
-            // If conditions in the below branch are for flow control. We read next user
-            // user request only when inflight messages are < configured inflight and there
-            // are no collisions while handling previous outgoing requests.
-            //
-            // Flow control is based on ack count. If inflight packet count in the buffer is
-            // less than max_inflight setting, next outgoing request will progress. For this
-            // to work correctly, broker should ack in sequence (a lot of brokers won't)
-            //
-            // E.g If max inflight = 5, user requests will be blocked when inflight queue
-            // looks like this                 -> [1, 2, 3, 4, 5].
-            // If broker acking 2 instead of 1 -> [1, x, 3, 4, 5].
-            // This pulls next user request. But because max packet id = max_inflight, next
-            // user request's packet id will roll to 1. This replaces existing packet id 1.
-            // Resulting in a collision
-            //
-            // Eventloop can stop receiving outgoing user requests when previous outgoing
-            // request collided. I.e collision state. Collision state will be cleared only
-            // when correct ack is received
-            // Full inflight queue will look like -> [1a, 2, 3, 4, 5].
-            // If 3 is acked instead of 1 first   -> [1a, 2, x, 4, 5].
-            // After collision with pkid 1        -> [1b ,2, x, 4, 5].
-            // 1a is saved to state and event loop is set to collision mode stopping new
-            // outgoing requests (along with 1b).
-            o = self.requests_rx.recv(), if !inflight_full && !pending && !collision => match o {
-                Ok(request) => {
+            let network = self.network.as_mut().unwrap();
+            // let await_acks = self.state.await_acks;
+            let inflight_full = self.state.inflight >= self.options.inflight;
+            let throttle = self.options.pending_throttle;
+            let pending = self.pending.len() > 0;
+            let collision = self.state.collision.is_some();
+            let max_size = self.options.max_incoming_packet_size;
+            let prioritized = !self.priority_rx.is_empty();
+            let holding = self.holding;
+            let chunked = !self.state.chunks.is_empty();
+
+            // Read buffered events from previous polls before calling a new poll
+            if let Some(event) = self.state.events.pop_front() {
+                return Ok(event);
+            }
+
+            // Report resumed session once replay of previous connection is done
+            if !pending {
+                if let Some(report) = self.report.take() {
+                    return Ok(Event::SessionResumed(report));
+                }
+            }
+
+            // Disconnect once everything is flushed and acked or the deadline elapses
+            let draining = self.shutdown.is_some() && self.requests_rx.is_empty() && !prioritized;
+            if let Some(deadline) = self.shutdown {
+                let drained = draining && !pending && !chunked && self.state.inflight() == 0;
+                if drained || Instant::now() >= deadline {
+                    if !drained {
+                        warn!(
+                            "Shutdown deadline elapsed. Inflight = {}, Queued = {}",
+                            self.state.inflight(),
+                            self.requests_rx.len() + self.priority_rx.len() + self.pending.len()
+                        );
+                    }
+
+                    self.state.handle_outgoing_packet(Request::Disconnect)?;
+                    network.flush(&mut self.state.write).await?;
+                    self.state.events.clear();
+                    self.network = None;
+                    self.keepalive_timeout = None;
+                    self.shutdown = None;
+                    return Ok(Event::StreamEnd);
+                }
+            }
+
+            // Arms which handle packets continue the loop, which yields the first queued event.
+            // Some packets (e.g partial chunks of QoS 0 publishes) queue no events. In that
+            // case, instead of returning a None event, we try again.
+            return select! {
+                // Pull a bunch of packets from network, reply in bunch and yield the first item
+                o = network.readb(&mut self.state) => {
+                    o.map_err(|e| protocol_error(e, max_size))?;
+                    // flush all the acks and return first incoming packet
+                    network.flush(&mut self.state.write).await?;
+                    continue;
+                },
+                // Pull next request from user requests channel.
+                // If conditions in the below branch are for flow control. We read next user
+                // user request only when inflight messages are < configured inflight and there
+                // are no collisions while handling previous outgoing requests.
+                //
+                // Flow control is based on ack count. If inflight packet count in the buffer is
+                // less than max_inflight setting, next outgoing request will progress. For this
+                // to work correctly, broker should ack in sequence (a lot of brokers won't)
+                //
+                // E.g If max inflight = 5, user requests will be blocked when inflight queue
+                // looks like this                 -> [1, 2, 3, 4, 5].
+                // If broker acking 2 instead of 1 -> [1, x, 3, 4, 5].
+                // This pulls next user request. But because max packet id = max_inflight, next
+                // user request's packet id will roll to 1. This replaces existing packet id 1.
+                // Resulting in a collision
+                //
+                // Eventloop can stop receiving outgoing user requests when previous outgoing
+                // request collided. I.e collision state. Collision state will be cleared only
+                // when correct ack is received
+                // Full inflight queue will look like -> [1a, 2, 3, 4, 5].
+                // If 3 is acked instead of 1 first   -> [1a, 2, x, 4, 5].
+                // After collision with pkid 1        -> [1b ,2, x, 4, 5].
+                // 1a is saved to state and event loop is set to collision mode stopping new
+                // outgoing requests (along with 1b).
+                //
+                // Priority requests are pulled first. Normal requests wait till the priority
+                // channel is empty
+                Ok(request) = self.priority_rx.recv(), if !inflight_full && !pending && !collision && !draining && !holding => {
                     self.state.handle_outgoing_packet(request)?;
+                    self.capacity.notify_waiters();
+                    network.flush(&mut self.state.write).await?;
+                    continue;
+                },
+                // Remaining chunks of a split publish go out before new requests
+                _ = std::future::ready(()), if chunked && !inflight_full && !pending && !collision && !prioritized && !holding => {
+                    self.state.handle_outgoing_chunk()?;
+                    network.flush(&mut self.state.write).await?;
+                    continue;
+                },
+                o = self.requests_rx.recv(), if !inflight_full && !pending && !collision && !draining && !prioritized && !holding && !chunked => match o {
+                    Ok(request) => {
+                        self.state.handle_outgoing_packet(request)?;
+
+                        // Coalesce requests which are already ready into the same write.
+                        // Outgoing events of the batch are yielded by next polls
+                        let (batch, bytes) = self.options.request_batch();
+                        for _ in 1..batch {
+                            let inflight_full = self.state.inflight >= self.options.inflight;
+                            let collision = self.state.collision.is_some();
+                            let prioritized = !self.priority_rx.is_empty();
+                            let full = self.state.write.len() >= bytes;
+                            let chunked = !self.state.chunks.is_empty();
+                            if inflight_full || collision || prioritized || full || chunked {
+                                break;
+                            }
+
+                            match self.requests_rx.try_recv() {
+                                Ok(request) => self.state.handle_outgoing_packet(request)?,
+                                Err(_) => break,
+                            }
+                        }
+
+                        self.capacity.notify_waiters();
+                        network.flush(&mut self.state.write).await?;
+                        continue;
+                    }
+                    Err(_) => Err(ConnectionError::RequestsDone),
+                },
+                // Handle the next pending packet from previous session. Disable
+                // this branch when done with all the pending packets
+                Some(request) = next_pending(throttle, &mut self.pending), if pending => {
+                    self.state.handle_outgoing_packet(request)?;
+                    network.flush(&mut self.state.write).await?;
+                    continue;
+                },
+                // We generate pings irrespective of network activity. This keeps the ping logic
+                // simple. We can change this behavior in future if necessary (to prevent extra pings)
+                _ = next_tick(self.keepalive_timeout.as_mut()) => {
+                    let timeout = self.keepalive_timeout.as_mut().unwrap();
+                    timeout.as_mut().reset(Instant::now() + self.options.keep_alive);
+
+                    self.state.handle_outgoing_packet(Request::PingReq)?;
                     network.flush(&mut self.state.write).await?;
-                    Ok(self.state.events.pop_front().unwrap())
+                    continue;
                 }
-                Err(_) => Err(ConnectionError::RequestsDone),
-            },
-            // Handle the next pending packet from previous session. Disable
-            // this branch when done with all the pending packets
-            Some(request) = next_pending(throttle, &mut self.pending), if pending => {
-                self.state.handle_outgoing_packet(request)?;
-                network.flush(&mut self.state.write).await?;
-                Ok(self.state.events.pop_front().unwrap())
-            },
-            // We generate pings irrespective of network activity. This keeps the ping logic
-            // simple. We can change this behavior in future if necessary (to prevent extra pings)
-            _ = self.keepalive_timeout.as_mut().unwrap() => {
-                let timeout = self.keepalive_timeout.as_mut().unwrap();
-                timeout.as_mut().reset(Instant::now() + self.options.keep_alive);
+                _ = next_tick(self.stats_timeout.as_mut()) => {
+                    let timeout = self.stats_timeout.as_mut().unwrap();
+                    let interval = self.options.stats_interval().unwrap();
+                    timeout.as_mut().reset(Instant::now() + interval);
+                    Ok(Event::Stats(self.stats()))
+                }
+                // Socket is open but publishes and acks stopped flowing. Cycle the connection
+                _ = next_tick(self.watchdog_timeout.as_mut()) => {
+                    let window = self.options.watchdog().unwrap();
+                    check_idle(&mut self.watchdog_timeout, self.state.last_activity, window)?;
+                    continue;
+                }
+                // Nothing is heard from the broker, pings included. Cycle the connection
+                _ = next_tick(self.liveness_timeout.as_mut()) => {
+                    let timeout = self.options.liveness_timeout().unwrap();
+                    check_idle(&mut self.liveness_timeout, self.state.last_incoming, timeout)?;
+                    continue;
+                }
+                // shutdown requests from clients. Drains before disconnecting
+                Ok(deadline) = self.shutdown_rx.recv(), if self.shutdown.is_none() => {
+                    self.shutdown(deadline);
+                    continue;
+                }
+                _ = next_deadline(self.shutdown) => continue,
+                // pause requests close the connection. Resumes of a live connection are ignored
+                Ok(pause) = self.pause_rx.recv() => {
+                    if !pause {
+                        continue;
+                    }
 
-                self.state.handle_outgoing_packet(Request::PingReq)?;
-                network.flush(&mut self.state.write).await?;
-                Ok(self.state.events.pop_front().unwrap())
-            }
-            // cancellation requests to stop the polling
-            _ = self.cancel_rx.recv() => {
-                Err(ConnectionError::Cancel)
-            }
+                    info!("Pausing connection. Inflight = {}", self.state.inflight());
+                    self.state.handle_outgoing_packet(Request::Disconnect)?;
+                    network.flush(&mut self.state.write).await?;
+                    self.state.events.clear();
+                    self.clean();
+                    self.paused = true;
+                    Ok(Event::Paused)
+                }
+                // cancellation requests to stop the polling
+                _ = self.cancel_rx.recv() => {
+                    Err(ConnectionError::Cancel)
+                }
+            };
         }
     }
 }
 
 async fn connect_or_cancel(
     options: &MqttOptions,
+    stream: Option<Network>,
+    requests_rx: &Receiver<Request>,
+    offline: &mut VecDeque<Request>,
     cancel_rx: &Receiver<()>,
 ) -> Result<(Network, Incoming), ConnectionError> {
+    let connect = connect(options, stream);
+    tokio::pin!(connect);
+
     // select here prevents cancel request from being blocked until connection request is
     // resolved. Returns with an error if connections fail continuously
-    select! {
-        o = connect(options) => o,
-        _ = cancel_rx.recv() => {
-            Err(ConnectionError::Cancel)
+    loop {
+        select! {
+            o = &mut connect => return o,
+            Ok(request) = requests_rx.recv(), if buffering(options, offline) => {
+                buffer(options, offline, request);
+            }
+            _ = cancel_rx.recv() => {
+                return Err(ConnectionError::Cancel)
+            }
+        }
+    }
+}
+
+/// Checks if requests are pulled into the offline buffer
+fn buffering(options: &MqttOptions, offline: &VecDeque<Request>) -> bool {
+    match options.offline_buffer() {
+        Some((capacity, OverflowPolicy::Error)) => offline.len() < capacity,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Buffers a request pulled while (re)connecting as per the overflow policy
+fn buffer(options: &MqttOptions, offline: &mut VecDeque<Request>, request: Request) {
+    let (capacity, policy) = match options.offline_buffer() {
+        Some(v) => v,
+        None => return,
+    };
+
+    if offline.len() < capacity {
+        offline.push_back(request);
+        return;
+    }
+
+    match policy {
+        OverflowPolicy::DropOldest => {
+            offline.push_back(request);
+            while offline.len() > capacity {
+                let dropped = offline.pop_front();
+                warn!("Offline buffer full. Dropping oldest = {:?}", dropped);
+            }
         }
+        OverflowPolicy::DropNewest => warn!("Offline buffer full. Dropping = {:?}", request),
+        OverflowPolicy::Error => unreachable!("Requests aren't pulled into a full buffer"),
     }
 }
 
@@ -238,15 +1018,29 @@ async fn connect_or_cancel(
 /// the stream.
 /// This function (for convenience) includes internal delays for users to perform internal sleeps
 /// between re-connections so that cancel semantics can be used during this sleep
-async fn connect(options: &MqttOptions) -> Result<(Network, Incoming), ConnectionError> {
-    // connect to the broker
-    let mut network = match network_connect(options).await {
-        Ok(network) => network,
-        Err(e) => {
-            return Err(e);
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(broker = %options.broker_addr, port = options.port))
+)]
+async fn connect(
+    options: &MqttOptions,
+    stream: Option<Network>,
+) -> Result<(Network, Incoming), ConnectionError> {
+    check_client_id(options)?;
+
+    // connect to the broker unless user supplied a stream
+    let mut network = match stream {
+        Some(network) => network,
+        None => {
+            let timeout = Duration::from_secs(options.connection_timeout());
+            time::timeout(timeout, network_connect(options)).await??
         }
     };
 
+    if let Some(tap) = options.wire_tap() {
+        network.set_wire_tap(tap);
+    }
+
     // make MQTT connection request (which internally awaits for ack)
     let packet = match mqtt_connect(options, &mut network).await {
         Ok(p) => p,
@@ -261,12 +1055,11 @@ async fn connect(options: &MqttOptions) -> Result<(Network, Incoming), Connectio
     Ok((network, packet))
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionError> {
     let network = match options.transport() {
         Transport::Tcp => {
-            let addr = options.broker_addr.as_str();
-            let port = options.port;
-            let socket = TcpStream::connect((addr, port)).await?;
+            let socket = tcp_connect(options).await?;
             Network::new(socket, options.max_incoming_packet_size)
         }
         Transport::Tls(tls_config) => {
@@ -277,8 +1070,8 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         Transport::Ws => {
             let request = http::Request::builder()
                 .method(http::Method::GET)
-                .uri(options.broker_addr.as_str())
-                .header("Sec-WebSocket-Protocol", "mqttv3.1")
+                .uri(options.ws_url("ws"))
+                .header("Sec-WebSocket-Protocol", "mqtt")
                 .body(())
                 .unwrap();
 
@@ -292,12 +1085,12 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         Transport::Wss(tls_config) => {
             let request = http::Request::builder()
                 .method(http::Method::GET)
-                .uri(options.broker_addr.as_str())
-                .header("Sec-WebSocket-Protocol", "mqttv3.1")
+                .uri(options.ws_url("wss"))
+                .header("Sec-WebSocket-Protocol", "mqtt")
                 .body(())
                 .unwrap();
 
-            let connector = tls::tls_connector(&tls_config).await?;
+            let connector = tls::tls_connector(&tls_config, options).await?;
 
             let (socket, _) = connect_async_with_tls_connector(request, Some(connector))
                 .await
@@ -310,6 +1103,112 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
     Ok(network)
 }
 
+/// Tcp connection to the broker with nodelay and keepalive of the options
+pub(crate) async fn tcp_connect(options: &MqttOptions) -> io::Result<TcpStream> {
+    let stream = dial(options).await?;
+    stream.set_nodelay(options.tcp_nodelay())?;
+    if let Some(idle) = options.tcp_keepalive() {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+    }
+
+    Ok(stream)
+}
+
+/// Tunnels through socks5 proxy when configured. Otherwise connects to one of
+/// the resolved addresses with happy eyeballs
+async fn dial(options: &MqttOptions) -> io::Result<TcpStream> {
+    #[cfg(feature = "socks5")]
+    if let Some(proxy) = options.socks5_proxy() {
+        let (addr, port) = (options.broker_addr.as_str(), options.port);
+        let proxy_addr = (proxy.host.as_str(), proxy.port);
+        let stream = match &proxy.credentials {
+            Some((username, password)) => {
+                Socks5Stream::connect_with_password(proxy_addr, (addr, port), username, password)
+                    .await
+            }
+            None => Socks5Stream::connect(proxy_addr, (addr, port)).await,
+        };
+
+        let stream = stream.map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+        return Ok(stream.into_inner());
+    }
+
+    let addrs = options.resolve().await?;
+    happy_eyeballs(addrs).await
+}
+
+/// Connects to the first reachable address. Addresses are tried alternating
+/// between address families and a new attempt starts every `ATTEMPT_DELAY`
+/// (or as soon as an attempt fails) without cancelling the running ones
+async fn happy_eyeballs(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut addrs = interleave(addrs).into_iter();
+    if addrs.len() == 1 {
+        return TcpStream::connect(addrs.next().unwrap()).await;
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut attempts = Vec::new();
+    let mut running = 0;
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "Broker address not resolved");
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            let tx = tx.clone();
+            attempts.push(tokio::spawn(async move {
+                let _ = tx.send((addr, TcpStream::connect(addr).await));
+            }));
+            running += 1;
+        }
+
+        if running == 0 {
+            return Err(error);
+        }
+
+        // Wait for an attempt to finish or for the delay before the next attempt
+        select! {
+            Some((addr, o)) = rx.recv() => {
+                running -= 1;
+                match o {
+                    Ok(stream) => {
+                        attempts.iter().for_each(|attempt| attempt.abort());
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        debug!("Connection to {} failed. Error = {:?}", addr, e);
+                        error = e;
+                    }
+                }
+            }
+            _ = time::sleep(ATTEMPT_DELAY), if addrs.len() > 0 => {}
+        }
+    }
+}
+
+/// Orders addresses alternating between address families, starting with the
+/// family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_ipv6 = matches!(addrs.first(), Some(addr) if addr.is_ipv6());
+    let count = addrs.len();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut addrs = Vec::with_capacity(count);
+    while addrs.len() < count {
+        addrs.extend(preferred.next());
+        addrs.extend(other.next());
+    }
+
+    addrs
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(client_id = %options.client_id()))
+)]
 async fn mqtt_connect(
     options: &MqttOptions,
     network: &mut Network,
@@ -323,7 +1222,7 @@ async fn mqtt_connect(
     connect.clean_session = clean_session;
     connect.last_will = last_will;
 
-    if let Some((username, password)) = options.credentials() {
+    if let Some((username, password)) = options.connect_credentials() {
         let login = Login::new(username, password);
         connect.login = Some(login);
     }
@@ -335,23 +1234,28 @@ async fn mqtt_connect(
     })
     .await??;
 
-    // wait for 'timeout' time to validate connack
-    let packet = time::timeout(Duration::from_secs(options.connection_timeout()), async {
+    // wait for 'connack_timeout' time to validate connack
+    let packet = time::timeout(Duration::from_secs(options.connack_timeout()), async {
         let packet = match network.read().await? {
             Incoming::ConnAck(connack) if connack.code == ConnectReturnCode::Success => {
+                if options.client_id().is_empty() {
+                    info!("Connected with a client id assigned by the broker");
+                }
+
                 Packet::ConnAck(connack)
             }
             Incoming::ConnAck(connack) => {
-                let error = format!("Broker rejected. Reason = {:?}", connack.code);
-                return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                let error = StateError::Connect(connack.code);
+                return Err(ConnectionError::MqttState(error));
             }
             packet => {
                 let error = format!("Expecting connack. Received = {:?}", packet);
-                return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                let error = io::Error::new(io::ErrorKind::InvalidData, error);
+                return Err(ConnectionError::Io(error));
             }
         };
 
-        Ok::<_, io::Error>(packet)
+        Ok::<_, ConnectionError>(packet)
     })
     .await??;
 
@@ -359,12 +1263,242 @@ async fn mqtt_connect(
 }
 
 /// Returns the next pending packet asynchronously to be used in select!
-/// This is a synchronous function but made async to make it fit in select!
+/// Publishes are delayed by the throttle while acks, pings and disconnects,
+/// which keep the protocol going, bypass it
 pub(crate) async fn next_pending(
     delay: Duration,
     pending: &mut IntoIter<Request>,
 ) -> Option<Request> {
-    // return next packet with a delay
-    time::sleep(delay).await;
+    let throttled = matches!(
+        pending.as_slice().first(),
+        Some(Request::Publish(_))
+            | Some(Request::TrackedPublish(..))
+            | Some(Request::ExpiringPublish(..))
+    );
+
+    if throttled {
+        time::sleep(delay).await;
+    }
+
     pending.next()
 }
+
+/// Fails with `ConnectionError::Inactive` when `last` activity is older than
+/// `window`. Otherwise rearms the timer to the end of the window
+fn check_idle(
+    timer: &mut Option<Pin<Box<Sleep>>>,
+    last: std::time::Instant,
+    window: Duration,
+) -> Result<(), ConnectionError> {
+    let idle = last.elapsed();
+    if idle >= window {
+        warn!("No incoming activity for {:?}. Reconnecting", idle);
+        return Err(ConnectionError::Inactive(idle));
+    }
+
+    let timer = timer.as_mut().unwrap();
+    timer.as_mut().reset(Instant::from_std(last) + window);
+    Ok(())
+}
+
+/// Waits for the timer. Pending forever when there is no timer
+async fn next_tick(timeout: Option<&mut Pin<Box<Sleep>>>) {
+    match timeout {
+        Some(timeout) => timeout.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Oversized and malformed incoming packets end the connection with descriptive errors
+fn protocol_error(error: StateError, max: usize) -> ConnectionError {
+    match error {
+        StateError::Deserialization(mqttbytes::Error::PayloadSizeLimitExceeded(size)) => {
+            error!("Incoming packet size = {}, limit = {}", size, max);
+            ConnectionError::PacketTooLarge { size, max }
+        }
+        StateError::Deserialization(e) => {
+            error!("Malformed incoming packet. Error = {:?}", e);
+            ConnectionError::Mqtt4Bytes(e)
+        }
+        e => ConnectionError::MqttState(e),
+    }
+}
+
+/// Completes at the shutdown `deadline`. Pending forever without a shutdown
+async fn next_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mqttbytes::QoS;
+
+    #[test]
+    fn addresses_alternate_between_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1883".parse().unwrap(),
+            "[::2]:1883".parse().unwrap(),
+            "[::3]:1883".parse().unwrap(),
+            "127.0.0.1:1883".parse().unwrap(),
+            "127.0.0.2:1883".parse().unwrap(),
+        ];
+
+        let ordered: Vec<SocketAddr> = vec![
+            "[::1]:1883".parse().unwrap(),
+            "127.0.0.1:1883".parse().unwrap(),
+            "[::2]:1883".parse().unwrap(),
+            "127.0.0.2:1883".parse().unwrap(),
+            "[::3]:1883".parse().unwrap(),
+        ];
+
+        assert_eq!(interleave(addrs), ordered);
+    }
+
+    #[test]
+    fn events_are_filtered_by_kind() {
+        let filter = EventFilter::PUBLISHES | EventFilter::CONNECTION;
+        assert!(filter.contains(EventFilter::PUBLISHES));
+        assert!(!filter.contains(EventFilter::ACKS));
+
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1]);
+        assert!(filter.allows(&Event::Incoming(Packet::Publish(publish))));
+        assert!(filter.allows(&Event::Reconnected(1)));
+        assert!(!filter.allows(&Event::Incoming(Packet::PubAck(PubAck::new(1)))));
+        assert!(!filter.allows(&Event::Incoming(Packet::PingResp)));
+        assert!(!filter.allows(&Event::Outgoing(Outgoing::Publish(1))));
+        assert!(EventFilter::ALL.allows(&Event::Outgoing(Outgoing::PingReq)));
+    }
+
+    #[tokio::test]
+    async fn tcp_options_are_applied_to_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut options = MqttOptions::new("dummy", "127.0.0.1", port);
+        let stream = tcp_connect(&options).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        options
+            .set_tcp_nodelay(true)
+            .set_tcp_keepalive(Duration::from_secs(30));
+        let stream = tcp_connect(&options).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_pending_publishes_are_throttled() {
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1]);
+        let pending = vec![
+            Request::PubAck(PubAck::new(1)),
+            Request::PingReq,
+            Request::Publish(publish),
+        ];
+
+        let delay = Duration::from_millis(200);
+        let mut pending = pending.into_iter();
+        let start = Instant::now();
+        next_pending(delay, &mut pending).await.unwrap();
+        next_pending(delay, &mut pending).await.unwrap();
+        assert!(start.elapsed() < delay);
+
+        next_pending(delay, &mut pending).await.unwrap();
+        assert!(start.elapsed() >= delay);
+    }
+
+    /// Collects names of spans and events with their fields
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Collector {
+        spans: Arc<std::sync::Mutex<Vec<&'static str>>>,
+        events: Arc<std::sync::Mutex<Vec<Fields>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Collector {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata().name());
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        names: Vec<String>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            match field.name() {
+                "message" => self.message = format!("{:?}", value),
+                name => self.names.push(name.to_owned()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tracing")]
+    async fn failed_connections_are_traced() {
+        let collector = Collector::default();
+        let _guard = tracing::subscriber::set_default(collector.clone());
+
+        // Nothing listens on the port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let options = MqttOptions::new("dummy", "127.0.0.1", port);
+        let mut eventloop = EventLoop::new(options, 10);
+        assert!(eventloop.poll().await.is_err());
+
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(*spans, vec!["connect", "network_connect"]);
+        let events = collector.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message, "connection failed");
+        assert_eq!(events[0].names, vec!["error"]);
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn outgoing_packets_are_traced_with_inflight_count() {
+        let collector = Collector::default();
+        let _guard = tracing::subscriber::set_default(collector.clone());
+
+        let mut state = MqttState::new(10);
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1]);
+        let request = Request::Publish(publish);
+        state.handle_outgoing_packet(request).unwrap();
+
+        let events = collector.events.lock().unwrap();
+        assert_eq!(events[0].message, "outgoing");
+        assert_eq!(events[0].names, vec!["request", "inflight"]);
+    }
+}
@@ -2,9 +2,12 @@ use bytes::BytesMut;
 use mqttbytes::v4::*;
 use mqttbytes::*;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant};
 
-use crate::{Incoming, MqttState, StateError};
+use crate::{Direction, Incoming, MqttState, StateError, WireTap};
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// Network transforms packets <-> frames efficiently. It takes
 /// advantage of pre-allocation, buffering and vectorization when
@@ -18,6 +21,10 @@ pub struct Network {
     max_incoming_size: usize,
     /// Maximum readv count
     max_readb_count: usize,
+    /// Outgoing bandwidth limit
+    bandwidth: Option<Bandwidth>,
+    /// Recorder of raw frames
+    tap: Option<Arc<dyn WireTap>>,
 }
 
 impl Network {
@@ -28,9 +35,51 @@ impl Network {
             read: BytesMut::with_capacity(10 * 1024),
             max_incoming_size,
             max_readb_count: 10,
+            bandwidth: None,
+            tap: None,
         }
     }
 
+    /// Records every frame read or written from now on with `tap`
+    pub fn set_wire_tap(&mut self, tap: Arc<dyn WireTap>) {
+        self.tap = Some(tap);
+    }
+
+    /// Frames next packet of the read buffer. Frame is recorded before it's parsed
+    fn frame(&mut self) -> Result<Incoming, Error> {
+        if let Some(tap) = &self.tap {
+            let len = check(self.read.iter(), self.max_incoming_size)?.frame_length();
+            tap.record(Direction::Incoming, SystemTime::now(), &self.read[..len]);
+        }
+
+        read(&mut self.read, self.max_incoming_size)
+    }
+
+    /// Records each frame of an outgoing buffer
+    fn record(&self, mut frames: &[u8]) {
+        let tap = match &self.tap {
+            Some(tap) => tap,
+            None => return,
+        };
+
+        let now = SystemTime::now();
+        while !frames.is_empty() {
+            let len = match check(frames.iter(), usize::MAX) {
+                Ok(header) => header.frame_length(),
+                Err(_) => frames.len(),
+            };
+
+            let (frame, rest) = frames.split_at(len);
+            tap.record(Direction::Outgoing, now, frame);
+            frames = rest;
+        }
+    }
+
+    /// Limits writes to `rate` bytes per second with bursts of `burst` bytes
+    pub fn set_bandwidth(&mut self, rate: usize, burst: usize) {
+        self.bandwidth = Some(Bandwidth::new(rate, burst));
+    }
+
     /// Reads more than 'required' bytes to frame a packet into self.read buffer
     async fn read_bytes(&mut self, required: usize) -> io::Result<usize> {
         let mut total_read = 0;
@@ -50,6 +99,7 @@ impl Network {
                 };
             }
 
+            trace_event!(trace, bytes = read, "read");
             total_read += read;
             if total_read >= required {
                 return Ok(total_read);
@@ -59,7 +109,7 @@ impl Network {
 
     pub async fn read(&mut self) -> Result<Incoming, io::Error> {
         loop {
-            let required = match read(&mut self.read, self.max_incoming_size) {
+            let required = match self.frame() {
                 Ok(packet) => return Ok(packet),
                 Err(mqttbytes::Error::InsufficientBytes(required)) => required,
                 Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
@@ -76,8 +126,10 @@ impl Network {
     pub async fn readb(&mut self, state: &mut MqttState) -> Result<(), StateError> {
         let mut count = 0;
         loop {
-            match read(&mut self.read, self.max_incoming_size) {
+            let len = self.read.len();
+            match self.frame() {
                 Ok(packet) => {
+                    state.stats.incoming.bytes += len - self.read.len();
                     state.handle_incoming_packet(packet)?;
 
                     count += 1;
@@ -103,6 +155,7 @@ impl Network {
             Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
         };
 
+        self.record(&write[..]);
         self.socket.write_all(&write[..]).await?;
         Ok(len)
     }
@@ -112,11 +165,133 @@ impl Network {
             return Ok(());
         }
 
-        self.socket.write_all(&write[..]).await?;
+        trace_event!(trace, bytes = write.len(), "write");
+        self.record(&write[..]);
+        match &mut self.bandwidth {
+            Some(bandwidth) => {
+                for chunk in write.chunks(bandwidth.burst) {
+                    bandwidth.acquire(chunk.len()).await;
+                    self.socket.write_all(chunk).await?;
+                }
+            }
+            None => self.socket.write_all(&write[..]).await?,
+        }
+
         write.clear();
         Ok(())
     }
 }
 
+/// Token bucket of outgoing bytes
+struct Bandwidth {
+    /// Bytes added to the bucket per second
+    rate: usize,
+    /// Bucket size
+    burst: usize,
+    /// Bytes which can be written now
+    tokens: f64,
+    /// Last refill time
+    last: Instant,
+}
+
+impl Bandwidth {
+    fn new(rate: usize, burst: usize) -> Bandwidth {
+        Bandwidth {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last = now;
+    }
+
+    /// Waits till `len` (<= burst) bytes can be written
+    async fn acquire(&mut self, len: usize) {
+        self.refill();
+
+        let len = len as f64;
+        if self.tokens < len {
+            let wait = (len - self.tokens) / self.rate as f64;
+            time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+
+        // Timer granularity might leave a tiny debt which is paid by the next write
+        self.tokens -= len;
+    }
+}
+
 pub trait N: AsyncRead + AsyncWrite + Send + Unpin {}
 impl<T> N for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        frames: Mutex<Vec<(Direction, Vec<u8>)>>,
+    }
+
+    impl WireTap for Recorder {
+        fn record(&self, direction: Direction, _time: SystemTime, frame: &[u8]) {
+            self.frames
+                .lock()
+                .unwrap()
+                .push((direction, frame.to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_recorded_by_the_wire_tap() {
+        let (client, mut server) = tokio::io::duplex(10 * 1024);
+        let mut network = Network::new(client, 10 * 1024);
+        let recorder = Arc::new(Recorder::default());
+        network.set_wire_tap(recorder.clone());
+
+        let mut write = BytesMut::new();
+        PingReq.write(&mut write).unwrap();
+        PubAck::new(10).write(&mut write).unwrap();
+        network.flush(&mut write).await.unwrap();
+
+        server.write_all(&[0xD0, 0x00]).await.unwrap();
+        assert_eq!(network.read().await.unwrap(), Incoming::PingResp);
+
+        let frames = recorder.frames.lock().unwrap();
+        let expected = vec![
+            (Direction::Outgoing, vec![0xC0, 0x00]),
+            (Direction::Outgoing, vec![0x40, 0x02, 0x00, 0x0A]),
+            (Direction::Incoming, vec![0xD0, 0x00]),
+        ];
+        assert_eq!(*frames, expected);
+    }
+
+    #[tokio::test]
+    async fn writes_are_limited_to_bandwidth() {
+        let (client, mut server) = tokio::io::duplex(10 * 1024);
+        let mut network = Network::new(client, 10 * 1024);
+        network.set_bandwidth(10_000, 1000);
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 10 * 1024];
+            while server.read(&mut buf).await.unwrap_or(0) > 0 {}
+        });
+
+        // First burst is immediate. Remaining 4000 bytes take 400ms
+        let start = Instant::now();
+        let mut write = BytesMut::from(&[0u8; 5000][..]);
+        network.flush(&mut write).await.unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(write.is_empty());
+        assert!(elapsed >= Duration::from_millis(390), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+}
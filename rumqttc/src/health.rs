@@ -0,0 +1,117 @@
+//! Connection status of an eventloop for liveness and readiness probes
+use tokio::sync::watch;
+
+/// State of the connection to the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected yet or stopped after an error which isn't retried
+    Disconnected,
+    /// Connack is received and the connection is up
+    Connected,
+    /// Connection failed and the eventloop is waiting to reconnect
+    Reconnecting,
+    /// Connection is closed by `client.pause()`
+    Paused,
+}
+
+/// Snapshot of the connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Last connection error. Kept after a successful reconnection
+    pub last_error: Option<String>,
+    /// Unacked outgoing publishes
+    pub inflight: u16,
+    /// Successful reconnections
+    pub reconnects: usize,
+}
+
+/// Shareable handle to the connection status of an eventloop. Cheap to clone
+/// and poll from http handlers (e.g kubernetes readiness probes) or to await
+/// status changes. Get one with `eventloop.health()`
+#[derive(Debug, Clone)]
+pub struct HealthProbe {
+    rx: watch::Receiver<ConnectionStatus>,
+}
+
+impl HealthProbe {
+    /// Current status of the connection
+    pub fn status(&self) -> ConnectionStatus {
+        self.rx.borrow().clone()
+    }
+
+    /// Checks if the connection is up. Maps to readiness probes
+    pub fn is_ready(&self) -> bool {
+        self.rx.borrow().state == ConnectionState::Connected
+    }
+
+    /// Waits for the next status change. Returns false once the eventloop is dropped
+    pub async fn changed(&mut self) -> bool {
+        self.rx.changed().await.is_ok()
+    }
+}
+
+/// Publishing end of the status. Holds a receiver to keep the channel open
+/// without probes
+#[derive(Debug)]
+pub(crate) struct Health {
+    tx: watch::Sender<ConnectionStatus>,
+    rx: watch::Receiver<ConnectionStatus>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        let status = ConnectionStatus {
+            state: ConnectionState::Disconnected,
+            last_error: None,
+            inflight: 0,
+            reconnects: 0,
+        };
+
+        let (tx, rx) = watch::channel(status);
+        Health { tx, rx }
+    }
+
+    pub fn probe(&self) -> HealthProbe {
+        HealthProbe {
+            rx: self.rx.clone(),
+        }
+    }
+
+    /// Updates the status. Probes are notified only when something changed
+    pub fn update<F: FnOnce(&mut ConnectionStatus)>(&self, f: F) {
+        let mut status = self.rx.borrow().clone();
+        f(&mut status);
+        if status != *self.rx.borrow() {
+            let _ = self.tx.send(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn probes_are_notified_of_status_changes() {
+        let health = Health::new();
+        let mut probe = health.probe();
+        assert!(!probe.is_ready());
+
+        health.update(|status| status.state = ConnectionState::Connected);
+        assert!(probe.changed().await);
+        assert!(probe.is_ready());
+
+        health.update(|status| {
+            status.state = ConnectionState::Reconnecting;
+            status.last_error = Some("I/O: connection reset".to_owned());
+        });
+        let status = probe.status();
+        assert_eq!(status.state, ConnectionState::Reconnecting);
+        assert_eq!(status.last_error.unwrap(), "I/O: connection reset");
+
+        drop(health);
+        probe.changed().await;
+        assert!(!probe.changed().await);
+    }
+}
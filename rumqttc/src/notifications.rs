@@ -0,0 +1,236 @@
+//! Eventloop running in the background and forwarding events to a bounded
+//! notifications channel, so that slow consumers don't stall the protocol loop
+use crate::{ConnectionError, Event, EventLoop, Incoming, QoS};
+
+use async_channel::{bounded, Receiver, Sender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Action when the notifications channel is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Eventloop waits for the consumer
+    Block,
+    /// Drops incoming QoS 0 publishes and waits for the consumer on other events
+    DropQos0,
+    /// Stops the eventloop with `ConnectionError::NotificationsFull`
+    Error,
+}
+
+/// Rates of a notifications channel which filled up over a window
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backpressure {
+    /// Incoming publishes per second
+    pub incoming_rate: f64,
+    /// Events read by the consumer per second
+    pub drain_rate: f64,
+    /// Events buffered in the notifications channel
+    pub queue_depth: usize,
+}
+
+/// Receiving end of a background eventloop. The eventloop stops at the first
+/// error it yields (enable `MqttOptions::set_reconnect_options` for automatic
+/// reconnections) or when this is dropped
+#[derive(Debug, Clone)]
+pub struct Notifications {
+    rx: Receiver<Result<Event, ConnectionError>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Notifications {
+    /// Next event. `None` once the eventloop stops and remaining events are read
+    pub async fn recv(&self) -> Option<Result<Event, ConnectionError>> {
+        self.rx.recv().await.ok()
+    }
+
+    /// Blocks current thread for the next event
+    pub fn blocking_recv(&self) -> Option<Result<Event, ConnectionError>> {
+        pollster::block_on(self.recv())
+    }
+
+    /// Number of events buffered in the channel
+    pub fn len(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+
+    /// Number of QoS 0 publishes dropped because of a slow consumer
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl EventLoop {
+    /// Runs this eventloop in a tokio task and forwards its events to a
+    /// notifications channel of `capacity`. `policy` decides what happens
+    /// when the consumer falls behind
+    pub fn spawn(self, capacity: usize, policy: SlowConsumerPolicy) -> Notifications {
+        let (notifications, forward) = notifications(capacity);
+        tokio::spawn(forward.run(self, policy));
+        notifications
+    }
+}
+
+pub(crate) fn notifications(capacity: usize) -> (Notifications, Forward) {
+    let (tx, rx) = bounded(capacity);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let notifications = Notifications {
+        rx,
+        dropped: dropped.clone(),
+    };
+
+    (notifications, Forward { tx, dropped })
+}
+
+/// Polls an eventloop into the notifications channel
+pub(crate) struct Forward {
+    tx: Sender<Result<Event, ConnectionError>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Forward {
+    pub(crate) async fn run(self, mut eventloop: EventLoop, policy: SlowConsumerPolicy) {
+        let window = eventloop.options.slow_consumer_window();
+        let mut meter = window.map(|window| Meter::new(window, self.tx.len()));
+        loop {
+            let o = eventloop.poll().await;
+            let stop = o.is_err();
+            if let Some(meter) = &mut meter {
+                meter.forwarded(is_publish(&o));
+            }
+
+            let o = match self.tx.try_send(o) {
+                Ok(_) => None,
+                Err(TrySendError::Closed(_)) => return,
+                Err(TrySendError::Full(o)) => Some(o),
+            };
+
+            if let Some(o) = o {
+                match policy {
+                    SlowConsumerPolicy::DropQos0 if is_qos0_publish(&o) => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        warn!("Notifications full. Dropping qos 0 publish");
+                    }
+                    SlowConsumerPolicy::Block | SlowConsumerPolicy::DropQos0 => {
+                        if self.tx.send(o).await.is_err() {
+                            return;
+                        }
+                    }
+                    SlowConsumerPolicy::Error => {
+                        error!("Notifications full. Stopping eventloop");
+                        let _ = self.tx.send(Err(ConnectionError::NotificationsFull)).await;
+                        return;
+                    }
+                }
+            }
+
+            if stop {
+                return;
+            }
+
+            let backpressure = match &mut meter {
+                Some(meter) => meter.check(self.tx.len()),
+                None => None,
+            };
+
+            if let Some(backpressure) = backpressure {
+                warn!("Notifications consumer is slow. {:?}", backpressure);
+                let event = Event::SlowConsumer(backpressure);
+                if eventloop.options.event_filter().allows(&event) {
+                    // warning is skipped when the channel is already full
+                    let _ = self.tx.try_send(Ok(event));
+                }
+            }
+        }
+    }
+}
+
+/// Publish and drain rates of the notifications channel over a window
+struct Meter {
+    window: Duration,
+    start: Instant,
+    depth: usize,
+    forwarded: usize,
+    publishes: usize,
+}
+
+impl Meter {
+    fn new(window: Duration, depth: usize) -> Meter {
+        Meter {
+            window,
+            start: Instant::now(),
+            depth,
+            forwarded: 0,
+            publishes: 0,
+        }
+    }
+
+    fn forwarded(&mut self, publish: bool) {
+        self.forwarded += 1;
+        if publish {
+            self.publishes += 1;
+        }
+    }
+
+    /// Rates of the elapsed window if publishes outpaced the consumer and the
+    /// channel kept filling up during it. Starts the next window
+    fn check(&mut self, depth: usize) -> Option<Backpressure> {
+        let elapsed = self.start.elapsed();
+        if elapsed < self.window {
+            return None;
+        }
+
+        let elapsed = elapsed.as_secs_f64();
+        let drained = (self.depth + self.forwarded).saturating_sub(depth);
+        let incoming_rate = self.publishes as f64 / elapsed;
+        let drain_rate = drained as f64 / elapsed;
+        let filling = depth > self.depth && incoming_rate > drain_rate;
+        *self = Meter::new(self.window, depth);
+
+        match filling {
+            true => Some(Backpressure {
+                incoming_rate,
+                drain_rate,
+                queue_depth: depth,
+            }),
+            false => None,
+        }
+    }
+}
+
+fn is_publish(o: &Result<Event, ConnectionError>) -> bool {
+    matches!(o, Ok(Event::Incoming(Incoming::Publish(_))))
+}
+
+fn is_qos0_publish(o: &Result<Event, ConnectionError>) -> bool {
+    matches!(o, Ok(Event::Incoming(Incoming::Publish(publish))) if publish.qos == QoS::AtMostOnce)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn filling_channels_are_reported_once_the_window_elapses() {
+        let mut meter = Meter::new(Duration::from_millis(100), 0);
+        for _ in 0..10 {
+            meter.forwarded(true);
+        }
+
+        assert!(meter.check(8).is_none());
+        thread::sleep(Duration::from_millis(150));
+        let backpressure = meter.check(8).unwrap();
+        assert_eq!(backpressure.queue_depth, 8);
+        assert!(backpressure.incoming_rate > backpressure.drain_rate);
+
+        // consumer catches up in the next window
+        meter.forwarded(true);
+        thread::sleep(Duration::from_millis(150));
+        assert!(meter.check(2).is_none());
+    }
+}
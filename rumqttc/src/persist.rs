@@ -0,0 +1,346 @@
+//! File backed log of unacked outgoing publishes which survives process crashes
+use crate::{PublishSnapshot, StateSnapshot};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use mqttbytes::v4::{self, Packet, Publish};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const PUBLISH: u8 = 1;
+const RELEASE: u8 = 2;
+const ACK: u8 = 3;
+
+/// Record of the log. Each record starts with its type
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Record<'a> {
+    /// New inflight QoS 1 or 2 publish
+    Publish(&'a Publish),
+    /// QoS 2 publish is received by the broker and is being released
+    Release(u16),
+    /// Publish or release is acked and can be forgotten
+    Ack(u16),
+}
+
+impl<'a> Record<'a> {
+    fn write(&self, buffer: &mut BytesMut) -> io::Result<()> {
+        match self {
+            Record::Publish(publish) => {
+                buffer.put_u8(PUBLISH);
+                publish
+                    .write(buffer)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+            }
+            Record::Release(pkid) => {
+                buffer.put_u8(RELEASE);
+                buffer.put_u16(*pkid);
+            }
+            Record::Ack(pkid) => {
+                buffer.put_u8(ACK);
+                buffer.put_u16(*pkid);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Append only log of outgoing QoS 1 and 2 publishes. Publishes are appended
+/// before they are written to the network and forgotten with acks. Log is
+/// truncated when nothing is unacked and is compacted to unacked packets when
+/// it grows beyond `max_bytes`
+#[derive(Debug, Clone)]
+pub(crate) struct Persistence {
+    log: Arc<Mutex<Log>>,
+}
+
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    file: File,
+    size: usize,
+    max_bytes: usize,
+    /// Encoded records of unacked packets and their sequence in the log
+    live: HashMap<u16, (u64, Bytes)>,
+    sequence: u64,
+}
+
+impl Persistence {
+    /// Opens the log at `path` and returns unacked packets of the previous
+    /// process. Partially written record of a crash is dropped
+    pub fn open(path: &Path, max_bytes: usize) -> io::Result<(Persistence, StateSnapshot)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        let (publishes, releases, last_pkid) = replay(BytesMut::from(&buffer[..]));
+
+        let mut log = Log {
+            path: path.to_owned(),
+            file,
+            size: 0,
+            max_bytes,
+            live: HashMap::new(),
+            sequence: 0,
+        };
+
+        for publish in publishes.iter() {
+            log.track(publish.pkid, Record::Publish(publish))?;
+        }
+
+        for pkid in releases.iter() {
+            log.track(*pkid, Record::Release(*pkid))?;
+        }
+
+        // Rewrite the log with just the unacked packets
+        log.compact()?;
+        let persistence = Persistence {
+            log: Arc::new(Mutex::new(log)),
+        };
+
+        let publishes = publishes
+            .into_iter()
+            .map(|publish| PublishSnapshot {
+                pkid: publish.pkid,
+                qos: publish.qos as u8,
+                retain: publish.retain,
+                topic: publish.topic,
+                payload: publish.payload.to_vec(),
+            })
+            .collect();
+
+        let snapshot = StateSnapshot {
+            last_pkid,
+            publishes,
+            releases,
+            ..Default::default()
+        };
+
+        Ok((persistence, snapshot))
+    }
+
+    /// Appends a record. Compacts the log when it is full
+    pub fn append(&self, record: Record) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let buffer = match record {
+            Record::Publish(publish) => log.track(publish.pkid, record)?,
+            Record::Release(pkid) => log.track(pkid, record)?,
+            Record::Ack(pkid) => {
+                if log.live.remove(&pkid).is_none() {
+                    return Ok(());
+                }
+
+                if log.live.is_empty() {
+                    log.file.set_len(0)?;
+                    log.size = 0;
+                    return Ok(());
+                }
+
+                let mut buffer = BytesMut::new();
+                record.write(&mut buffer)?;
+                buffer.freeze()
+            }
+        };
+
+        if log.size + buffer.len() > log.max_bytes {
+            return log.compact();
+        }
+
+        log.file.write_all(&buffer)?;
+        log.size += buffer.len();
+        Ok(())
+    }
+
+    /// Truncates the log. Used when unacked packets are dropped
+    pub fn clear(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.live.clear();
+        log.file.set_len(0)?;
+        log.size = 0;
+        Ok(())
+    }
+}
+
+impl Log {
+    /// Encodes the record of an unacked packet and tracks it for compactions.
+    /// Releases take the place of their publishes
+    fn track(&mut self, pkid: u16, record: Record) -> io::Result<Bytes> {
+        let mut buffer = BytesMut::new();
+        record.write(&mut buffer)?;
+        let buffer = buffer.freeze();
+
+        let sequence = match (record, self.live.get(&pkid)) {
+            (Record::Release(_), Some((sequence, _))) => *sequence,
+            _ => {
+                self.sequence += 1;
+                self.sequence
+            }
+        };
+
+        self.live.insert(pkid, (sequence, buffer.clone()));
+        Ok(buffer)
+    }
+
+    /// Atomically replaces the log with records of unacked packets
+    fn compact(&mut self) -> io::Result<()> {
+        let mut live: Vec<&(u64, Bytes)> = self.live.values().collect();
+        live.sort_by_key(|(sequence, _)| *sequence);
+        let buffer: Vec<u8> = live
+            .iter()
+            .flat_map(|(_, record)| record.iter())
+            .copied()
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.size = buffer.len();
+        if self.size > self.max_bytes {
+            warn!(
+                "Unacked packets ({} bytes) don't fit in persistence of {} bytes",
+                self.size, self.max_bytes
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Unacked publishes (in the order of their records), releases and packet id
+/// of the last publish in the log
+fn replay(mut buffer: BytesMut) -> (Vec<Publish>, Vec<u16>, u16) {
+    let mut publishes: Vec<Publish> = Vec::new();
+    let mut releases: Vec<u16> = Vec::new();
+    let mut last_pkid = 0;
+
+    while buffer.has_remaining() {
+        match buffer[0] {
+            PUBLISH => {
+                let mut record = buffer.clone();
+                record.advance(1);
+                let publish = match v4::read(&mut record, usize::MAX) {
+                    Ok(Packet::Publish(publish)) => publish,
+                    _ => break,
+                };
+
+                publishes.retain(|p| p.pkid != publish.pkid);
+                releases.retain(|pkid| *pkid != publish.pkid);
+                last_pkid = publish.pkid;
+                publishes.push(publish);
+                buffer = record;
+            }
+            RELEASE | ACK if buffer.len() >= 3 => {
+                let kind = buffer.get_u8();
+                let pkid = buffer.get_u16();
+                publishes.retain(|p| p.pkid != pkid);
+                releases.retain(|p| *p != pkid);
+                if kind == RELEASE {
+                    releases.push(pkid);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    if buffer.has_remaining() {
+        warn!("Dropping {} bytes of a partial record", buffer.len());
+    }
+
+    (publishes, releases, last_pkid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EventLoop, MqttOptions};
+    use mqttbytes::QoS;
+    use std::env;
+
+    fn publish(pkid: u16) -> Publish {
+        let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        publish.pkid = pkid;
+        publish
+    }
+
+    #[test]
+    fn unacked_packets_are_recovered_after_restart() {
+        let path = env::temp_dir().join("rumqttc-persistence-recovery.log");
+        let _ = fs::remove_file(&path);
+
+        let (persistence, snapshot) = Persistence::open(&path, 1024).unwrap();
+        assert!(snapshot.publishes.is_empty());
+        for pkid in 1..=3 {
+            persistence.append(Record::Publish(&publish(pkid))).unwrap();
+        }
+
+        persistence.append(Record::Ack(1)).unwrap();
+        persistence.append(Record::Release(2)).unwrap();
+        drop(persistence);
+
+        // crash while writing the next record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[PUBLISH, 0x32]).unwrap();
+
+        let (_, snapshot) = Persistence::open(&path, 1024).unwrap();
+        let pkids: Vec<u16> = snapshot.publishes.iter().map(|p| p.pkid).collect();
+        assert_eq!(pkids, vec![3]);
+        assert_eq!(snapshot.releases, vec![2]);
+        assert_eq!(snapshot.last_pkid, 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_is_truncated_and_compacted_to_unacked_packets() {
+        let path = env::temp_dir().join("rumqttc-persistence-compaction.log");
+        let _ = fs::remove_file(&path);
+
+        let (persistence, _) = Persistence::open(&path, 100).unwrap();
+        persistence.append(Record::Publish(&publish(1))).unwrap();
+        persistence.append(Record::Ack(1)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        for pkid in 1..=10 {
+            persistence.append(Record::Publish(&publish(pkid))).unwrap();
+            if pkid < 10 {
+                persistence.append(Record::Ack(pkid - 1)).unwrap();
+            }
+        }
+
+        assert!(fs::metadata(&path).unwrap().len() <= 100);
+        drop(persistence);
+
+        let (_, snapshot) = Persistence::open(&path, 100).unwrap();
+        let pkids: Vec<u16> = snapshot.publishes.iter().map(|p| p.pkid).collect();
+        assert_eq!(pkids, vec![9, 10]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logs_of_larger_inflight_windows_are_not_restored() {
+        let path = env::temp_dir().join("rumqttc-persistence-window.log");
+        let _ = fs::remove_file(&path);
+
+        let (persistence, _) = Persistence::open(&path, 1024).unwrap();
+        persistence.append(Record::Publish(&publish(1))).unwrap();
+        persistence.append(Record::Publish(&publish(50))).unwrap();
+        persistence.append(Record::Ack(50)).unwrap();
+        drop(persistence);
+
+        // Last packet id 50 is beyond the inflight window of 5
+        let mut options = MqttOptions::new("dummy", "localhost", 1883);
+        options.set_inflight(5).set_persistence(&path, 1024);
+        let mut eventloop = EventLoop::new(options, 10);
+        assert!(eventloop.state.clean().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
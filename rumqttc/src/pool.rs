@@ -0,0 +1,104 @@
+//! Many independent connections (e.g one per tenant or broker shard) with a
+//! single notification stream for gateways bridging many devices
+use crate::{AsyncClient, ConnectionError, Event, EventLoop, MqttOptions};
+
+use async_channel::{bounded, Receiver, Sender};
+use std::collections::HashMap;
+
+/// Event of a pooled connection tagged with its client id
+pub type PoolEvent = (String, Result<Event, ConnectionError>);
+
+/// Errors while adding connections to a pool
+#[derive(Debug, thiserror::Error)]
+pub enum PoolError {
+    #[error("Duplicate client id = {0}")]
+    Duplicate(String),
+}
+
+/// Manages connections whose eventloops run in tokio tasks and multiplexes
+/// their events into one channel. An eventloop stops at the first error it
+/// yields (enable `MqttOptions::set_reconnect_options` for automatic
+/// reconnections), when its client is removed or when the pool is dropped
+#[derive(Debug)]
+pub struct ClientPool {
+    clients: HashMap<String, AsyncClient>,
+    tx: Sender<PoolEvent>,
+    rx: Receiver<PoolEvent>,
+}
+
+impl ClientPool {
+    /// Creates a pool whose shared notification channel holds `capacity` events.
+    /// Eventloops wait when the consumer falls behind
+    pub fn new(capacity: usize) -> ClientPool {
+        let (tx, rx) = bounded(capacity);
+        ClientPool {
+            clients: HashMap::new(),
+            tx,
+            rx,
+        }
+    }
+
+    /// Starts a new connection with a requests channel of `cap` and returns
+    /// its client. Client ids identify connections and can't be repeated.
+    /// Must be called within a tokio runtime
+    pub fn add(&mut self, options: MqttOptions, cap: usize) -> Result<AsyncClient, PoolError> {
+        let id = options.client_id();
+        if self.clients.contains_key(&id) {
+            return Err(PoolError::Duplicate(id));
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, cap);
+        tokio::spawn(forward(id.clone(), eventloop, self.tx.clone()));
+        self.clients.insert(id, client.clone());
+        Ok(client)
+    }
+
+    /// Client of a connection in this pool
+    pub fn get(&self, id: &str) -> Option<&AsyncClient> {
+        self.clients.get(id)
+    }
+
+    /// Cancels the eventloop of a connection and removes it from the pool
+    pub async fn remove(&mut self, id: &str) -> Option<AsyncClient> {
+        let client = self.clients.remove(id)?;
+        if let Err(e) = client.cancel().await {
+            debug!("Eventloop of {} is already stopped. Error = {:?}", id, e);
+        }
+
+        Some(client)
+    }
+
+    /// Client ids of all the connections in this pool
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.clients.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Next event of any connection in the pool
+    pub async fn recv(&self) -> PoolEvent {
+        // pool holds a sender. channel is never closed
+        self.rx.recv().await.unwrap()
+    }
+
+    /// Next event without waiting. `None` when no event is buffered
+    pub fn try_recv(&self) -> Option<PoolEvent> {
+        self.rx.try_recv().ok()
+    }
+}
+
+async fn forward(id: String, mut eventloop: EventLoop, tx: Sender<PoolEvent>) {
+    loop {
+        let o = eventloop.poll().await;
+        let stop = o.is_err();
+        if tx.send((id.clone(), o)).await.is_err() || stop {
+            return;
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! `tower::Service` adapter over `AsyncClient` to compose publishes with tower
+//! middleware like retries, rate limits and load shedding
+use crate::{AsyncClient, ClientError, Request};
+
+use bytes::Bytes;
+use mqttbytes::v4::Publish;
+use mqttbytes::QoS;
+use tower_service::Service;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Publish handled by `PublishService`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishRequest {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Bytes,
+}
+
+impl PublishRequest {
+    pub fn new<S: Into<String>, P: Into<Bytes>>(topic: S, qos: QoS, payload: P) -> PublishRequest {
+        PublishRequest {
+            topic: topic.into(),
+            qos,
+            retain: false,
+            payload: payload.into(),
+        }
+    }
+}
+
+type Wait = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Publishes through the eventloop of an `AsyncClient`. The service is ready
+/// when the requests channel has space. Eventloop stops pulling requests when
+/// inflight queue is full, so readiness follows inflight capacity.
+/// **NOTE** Clients created with `AsyncClient::from_senders` aren't notified
+/// by an eventloop and stay pending once their channel is full
+pub struct PublishService {
+    client: AsyncClient,
+    /// Waits for eventloop to pull a request out of a full channel
+    wait: Option<Wait>,
+}
+
+impl PublishService {
+    pub fn new(client: AsyncClient) -> PublishService {
+        PublishService { client, wait: None }
+    }
+}
+
+impl Clone for PublishService {
+    fn clone(&self) -> Self {
+        PublishService::new(self.client.clone())
+    }
+}
+
+impl Service<PublishRequest> for PublishService {
+    type Response = ();
+    type Error = ClientError;
+    type Future = Pin<Box<dyn Future<Output = Result<(), ClientError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            if let Some(wait) = self.wait.as_mut() {
+                if wait.as_mut().poll(cx).is_pending() {
+                    // Capacity might have freed up before the waker is registered
+                    if self.client.request_tx.is_full() {
+                        return Poll::Pending;
+                    }
+                }
+
+                self.wait = None;
+            }
+
+            // Closed channel is reported by the next `call`
+            let tx = &self.client.request_tx;
+            if !tx.is_full() || tx.is_closed() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let capacity = self.client.capacity.clone();
+            self.wait = Some(Box::pin(async move { capacity.notified().await }));
+        }
+    }
+
+    fn call(&mut self, request: PublishRequest) -> Self::Future {
+        let mut publish = Publish::from_bytes(request.topic, request.qos, request.payload);
+        publish.retain = request.retain;
+
+        let tx = self.client.request_tx.clone();
+        Box::pin(async move {
+            tx.send(Request::Publish(publish)).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MqttOptions;
+    use std::time::Duration;
+    use tokio::time;
+
+    struct Ready<'a>(&'a mut PublishService);
+
+    impl Future for Ready<'_> {
+        type Output = Result<(), ClientError>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.0.poll_ready(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn service_is_ready_only_when_eventloop_has_capacity() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 1);
+        let mut service = PublishService::new(client);
+
+        Ready(&mut service).await.unwrap();
+        let request = PublishRequest::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        service.call(request).await.unwrap();
+
+        // Channel is full until eventloop pulls the request
+        let ready = time::timeout(Duration::from_millis(100), Ready(&mut service)).await;
+        assert!(ready.is_err());
+
+        let requests_rx = eventloop.requests_rx.clone();
+        let capacity = eventloop.capacity.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            requests_rx.recv().await.unwrap();
+            capacity.notify_waiters();
+        });
+
+        let ready = time::timeout(Duration::from_secs(1), Ready(&mut service)).await;
+        assert!(ready.is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_to_eventloop_as_publishes() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let mut service = PublishService::new(client);
+
+        let mut request = PublishRequest::new("hello/world", QoS::ExactlyOnce, vec![1, 2, 3]);
+        request.retain = true;
+        Ready(&mut service).await.unwrap();
+        service.call(request).await.unwrap();
+
+        match eventloop.requests_rx.recv().await.unwrap() {
+            Request::Publish(publish) => {
+                assert_eq!(publish.topic, "hello/world");
+                assert_eq!(publish.qos, QoS::ExactlyOnce);
+                assert!(publish.retain);
+                assert_eq!(publish.payload, Bytes::from(vec![1, 2, 3]));
+            }
+            request => panic!("Unexpected request = {:?}", request),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_fail_once_eventloop_is_dropped() {
+        let options = MqttOptions::new("test-1", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 1);
+        let mut service = PublishService::new(client);
+        let request = PublishRequest::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        service.call(request.clone()).await.unwrap();
+        drop(eventloop);
+
+        // Full but closed channel doesn't leave the service pending
+        let ready = time::timeout(Duration::from_secs(1), Ready(&mut service)).await;
+        assert!(ready.unwrap().is_ok());
+
+        match service.call(request).await {
+            Err(ClientError::Request(e)) => match e.into_inner() {
+                Request::Publish(publish) => assert_eq!(publish.topic, "hello/world"),
+                request => panic!("Unexpected request = {:?}", request),
+            },
+            result => panic!("Unexpected result = {:?}", result),
+        }
+    }
+}
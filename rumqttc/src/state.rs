@@ -1,9 +1,16 @@
-use crate::{Event, Incoming, Outgoing, Request};
-
-use bytes::BytesMut;
+use crate::chunk::Chunking;
+use crate::client::get_ack_req;
+use crate::persist::{Persistence, Record};
+use crate::transform::Transforms;
+use crate::PkidExhaustion;
+use crate::{Event, Incoming, Outgoing, Request, Stats, SubscribeResult, TokenTx, TopicAliases};
+
+use bytes::{Bytes, BytesMut};
 use mqttbytes::v4::*;
 use mqttbytes::*;
-use std::collections::VecDeque;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::{io, mem, time::Instant};
 
 /// Errors during state handling
@@ -29,6 +36,9 @@ pub enum StateError {
     WrongPacket,
     #[error("Timeout while waiting to resolve collision")]
     CollisionTimeout,
+    /// All the packet ids of the range are used by unacked packets
+    #[error("Packet ids exhausted")]
+    PkidsExhausted,
     #[error("Mqtt serialization/deserialization error")]
     Deserialization(mqttbytes::Error),
 }
@@ -39,6 +49,34 @@ impl From<mqttbytes::Error> for StateError {
     }
 }
 
+/// Unacked outgoing publish in a `StateSnapshot`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PublishSnapshot {
+    pub pkid: u16,
+    pub qos: u8,
+    pub retain: bool,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Session state which has to survive process restarts for persistent
+/// sessions. Serializable with `serde` feature
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StateSnapshot {
+    /// Packet id of the last outgoing packet
+    pub last_pkid: u16,
+    /// Outgoing QoS 1, 2 publishes which aren't acked yet
+    pub publishes: Vec<PublishSnapshot>,
+    /// Packet ids of released QoS 2 publishes which aren't completed yet
+    pub releases: Vec<u16>,
+    /// Packet ids of incoming QoS 2 publishes which aren't released yet
+    pub incoming: Vec<u16>,
+    /// Active subscriptions and their qos
+    pub subscriptions: Vec<(String, u8)>,
+}
+
 /// State of the mqtt connection.
 // Design: Methods will just modify the state of the object without doing any network operations
 // Design: All inflight queues are maintained in a pre initialized vec with index as packet id.
@@ -49,28 +87,71 @@ impl From<mqttbytes::Error> for StateError {
 pub struct MqttState {
     /// Status of last ping
     pub await_pingresp: bool,
+    /// Consecutive pings without a pingresp
+    pub(crate) missed_pings: usize,
+    /// Missed pings after which the connection is considered half open
+    pub(crate) max_missed_pings: usize,
     /// Collision ping count. Collisions stop user requests
     /// which inturn trigger pings. Multiple pings without
     /// resolving collisions will result in error
     pub collision_ping_count: usize,
     /// Last incoming packet time
-    last_incoming: Instant,
+    pub(crate) last_incoming: Instant,
     /// Last outgoing packet time
     last_outgoing: Instant,
+    /// Last incoming publish or ack time
+    pub(crate) last_activity: Instant,
     /// Packet id of the last outgoing packet
     pub(crate) last_pkid: u16,
+    /// First and last packet ids of the allocation range
+    pub(crate) pkid_range: (u16, u16),
+    /// Action when the next packet id of a publish is still unacked
+    pub(crate) pkid_exhaustion: PkidExhaustion,
     /// Number of outgoing inflight publishes
     pub(crate) inflight: u16,
-    /// Maximum number of allowed inflight
-    pub(crate) max_inflight: u16,
     /// Outgoing QoS 1, 2 publishes which aren't acked yet
     pub(crate) outgoing_pub: Vec<Option<Publish>>,
     /// Packet ids of released QoS 2 publishes
     pub(crate) outgoing_rel: Vec<Option<u16>>,
     /// Packet ids on incoming QoS 2 publishes
     pub(crate) incoming_pub: Vec<Option<u16>>,
+    /// Number of incoming QoS 2 publishes waiting for their release
+    pub(crate) incoming_tracked: usize,
+    /// Maximum number of incoming QoS 2 publishes deduplicated at once
+    pub(crate) dedup_window: usize,
     /// Last collision due to broker not acking in order
     pub collision: Option<Publish>,
+    /// Tokens of outgoing publishes which resolve on ack
+    pub(crate) tokens: HashMap<u16, TokenTx>,
+    /// Token of the collided publish
+    pub(crate) collision_token: Option<TokenTx>,
+    /// Active subscriptions of this client
+    pub(crate) subscriptions: HashMap<String, QoS>,
+    /// Topics and requested qos of subscribes waiting for their subacks
+    pub(crate) pending_subscribes: HashMap<u16, Vec<(String, QoS)>>,
+    /// Packet ids of unsubscribes waiting for their unsubacks
+    pub(crate) pending_unsubscribes: HashSet<u16>,
+    /// Local maximum qos of subscriptions
+    pub(crate) max_subscribe_qos: QoS,
+    /// Incoming publishes are acked by the user
+    pub(crate) manual_acks: bool,
+    /// Time of the oldest unanswered pingreq
+    ping_sent: Option<Instant>,
+    /// Packet and byte counters
+    pub(crate) stats: Stats,
+    /// Log of unacked outgoing publishes on disk
+    pub(crate) persistence: Option<Persistence>,
+    /// Aliases of topic prefixes on the wire
+    pub(crate) aliases: TopicAliases,
+    /// Payload transforms of matching topics
+    pub(crate) transforms: Transforms,
+    /// Payload chunking of matching topics
+    pub(crate) chunking: Chunking,
+    /// Remaining chunks of split publishes. Last chunk carries the token of the publish
+    pub(crate) chunks: VecDeque<(Publish, Option<TokenTx>)>,
+    /// Held acks of the other chunks of joined publishes by the packet id of
+    /// the last chunk. Sent when the user acks the publish
+    pub(crate) chunk_acks: HashMap<u16, Vec<Request>>,
     /// Buffered incoming packets
     pub events: VecDeque<Event>,
     /// Write buffer
@@ -84,62 +165,245 @@ impl MqttState {
     pub fn new(max_inflight: u16) -> Self {
         MqttState {
             await_pingresp: false,
+            missed_pings: 0,
+            max_missed_pings: 1,
             collision_ping_count: 0,
             last_incoming: Instant::now(),
             last_outgoing: Instant::now(),
+            last_activity: Instant::now(),
             last_pkid: 0,
+            pkid_range: (1, max_inflight),
+            pkid_exhaustion: PkidExhaustion::Wait,
             inflight: 0,
-            max_inflight,
             // index 0 is wasted as 0 is not a valid packet id
             outgoing_pub: vec![None; max_inflight as usize + 1],
             outgoing_rel: vec![None; max_inflight as usize + 1],
             incoming_pub: vec![None; std::u16::MAX as usize + 1],
+            incoming_tracked: 0,
+            dedup_window: u16::MAX as usize,
             collision: None,
+            tokens: HashMap::new(),
+            collision_token: None,
+            subscriptions: HashMap::new(),
+            pending_subscribes: HashMap::new(),
+            pending_unsubscribes: HashSet::new(),
+            max_subscribe_qos: QoS::ExactlyOnce,
+            manual_acks: false,
+            ping_sent: None,
+            stats: Stats::default(),
+            persistence: None,
+            aliases: TopicAliases::new(),
+            transforms: Transforms::default(),
+            chunking: Chunking::default(),
+            chunks: VecDeque::new(),
+            chunk_acks: HashMap::new(),
             // TODO: Optimize these sizes later
             events: VecDeque::with_capacity(100),
             write: BytesMut::with_capacity(10 * 1024),
         }
     }
 
-    /// Returns inflight outgoing packets and clears internal queues
+    /// Allocates packet ids in `start..=end` instead of `1..=max_inflight`
+    pub(crate) fn set_pkid_range(&mut self, start: u16, end: u16) {
+        let len = end as usize + 1;
+        self.outgoing_pub.resize(len, None);
+        self.outgoing_rel.resize(len, None);
+        self.pkid_range = (start, end);
+    }
+
+    /// Returns inflight outgoing packets in their original order and clears
+    /// internal queues. Packet ids of incoming QoS 2 publishes are retained to
+    /// detect redeliveries when the broker resumes the session
     pub fn clean(&mut self) -> Vec<Request> {
         let mut pending = Vec::with_capacity(100);
-        // remove and collect pending publishes
-        for publish in self.outgoing_pub.iter_mut() {
-            if let Some(publish) = publish.take() {
-                let request = Request::Publish(publish);
-                pending.push(request);
+
+        // Packet ids are allocated sequentially. Oldest inflight packet id
+        // follows the last allocated one
+        let start = self.last_pkid as usize + 1;
+        let len = self.outgoing_pub.len();
+        for pkid in (start..len).chain(1..start) {
+            // remove and collect pending publishes. These are retransmitted as duplicates
+            if let Some(mut publish) = self.outgoing_pub[pkid].take() {
+                publish.dup = true;
+                pending.push(Request::Publish(publish));
+            }
+
+            // remove and collect pending releases
+            if let Some(pkid) = self.outgoing_rel[pkid].take() {
+                pending.push(Request::PubRel(PubRel::new(pkid)));
             }
         }
 
-        // remove and collect pending releases
-        for rel in self.outgoing_rel.iter_mut() {
-            if let Some(pkid) = rel.take() {
-                let request = Request::PubRel(PubRel::new(pkid));
-                pending.push(request);
+        // Subscribes aren't retransmitted. Their subacks never arrive
+        self.pending_subscribes.clear();
+        self.pending_unsubscribes.clear();
+        self.await_pingresp = false;
+        self.missed_pings = 0;
+        self.ping_sent = None;
+        self.collision_ping_count = 0;
+        self.inflight = 0;
+        pending
+    }
+
+    /// Adapts requests of the previous connection to a broker which didn't resume
+    /// the session. Releases are completed as the broker has no record of them.
+    /// Subscriptions are restored ahead of the retransmitted publishes
+    pub fn expire_session(&mut self, pending: Vec<Request>) -> Vec<Request> {
+        let mut requests = Vec::with_capacity(pending.len() + 1);
+        if !self.subscriptions.is_empty() {
+            let filters = self
+                .subscriptions
+                .iter()
+                .map(|(filter, qos)| SubscribeFilter::new(filter.clone(), *qos));
+            requests.push(Request::Subscribe(Subscribe::new_many(filters)));
+        }
+
+        for request in pending {
+            match request {
+                Request::PubRel(pubrel) => {
+                    debug!("Completing release of expired session = {}", pubrel.pkid);
+                    if let Some(token) = self.tokens.remove(&pubrel.pkid) {
+                        token.success();
+                    }
+
+                    self.persist(Record::Ack(pubrel.pkid));
+                }
+                request => requests.push(request),
             }
         }
 
-        // remove packed ids of incoming qos2 publishes
+        requests
+    }
+
+    /// Removes packet ids of incoming QoS 2 publishes. Used when the broker
+    /// doesn't resume the session as it won't redeliver those publishes
+    pub fn clean_incoming(&mut self) {
         for id in self.incoming_pub.iter_mut() {
             id.take();
         }
 
-        self.await_pingresp = false;
-        self.collision_ping_count = 0;
-        self.inflight = 0;
-        pending
+        self.incoming_tracked = 0;
+        self.chunk_acks.clear();
     }
 
     pub fn inflight(&self) -> u16 {
         self.inflight
     }
 
+    /// Captures inflight packets and subscriptions to persist them. Collided
+    /// publish is considered unacked as well
+    pub fn snapshot(&self) -> StateSnapshot {
+        let outgoing = self.outgoing_pub.iter().flatten();
+        let publishes = outgoing
+            .chain(self.collision.iter())
+            .map(|publish| PublishSnapshot {
+                pkid: publish.pkid,
+                qos: publish.qos as u8,
+                retain: publish.retain,
+                topic: publish.topic.clone(),
+                payload: publish.payload.to_vec(),
+            })
+            .collect();
+
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .map(|(filter, qos)| (filter.clone(), *qos as u8))
+            .collect();
+
+        StateSnapshot {
+            last_pkid: self.last_pkid,
+            publishes,
+            releases: self.outgoing_rel.iter().flatten().copied().collect(),
+            incoming: self.incoming_pub.iter().flatten().copied().collect(),
+            subscriptions,
+        }
+    }
+
+    /// Restores state captured by `snapshot`. Restored publishes and releases are
+    /// inflight and are retransmitted by the eventloop after connection
+    pub fn restore(&mut self, snapshot: StateSnapshot) -> Result<(), StateError> {
+        // Snapshots of a larger inflight window can't be restored
+        if snapshot.last_pkid as usize >= self.outgoing_pub.len() {
+            return Err(StateError::Unsolicited(snapshot.last_pkid));
+        }
+
+        for publish in snapshot.publishes {
+            let pkid = publish.pkid as usize;
+            if pkid == 0 || pkid >= self.outgoing_pub.len() {
+                return Err(StateError::Unsolicited(publish.pkid));
+            }
+
+            let qos = qos(publish.qos)?;
+            let payload = Bytes::from(publish.payload);
+            let mut p = Publish::from_bytes(publish.topic, qos, payload);
+            p.pkid = publish.pkid;
+            p.retain = publish.retain;
+
+            self.outgoing_pub[pkid] = Some(p);
+            self.inflight += 1;
+        }
+
+        for pkid in snapshot.releases {
+            if pkid == 0 || pkid as usize >= self.outgoing_rel.len() {
+                return Err(StateError::Unsolicited(pkid));
+            }
+
+            self.outgoing_rel[pkid as usize] = Some(pkid);
+            self.inflight += 1;
+        }
+
+        for pkid in snapshot.incoming {
+            self.track_incoming(pkid);
+        }
+
+        for (filter, q) in snapshot.subscriptions {
+            self.subscriptions.insert(filter, qos(q)?);
+        }
+
+        self.last_pkid = snapshot.last_pkid;
+        Ok(())
+    }
+
+    /// Appends a record of an inflight packet to the persistence log. Failures
+    /// are logged and don't fail the connection
+    fn persist(&self, record: Record) {
+        if let Some(persistence) = &self.persistence {
+            if let Err(e) = persistence.append(record) {
+                error!("Failed to persist {:?}. Error = {:?}", record, e);
+            }
+        }
+    }
+
     /// Consolidates handling of all outgoing mqtt packet logic. Returns a packet which should
     /// be put on to the network by the eventloop
     pub fn handle_outgoing_packet(&mut self, request: Request) -> Result<(), StateError> {
+        trace_event!(debug, ?request, inflight = self.inflight, "outgoing");
+        let written = self.write.len();
+        let o = self.outgoing_packet(request);
+        self.stats.outgoing.outgoing(&self.write[written..]);
+        o
+    }
+
+    fn outgoing_packet(&mut self, request: Request) -> Result<(), StateError> {
         match request {
-            Request::Publish(publish) => self.outgoing_publish(publish)?,
+            Request::Publish(publish) => {
+                self.outgoing_publish(publish)?;
+            }
+            Request::TrackedPublish(publish, token) => {
+                self.outgoing_tracked_publish(publish, token)?
+            }
+            Request::ExpiringPublish(publish, deadline) => {
+                if Instant::now() >= deadline {
+                    warn!("Dropping expired publish. Topic = {}", publish.topic);
+                    self.events.push_back(Event::Expired(publish));
+                    return Ok(());
+                }
+
+                self.outgoing_publish(publish)?;
+            }
+            Request::PubAck(puback) => self.outgoing_puback(puback)?,
+            Request::PubRec(pubrec) => self.outgoing_pubrec(pubrec)?,
             Request::PubRel(pubrel) => self.outgoing_pubrel(pubrel)?,
             Request::Subscribe(subscribe) => self.outgoing_subscribe(subscribe)?,
             Request::Unsubscribe(unsubscribe) => self.outgoing_unsubscribe(unsubscribe)?,
@@ -157,11 +421,79 @@ impl MqttState {
     /// E.g For incoming QoS1 publish packet, this method returns (Publish, Puback). Publish packet will
     /// be forwarded to user and Pubck packet will be written to network
     pub fn handle_incoming_packet(&mut self, packet: Incoming) -> Result<(), StateError> {
+        trace_event!(debug, ?packet, inflight = self.inflight, "incoming");
+        self.stats.incoming.incoming(&packet);
+        let written = self.write.len();
+        let o = self.incoming_packet(packet);
+        self.stats.outgoing.outgoing(&self.write[written..]);
+        o
+    }
+
+    fn incoming_packet(&mut self, mut packet: Incoming) -> Result<(), StateError> {
+        if let Incoming::Publish(publish) = &mut packet {
+            if let Some(topic) = self.aliases.expand(&publish.topic) {
+                publish.topic = topic;
+            }
+
+            if !self.chunking.is_empty()
+                && self.chunking.applies(&publish.topic)
+                && !self.is_duplicate(publish)
+            {
+                // With manual acks, chunks are acked when the user acks the payload
+                let payload = publish.payload.clone();
+                let ack = get_ack_req(publish).filter(|_| self.manual_acks);
+                match self.chunking.join(&publish.topic, payload, ack) {
+                    Ok(Some((payload, acks))) => {
+                        publish.payload = payload;
+                        if !acks.is_empty() {
+                            self.chunk_acks.insert(publish.pkid, acks);
+                        }
+                    }
+                    Ok(None) => {
+                        // Partial payloads aren't forwarded
+                        if !self.manual_acks {
+                            self.ack_incoming_publish(publish)?;
+                        }
+
+                        self.last_incoming = Instant::now();
+                        self.last_activity = self.last_incoming;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        // Corrupt chunks are acked and dropped
+                        error!("Failed to join chunk of {}. Error = {}", publish.topic, e);
+                        self.ack_incoming_publish(publish)?;
+                        self.last_incoming = Instant::now();
+                        self.last_activity = self.last_incoming;
+                        return Ok(());
+                    }
+                }
+            }
+
+            if !self.transforms.is_empty() {
+                let payload = publish.payload.clone();
+                match self.transforms.decode(&publish.topic, payload) {
+                    Ok(payload) => publish.payload = payload,
+                    Err(e) => error!(
+                        "Failed to decode payload of {}. Error = {}",
+                        publish.topic, e
+                    ),
+                }
+            }
+        }
+
         let out = match &packet {
+            Incoming::Publish(publish) if self.is_duplicate(publish) => {
+                // Redelivered QoS 2 publish is acked again but not forwarded to the user
+                self.handle_duplicate_publish(publish)?;
+                self.last_incoming = Instant::now();
+                self.last_activity = self.last_incoming;
+                return Ok(());
+            }
             Incoming::PingResp => self.handle_incoming_pingresp(),
             Incoming::Publish(publish) => self.handle_incoming_publish(publish),
-            Incoming::SubAck(_suback) => self.handle_incoming_suback(),
-            Incoming::UnsubAck(_unsuback) => self.handle_incoming_unsuback(),
+            Incoming::SubAck(suback) => self.handle_incoming_suback(suback),
+            Incoming::UnsubAck(unsuback) => self.handle_incoming_unsuback(unsuback),
             Incoming::PubAck(puback) => self.handle_incoming_puback(puback),
             Incoming::PubRec(pubrec) => self.handle_incoming_pubrec(pubrec),
             Incoming::PubRel(pubrel) => self.handle_incoming_pubrel(pubrel),
@@ -173,25 +505,82 @@ impl MqttState {
         };
 
         out?;
-        self.events.push_back(Event::Incoming(packet));
         self.last_incoming = Instant::now();
+        if packet != Incoming::PingResp {
+            self.last_activity = self.last_incoming;
+        }
+
+        self.events.push_back(Event::Incoming(packet));
         Ok(())
     }
 
-    fn handle_incoming_suback(&mut self) -> Result<(), StateError> {
+    fn handle_incoming_suback(&mut self, suback: &SubAck) -> Result<(), StateError> {
+        let topics = match self.pending_subscribes.remove(&suback.pkid) {
+            Some(topics) => topics,
+            None => {
+                warn!("Suback of an unknown subscribe: {:?}", suback.pkid);
+                return Ok(());
+            }
+        };
+
+        let mut results = Vec::with_capacity(topics.len());
+        let mut downgrades = Vec::new();
+        for ((topic, requested), code) in topics.into_iter().zip(suback.return_codes.iter()) {
+            match code {
+                SubscribeReasonCode::Failure => {
+                    warn!("Subscription to {} rejected by the broker", topic);
+                    self.subscriptions.remove(&topic);
+                }
+                SubscribeReasonCode::Success(granted) if *granted < requested => {
+                    warn!(
+                        "Subscription to {} downgraded from {:?} to {:?}",
+                        topic, requested, granted
+                    );
+
+                    // Resubscriptions ask for what the broker grants
+                    if let Some(qos) = self.subscriptions.get_mut(&topic) {
+                        *qos = *granted;
+                    }
+
+                    downgrades.push((topic.clone(), *granted));
+                }
+                SubscribeReasonCode::Success(_) => (),
+            }
+
+            results.push((topic, *code));
+        }
+
+        let result = SubscribeResult {
+            pkid: suback.pkid,
+            results,
+            downgrades,
+        };
+
+        self.events.push_back(Event::Subscribed(result));
         Ok(())
     }
 
-    fn handle_incoming_unsuback(&mut self) -> Result<(), StateError> {
+    fn handle_incoming_unsuback(&mut self, unsuback: &UnsubAck) -> Result<(), StateError> {
+        if !self.pending_unsubscribes.remove(&unsuback.pkid) {
+            warn!("Unsuback of an unknown unsubscribe: {:?}", unsuback.pkid);
+        }
+
         Ok(())
     }
 
     /// Results in a publish notification in all the QoS cases. Replys with an ack
-    /// in case of QoS1 and Replys rec in case of QoS while also storing the message
+    /// in case of QoS1 and Replys rec in case of QoS while also storing the message.
+    /// With manual acks, user acks the publish after processing it
     fn handle_incoming_publish(&mut self, publish: &Publish) -> Result<(), StateError> {
-        let qos = publish.qos;
+        if self.manual_acks {
+            return Ok(());
+        }
 
-        match qos {
+        self.ack_incoming_publish(publish)
+    }
+
+    fn ack_incoming_publish(&mut self, publish: &Publish) -> Result<(), StateError> {
+        match publish.qos {
             QoS::AtMostOnce => Ok(()),
             QoS::AtLeastOnce => {
                 let pkid = publish.pkid;
@@ -204,7 +593,12 @@ impl MqttState {
             QoS::ExactlyOnce => {
                 let pkid = publish.pkid;
                 PubRec::new(pkid).write(&mut self.write)?;
-                self.incoming_pub[pkid as usize] = Some(pkid);
+
+                // Publishes on downgraded subscriptions are delivered at least once
+                if self.exactly_once(&publish.topic) {
+                    self.track_incoming(pkid);
+                }
+
                 let event = Event::Outgoing(Outgoing::PubRec(pkid));
                 self.events.push_back(event);
                 Ok(())
@@ -212,10 +606,70 @@ impl MqttState {
         }
     }
 
+    /// Exactly once bookkeeping is done for topics without subscriptions (previous
+    /// session) or if any of the matching subscriptions is `ExactlyOnce`
+    fn exactly_once(&self, topic: &str) -> bool {
+        if self.max_subscribe_qos != QoS::ExactlyOnce {
+            return false;
+        }
+
+        let mut matched = self
+            .subscriptions
+            .iter()
+            .filter(|(filter, _)| matches(topic, filter))
+            .peekable();
+
+        if matched.peek().is_none() {
+            return true;
+        }
+
+        matched.any(|(_, qos)| *qos == QoS::ExactlyOnce)
+    }
+
+    /// Remembers an incoming QoS 2 publish till its release to detect its
+    /// redeliveries. Publishes beyond the dedup window are delivered at least once
+    fn track_incoming(&mut self, pkid: u16) {
+        if self.incoming_pub[pkid as usize].is_some() {
+            return;
+        }
+
+        if self.incoming_tracked >= self.dedup_window {
+            warn!("Dedup window full. Pkid = {} isn't deduplicated", pkid);
+            return;
+        }
+
+        self.incoming_pub[pkid as usize] = Some(pkid);
+        self.incoming_tracked += 1;
+    }
+
+    /// QoS 2 publish whose release isn't received yet. Broker redelivers these
+    /// (with dup flag) when it doesn't see our PubRec
+    fn is_duplicate(&self, publish: &Publish) -> bool {
+        publish.qos == QoS::ExactlyOnce && self.incoming_pub[publish.pkid as usize].is_some()
+    }
+
+    fn handle_duplicate_publish(&mut self, publish: &Publish) -> Result<(), StateError> {
+        let pkid = publish.pkid;
+        warn!(
+            "Duplicate QoS 2 publish. Pkid = {}, Dup = {}",
+            pkid, publish.dup
+        );
+
+        PubRec::new(pkid).write(&mut self.write)?;
+        let event = Event::Outgoing(Outgoing::PubRec(pkid));
+        self.events.push_back(event);
+        Ok(())
+    }
+
     fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<(), StateError> {
         let v = match mem::replace(&mut self.outgoing_pub[puback.pkid as usize], None) {
             Some(_) => {
                 self.inflight -= 1;
+                if let Some(token) = self.tokens.remove(&puback.pkid) {
+                    token.success();
+                }
+
+                self.persist(Record::Ack(puback.pkid));
                 Ok(())
             }
             None => {
@@ -227,8 +681,12 @@ impl MqttState {
         if let Some(publish) = self.check_collision(puback.pkid) {
             self.outgoing_pub[publish.pkid as usize] = Some(publish.clone());
             self.inflight += 1;
+            self.persist(Record::Publish(&publish));
+            if let Some(token) = self.collision_token.take() {
+                self.tokens.insert(publish.pkid, token);
+            }
 
-            publish.write(&mut self.write)?;
+            self.write_publish(&publish)?;
             let event = Event::Outgoing(Outgoing::Publish(publish.pkid));
             self.events.push_back(event);
             self.collision_ping_count = 0;
@@ -242,6 +700,7 @@ impl MqttState {
             Some(_) => {
                 // NOTE: Inflight - 1 for qos2 in comp
                 self.outgoing_rel[pubrec.pkid as usize] = Some(pubrec.pkid);
+                self.persist(Record::Release(pubrec.pkid));
                 PubRel::new(pubrec.pkid).write(&mut self.write)?;
 
                 let event = Event::Outgoing(Outgoing::PubRel(pubrec.pkid));
@@ -258,21 +717,43 @@ impl MqttState {
     fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<(), StateError> {
         match mem::replace(&mut self.incoming_pub[pubrel.pkid as usize], None) {
             Some(_) => {
+                self.incoming_tracked -= 1;
                 PubComp::new(pubrel.pkid).write(&mut self.write)?;
                 let event = Event::Outgoing(Outgoing::PubComp(pubrel.pkid));
                 self.events.push_back(event);
                 Ok(())
             }
+            // Release of a publish which is already completed. This happens when broker
+            // retransmits pubrel after a reconnection as our pubcomp didn't reach it
             None => {
-                error!("Unsolicited pubrel packet: {:?}", pubrel.pkid);
-                Err(StateError::Unsolicited(pubrel.pkid))
+                warn!("Pubrel for unknown pkid = {}. Completing", pubrel.pkid);
+                PubComp::new(pubrel.pkid).write(&mut self.write)?;
+                let event = Event::Outgoing(Outgoing::PubComp(pubrel.pkid));
+                self.events.push_back(event);
+                Ok(())
             }
         }
     }
 
     fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<(), StateError> {
+        // Completion is persisted before the collided publish takes its packet id
+        if self.outgoing_rel[pubcomp.pkid as usize].is_some() {
+            if let Some(token) = self.tokens.remove(&pubcomp.pkid) {
+                token.success();
+            }
+
+            self.persist(Record::Ack(pubcomp.pkid));
+        }
+
         if let Some(publish) = self.check_collision(pubcomp.pkid) {
-            publish.write(&mut self.write)?;
+            self.outgoing_pub[publish.pkid as usize] = Some(publish.clone());
+            self.inflight += 1;
+            self.persist(Record::Publish(&publish));
+            if let Some(token) = self.collision_token.take() {
+                self.tokens.insert(publish.pkid, token);
+            }
+
+            self.write_publish(&publish)?;
             let event = Event::Outgoing(Outgoing::Publish(publish.pkid));
             self.events.push_back(event);
             self.collision_ping_count = 0;
@@ -292,35 +773,111 @@ impl MqttState {
 
     fn handle_incoming_pingresp(&mut self) -> Result<(), StateError> {
         self.await_pingresp = false;
+        self.missed_pings = 0;
+        if let Some(sent) = self.ping_sent.take() {
+            let rtt = sent.elapsed();
+            self.stats.ping_rtt = Some(rtt);
+            self.events.push_back(Event::PingRtt(rtt));
+            trace_event!(debug, ?rtt, "pingresp");
+        }
+
         Ok(())
     }
 
+    /// Sends the next queued chunk of a split publish
+    pub fn handle_outgoing_chunk(&mut self) -> Result<(), StateError> {
+        let (publish, token) = match self.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+
+        let written = self.write.len();
+        let o = self.send_tracked_publish(publish, token);
+        self.stats.outgoing.outgoing(&self.write[written..]);
+        self.last_outgoing = Instant::now();
+        o
+    }
+
+    /// Encodes the payload of a new publish and splits it on chunked topics.
+    /// Returns the first chunk and queues the rest. Token of a split publish
+    /// moves to its last chunk
+    fn prepare_publish(
+        &mut self,
+        mut publish: Publish,
+        mut token: Option<TokenTx>,
+    ) -> (Publish, Option<TokenTx>) {
+        // Inflight and persisted publishes are already encoded
+        if publish.pkid != 0 {
+            return (publish, token);
+        }
+
+        if !self.transforms.is_empty() {
+            let payload = publish.payload.clone();
+            publish.payload = self.transforms.encode(&publish.topic, payload);
+        }
+
+        if self.chunking.is_empty() {
+            return (publish, token);
+        }
+
+        let payload = publish.payload.clone();
+        let mut chunks = match self.chunking.split(&publish.topic, payload) {
+            Some(chunks) => chunks.into_iter(),
+            None => return (publish, token),
+        };
+
+        publish.payload = chunks.next().unwrap();
+        let last = chunks.len();
+        for (i, payload) in chunks.enumerate() {
+            let mut chunk = publish.clone();
+            chunk.payload = payload;
+            let token = if i + 1 == last { token.take() } else { None };
+            self.chunks.push_back((chunk, token));
+        }
+
+        (publish, token)
+    }
+
     /// Adds next packet identifier to QoS 1 and 2 publish packets and returns
-    /// it buy wrapping publish in packet
-    fn outgoing_publish(&mut self, mut publish: Publish) -> Result<(), StateError> {
+    /// the packet identifier
+    fn outgoing_publish(&mut self, publish: Publish) -> Result<u16, StateError> {
+        let (publish, _) = self.prepare_publish(publish, None);
+        self.send_publish(publish)
+    }
+
+    fn send_publish(&mut self, mut publish: Publish) -> Result<u16, StateError> {
         if publish.qos != QoS::AtMostOnce {
+            // Replays of previous connections are already persisted
+            let replay = publish.pkid != 0;
             if publish.pkid == 0 {
-                publish.pkid = self.next_pkid();
+                let wait = self.pkid_exhaustion == PkidExhaustion::Wait;
+                publish.pkid = self.allocate_pkid(wait)?;
             }
 
+            // QoS 2 publish holds on to its packet id until it is completed
             let pkid = publish.pkid;
             if self
                 .outgoing_pub
                 .get(publish.pkid as usize)
                 .unwrap()
                 .is_some()
+                || self.outgoing_rel[pkid as usize].is_some()
             {
                 info!("Collision on packet id = {:?}", publish.pkid);
+                trace_event!(info, pkid = publish.pkid, "collision");
                 self.collision = Some(publish);
                 let event = Event::Outgoing(Outgoing::AwaitAck(pkid));
                 self.events.push_back(event);
-                return Ok(());
+                return Ok(pkid);
             }
 
             // if there is an existing publish at this pkid, this implies that broker hasn't acked this
             // packet yet. This error is possible only when broker isn't acking sequentially
             self.outgoing_pub[pkid as usize] = Some(publish.clone());
             self.inflight += 1;
+            if !replay {
+                self.persist(Record::Publish(&publish));
+            }
         };
 
         debug!(
@@ -330,14 +887,111 @@ impl MqttState {
             publish.payload.len()
         );
 
-        publish.write(&mut self.write)?;
+        self.write_publish(&publish)?;
         let event = Event::Outgoing(Outgoing::Publish(publish.pkid));
         self.events.push_back(event);
+        Ok(publish.pkid)
+    }
+
+    /// Writes the publish with its topic compressed by the aliases. Inflight
+    /// and persisted publishes keep their full topics
+    fn write_publish(&mut self, publish: &Publish) -> Result<(), StateError> {
+        match self.aliases.compress(&publish.topic) {
+            Some(topic) => {
+                let mut publish = publish.clone();
+                publish.topic = topic;
+                publish.write(&mut self.write)?;
+            }
+            None => {
+                publish.write(&mut self.write)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish whose token resolves on ack. QoS 0 publishes resolve right away
+    fn outgoing_tracked_publish(
+        &mut self,
+        publish: Publish,
+        token: TokenTx,
+    ) -> Result<(), StateError> {
+        let (publish, token) = self.prepare_publish(publish, Some(token));
+        self.send_tracked_publish(publish, token)
+    }
+
+    fn send_tracked_publish(
+        &mut self,
+        publish: Publish,
+        token: Option<TokenTx>,
+    ) -> Result<(), StateError> {
+        let collided = self.collision.is_some();
+        let pkid = self.send_publish(publish)?;
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        if pkid == 0 {
+            token.success();
+        } else if !collided && self.collision.is_some() {
+            self.collision_token = Some(token);
+        } else {
+            self.tokens.insert(pkid, token);
+        }
+
+        Ok(())
+    }
+
+    /// Manual ack of an incoming QoS 1 publish
+    fn outgoing_puback(&mut self, puback: PubAck) -> Result<(), StateError> {
+        debug!("Puback. Pkid = {}", puback.pkid);
+        puback.write(&mut self.write)?;
+
+        let event = Event::Outgoing(Outgoing::PubAck(puback.pkid));
+        self.events.push_back(event);
+        self.outgoing_chunk_acks(puback.pkid)
+    }
+
+    /// Manual ack of an incoming QoS 2 publish. Redeliveries of this publish
+    /// are deduplicated from here on till it is released
+    fn outgoing_pubrec(&mut self, pubrec: PubRec) -> Result<(), StateError> {
+        let pkid = pubrec.pkid;
+        debug!("Pubrec. Pkid = {}", pkid);
+        pubrec.write(&mut self.write)?;
+
+        if self.max_subscribe_qos == QoS::ExactlyOnce {
+            self.track_incoming(pkid);
+        }
+
+        let event = Event::Outgoing(Outgoing::PubRec(pkid));
+        self.events.push_back(event);
+        self.outgoing_chunk_acks(pkid)
+    }
+
+    /// Acks the other chunks of a joined publish once the user acks it
+    fn outgoing_chunk_acks(&mut self, pkid: u16) -> Result<(), StateError> {
+        let acks = match self.chunk_acks.remove(&pkid) {
+            Some(acks) => acks,
+            None => return Ok(()),
+        };
+
+        for ack in acks {
+            match ack {
+                Request::PubAck(puback) => self.outgoing_puback(puback)?,
+                Request::PubRec(pubrec) => self.outgoing_pubrec(pubrec)?,
+                _ => (),
+            }
+        }
+
         Ok(())
     }
 
+    /// Retransmits release of a QoS 2 publish after reconnection. Publish
+    /// stays inflight until the broker completes it
     fn outgoing_pubrel(&mut self, pubrel: PubRel) -> Result<(), StateError> {
         let pubrel = self.save_pubrel(pubrel)?;
+        self.inflight += 1;
 
         debug!("Pubrel. Pkid = {}", pubrel.pkid);
         PubRel::new(pubrel.pkid).write(&mut self.write)?;
@@ -361,9 +1015,14 @@ impl MqttState {
             }
         }
 
-        // raise error if last ping didn't receive ack
+        // raise error if last pings didn't receive acks
         if self.await_pingresp {
-            return Err(StateError::AwaitPingResp);
+            self.missed_pings += 1;
+            if self.missed_pings >= self.max_missed_pings {
+                return Err(StateError::AwaitPingResp);
+            }
+
+            warn!("Missed pingresp. Count = {}", self.missed_pings);
         }
 
         self.await_pingresp = true;
@@ -377,13 +1036,14 @@ impl MqttState {
         );
 
         PingReq.write(&mut self.write)?;
+        self.ping_sent.get_or_insert_with(Instant::now);
         let event = Event::Outgoing(Outgoing::PingReq);
         self.events.push_back(event);
         Ok(())
     }
 
     fn outgoing_subscribe(&mut self, mut subscription: Subscribe) -> Result<(), StateError> {
-        let pkid = self.next_pkid();
+        let pkid = self.allocate_pkid(false)?;
         subscription.pkid = pkid;
 
         debug!(
@@ -391,6 +1051,31 @@ impl MqttState {
             subscription.filters, subscription.pkid
         );
 
+        for filter in subscription.filters.iter_mut() {
+            if filter.qos > self.max_subscribe_qos {
+                debug!(
+                    "Downgrading {} to {:?}",
+                    filter.path, self.max_subscribe_qos
+                );
+                filter.qos = self.max_subscribe_qos;
+            }
+
+            self.subscriptions.insert(filter.path.clone(), filter.qos);
+        }
+
+        let topics = subscription
+            .filters
+            .iter()
+            .map(|f| (f.path.clone(), f.qos))
+            .collect();
+        self.pending_subscribes.insert(pkid, topics);
+
+        for filter in subscription.filters.iter_mut() {
+            if let Some(path) = self.aliases.compress(&filter.path) {
+                filter.path = path;
+            }
+        }
+
         subscription.write(&mut self.write)?;
         let event = Event::Outgoing(Outgoing::Subscribe(subscription.pkid));
         self.events.push_back(event);
@@ -398,14 +1083,22 @@ impl MqttState {
     }
 
     fn outgoing_unsubscribe(&mut self, mut unsub: Unsubscribe) -> Result<(), StateError> {
-        let pkid = self.next_pkid();
+        let pkid = self.allocate_pkid(false)?;
         unsub.pkid = pkid;
+        self.pending_unsubscribes.insert(pkid);
 
         debug!(
             "Unsubscribe. Topics = {:?}, Pkid = {:?}",
             unsub.topics, unsub.pkid
         );
 
+        for topic in unsub.topics.iter_mut() {
+            self.subscriptions.remove(topic);
+            if let Some(alias) = self.aliases.compress(topic) {
+                *topic = alias;
+            }
+        }
+
         unsub.write(&mut self.write)?;
         let event = Event::Outgoing(Outgoing::Unsubscribe(unsub.pkid));
         self.events.push_back(event);
@@ -445,22 +1138,45 @@ impl MqttState {
         Ok(pubrel)
     }
 
+    /// Next packet id which isn't used by an unacked packet. With `wait`, ids of
+    /// unacked publishes and releases aren't skipped. Such publishes wait for
+    /// their ids as collisions. Ids of pending subscribes and unsubscribes are
+    /// always skipped
+    fn allocate_pkid(&mut self, wait: bool) -> Result<u16, StateError> {
+        let (start, end) = self.pkid_range;
+        for _ in start..=end {
+            let pkid = self.next_pkid();
+            let acking = self.pending_subscribes.contains_key(&pkid)
+                || self.pending_unsubscribes.contains(&pkid);
+            let inflight = self.outgoing_pub[pkid as usize].is_some()
+                || self.outgoing_rel[pkid as usize].is_some();
+
+            if !acking && (wait || !inflight) {
+                return Ok(pkid);
+            }
+        }
+
+        error!("All packet ids in {}..={} are unacked", start, end);
+        Err(StateError::PkidsExhausted)
+    }
+
     /// http://stackoverflow.com/questions/11115364/mqtt-messageid-practical-implementation
-    /// Packet ids are incremented till maximum set inflight messages and reset to 1 after that.
+    /// Packet ids are incremented till the end of the range (maximum set inflight
+    /// messages by default) and reset to the start after that.
     ///
     fn next_pkid(&mut self) -> u16 {
-        let next_pkid = self.last_pkid + 1;
-
-        // When next packet id is at the edge of inflight queue,
-        // set await flag. This instructs eventloop to stop
-        // processing requests until all the inflight publishes
-        // are acked
-        if next_pkid == self.max_inflight {
-            self.last_pkid = 0;
-            return next_pkid;
+        let (start, end) = self.pkid_range;
+        let mut next_pkid = self.last_pkid.wrapping_add(1);
+        if next_pkid < start || next_pkid > end {
+            next_pkid = start;
         }
 
-        self.last_pkid = next_pkid;
+        // When next packet id is at the edge of the range, wrap around
+        self.last_pkid = match next_pkid == end {
+            true => start - 1,
+            false => next_pkid,
+        };
+
         next_pkid
     }
 }
@@ -468,9 +1184,15 @@ impl MqttState {
 #[cfg(test)]
 mod test {
     use super::{MqttState, StateError};
-    use crate::{Incoming, MqttOptions, Request};
+    use crate::chunk;
+    use crate::{token, Event, Incoming, MqttOptions, Outgoing, PayloadTransform, Request};
+    use crate::{PkidExhaustion, TokenError};
+    use bytes::Bytes;
     use mqttbytes::v4::*;
     use mqttbytes::*;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
         let topic = "hello/world".to_owned();
@@ -512,6 +1234,34 @@ mod test {
         }
     }
 
+    #[test]
+    fn pkids_are_allocated_in_range_and_never_reused_while_unacked() {
+        let mut mqtt = build_mqttstate();
+        mqtt.set_pkid_range(10, 12);
+        mqtt.pkid_exhaustion = PkidExhaustion::Error;
+
+        for pkid in 10..=12 {
+            let publish = build_outgoing_publish(QoS::AtLeastOnce);
+            assert_eq!(mqtt.outgoing_publish(publish).unwrap(), pkid);
+        }
+
+        // out of order ack. 10 is skipped
+        mqtt.handle_incoming_puback(&PubAck::new(11)).unwrap();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        assert_eq!(mqtt.outgoing_publish(publish).unwrap(), 11);
+
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        let o = mqtt.outgoing_publish(publish);
+        assert!(matches!(o, Err(StateError::PkidsExhausted)));
+
+        // subscribes skip unacked ids with waiting publishes as well
+        mqtt.pkid_exhaustion = PkidExhaustion::Wait;
+        mqtt.handle_incoming_puback(&PubAck::new(12)).unwrap();
+        let subscribe = Subscribe::new("hello/world", QoS::AtLeastOnce);
+        mqtt.outgoing_subscribe(subscribe).unwrap();
+        assert!(mqtt.pending_subscribes.contains_key(&12));
+    }
+
     #[test]
     fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let mut mqtt = build_mqttstate();
@@ -677,6 +1427,288 @@ mod test {
         assert_eq!(mqtt.inflight, 0);
     }
 
+    #[test]
+    fn redelivered_qos2_publish_is_acked_but_not_forwarded() {
+        let mut mqtt = build_mqttstate();
+        let publish = build_incoming_publish(QoS::ExactlyOnce, 1);
+
+        mqtt.handle_incoming_packet(Incoming::Publish(publish.clone()))
+            .unwrap();
+
+        // Reconnection doesn't forget incoming QoS 2 publishes which aren't released
+        mqtt.clean();
+        let mut publish = publish;
+        publish.dup = true;
+        mqtt.handle_incoming_packet(Incoming::Publish(publish))
+            .unwrap();
+
+        let forwarded = mqtt
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::Incoming(Incoming::Publish(_))))
+            .count();
+
+        let acked = mqtt
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::Outgoing(Outgoing::PubRec(1))))
+            .count();
+
+        assert_eq!(forwarded, 1);
+        assert_eq!(acked, 2);
+
+        // Release completes the publish. Retransmitted release is completed as well
+        mqtt.handle_incoming_pubrel(&PubRel::new(1)).unwrap();
+        mqtt.handle_incoming_pubrel(&PubRel::new(1)).unwrap();
+        let mut comps = 0;
+        while let Ok(packet) = read(&mut mqtt.write, 10 * 1024) {
+            if let Packet::PubComp(pubcomp) = packet {
+                assert_eq!(pubcomp.pkid, 1);
+                comps += 1;
+            }
+        }
+
+        assert_eq!(comps, 2);
+    }
+
+    #[test]
+    fn qos2_publishes_beyond_dedup_window_are_delivered_at_least_once() {
+        let mut mqtt = build_mqttstate();
+        mqtt.dedup_window = 1;
+        let publish1 = build_incoming_publish(QoS::ExactlyOnce, 1);
+        let publish2 = build_incoming_publish(QoS::ExactlyOnce, 2);
+        mqtt.handle_incoming_packet(Incoming::Publish(publish1.clone()))
+            .unwrap();
+        mqtt.handle_incoming_packet(Incoming::Publish(publish2.clone()))
+            .unwrap();
+        assert!(mqtt.is_duplicate(&publish1));
+        assert!(!mqtt.is_duplicate(&publish2));
+
+        // release frees the window
+        mqtt.handle_incoming_pubrel(&PubRel::new(1)).unwrap();
+        mqtt.handle_incoming_packet(Incoming::Publish(publish2.clone()))
+            .unwrap();
+        assert!(mqtt.is_duplicate(&publish2));
+    }
+
+    #[test]
+    fn topics_are_aliased_on_the_wire_only() {
+        let mut mqtt = build_mqttstate();
+        mqtt.aliases.add("hello/", "$a/1/");
+
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce))
+            .unwrap();
+        match read(&mut mqtt.write, 10 * 1024).unwrap() {
+            Packet::Publish(publish) => assert_eq!(publish.topic, "$a/1/world"),
+            packet => panic!("Unexpected packet = {:?}", packet),
+        }
+
+        let inflight = mqtt.outgoing_pub[1].as_ref().unwrap();
+        assert_eq!(inflight.topic, "hello/world");
+
+        let mut publish = build_incoming_publish(QoS::AtMostOnce, 0);
+        publish.topic = "$a/1/world".to_owned();
+        mqtt.handle_incoming_packet(Incoming::Publish(publish))
+            .unwrap();
+        match mqtt.events.pop_back() {
+            Some(Event::Incoming(Incoming::Publish(p))) => assert_eq!(p.topic, "hello/world"),
+            event => panic!("Unexpected event = {:?}", event),
+        }
+    }
+
+    struct Increment;
+
+    impl PayloadTransform for Increment {
+        fn encode(&self, _topic: &str, payload: Bytes) -> Bytes {
+            payload.iter().map(|b| b + 1).collect::<Vec<u8>>().into()
+        }
+
+        fn decode(&self, _topic: &str, payload: Bytes) -> io::Result<Bytes> {
+            Ok(payload.iter().map(|b| b - 1).collect::<Vec<u8>>().into())
+        }
+    }
+
+    #[test]
+    fn payloads_are_encoded_once_and_decoded_on_matching_topics() {
+        let mut mqtt = build_mqttstate();
+        mqtt.transforms
+            .add("hello/+".to_owned(), Arc::new(Increment));
+
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce))
+            .unwrap();
+        let inflight = mqtt.outgoing_pub[1].take().unwrap();
+        assert_eq!(inflight.payload, Bytes::from(vec![2, 3, 4]));
+
+        // retransmission isn't encoded again
+        mqtt.write.clear();
+        mqtt.outgoing_publish(inflight).unwrap();
+        match read(&mut mqtt.write, 10 * 1024).unwrap() {
+            Packet::Publish(publish) => assert_eq!(publish.payload, Bytes::from(vec![2, 3, 4])),
+            packet => panic!("Unexpected packet = {:?}", packet),
+        }
+
+        let mut publish = build_incoming_publish(QoS::AtMostOnce, 0);
+        publish.payload = Bytes::from(vec![2, 3, 4]);
+        mqtt.handle_incoming_packet(Incoming::Publish(publish))
+            .unwrap();
+        match mqtt.events.pop_back() {
+            Some(Event::Incoming(Incoming::Publish(p))) => assert_eq!(&p.payload[..], &[1, 2, 3]),
+            event => panic!("Unexpected event = {:?}", event),
+        }
+    }
+
+    #[test]
+    fn qos2_publishes_and_releases_are_retransmitted_after_reconnection() {
+        let mut mqtt = build_mqttstate();
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce))
+            .unwrap();
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce))
+            .unwrap();
+        mqtt.handle_incoming_pubrec(&PubRec::new(1)).unwrap();
+
+        let pending = mqtt.clean();
+        assert_eq!(mqtt.inflight, 0);
+        assert_eq!(pending.len(), 2);
+
+        for request in pending {
+            if let Request::Publish(publish) = &request {
+                assert!(publish.dup);
+                assert_eq!(publish.pkid, 2);
+            }
+
+            mqtt.handle_outgoing_packet(request).unwrap();
+        }
+
+        assert_eq!(mqtt.inflight, 2);
+        mqtt.handle_incoming_pubcomp(&PubComp::new(1)).unwrap();
+        mqtt.handle_incoming_pubrec(&PubRec::new(2)).unwrap();
+        mqtt.handle_incoming_pubcomp(&PubComp::new(2)).unwrap();
+        assert_eq!(mqtt.inflight, 0);
+    }
+
+    #[test]
+    fn downgraded_subscriptions_skip_exactly_once_bookkeeping() {
+        let mut mqtt = build_mqttstate();
+        mqtt.max_subscribe_qos = QoS::AtLeastOnce;
+
+        let subscribe = Subscribe::new("hello/world", QoS::ExactlyOnce);
+        mqtt.outgoing_subscribe(subscribe).unwrap();
+        assert_eq!(mqtt.subscriptions["hello/world"], QoS::AtLeastOnce);
+
+        let packet = read(&mut mqtt.write, 10 * 1024).unwrap();
+        match packet {
+            Packet::Subscribe(subscribe) => assert_eq!(subscribe.filters[0].qos, QoS::AtLeastOnce),
+            packet => panic!("Invalid network request: {:?}", packet),
+        }
+
+        // Broker sending qos 2 anyway is still acked as per protocol
+        let publish = build_incoming_publish(QoS::ExactlyOnce, 1);
+        mqtt.handle_incoming_publish(&publish).unwrap();
+        assert!(mqtt.incoming_pub[1].is_none());
+
+        mqtt.handle_incoming_pubrel(&PubRel::new(1)).unwrap();
+        let mut packets = Vec::new();
+        while let Ok(packet) = read(&mut mqtt.write, 10 * 1024) {
+            packets.push(packet);
+        }
+
+        assert_eq!(
+            packets,
+            vec![
+                Packet::PubRec(PubRec::new(1)),
+                Packet::PubComp(PubComp::new(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn restored_snapshot_retransmits_inflight_packets() {
+        let mut mqtt = build_mqttstate();
+        mqtt.outgoing_subscribe(Subscribe::new("hello/+", QoS::ExactlyOnce))
+            .unwrap();
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce))
+            .unwrap();
+        mqtt.outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce))
+            .unwrap();
+        mqtt.handle_incoming_pubrec(&PubRec::new(3)).unwrap();
+        mqtt.handle_incoming_publish(&build_incoming_publish(QoS::ExactlyOnce, 7))
+            .unwrap();
+
+        let snapshot = mqtt.snapshot();
+        assert_eq!(snapshot.publishes.len(), 1);
+        assert_eq!(snapshot.releases, vec![3]);
+        assert_eq!(snapshot.incoming, vec![7]);
+
+        // Process restart
+        let mut mqtt = build_mqttstate();
+        mqtt.restore(snapshot.clone()).unwrap();
+        assert_eq!(mqtt.inflight, 2);
+        assert_eq!(mqtt.snapshot(), snapshot);
+
+        let pending = mqtt.clean();
+        assert_eq!(pending.len(), 2);
+        match &pending[0] {
+            Request::Publish(publish) => assert_eq!(publish.pkid, 2),
+            request => panic!("Unexpected request = {:?}", request),
+        }
+
+        assert_eq!(pending[1], Request::PubRel(PubRel::new(3)));
+    }
+
+    #[test]
+    fn snapshots_of_larger_inflight_windows_are_rejected() {
+        let mut snapshot = MqttState::new(100).snapshot();
+        snapshot.last_pkid = 50;
+        let mut mqtt = MqttState::new(5);
+        match mqtt.restore(snapshot) {
+            Err(StateError::Unsolicited(50)) => (),
+            result => panic!("Unexpected result = {:?}", result),
+        }
+
+        assert_eq!(mqtt.last_pkid, 0);
+        assert!(mqtt.clean().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_tracked_until_unsubscribed() {
+        let mut mqtt = build_mqttstate();
+
+        let mut subscribe = Subscribe::new("hello/world", QoS::AtLeastOnce);
+        subscribe.add("hello/rumqtt".to_owned(), QoS::AtMostOnce);
+        mqtt.outgoing_subscribe(subscribe).unwrap();
+        assert_eq!(mqtt.subscriptions.len(), 2);
+
+        mqtt.outgoing_unsubscribe(Unsubscribe::new("hello/world"))
+            .unwrap();
+        assert_eq!(mqtt.subscriptions.len(), 1);
+        assert_eq!(mqtt.subscriptions["hello/rumqtt"], QoS::AtMostOnce);
+
+        // subscriptions survive connection cleanup
+        mqtt.clean();
+        assert_eq!(mqtt.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn expired_publishes_are_dropped_with_a_notification() {
+        let mut mqtt = build_mqttstate();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        mqtt.handle_outgoing_packet(Request::ExpiringPublish(publish, deadline))
+            .unwrap();
+        assert_eq!(mqtt.inflight, 1);
+
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        let written = mqtt.write.len();
+        mqtt.handle_outgoing_packet(Request::ExpiringPublish(publish, Instant::now()))
+            .unwrap();
+        assert_eq!(mqtt.inflight, 1);
+        assert_eq!(mqtt.write.len(), written);
+        match mqtt.events.pop_back() {
+            Some(Event::Expired(publish)) => assert_eq!(publish.topic, "hello/world"),
+            event => panic!("Unexpected event = {:?}", event),
+        }
+    }
+
     #[test]
     fn outgoing_ping_handle_should_throw_errors_for_no_pingresp() {
         let mut mqtt = build_mqttstate();
@@ -713,4 +1745,330 @@ mod test {
         // should ping
         mqtt.outgoing_ping().unwrap();
     }
+
+    #[test]
+    fn suback_results_are_matched_with_subscribed_topics() {
+        let mut mqtt = build_mqttstate();
+        let filters = vec![
+            SubscribeFilter::new("hello/1".to_owned(), QoS::AtLeastOnce),
+            SubscribeFilter::new("hello/2".to_owned(), QoS::AtLeastOnce),
+        ];
+
+        let subscribe = Subscribe::new_many(filters);
+        mqtt.handle_outgoing_packet(Request::Subscribe(subscribe))
+            .unwrap();
+        mqtt.events.clear();
+
+        let codes = vec![
+            SubscribeReasonCode::Success(QoS::AtLeastOnce),
+            SubscribeReasonCode::Failure,
+        ];
+        mqtt.handle_incoming_packet(Incoming::SubAck(SubAck::new(1, codes)))
+            .unwrap();
+
+        let result = match mqtt.events.pop_front() {
+            Some(Event::Subscribed(result)) => result,
+            event => panic!("Unexpected event = {:?}", event),
+        };
+
+        assert_eq!(result.failures().collect::<Vec<_>>(), vec!["hello/2"]);
+        assert!(mqtt.subscriptions.contains_key("hello/1"));
+        assert!(!mqtt.subscriptions.contains_key("hello/2"));
+        assert!(mqtt.pending_subscribes.is_empty());
+    }
+
+    #[test]
+    fn missed_pings_are_tolerated_till_the_configured_count() {
+        let mut mqtt = build_mqttstate();
+        mqtt.max_missed_pings = 3;
+
+        mqtt.outgoing_ping().unwrap();
+        mqtt.outgoing_ping().unwrap();
+        mqtt.outgoing_ping().unwrap();
+        mqtt.events.clear();
+
+        // late pingresp resets the count and reports rtt of the oldest ping
+        mqtt.handle_incoming_packet(Incoming::PingResp).unwrap();
+        assert_eq!(mqtt.missed_pings, 0);
+        assert!(matches!(mqtt.events.pop_front(), Some(Event::PingRtt(_))));
+        assert_eq!(
+            mqtt.events.pop_front(),
+            Some(Event::Incoming(Incoming::PingResp))
+        );
+
+        mqtt.outgoing_ping().unwrap();
+        mqtt.outgoing_ping().unwrap();
+        mqtt.outgoing_ping().unwrap();
+        match mqtt.outgoing_ping() {
+            Err(StateError::AwaitPingResp) => (),
+            o => panic!("Should throw pingresp await error. Found = {:?}", o),
+        }
+    }
+
+    #[test]
+    fn incoming_publishes_are_acked_by_the_user_with_manual_acks() {
+        let mut mqtt = build_mqttstate();
+        mqtt.manual_acks = true;
+
+        let publish1 = build_incoming_publish(QoS::AtLeastOnce, 1);
+        let publish2 = build_incoming_publish(QoS::ExactlyOnce, 2);
+        mqtt.handle_incoming_publish(&publish1).unwrap();
+        mqtt.handle_incoming_publish(&publish2).unwrap();
+        assert!(mqtt.write.is_empty());
+        assert!(mqtt.events.is_empty());
+
+        // Unacked QoS 2 publish isn't a duplicate. Redelivery reaches the user again
+        assert!(!mqtt.is_duplicate(&publish2));
+
+        mqtt.handle_outgoing_packet(Request::PubAck(PubAck::new(1)))
+            .unwrap();
+        mqtt.handle_outgoing_packet(Request::PubRec(PubRec::new(2)))
+            .unwrap();
+        assert_eq!(
+            mqtt.events.pop_front(),
+            Some(Event::Outgoing(Outgoing::PubAck(1)))
+        );
+        assert_eq!(
+            mqtt.events.pop_front(),
+            Some(Event::Outgoing(Outgoing::PubRec(2)))
+        );
+
+        let packet = read(&mut mqtt.write, 10 * 1024).unwrap();
+        assert_eq!(packet, Packet::PubAck(PubAck::new(1)));
+        let packet = read(&mut mqtt.write, 10 * 1024).unwrap();
+        assert_eq!(packet, Packet::PubRec(PubRec::new(2)));
+        assert!(mqtt.is_duplicate(&publish2));
+    }
+
+    #[test]
+    fn clean_returns_inflight_packets_in_original_order() {
+        let mut mqtt = MqttState::new(5);
+        for _ in 1..=5 {
+            let publish = build_outgoing_publish(QoS::AtLeastOnce);
+            mqtt.outgoing_publish(publish).unwrap();
+        }
+
+        // Acked packet ids are reused by newer publishes
+        mqtt.handle_incoming_puback(&PubAck::new(1)).unwrap();
+        mqtt.handle_incoming_puback(&PubAck::new(2)).unwrap();
+        for _ in 1..=2 {
+            let publish = build_outgoing_publish(QoS::AtLeastOnce);
+            mqtt.outgoing_publish(publish).unwrap();
+        }
+
+        let pkids: Vec<u16> = mqtt
+            .clean()
+            .into_iter()
+            .map(|request| match request {
+                Request::Publish(publish) if publish.dup => publish.pkid,
+                request => panic!("Unexpected request = {:?}", request),
+            })
+            .collect();
+
+        assert_eq!(pkids, vec![3, 4, 5, 1, 2]);
+        assert_eq!(mqtt.inflight, 0);
+    }
+
+    #[test]
+    fn publish_tokens_resolve_on_acks() {
+        let mut mqtt = build_mqttstate();
+        let (tx0, token0) = token::token();
+        let (tx1, token1) = token::token();
+        let (tx2, token2) = token::token();
+        let (tx3, token3) = token::token();
+
+        let publish = build_outgoing_publish(QoS::AtMostOnce);
+        mqtt.handle_outgoing_packet(Request::TrackedPublish(publish, tx0))
+            .unwrap();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::TrackedPublish(publish, tx1))
+            .unwrap();
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        mqtt.handle_outgoing_packet(Request::TrackedPublish(publish, tx2))
+            .unwrap();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::TrackedPublish(publish, tx3))
+            .unwrap();
+
+        mqtt.handle_incoming_puback(&PubAck::new(1)).unwrap();
+        mqtt.handle_incoming_pubrec(&PubRec::new(2)).unwrap();
+        mqtt.handle_incoming_pubcomp(&PubComp::new(2)).unwrap();
+        assert_eq!(token0.blocking_wait(), Ok(()));
+        assert_eq!(token1.blocking_wait(), Ok(()));
+        assert_eq!(token2.blocking_wait(), Ok(()));
+
+        // Dropped publish errors
+        mqtt.tokens.clear();
+        assert_eq!(token3.blocking_wait(), Err(TokenError::Dropped));
+    }
+
+    #[test]
+    fn chunked_publishes_are_split_and_reassembled() {
+        let mut mqtt = build_mqttstate();
+        mqtt.chunking
+            .add("hello/+".to_owned(), chunk::HEADER_LEN + 1);
+        let mut receiver = build_mqttstate();
+        receiver.chunking = mqtt.chunking.clone();
+
+        let (tx, token) = token::token();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::TrackedPublish(publish, tx))
+            .unwrap();
+        assert_eq!(mqtt.chunks.len(), 2);
+        mqtt.handle_outgoing_chunk().unwrap();
+        mqtt.handle_outgoing_chunk().unwrap();
+        assert_eq!(mqtt.inflight, 3);
+
+        // token moves to the last chunk
+        assert!(mqtt.tokens.contains_key(&3) && mqtt.tokens.len() == 1);
+
+        for pkid in 1..=3 {
+            let chunk = mqtt.outgoing_pub[pkid].clone().unwrap();
+            receiver.events.clear();
+            receiver
+                .handle_incoming_packet(Incoming::Publish(chunk))
+                .unwrap();
+            mqtt.handle_incoming_puback(&PubAck::new(pkid as u16))
+                .unwrap();
+        }
+
+        // partial chunks are acked but only the full payload is yielded
+        assert_eq!(receiver.write.len(), 3 * 4);
+        match receiver.events.pop_back() {
+            Some(Event::Incoming(Incoming::Publish(p))) => assert_eq!(&p.payload[..], &[1, 2, 3]),
+            event => panic!("Unexpected event = {:?}", event),
+        }
+
+        assert_eq!(token.blocking_wait(), Ok(()));
+    }
+
+    #[test]
+    fn chunks_are_acked_with_the_joined_publish_with_manual_acks() {
+        let mut mqtt = build_mqttstate();
+        mqtt.chunking
+            .add("hello/+".to_owned(), chunk::HEADER_LEN + 1);
+        let mut receiver = build_mqttstate();
+        receiver.chunking = mqtt.chunking.clone();
+        receiver.manual_acks = true;
+
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::Publish(publish))
+            .unwrap();
+        mqtt.handle_outgoing_chunk().unwrap();
+        mqtt.handle_outgoing_chunk().unwrap();
+
+        for pkid in 1..=3 {
+            let chunk = mqtt.outgoing_pub[pkid].clone().unwrap();
+            receiver
+                .handle_incoming_packet(Incoming::Publish(chunk))
+                .unwrap();
+        }
+
+        // Nothing is acked till the user acks the joined publish
+        assert!(receiver.write.is_empty());
+        let publish = match receiver.events.pop_back() {
+            Some(Event::Incoming(Incoming::Publish(p))) => p,
+            event => panic!("Unexpected event = {:?}", event),
+        };
+
+        assert_eq!(publish.pkid, 3);
+        receiver
+            .handle_outgoing_packet(Request::PubAck(PubAck::new(3)))
+            .unwrap();
+        for pkid in [3, 1, 2].iter() {
+            let packet = read(&mut receiver.write, 10 * 1024).unwrap();
+            assert_eq!(packet, Packet::PubAck(PubAck::new(*pkid)));
+        }
+
+        assert!(receiver.chunk_acks.is_empty());
+
+        // Corrupt chunks are acked but not forwarded
+        receiver.events.clear();
+        let corrupt = build_incoming_publish(QoS::AtLeastOnce, 4);
+        receiver
+            .handle_incoming_packet(Incoming::Publish(corrupt))
+            .unwrap();
+        let packet = read(&mut receiver.write, 10 * 1024).unwrap();
+        assert_eq!(packet, Packet::PubAck(PubAck::new(4)));
+        assert!(!receiver
+            .events
+            .iter()
+            .any(|event| matches!(event, Event::Incoming(Incoming::Publish(_)))));
+    }
+
+    #[test]
+    fn packets_and_bytes_are_counted_per_type() {
+        let mut mqtt = build_mqttstate();
+
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::Publish(publish))
+            .unwrap();
+        mqtt.handle_incoming_packet(Incoming::PubAck(PubAck::new(1)))
+            .unwrap();
+
+        // incoming publish is acked
+        let publish = build_incoming_publish(QoS::AtLeastOnce, 5);
+        mqtt.handle_incoming_packet(Incoming::Publish(publish))
+            .unwrap();
+
+        mqtt.handle_outgoing_packet(Request::PingReq).unwrap();
+        mqtt.handle_incoming_packet(Incoming::PingResp).unwrap();
+
+        let stats = &mqtt.stats;
+        assert_eq!(stats.outgoing.publish, 1);
+        assert_eq!(stats.outgoing.puback, 1);
+        assert_eq!(stats.outgoing.pingreq, 1);
+        assert_eq!(stats.outgoing.bytes, 20 + 4 + 2);
+        assert_eq!(stats.incoming.publish, 1);
+        assert_eq!(stats.incoming.puback, 1);
+        assert_eq!(stats.incoming.pingresp, 1);
+        assert!(stats.ping_rtt.is_some());
+    }
+
+    #[test]
+    fn expired_sessions_restore_subscriptions_before_replays() {
+        let mut mqtt = build_mqttstate();
+        mqtt.subscriptions
+            .insert("hello/world".to_owned(), QoS::AtLeastOnce);
+
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        let pending = vec![
+            Request::PubRel(PubRel::new(1)),
+            Request::Publish(publish.clone()),
+        ];
+
+        let requests = mqtt.expire_session(pending);
+        assert_eq!(requests.len(), 2);
+        match &requests[0] {
+            Request::Subscribe(subscribe) => {
+                assert_eq!(subscribe.filters[0].path, "hello/world");
+                assert_eq!(subscribe.filters[0].qos, QoS::AtLeastOnce);
+            }
+            request => panic!("Unexpected request = {:?}", request),
+        }
+
+        assert_eq!(requests[1], Request::Publish(publish));
+    }
+
+    #[test]
+    fn granted_qos_downgrades_are_recorded_for_resubscriptions() {
+        let mut mqtt = build_mqttstate();
+        let subscribe = Subscribe::new("hello/world", QoS::ExactlyOnce);
+        mqtt.handle_outgoing_packet(Request::Subscribe(subscribe))
+            .unwrap();
+        mqtt.events.clear();
+
+        let codes = vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)];
+        mqtt.handle_incoming_packet(Incoming::SubAck(SubAck::new(1, codes)))
+            .unwrap();
+
+        let result = match mqtt.events.pop_front() {
+            Some(Event::Subscribed(result)) => result,
+            event => panic!("Unexpected event = {:?}", event),
+        };
+
+        let downgrade = ("hello/world".to_owned(), QoS::AtLeastOnce);
+        assert_eq!(result.downgrades, vec![downgrade]);
+        assert_eq!(mqtt.subscriptions["hello/world"], QoS::AtLeastOnce);
+    }
 }
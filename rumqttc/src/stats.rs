@@ -0,0 +1,93 @@
+use crate::Incoming;
+
+use mqttbytes::{check, PacketType};
+use std::time::Duration;
+
+/// Counters of a connection. Cumulative across reconnections
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Packets and bytes received from the broker
+    pub incoming: PacketStats,
+    /// Packets and bytes sent to the broker (excluding connects)
+    pub outgoing: PacketStats,
+    /// Successful reconnections
+    pub reconnects: usize,
+    /// Round trip time of the last ping
+    pub ping_rtt: Option<Duration>,
+    /// Unacked outgoing publishes
+    pub inflight: u16,
+}
+
+/// Packet count per packet type and total bytes in one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PacketStats {
+    pub bytes: usize,
+    pub publish: usize,
+    pub puback: usize,
+    pub pubrec: usize,
+    pub pubrel: usize,
+    pub pubcomp: usize,
+    pub subscribe: usize,
+    pub suback: usize,
+    pub unsubscribe: usize,
+    pub unsuback: usize,
+    pub pingreq: usize,
+    pub pingresp: usize,
+    pub disconnect: usize,
+}
+
+impl PacketStats {
+    fn packet(&mut self, packet_type: PacketType) {
+        let count = match packet_type {
+            PacketType::Publish => &mut self.publish,
+            PacketType::PubAck => &mut self.puback,
+            PacketType::PubRec => &mut self.pubrec,
+            PacketType::PubRel => &mut self.pubrel,
+            PacketType::PubComp => &mut self.pubcomp,
+            PacketType::Subscribe => &mut self.subscribe,
+            PacketType::SubAck => &mut self.suback,
+            PacketType::Unsubscribe => &mut self.unsubscribe,
+            PacketType::UnsubAck => &mut self.unsuback,
+            PacketType::PingReq => &mut self.pingreq,
+            PacketType::PingResp => &mut self.pingresp,
+            PacketType::Disconnect => &mut self.disconnect,
+            PacketType::Connect | PacketType::ConnAck => return,
+        };
+
+        *count += 1;
+    }
+
+    /// Counts an incoming packet. Bytes are counted by the network
+    pub(crate) fn incoming(&mut self, packet: &Incoming) {
+        let packet_type = match packet {
+            Incoming::Publish(_) => PacketType::Publish,
+            Incoming::PubAck(_) => PacketType::PubAck,
+            Incoming::PubRec(_) => PacketType::PubRec,
+            Incoming::PubRel(_) => PacketType::PubRel,
+            Incoming::PubComp(_) => PacketType::PubComp,
+            Incoming::Subscribe(_) => PacketType::Subscribe,
+            Incoming::SubAck(_) => PacketType::SubAck,
+            Incoming::Unsubscribe(_) => PacketType::Unsubscribe,
+            Incoming::UnsubAck(_) => PacketType::UnsubAck,
+            Incoming::PingReq => PacketType::PingReq,
+            Incoming::PingResp => PacketType::PingResp,
+            Incoming::Disconnect => PacketType::Disconnect,
+            Incoming::Connect(_) | Incoming::ConnAck(_) => return,
+        };
+
+        self.packet(packet_type);
+    }
+
+    /// Counts serialized packets in `frames`
+    pub(crate) fn outgoing(&mut self, mut frames: &[u8]) {
+        while let Ok(header) = check(frames.iter(), usize::MAX) {
+            if let Ok(packet_type) = header.packet_type() {
+                self.packet(packet_type);
+            }
+
+            let len = header.frame_length();
+            self.bytes += len;
+            frames = &frames[len..];
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Hook which sees raw mqtt frames on the wire to record and replay traffic
+use std::time::SystemTime;
+
+/// Direction of a frame on the wire
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// Read from the broker
+    Incoming,
+    /// Written to the broker
+    Outgoing,
+}
+
+/// Receives every raw mqtt frame of the connection (connect included) with
+/// the time it's read or written. Frames are the exact bytes on the wire,
+/// before topic alias expansion and payload transforms of incoming publishes.
+/// Recording them to disk (e.g with a timestamp, direction and length prefix)
+/// lets broker interop issues be replayed. Register with `MqttOptions::set_wire_tap`
+///
+/// Called on the eventloop. Slow taps slow down the connection
+pub trait WireTap: Send + Sync {
+    fn record(&self, direction: Direction, time: SystemTime, frame: &[u8]);
+}
@@ -0,0 +1,169 @@
+//! In memory broker to deterministically test reconnection and ack handling of
+//! applications without binding tcp ports
+use crate::framed::Network;
+use crate::EventLoop;
+
+use bytes::BytesMut;
+use mqttbytes::v4::*;
+use std::io;
+use tokio::io::duplex;
+
+/// Broker end of an in memory connection of an eventloop. Tests script the
+/// broker step by step by reading packets of the client and writing replies.
+/// Dropping the broker drops the connection. `attach` a new broker to script
+/// the reconnection
+pub struct MockBroker {
+    network: Network,
+    write: BytesMut,
+}
+
+impl MockBroker {
+    /// Sets an in memory stream as the next connection of the eventloop and
+    /// returns the broker end of it
+    pub fn attach(eventloop: &mut EventLoop) -> MockBroker {
+        let (client, broker) = duplex(64 * 1024);
+        eventloop.set_network(client);
+
+        let max_packet_size = eventloop.options.max_outgoing_packet_size;
+        MockBroker {
+            network: Network::new(broker, max_packet_size),
+            write: BytesMut::with_capacity(10 * 1024),
+        }
+    }
+
+    /// Next packet sent by the client
+    pub async fn read(&mut self) -> io::Result<Packet> {
+        self.network.read().await
+    }
+
+    /// Writes a packet to the client
+    pub async fn write(&mut self, packet: Packet) -> io::Result<()> {
+        let o = match packet {
+            Packet::Connect(connect) => connect.write(&mut self.write),
+            Packet::ConnAck(connack) => connack.write(&mut self.write),
+            Packet::Publish(publish) => publish.write(&mut self.write),
+            Packet::PubAck(puback) => puback.write(&mut self.write),
+            Packet::PubRec(pubrec) => pubrec.write(&mut self.write),
+            Packet::PubRel(pubrel) => pubrel.write(&mut self.write),
+            Packet::PubComp(pubcomp) => pubcomp.write(&mut self.write),
+            Packet::Subscribe(subscribe) => subscribe.write(&mut self.write),
+            Packet::SubAck(suback) => suback.write(&mut self.write),
+            Packet::Unsubscribe(unsubscribe) => unsubscribe.write(&mut self.write),
+            Packet::UnsubAck(unsuback) => unsuback.write(&mut self.write),
+            Packet::PingReq => PingReq.write(&mut self.write),
+            Packet::PingResp => PingResp.write(&mut self.write),
+            Packet::Disconnect => Disconnect.write(&mut self.write),
+        };
+
+        o.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.network.flush(&mut self.write).await
+    }
+
+    /// Reads the connect packet of the client and accepts it
+    pub async fn accept(&mut self, session_present: bool) -> io::Result<Connect> {
+        let connect = match self.read().await? {
+            Packet::Connect(connect) => connect,
+            packet => return Err(unexpected(packet)),
+        };
+
+        let connack = ConnAck::new(ConnectReturnCode::Success, session_present);
+        self.write(Packet::ConnAck(connack)).await?;
+        Ok(connect)
+    }
+
+    /// Next publish of the client. Pings are answered on the way
+    pub async fn read_publish(&mut self) -> io::Result<Publish> {
+        loop {
+            match self.read().await? {
+                Packet::Publish(publish) => return Ok(publish),
+                Packet::PingReq => self.write(Packet::PingResp).await?,
+                packet => return Err(unexpected(packet)),
+            }
+        }
+    }
+
+    /// Acks a QoS 1 publish of the client
+    pub async fn ack(&mut self, pkid: u16) -> io::Result<()> {
+        self.write(Packet::PubAck(PubAck::new(pkid))).await
+    }
+}
+
+fn unexpected(packet: Packet) -> io::Error {
+    let error = format!("Unexpected packet = {:?}", packet);
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Event, MqttOptions, QoS, Request};
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn unacked_publishes_are_retransmitted_to_the_next_broker() {
+        let options = MqttOptions::new("dummy", "127.0.0.1", 1883);
+        let mut eventloop = EventLoop::new(options, 10);
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1]);
+        eventloop
+            .handle()
+            .send(Request::Publish(publish))
+            .await
+            .unwrap();
+
+        let mut broker = MockBroker::attach(&mut eventloop);
+        tokio::spawn(async move {
+            broker.accept(false).await.unwrap();
+            broker.read_publish().await.unwrap();
+        });
+
+        // publish isn't acked before the connection drops
+        while eventloop.poll().await.is_ok() {}
+
+        let mut broker = MockBroker::attach(&mut eventloop);
+        tokio::spawn(async move {
+            broker.accept(true).await.unwrap();
+            let publish = broker.read_publish().await.unwrap();
+            assert!(publish.dup);
+            broker.ack(publish.pkid).await.unwrap();
+        });
+
+        loop {
+            if let Event::Incoming(Packet::PubAck(ack)) = eventloop.poll().await.unwrap() {
+                assert_eq!(ack.pkid, 1);
+                break;
+            }
+        }
+    }
+    #[tokio::test]
+    async fn lone_partial_chunks_of_qos0_publishes_are_absorbed() {
+        let mut options = MqttOptions::new("dummy", "127.0.0.1", 1883);
+        options.add_payload_chunking("firmware/+", 12);
+        let mut chunking = options.chunking.clone();
+        let mut eventloop = EventLoop::new(options, 10);
+
+        let payload = Bytes::from(vec![1, 2, 3, 4, 5, 6]);
+        let chunks = chunking.split("firmware/v1", payload.clone()).unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        // Chunks arrive in separate reads of the eventloop
+        let mut broker = MockBroker::attach(&mut eventloop);
+        tokio::spawn(async move {
+            broker.accept(false).await.unwrap();
+            for chunk in chunks {
+                let publish = Publish::from_bytes("firmware/v1", QoS::AtMostOnce, chunk);
+                broker.write(Packet::Publish(publish)).await.unwrap();
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+
+        loop {
+            let event = time::timeout(Duration::from_secs(5), eventloop.poll());
+            if let Event::Incoming(Packet::Publish(publish)) = event.await.unwrap().unwrap() {
+                assert_eq!(publish.payload, payload);
+                break;
+            }
+        }
+    }
+}
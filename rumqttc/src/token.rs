@@ -0,0 +1,59 @@
+use async_channel::{bounded, Receiver, Sender};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+/// Error of a `PublishToken`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TokenError {
+    #[error("Publish dropped before it is acked")]
+    Dropped,
+}
+
+/// Resolves when the broker acks a publish (PubAck for QoS 1, PubComp for
+/// QoS 2). QoS 0 publishes resolve once they are written to the network.
+/// Unacked publishes retransmitted after reconnection stay tracked. Token
+/// errors when the publish is dropped (retransmission disabled, offline buffer
+/// overflow or eventloop drop)
+#[derive(Debug)]
+pub struct PublishToken {
+    rx: Receiver<()>,
+}
+
+impl PublishToken {
+    /// Waits for the ack
+    pub async fn wait(self) -> Result<(), TokenError> {
+        self.rx.recv().await.map_err(|_| TokenError::Dropped)
+    }
+
+    /// Blocks current thread till the ack
+    pub fn blocking_wait(self) -> Result<(), TokenError> {
+        pollster::block_on(self.wait())
+    }
+}
+
+/// Eventloop's handle to resolve a `PublishToken`
+#[derive(Clone)]
+pub struct TokenTx(Arc<Sender<()>>);
+
+impl TokenTx {
+    pub(crate) fn success(self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+impl Debug for TokenTx {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("TokenTx")
+    }
+}
+
+impl PartialEq for TokenTx {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+pub(crate) fn token() -> (TokenTx, PublishToken) {
+    let (tx, rx) = bounded(1);
+    (TokenTx(Arc::new(tx)), PublishToken { rx })
+}
@@ -0,0 +1,95 @@
+//! Payload transforms (e.g compression) of publishes on matching topics
+use bytes::Bytes;
+use mqttbytes::matches;
+
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::sync::Arc;
+
+/// Transforms payloads of publishes on the wire. E.g gzip or zstd compression
+/// for bandwidth limited links. Register with `MqttOptions::add_payload_transform`
+pub trait PayloadTransform: Send + Sync {
+    /// Transforms the payload of an outgoing publish on `topic`
+    fn encode(&self, topic: &str, payload: Bytes) -> Bytes;
+
+    /// Reverts `encode` on the payload of an incoming publish on `topic`
+    fn decode(&self, topic: &str, payload: Bytes) -> io::Result<Bytes>;
+}
+
+/// Transforms and the topic filters they apply to. First matching filter wins
+#[derive(Clone, Default)]
+pub(crate) struct Transforms {
+    transforms: Vec<(String, Arc<dyn PayloadTransform>)>,
+}
+
+impl Transforms {
+    pub fn add(&mut self, filter: String, transform: Arc<dyn PayloadTransform>) {
+        self.transforms.push((filter, transform));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    fn find(&self, topic: &str) -> Option<&dyn PayloadTransform> {
+        self.transforms
+            .iter()
+            .find(|(filter, _)| matches(topic, filter))
+            .map(|(_, transform)| transform.as_ref())
+    }
+
+    /// Encoded payload. Payloads of topics without transforms are returned as is
+    pub fn encode(&self, topic: &str, payload: Bytes) -> Bytes {
+        match self.find(topic) {
+            Some(transform) => transform.encode(topic, payload),
+            None => payload,
+        }
+    }
+
+    /// Decoded payload. Payloads of topics without transforms are returned as is
+    pub fn decode(&self, topic: &str, payload: Bytes) -> io::Result<Bytes> {
+        match self.find(topic) {
+            Some(transform) => transform.decode(topic, payload),
+            None => Ok(payload),
+        }
+    }
+}
+
+impl Debug for Transforms {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let filters: Vec<&String> = self.transforms.iter().map(|(filter, _)| filter).collect();
+        f.debug_tuple("Transforms").field(&filters).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Reverse;
+
+    impl PayloadTransform for Reverse {
+        fn encode(&self, _topic: &str, payload: Bytes) -> Bytes {
+            payload.iter().rev().copied().collect::<Vec<u8>>().into()
+        }
+
+        fn decode(&self, topic: &str, payload: Bytes) -> io::Result<Bytes> {
+            Ok(self.encode(topic, payload))
+        }
+    }
+
+    #[test]
+    fn payloads_are_transformed_on_matching_topics() {
+        let mut transforms = Transforms::default();
+        transforms.add("hello/+/compressed".to_owned(), Arc::new(Reverse));
+
+        let payload = Bytes::from(vec![1, 2, 3]);
+        let encoded = transforms.encode("hello/world/compressed", payload.clone());
+        assert_eq!(encoded, Bytes::from(vec![3, 2, 1]));
+        let decoded = transforms.decode("hello/world/compressed", encoded);
+        assert_eq!(decoded.unwrap(), payload);
+
+        let encoded = transforms.encode("hello/world", payload.clone());
+        assert_eq!(encoded, payload);
+    }
+}
@@ -1,4 +1,8 @@
 use matches::assert_matches;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use tokio::{task, time};
 
@@ -74,6 +78,31 @@ async fn connection_should_timeout_on_time() {
     assert_eq!(elapsed.as_secs(), 5);
 }
 
+#[tokio::test]
+async fn connack_timeout_is_configurable() {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3107);
+    options.set_connection_timeout(10).set_connack_timeout(2);
+    let mut eventloop = EventLoop::new(options, 5);
+    let (client, mut broker) = duplex(1024);
+    eventloop.set_network(client);
+
+    // broker reads connect but never acks
+    task::spawn(async move {
+        let mut connect = [0u8; 1024];
+        let _ = broker.read(&mut connect).await.unwrap();
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    let start = Instant::now();
+    let o = eventloop.poll().await;
+    let elapsed = start.elapsed();
+
+    assert_matches!(o, Err(ConnectionError::Timeout(_)));
+    assert_eq!(elapsed.as_secs(), 2);
+}
+
 //
 // All keep alive tests here
 //
@@ -427,9 +456,10 @@ async fn next_poll_after_connect_failure_reconnects() {
     let mut eventloop = EventLoop::new(options, 5);
 
     let event = eventloop.poll().await;
-    let error = "Broker rejected. Reason = BadUserNamePassword";
     match event {
-        Err(ConnectionError::Io(e)) => assert_eq!(e.to_string(), error),
+        Err(ConnectionError::MqttState(StateError::Connect(code))) => {
+            assert_eq!(code, ConnectReturnCode::BadUserNamePassword)
+        }
         v => panic!("Expected bad username password error. Found = {:?}", v),
     }
 
@@ -438,6 +468,27 @@ async fn next_poll_after_connect_failure_reconnects() {
     assert_eq!(event, Event::Incoming(Packet::ConnAck(connack)));
 }
 
+#[tokio::test]
+async fn rejected_connections_are_not_retried() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3128);
+    let mut reconnect = ReconnectOptions::new();
+    reconnect.set_backoff(Duration::from_millis(100), Duration::from_millis(100));
+    options.set_reconnect_options(reconnect);
+
+    task::spawn(async move {
+        let _broker = Broker::new(3128, 1).await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    // Retries of the rejected connection would fail to connect forever
+    time::sleep(Duration::from_secs(1)).await;
+    let mut eventloop = EventLoop::new(options, 5);
+    let event = time::timeout(Duration::from_secs(5), eventloop.poll()).await;
+    let error = event.unwrap().unwrap_err();
+    assert_eq!(error.category(), ErrorCategory::Authentication);
+    assert!(!error.is_retryable());
+}
+
 #[tokio::test]
 async fn reconnection_resumes_from_the_previous_state() {
     let mut options = MqttOptions::new("dummy", "127.0.0.1", 3001);
@@ -512,3 +563,796 @@ async fn reconnection_resends_unacked_packets_from_the_previous_connection_first
         assert_eq!(i, packet.payload[0]);
     }
 }
+
+#[cfg(feature = "socks5")]
+async fn socks5_proxy(port: u16) {
+    use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    let (mut client, _) = listener.accept().await.unwrap();
+
+    // greeting. select username/password authentication
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await.unwrap();
+    let mut methods = vec![0; header[1] as usize];
+    client.read_exact(&mut methods).await.unwrap();
+    assert!(methods.contains(&2));
+    client.write_all(&[5, 2]).await.unwrap();
+
+    // username/password authentication. version, username len, username,
+    // password len, password
+    let mut auth = Vec::new();
+    let mut version = [0u8; 1];
+    client.read_exact(&mut version).await.unwrap();
+    for _ in 0..2 {
+        let len = client.read_u8().await.unwrap();
+        let mut field = vec![0; len as usize];
+        client.read_exact(&mut field).await.unwrap();
+        auth.push(String::from_utf8(field).unwrap());
+    }
+    assert_eq!(auth, vec!["user", "pass"]);
+    client.write_all(&[1, 0]).await.unwrap();
+
+    // connect request with ipv4 target
+    let mut request = [0u8; 10];
+    client.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request[..4], &[5, 1, 0, 1]);
+    let target = u16::from_be_bytes([request[8], request[9]]);
+    let mut broker = TcpStream::connect(("127.0.0.1", target)).await.unwrap();
+    client
+        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, request[8], request[9]])
+        .await
+        .unwrap();
+
+    let _ = copy_bidirectional(&mut client, &mut broker).await;
+}
+
+#[cfg(feature = "socks5")]
+#[tokio::test]
+async fn connection_is_tunneled_through_socks5_proxy() {
+    task::spawn(socks5_proxy(3100));
+    task::spawn(async move {
+        let _broker = Broker::new(3101, 0).await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    time::sleep(Duration::from_secs(1)).await;
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3101);
+    let credentials = Some(("user".to_owned(), "pass".to_owned()));
+    options.set_socks5_proxy("127.0.0.1", 3100, credentials);
+    let mut eventloop = EventLoop::new(options, 5);
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+}
+
+#[tokio::test]
+async fn user_supplied_stream_is_used_instead_of_dialing() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    // nothing is listening on this port
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3102);
+    let mut eventloop = EventLoop::new(options, 5);
+    let (client, mut broker) = duplex(1024);
+    eventloop.set_network(client);
+
+    task::spawn(async move {
+        let mut connect = [0u8; 1024];
+        let _ = broker.read(&mut connect).await.unwrap();
+        assert_eq!(connect[0], 0x10);
+        broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+}
+
+#[tokio::test]
+async fn requests_are_buffered_offline_as_per_overflow_policy() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3103);
+    let mut reconnect = ReconnectOptions::new();
+    reconnect.set_backoff(Duration::from_secs(1), Duration::from_secs(1));
+    options.set_reconnect_options(reconnect);
+    options.set_offline_buffer(2, OverflowPolicy::DropOldest);
+
+    // broker is down. eventloop pulls requests into the offline buffer
+    let mut eventloop = EventLoop::new(options, 10);
+    let requests_tx = eventloop.handle();
+    start_requests(4, QoS::AtLeastOnce, 0, requests_tx).await;
+    task::spawn(async move {
+        run(&mut eventloop, false).await.unwrap();
+    });
+
+    time::sleep(Duration::from_secs(2)).await;
+    let mut broker = Broker::new(3103, 0).await;
+    for i in 3..=4 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+    }
+}
+
+#[tokio::test]
+async fn unacked_packets_are_dropped_when_retransmission_is_disabled() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3104);
+    options.set_keep_alive(5).set_retransmit_inflight(false);
+
+    let mut eventloop = EventLoop::new(options, 5);
+    let requests_tx = eventloop.handle();
+    task::spawn(async move {
+        start_requests(4, QoS::AtLeastOnce, 1, requests_tx).await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    // broker connection 1. receive but don't ack
+    let mut broker = Broker::new(3104, 0).await;
+    for i in 1..=2 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+    }
+
+    // broker connection 2 only receives new publishes
+    drop(broker);
+    let mut broker = Broker::new(3104, 0).await;
+    for i in 3..=4 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+    }
+}
+
+#[tokio::test]
+async fn try_publish_fails_fast_when_inflight_window_is_full() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3105);
+    options.set_inflight(2);
+
+    let (client, mut eventloop) = AsyncClient::new(options, 5);
+    task::spawn(async move {
+        run(&mut eventloop, false).await.unwrap();
+    });
+
+    // broker receives but doesn't ack
+    let mut broker = Broker::new(3105, 0).await;
+    for i in 1..=2 {
+        client
+            .try_publish("hello/world", QoS::AtLeastOnce, false, vec![i])
+            .unwrap();
+        broker.read_publish().await.unwrap();
+    }
+
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(client.inflight(), 2);
+    assert_eq!(client.queued(), 0);
+
+    let o = client.try_publish("hello/world", QoS::AtLeastOnce, false, vec![3]);
+    assert_matches!(o, Err(ClientError::InflightFull));
+}
+
+#[tokio::test]
+async fn credentials_are_fetched_from_provider_before_connection() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3106);
+    options.set_credentials("stale", "stale");
+    let count = AtomicUsize::new(0);
+    options.set_credential_provider(move || {
+        let count = count.fetch_add(1, Ordering::SeqCst) + 1;
+        ("user".to_owned(), format!("token-{}", count))
+    });
+
+    let mut eventloop = EventLoop::new(options, 5);
+    let (client, mut broker) = duplex(1024);
+    eventloop.set_network(client);
+
+    task::spawn(async move {
+        let _ = eventloop.poll().await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    let mut connect = bytes::BytesMut::new();
+    broker.read_buf(&mut connect).await.unwrap();
+    broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+    let connect = match read(&mut connect, 1024).unwrap() {
+        Packet::Connect(connect) => connect,
+        packet => panic!("Unexpected packet = {:?}", packet),
+    };
+
+    let login = connect.login.unwrap();
+    assert_eq!(login.username, "user");
+    assert_eq!(login.password, "token-1");
+}
+
+#[tokio::test]
+async fn connection_fails_over_to_next_endpoint() {
+    // nothing is listening on the first broker
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3108);
+    options.set_endpoints(vec![("127.0.0.1".to_owned(), 3109)]);
+
+    task::spawn(async move {
+        let _broker = Broker::new(3109, 0).await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    time::sleep(Duration::from_secs(1)).await;
+    let mut eventloop = EventLoop::new(options, 5);
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Err(ConnectionError::Io(_)));
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+    assert_eq!(
+        eventloop.options.broker_address(),
+        ("127.0.0.1".to_owned(), 3109)
+    );
+    assert_eq!(
+        eventloop.options.endpoints(),
+        vec![("127.0.0.1".to_owned(), 3108)]
+    );
+}
+
+#[tokio::test]
+async fn resolved_addresses_are_tried_until_one_connects() {
+    let mut options = MqttOptions::new("dummy", "broker.cluster.local", 3110);
+    options.set_resolver(|host: &str, port| {
+        assert_eq!(host, "broker.cluster.local");
+        // nothing is listening on the first address
+        let addrs = vec![
+            "127.0.0.1:3111".parse().unwrap(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+        ];
+
+        Ok(addrs)
+    });
+
+    task::spawn(async move {
+        let _broker = Broker::new(3110, 0).await;
+        time::sleep(Duration::from_secs(10)).await;
+    });
+
+    time::sleep(Duration::from_secs(1)).await;
+    let mut eventloop = EventLoop::new(options, 5);
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+}
+
+#[tokio::test]
+async fn qos0_publishes_are_dropped_for_slow_notification_consumers() {
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3112);
+
+    task::spawn(async move {
+        let mut broker = Broker::new(3112, 0).await;
+        broker.spawn_publishes(10, QoS::AtMostOnce, 0).await;
+        loop {
+            broker.tick().await;
+        }
+    });
+
+    time::sleep(Duration::from_secs(1)).await;
+    let eventloop = EventLoop::new(options, 5);
+    let notifications = eventloop.spawn(2, SlowConsumerPolicy::DropQos0);
+
+    // slow consumer
+    time::sleep(Duration::from_secs(1)).await;
+    let mut publishes = 0;
+    while let Ok(Some(event)) =
+        time::timeout(Duration::from_millis(100), notifications.recv()).await
+    {
+        if let Event::Incoming(Packet::Publish(_)) = event.unwrap() {
+            publishes += 1;
+        }
+    }
+
+    assert!(notifications.dropped() > 0);
+    assert_eq!(publishes + notifications.dropped(), 10);
+}
+
+/// Stream which counts writes to the network
+struct CountingStream {
+    inner: tokio::io::DuplexStream,
+    writes: Arc<AtomicUsize>,
+}
+
+impl tokio::io::AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn ready_requests_are_batched_into_one_write() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3113);
+    options.set_request_batch(10, 64 * 1024);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let (stream, mut broker) = duplex(64 * 1024);
+    let writes = Arc::new(AtomicUsize::new(0));
+    eventloop.set_network(CountingStream {
+        inner: stream,
+        writes: writes.clone(),
+    });
+
+    task::spawn(async move {
+        let mut buf = [0u8; 1024];
+        let _ = broker.read(&mut buf).await.unwrap();
+        broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        while broker.read(&mut buf).await.unwrap_or(0) > 0 {}
+    });
+
+    for i in 0..10 {
+        client
+            .publish("hello/world", QoS::AtMostOnce, false, vec![i])
+            .await
+            .unwrap();
+    }
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+    for _ in 0..10 {
+        let o = eventloop.poll().await;
+        assert_matches!(o, Ok(Event::Outgoing(Outgoing::Publish(_))));
+    }
+
+    assert_eq!(writes.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn stats_are_yielded_periodically() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3114);
+    options.set_stats_interval(Duration::from_secs(1));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let (stream, mut broker) = duplex(1024);
+    eventloop.set_network(stream);
+
+    task::spawn(async move {
+        let mut buf = [0u8; 1024];
+        let _ = broker.read(&mut buf).await.unwrap();
+        broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        while broker.read(&mut buf).await.unwrap_or(0) > 0 {}
+    });
+
+    client
+        .publish("hello/world", QoS::AtMostOnce, false, vec![1, 2, 3])
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let stats = loop {
+        match eventloop.poll().await.unwrap() {
+            Event::Stats(stats) => break stats,
+            _ => continue,
+        }
+    };
+
+    assert_eq!(start.elapsed().as_secs(), 1);
+    assert_eq!(stats.outgoing.publish, 1);
+    assert_eq!(stats.outgoing.bytes, 18);
+    assert_eq!(stats, eventloop.stats());
+}
+
+#[tokio::test]
+async fn shutdown_drains_inflight_publishes_before_disconnecting() {
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3115);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    task::spawn(async move {
+        for i in 1..=3 {
+            client
+                .publish("hello/world", QoS::AtLeastOnce, false, vec![i])
+                .await
+                .unwrap();
+        }
+
+        client.shutdown(Duration::from_secs(5)).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        let o = client
+            .publish("hello/world", QoS::AtLeastOnce, false, vec![4])
+            .await;
+        assert!(o.is_err());
+    });
+
+    let handle = task::spawn(async move {
+        let mut acks = 0;
+        loop {
+            match eventloop.poll().await.unwrap() {
+                Event::Incoming(Packet::PubAck(_)) => acks += 1,
+                Event::StreamEnd => break,
+                _ => continue,
+            }
+        }
+
+        let o = eventloop.poll().await;
+        assert_matches!(o, Err(ConnectionError::RequestsDone));
+        acks
+    });
+
+    let mut broker = Broker::new(3115, 0).await;
+    for i in 1..=3 {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(publish.payload[0], i);
+        time::sleep(Duration::from_millis(100)).await;
+        broker.ack(publish.pkid).await;
+    }
+
+    assert_eq!(broker.read_packet().await, Packet::Disconnect);
+    assert_eq!(handle.await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn paused_connections_retain_state_and_resume() {
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3116);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    task::spawn(async move {
+        loop {
+            eventloop.poll().await.unwrap();
+        }
+    });
+
+    let mut broker = Broker::new(3116, 0).await;
+    client
+        .publish("hello/world", QoS::AtLeastOnce, false, vec![1])
+        .await
+        .unwrap();
+    let publish = broker.read_publish().await.unwrap();
+    assert_eq!(publish.payload[0], 1);
+
+    // Unacked publish is retained and new requests are queued while paused
+    client.pause().await.unwrap();
+    assert_eq!(broker.read_packet().await, Packet::Disconnect);
+    client
+        .publish("hello/world", QoS::AtLeastOnce, false, vec![2])
+        .await
+        .unwrap();
+    drop(broker);
+
+    time::sleep(Duration::from_secs(1)).await;
+    client.resume().await.unwrap();
+    let mut broker = Broker::new(3116, 0).await;
+    for i in 1..=2 {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(publish.payload[0], i);
+    }
+}
+
+#[tokio::test]
+async fn oversized_incoming_packets_end_the_connection() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3117);
+    options.set_max_incoming_packet_size(1024);
+    let mut eventloop = EventLoop::new(options, 10);
+    let (stream, mut broker) = duplex(1024);
+    eventloop.set_network(stream);
+
+    task::spawn(async move {
+        let mut buf = [0u8; 1024];
+        let _ = broker.read(&mut buf).await.unwrap();
+        broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+        // Fixed header of a 2000 byte publish. Payload is never sent
+        broker.write_all(&[0x30, 0xD0, 0x0F]).await.unwrap();
+        while broker.read(&mut buf).await.unwrap_or(0) > 0 {}
+    });
+
+    let o = eventloop.poll().await;
+    assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+
+    let o = eventloop.poll().await;
+    assert_matches!(
+        o,
+        Err(ConnectionError::PacketTooLarge {
+            size: 2000,
+            max: 1024
+        })
+    );
+}
+
+#[cfg(feature = "compat")]
+#[test]
+fn eventloop_is_polled_outside_of_tokio() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3118);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let (stream, mut broker) = duplex(1024);
+    eventloop.set_network(stream);
+
+    std::thread::spawn(move || {
+        pollster::block_on(async move {
+            let mut buf = [0u8; 1024];
+            let _ = broker.read(&mut buf).await.unwrap();
+            broker.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            while broker.read(&mut buf).await.unwrap_or(0) > 0 {}
+        })
+    });
+
+    pollster::block_on(async move {
+        client
+            .publish("hello/world", QoS::AtMostOnce, false, vec![1, 2, 3])
+            .await
+            .unwrap();
+
+        let o = eventloop.poll_compat().await;
+        assert_matches!(o, Ok(Event::Incoming(Packet::ConnAck(_))));
+        let o = eventloop.poll_compat().await;
+        assert_matches!(o, Ok(Event::Outgoing(Outgoing::Publish(_))));
+    });
+}
+
+#[tokio::test]
+async fn priority_requests_jump_ahead_of_offline_requests() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3119);
+    let mut reconnect = ReconnectOptions::new();
+    reconnect.set_backoff(Duration::from_secs(1), Duration::from_secs(1));
+    options.set_reconnect_options(reconnect);
+    options.set_offline_buffer(10, OverflowPolicy::DropNewest);
+
+    // broker is down. normal requests are buffered offline
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    for i in 1..=3 {
+        client
+            .publish("hello/world", QoS::AtLeastOnce, false, vec![i])
+            .await
+            .unwrap();
+    }
+
+    let mut alarm = PublishBuilder::new("hello/alarm", vec![9]);
+    alarm.set_qos(QoS::AtLeastOnce).set_priority(true);
+    client.publish_with(alarm).await.unwrap();
+    task::spawn(async move {
+        run(&mut eventloop, false).await.unwrap();
+    });
+
+    time::sleep(Duration::from_secs(2)).await;
+    let mut broker = Broker::new(3119, 0).await;
+    let packet = broker.read_publish().await.unwrap();
+    assert_eq!(packet.payload[0], 9);
+    for i in 1..=3 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+    }
+}
+
+#[tokio::test]
+async fn requests_are_forwarded_from_std_and_crossbeam_channels() {
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3120);
+    let mut eventloop = EventLoop::new(options, 10);
+
+    let (std_tx, std_rx) = std::sync::mpsc::channel();
+    let (crossbeam_tx, crossbeam_rx) = crossbeam_channel::unbounded();
+    eventloop.forward_requests(std_rx);
+    eventloop.forward_requests(crossbeam_rx);
+
+    std::thread::spawn(move || {
+        for i in 1..=2 {
+            let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![i]);
+            std_tx.send(Request::Publish(publish)).unwrap();
+        }
+
+        for i in 3..=4 {
+            let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![i]);
+            crossbeam_tx.send(Request::Publish(publish)).unwrap();
+        }
+    });
+
+    task::spawn(async move {
+        run(&mut eventloop, false).await.unwrap();
+    });
+
+    let mut broker = Broker::new(3120, 0).await;
+    let mut payloads = Vec::new();
+    for _ in 1..=4 {
+        let packet = broker.read_publish().await.unwrap();
+        payloads.push(packet.payload[0]);
+    }
+
+    payloads.sort_unstable();
+    assert_eq!(payloads, vec![1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn blocking_connections_return_their_final_status() {
+    // broker is down
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3122);
+    let (_client, connection) = Client::new(options, 10);
+    let handle = connection.start_in_thread(|_| {});
+    let status = task::spawn_blocking(move || handle.join().unwrap()).await;
+    assert_matches!(status.unwrap(), Err(ConnectionError::Io(_)));
+
+    let options = MqttOptions::new("dummy", "127.0.0.1", 3121);
+    let (mut client, connection) = Client::new(options, 10);
+    let (events_tx, events_rx) = std::sync::mpsc::channel();
+    let handle = connection.start_in_thread(move |event| events_tx.send(event).unwrap());
+
+    let mut broker = Broker::new(3121, 0).await;
+    client
+        .publish("hello/world", QoS::AtLeastOnce, false, vec![1])
+        .unwrap();
+    let publish = broker.read_publish().await.unwrap();
+    broker.ack(publish.pkid).await;
+
+    let acked = task::spawn_blocking(move || loop {
+        if let Event::Incoming(Packet::PubAck(_)) = events_rx.recv().unwrap() {
+            break;
+        }
+    });
+
+    acked.await.unwrap();
+    client.cancel().unwrap();
+    let status = task::spawn_blocking(move || handle.join().unwrap()).await;
+    assert!(status.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn strict_ordering_holds_new_publishes_till_retransmissions_are_acked() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3123);
+    options.set_strict_ordering(true);
+
+    let mut eventloop = EventLoop::new(options, 10);
+    let requests_tx = eventloop.handle();
+    start_requests(2, QoS::AtLeastOnce, 0, requests_tx.clone()).await;
+    task::spawn(async move {
+        // new publish after the first connection drops
+        while eventloop.poll().await.is_ok() {}
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![3]);
+        requests_tx.send(Request::Publish(publish)).await.unwrap();
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    // broker connection 1. receive but don't ack
+    let mut broker = Broker::new(3123, 0).await;
+    for i in 1..=2 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+    }
+
+    drop(broker);
+
+    // broker connection 2. new publish is held till retransmissions are acked
+    let mut broker = Broker::new(3123, 0).await;
+    for i in 1..=2 {
+        let packet = broker.read_publish().await.unwrap();
+        assert_eq!(i, packet.payload[0]);
+        assert!(packet.dup);
+    }
+
+    assert!(broker.read_publish().await.is_none());
+    broker.ack(1).await;
+    broker.ack(2).await;
+    let packet = broker.read_publish().await.unwrap();
+    assert_eq!(packet.payload[0], 3);
+}
+
+#[tokio::test]
+async fn watchdog_cycles_connections_without_publish_or_ack_activity() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3124);
+    options.set_watchdog(Duration::from_secs(1));
+
+    let mut eventloop = EventLoop::new(options, 10);
+    task::spawn(async move {
+        // broker accepts the connection but blackholes everything
+        let mut broker = Broker::new(3124, 0).await;
+        broker.blackhole().await;
+    });
+
+    // wait for the broker to come up
+    let start = loop {
+        if let Ok(Event::Incoming(Packet::ConnAck(_))) = eventloop.poll().await {
+            break Instant::now();
+        }
+    };
+
+    let e = eventloop.poll().await.unwrap_err();
+    assert_matches!(e, ConnectionError::Inactive(_));
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(e.is_retryable());
+}
+
+#[tokio::test]
+async fn pooled_connections_share_a_tagged_notification_stream() {
+    let mut reconnect = ReconnectOptions::new();
+    reconnect.set_backoff(Duration::from_millis(100), Duration::from_millis(100));
+    let mut options1 = MqttOptions::new("tenant-1", "127.0.0.1", 3125);
+    options1.set_reconnect_options(reconnect.clone());
+    let mut options2 = MqttOptions::new("tenant-2", "127.0.0.1", 3126);
+    options2.set_reconnect_options(reconnect);
+
+    let mut pool = ClientPool::new(10);
+    pool.add(options1, 10).unwrap();
+    let client = pool.add(options2, 10).unwrap();
+
+    // client ids identify pooled connections
+    let duplicate = MqttOptions::new("tenant-1", "127.0.0.1", 3126);
+    let o = pool.add(duplicate, 10);
+    assert_matches!(o, Err(PoolError::Duplicate(id)) if id == "tenant-1");
+    assert_eq!(pool.len(), 2);
+
+    let _broker1 = Broker::new(3125, 0).await;
+    let _broker2 = Broker::new(3126, 0).await;
+    let mut connected = Vec::new();
+    while connected.len() < 2 {
+        if let (id, Ok(Event::Incoming(Packet::ConnAck(_)))) = pool.recv().await {
+            connected.push(id);
+        }
+    }
+
+    connected.sort();
+    assert_eq!(connected, vec!["tenant-1", "tenant-2"]);
+
+    // requests of a client go to its own broker
+    client
+        .publish("hello/world", QoS::AtMostOnce, false, vec![1])
+        .await
+        .unwrap();
+    let (id, o) = pool.recv().await;
+    assert_eq!(id, "tenant-2");
+    assert_matches!(o, Ok(Event::Outgoing(Outgoing::Publish(0))));
+
+    // removed connections are cancelled
+    pool.remove("tenant-1").await.unwrap();
+    let (id, o) = pool.recv().await;
+    assert_eq!(id, "tenant-1");
+    assert_matches!(o, Err(ConnectionError::Cancel));
+    assert_eq!(pool.len(), 1);
+    assert!(pool.get("tenant-1").is_none());
+}
+
+#[tokio::test]
+async fn liveness_timeout_detects_dead_connections_without_keep_alive() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3127);
+    options
+        .set_keep_alive(0)
+        .set_liveness_timeout(Duration::from_secs(1));
+
+    let mut eventloop = EventLoop::new(options, 10);
+    task::spawn(async move {
+        let mut broker = Broker::new(3127, 0).await;
+        broker.blackhole().await;
+    });
+
+    // wait for the broker to come up
+    let start = loop {
+        if let Ok(Event::Incoming(Packet::ConnAck(_))) = eventloop.poll().await {
+            break Instant::now();
+        }
+    };
+
+    // no pings are sent before the connection is declared dead
+    let e = eventloop.poll().await.unwrap_err();
+    assert_matches!(e, ConnectionError::Inactive(_));
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
@@ -5,7 +5,7 @@ fn main() {
     pretty_env_logger::init();
     let config: Config = confy::load_path("config/rumqttd.conf").unwrap();
 
-    let (mut router, console, servers, builder) = construct_broker(config);
+    let (mut router, console, servers, builder) = construct_broker(config).unwrap();
 
     thread::spawn(move || {
         router.start().unwrap();
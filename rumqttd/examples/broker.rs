@@ -4,7 +4,7 @@ use std::thread;
 fn main() {
     pretty_env_logger::init();
     let config: Config = confy::load_path("config/rumqttd.conf").unwrap();
-    let mut broker = Broker::new(config);
+    let mut broker = Broker::new(config).unwrap();
 
     let mut tx = broker.link("localclient").unwrap();
     thread::spawn(move || {
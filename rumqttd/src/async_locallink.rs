@@ -7,6 +7,7 @@ use crate::{
 use futures_util::stream::{FuturesUnordered, StreamExt};
 use mqttbytes::v4::*;
 use mqttbytes::*;
+use rumqttlog::storage::StorageError;
 use rumqttlog::{
     Connection, ConnectionAck, Data, Event, Notification, Receiver, RecvError, Router, SendError,
     Sender,
@@ -107,6 +108,7 @@ impl AsyncLinkRx {
                 self.router_tx.async_send(message).await?;
                 Ok(None)
             }
+            Notification::SubscribeProgress(_) => Ok(None),
             notification => {
                 warn!("{:?} not supported in local link", notification);
                 Ok(None)
@@ -160,13 +162,16 @@ impl LinkBuilder {
 /// Returns a Router struct to run router on a thread, console server async task, mqtt servers async task, and a LinkBuilder to make local links
 pub fn construct_broker(
     config: Config,
-) -> (
-    Router,
-    impl std::future::Future<Output = ()>,
-    impl std::future::Future<Output = ()>,
-    LinkBuilder,
-) {
-    let (router, router_tx) = Router::new(Arc::new(config.router.clone()));
+) -> Result<
+    (
+        Router,
+        impl std::future::Future<Output = ()>,
+        impl std::future::Future<Output = ()>,
+        LinkBuilder,
+    ),
+    StorageError,
+> {
+    let (router, router_tx) = Router::new(Arc::new(config.router.clone()))?;
 
     let console = {
         let config = config.clone().into();
@@ -184,7 +189,7 @@ pub fn construct_broker(
         .map(|(id, config)| {
             let router_tx = router_tx.clone();
             async {
-                if let Err(e) = Server::new(id, config, router_tx).start().await {
+                if let Err(e) = Server::new(id, config, router_tx, None).start().await {
                     error!("Accept loop error: {:?}", e);
                 }
             }
@@ -194,5 +199,5 @@ pub fn construct_broker(
 
     let builder = LinkBuilder { router_tx };
 
-    (router, console, server, builder)
+    Ok((router, console, server, builder))
 }
@@ -0,0 +1,85 @@
+//! Authentication of connecting clients. Every mqtt connect is checked by the
+//! authenticator of its server before the connection is registered with the router
+use crate::ConnectionLoginCredentials;
+use mqttbytes::v4::ConnectReturnCode;
+
+/// Identity a client presents while connecting
+#[derive(Debug)]
+pub struct ClientInfo<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// DER encoded certificate of a client which authenticated with tls
+    pub certificate: Option<&'a [u8]>,
+}
+
+/// Decides if a client can connect. Rejected clients receive the returned
+/// code in their connack and are disconnected
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, client: &ClientInfo) -> Result<(), ConnectReturnCode>;
+}
+
+/// Username and password pairs of `ConnectionSettings::login_credentials`
+pub(crate) struct Credentials(pub Vec<ConnectionLoginCredentials>);
+
+impl Authenticator for Credentials {
+    fn authenticate(&self, client: &ClientInfo) -> Result<(), ConnectReturnCode> {
+        let (username, password) = match (client.username, client.password) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return Err(ConnectReturnCode::BadUserNamePassword),
+        };
+
+        let valid = self
+            .0
+            .iter()
+            .any(|entry| entry.username == username && entry.password == password);
+
+        match valid {
+            true => Ok(()),
+            false => Err(ConnectReturnCode::BadUserNamePassword),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client<'a>(username: Option<&'a str>, password: Option<&'a str>) -> ClientInfo<'a> {
+        ClientInfo {
+            client_id: "device-1",
+            username,
+            password,
+            certificate: None,
+        }
+    }
+
+    #[test]
+    fn login_credentials_authenticate_matching_pairs_only() {
+        let credentials = Credentials(vec![
+            ConnectionLoginCredentials {
+                username: "a".to_owned(),
+                password: "1".to_owned(),
+            },
+            ConnectionLoginCredentials {
+                username: "b".to_owned(),
+                password: "2".to_owned(),
+            },
+        ]);
+
+        let accepted = client(Some("b"), Some("2"));
+        assert!(credentials.authenticate(&accepted).is_ok());
+
+        let rejected = [
+            (Some("a"), Some("2")),
+            (Some("c"), Some("1")),
+            (Some("a"), None),
+            (None, None),
+        ];
+        for (username, password) in rejected.iter() {
+            let client = client(*username, *password);
+            let code = credentials.authenticate(&client);
+            assert_eq!(code, Err(ConnectReturnCode::BadUserNamePassword));
+        }
+    }
+}
@@ -30,7 +30,10 @@ fn main() {
     #[cfg(feature = "prof")]
     let _guard = prof::new(commandline.profile);
 
-    let o = Broker::new(config).start();
+    let o = match Broker::new(config) {
+        Ok(mut broker) => broker.start(),
+        Err(e) => Err(e),
+    };
     println!("Stopping broker!! Error = {:?}", o);
 }
 
@@ -3,6 +3,7 @@ use rumqttlog::ConnectionId;
 use rumqttlog::{
     Connection, ConnectionAck, Event, MetricsReply, MetricsRequest, Notification, Receiver, Sender,
 };
+use std::collections::HashMap;
 use std::sync::Arc;
 use warp::Filter;
 
@@ -63,6 +64,77 @@ pub async fn start(console: Arc<ConsoleLink>) {
         }
     });
 
+    // Topics have slashes. Topic is a query parameter, e.g. /node/topic?name=hello/world
+    let topic_console = console.clone();
+    let topic = warp::path!("node" / "topic")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |query: HashMap<String, String>| {
+            let topic = query.get("name").cloned().unwrap_or_default();
+            let message = Event::Metrics(MetricsRequest::Topic(topic));
+            topic_console
+                .router_tx
+                .send((topic_console.id, message))
+                .unwrap();
+
+            match topic_console.link_rx.recv().unwrap() {
+                Notification::Metrics(MetricsReply::Topic(v)) => warp::reply::json(&v),
+                v => unreachable!("{:?}", v),
+            }
+        });
+
+    // Deletes the topic along with its commitlog, e.g. DELETE /node/topic?name=hello/world
+    let delete_console = console.clone();
+    let delete = warp::path!("node" / "topic")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |query: HashMap<String, String>| {
+            let topic = query.get("name").cloned().unwrap_or_default();
+            let message = Event::DeleteTopic(topic);
+            delete_console
+                .router_tx
+                .send((delete_console.id, message))
+                .unwrap();
+
+            match delete_console.link_rx.recv().unwrap() {
+                Notification::Metrics(MetricsReply::Topic(v)) => warp::reply::json(&v),
+                v => unreachable!("{:?}", v),
+            }
+        });
+
+    // Drops data of the topic before the offset, e.g.
+    // POST /node/topic/purge?name=hello/world&offset=1000
+    let purge_console = console.clone();
+    let purge = warp::path!("node" / "topic" / "purge")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |query: HashMap<String, String>| {
+            let topic = query.get("name").cloned().unwrap_or_default();
+            let offset = query.get("offset").and_then(|o| o.parse().ok());
+            let message = Event::PurgeTopic(topic, offset.unwrap_or(0));
+            purge_console
+                .router_tx
+                .send((purge_console.id, message))
+                .unwrap();
+
+            match purge_console.link_rx.recv().unwrap() {
+                Notification::Metrics(MetricsReply::Topic(v)) => warp::reply::json(&v),
+                v => unreachable!("{:?}", v),
+            }
+        });
+
+    // Records replicas of peer routers are behind every topic they pull
+    let replication_console = console.clone();
+    let replication = warp::path!("node" / "replication").map(move || {
+        let message = Event::Metrics(MetricsRequest::Replication);
+        replication_console
+            .router_tx
+            .send((replication_console.id, message))
+            .unwrap();
+
+        match replication_console.link_rx.recv().unwrap() {
+            Notification::Metrics(MetricsReply::Replication(v)) => warp::reply::json(&v),
+            v => unreachable!("{:?}", v),
+        }
+    });
+
     let connection_console = console.clone();
     let connection = warp::path!("node" / String).map(move |id| {
         let message = Event::Metrics(MetricsRequest::Connection(id));
@@ -77,6 +149,9 @@ pub async fn start(console: Arc<ConsoleLink>) {
         }
     });
 
-    let routes = warp::get().and(config.or(router).or(connection));
+    let routes = warp::get()
+        .and(config.or(router).or(topic).or(replication).or(connection))
+        .or(warp::delete().and(delete))
+        .or(warp::post().and(purge));
     warp::serve(routes).run(address).await;
 }
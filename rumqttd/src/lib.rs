@@ -7,10 +7,12 @@ use std::{io, thread};
 use std::{net::SocketAddr, sync::Arc};
 
 use mqttbytes::v4::Packet;
+use rumqttlog::storage::StorageError;
 use rumqttlog::*;
 use tokio::time::error::Elapsed;
 
 use crate::remotelink::RemoteLink;
+use crate::replicalink::ReplicaLink;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
@@ -21,7 +23,7 @@ use tokio::{task, time};
 use tokio_rustls::rustls::internal::pemfile::{certs, rsa_private_keys};
 #[cfg(feature = "use-rustls")]
 use tokio_rustls::rustls::{
-    AllowAnyAuthenticatedClient, RootCertStore, ServerConfig, TLSError as RustlsError,
+    AllowAnyAuthenticatedClient, RootCertStore, ServerConfig, Session, TLSError as RustlsError,
 };
 
 // All requirements for `native-tls`
@@ -32,12 +34,16 @@ use tokio_native_tls::native_tls;
 #[cfg(feature = "use-native-tls")]
 use tokio_native_tls::native_tls::Error as NativeTlsError;
 pub mod async_locallink;
+mod auth;
 mod consolelink;
 mod locallink;
 mod network;
 mod remotelink;
+mod replicalink;
 mod state;
 
+use crate::auth::Credentials;
+pub use crate::auth::{Authenticator, ClientInfo};
 use crate::consolelink::ConsoleLink;
 pub use crate::locallink::{LinkError, LinkRx, LinkTx};
 use crate::network::Network;
@@ -56,13 +62,19 @@ pub enum Error {
     #[error("I/O {0}")]
     Io(#[from] io::Error),
     #[error("Connection error {0}")]
-    Connection(#[from] remotelink::Error),
+    Connection(#[source] Box<remotelink::Error>),
+    #[error("Replication error {0}")]
+    Replication(#[source] Box<replicalink::Error>),
+    #[error("Cluster config of router {0} not found")]
+    MeshSettingsNotFound(usize),
+    #[error("Replicator settings not provided")]
+    ReplicatorSettingsRequired,
     #[error("Timeout")]
     Timeout(#[from] Elapsed),
     #[error("Channel recv error")]
     Recv(#[from] RecvError),
     #[error("Channel send error")]
-    Send(#[from] SendError<(Id, Event)>),
+    Send(#[source] Box<SendError<(Id, Event)>>),
     #[cfg(feature = "use-native-tls")]
     #[error("Native TLS error {0}")]
     NativeTls(#[from] NativeTlsError),
@@ -87,11 +99,32 @@ pub enum Error {
     InvalidServerPass(),
     #[error("Invalid server key file {0}")]
     InvalidServerKey(String),
+    #[error("Router storage error {0}")]
+    Storage(#[from] StorageError),
     RustlsNotEnabled,
     NativeTlsNotEnabled,
     Disconnected,
     NetworkClosed,
-    WrongPacket(Packet),
+    WrongPacket(Box<Packet>),
+}
+
+// Links and router channel errors are boxed to keep results small
+impl From<remotelink::Error> for Error {
+    fn from(e: remotelink::Error) -> Error {
+        Error::Connection(Box::new(e))
+    }
+}
+
+impl From<replicalink::Error> for Error {
+    fn from(e: replicalink::Error) -> Error {
+        Error::Replication(Box::new(e))
+    }
+}
+
+impl From<SendError<(Id, Event)>> for Error {
+    fn from(e: SendError<(Id, Event)>) -> Error {
+        Error::Send(Box::new(e))
+    }
 }
 
 type Id = usize;
@@ -153,11 +186,25 @@ pub struct ConnectionSettings {
     pub max_inflight_count: u16,
     pub max_inflight_size: usize,
     pub login_credentials: Option<Vec<ConnectionLoginCredentials>>,
+    /// Subscribe only listener. Connections publishing on this listener are
+    /// disconnected and their last wills are dropped
+    #[serde(default)]
+    pub read_only: bool,
+    /// Handling of connections which can't keep up with their subscriptions
+    #[serde(default)]
+    pub slow_consumer: SlowConsumer,
+    /// Ack mode of publishes on topics without an ack mode policy in the router
+    #[serde(default)]
+    pub ack_mode: AckMode,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeshSettings {
     pub address: SocketAddr,
+    /// Topics published at this router and replicated to the others.
+    /// Topics of different routers shouldn't overlap
+    #[serde(default)]
+    pub topics: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -183,22 +230,48 @@ impl Default for ConsoleSettings {
     }
 }
 
+/// Router of the broker. Topics are partitioned across routers
+/// when `router.shards` is more than 1
+enum BrokerRouter {
+    Single(Box<Router>),
+    Sharded(Box<ShardedRouter>),
+}
+
 pub struct Broker {
     config: Arc<Config>,
     router_tx: Sender<(Id, Event)>,
-    router: Option<Router>,
+    router: Option<BrokerRouter>,
+    authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 impl Broker {
-    pub fn new(config: Config) -> Broker {
+    /// Fails if the router can't open its storage, logs or snapshot
+    pub fn new(config: Config) -> Result<Broker, Error> {
         let config = Arc::new(config);
         let router_config = Arc::new(config.router.clone());
-        let (router, router_tx) = Router::new(router_config);
-        Broker {
+        let (router, router_tx) = if config.router.shards > 1 {
+            let (router, router_tx) = ShardedRouter::new(router_config)?;
+            (BrokerRouter::Sharded(Box::new(router)), router_tx)
+        } else if config.router.snapshot_interval_secs.is_some() {
+            let (router, router_tx) = Router::from_snapshot(router_config)?;
+            (BrokerRouter::Single(Box::new(router)), router_tx)
+        } else {
+            let (router, router_tx) = Router::new(router_config)?;
+            (BrokerRouter::Single(Box::new(router)), router_tx)
+        };
+
+        Ok(Broker {
             config,
             router_tx,
             router: Some(router),
-        }
+            authenticator: None,
+        })
+    }
+
+    /// Authenticates clients of all the servers. Replaces `login_credentials`
+    /// of the servers
+    pub fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.authenticator = Some(authenticator);
     }
 
     pub fn router_handle(&self) -> Sender<(Id, Event)> {
@@ -215,15 +288,36 @@ impl Broker {
 
     pub fn start(&mut self) -> Result<(), Error> {
         // spawn the router in a separate thread
-        let mut router = self.router.take().unwrap();
+        let router = self.router.take().unwrap();
         let router_thread = thread::Builder::new().name("rumqttd-router".to_owned());
-        router_thread.spawn(move || router.start())?;
+        router_thread.spawn(move || match router {
+            BrokerRouter::Single(mut router) => router.start(),
+            BrokerRouter::Sharded(mut router) => router.start(),
+        })?;
+
+        // spawn replication links with rest of the mesh in a separate thread
+        if let Some(cluster) = self.config.cluster.clone() {
+            let replicator = self.config.replicator.clone();
+            let replicator = replicator.ok_or(Error::ReplicatorSettingsRequired)?;
+            let mesh = Mesh::new(self.config.id, cluster, replicator, self.router_tx.clone())?;
+            let mesh_thread = thread::Builder::new().name("rumqttd-replicator".to_owned());
+            mesh_thread.spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                let runtime = runtime.enable_all().build().unwrap();
+                runtime.block_on(async {
+                    if let Err(e) = mesh.start().await {
+                        error!("Stopping replication. Error: {:?}", e.to_string());
+                    }
+                });
+            })?;
+        }
 
         // spawn servers in a separate thread
         for (id, config) in self.config.servers.clone() {
             let server_name = format!("rumqttd-server-{}", id);
             let server_thread = thread::Builder::new().name(server_name);
-            let server = Server::new(id, config, self.router_tx.clone());
+            let authenticator = self.authenticator.clone();
+            let server = Server::new(id, config, self.router_tx.clone(), authenticator);
             server_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
                 let runtime = runtime.enable_all().build().unwrap();
@@ -253,14 +347,27 @@ struct Server {
     id: String,
     config: ServerSettings,
     router_tx: Sender<(Id, Event)>,
+    authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 impl Server {
-    pub fn new(id: String, config: ServerSettings, router_tx: Sender<(Id, Event)>) -> Server {
+    /// Server without an authenticator checks the login credentials of its config
+    pub fn new(
+        id: String,
+        config: ServerSettings,
+        router_tx: Sender<(Id, Event)>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+    ) -> Server {
+        let authenticator = authenticator.or_else(|| {
+            let credentials = config.connections.login_credentials.clone()?;
+            Some(Arc::new(Credentials(credentials)) as Arc<dyn Authenticator>)
+        });
+
         Server {
             id,
             config,
             router_tx,
+            authenticator,
         }
     }
 
@@ -400,8 +507,9 @@ impl Server {
             };
 
             // Depending on TLS or not create a new Network
+            // Certificate of the client is its identity for the authenticator
             #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
-            let network = match &acceptor {
+            let (network, certificate) = match &acceptor {
                 Some(a) => {
                     info!("{}. Accepting TLS connection from: {}", count, addr);
 
@@ -417,7 +525,13 @@ impl Server {
                                 }
                             };
 
-                            Network::new(stream, max_incoming_size)
+                            let (_, session) = stream.get_ref();
+                            let certificate = session
+                                .get_peer_certificates()
+                                .and_then(|certificates| certificates.into_iter().next())
+                                .map(|certificate| certificate.0);
+
+                            (Network::new(stream, max_incoming_size), certificate)
                         }
                         #[cfg(feature = "use-native-tls")]
                         ServerTLSAcceptor::NativeTLSAcceptor { acceptor } => {
@@ -429,30 +543,36 @@ impl Server {
                                 }
                             };
 
-                            Network::new(stream, max_incoming_size)
+                            let certificate = match stream.get_ref().peer_certificate() {
+                                Ok(Some(certificate)) => certificate.to_der().ok(),
+                                _ => None,
+                            };
+
+                            (Network::new(stream, max_incoming_size), certificate)
                         }
                     }
                 }
                 None => {
                     info!("{}. Accepting TCP connection from: {}", count, addr);
-                    Network::new(stream, max_incoming_size)
+                    (Network::new(stream, max_incoming_size), None)
                 }
             };
             #[cfg(not(any(feature = "use-rustls", feature = "use-native-tls")))]
-            let network = {
+            let (network, certificate) = {
                 info!("{}. Accepting TCP connection from: {}", count, addr);
-                Network::new(stream, max_incoming_size)
+                (Network::new(stream, max_incoming_size), None)
             };
 
             count += 1;
 
             let config = config.clone();
             let router_tx = self.router_tx.clone();
+            let authenticator = self.authenticator.clone();
 
             // Spawn a new thread to handle this connection.
             task::spawn(async {
-                let connector = Connector::new(config, router_tx);
-                if let Err(e) = connector.new_connection(network).await {
+                let connector = Connector::new(config, router_tx, authenticator);
+                if let Err(e) = connector.new_connection(network, certificate).await {
                     error!("Dropping link task!! Result = {:?}", e);
                 }
             });
@@ -462,14 +582,126 @@ impl Server {
     }
 }
 
+/// Replication links of this router with the other routers of the mesh.
+/// Routers connect to the peers with bigger ids and accept the rest
+struct Mesh {
+    id: usize,
+    cluster: HashMap<String, MeshSettings>,
+    config: Arc<ConnectionSettings>,
+    router_tx: Sender<(Id, Event)>,
+}
+
+impl Mesh {
+    fn new(
+        id: usize,
+        cluster: HashMap<String, MeshSettings>,
+        config: ConnectionSettings,
+        router_tx: Sender<(Id, Event)>,
+    ) -> Result<Mesh, Error> {
+        if !cluster.contains_key(&id.to_string()) {
+            return Err(Error::MeshSettingsNotFound(id));
+        }
+
+        Ok(Mesh {
+            id,
+            cluster,
+            config: Arc::new(config),
+            router_tx,
+        })
+    }
+
+    async fn start(&self) -> Result<(), Error> {
+        let settings = &self.cluster[&self.id.to_string()];
+        let topics = Arc::new(settings.topics.clone());
+
+        for (peer, settings) in self.cluster.iter() {
+            let peer: usize = match peer.parse() {
+                Ok(peer) if peer > self.id => peer,
+                Ok(_) => continue,
+                Err(_) => {
+                    error!("Invalid router id {} in cluster config", peer);
+                    continue;
+                }
+            };
+
+            let id = self.id;
+            let address = settings.address.to_string();
+            let config = self.config.clone();
+            let topics = topics.clone();
+            let router_tx = self.router_tx.clone();
+            task::spawn(async move {
+                loop {
+                    let link = ReplicaLink::connect(
+                        config.clone(),
+                        id,
+                        peer,
+                        &address,
+                        &topics,
+                        router_tx.clone(),
+                    );
+
+                    match link.await {
+                        Ok(link) => replicate(link, &router_tx).await,
+                        Err(e) => error!("Replication connect error. Peer = {}, {}", peer, e),
+                    }
+
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            });
+        }
+
+        let listener = TcpListener::bind(&settings.address).await?;
+        info!("Waiting for replicators on {}", settings.address);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("Accepting replicator connection from: {}", addr);
+
+            let network = Network::new(stream, self.config.max_payload_size);
+            let config = self.config.clone();
+            let topics = topics.clone();
+            let router_tx = self.router_tx.clone();
+            task::spawn(async move {
+                match ReplicaLink::accept(config, network, &topics, router_tx.clone()).await {
+                    Ok(link) => replicate(link, &router_tx).await,
+                    Err(e) => error!("Replication accept error. {}", e),
+                }
+            });
+        }
+    }
+}
+
+/// Runs the link till the peer disconnects and disconnects its replicator
+/// from the router. Data which isn't acked yet is redelivered on reconnection
+async fn replicate(mut link: ReplicaLink, router_tx: &Sender<(Id, Event)>) {
+    let peer = link.peer();
+    if let Err(e) = link.start().await {
+        error!("Replication stopped!! Peer = {}, {}", peer, e);
+    }
+
+    let disconnect = Disconnection::new(replicator_id(peer), false, link.state.clean());
+    let message = (peer, Event::Disconnect(disconnect));
+    if let Err(e) = router_tx.send(message) {
+        error!("Failed to disconnect replicator {}. Error = {:?}", peer, e);
+    }
+}
+
 struct Connector {
     config: Arc<ConnectionSettings>,
     router_tx: Sender<(Id, Event)>,
+    authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 impl Connector {
-    fn new(config: Arc<ConnectionSettings>, router_tx: Sender<(Id, Event)>) -> Connector {
-        Connector { config, router_tx }
+    fn new(
+        config: Arc<ConnectionSettings>,
+        router_tx: Sender<(Id, Event)>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+    ) -> Connector {
+        Connector {
+            config,
+            router_tx,
+            authenticator,
+        }
     }
 
     /// A new network connection should wait for mqtt connect packet. This handling should be handled
@@ -477,12 +709,18 @@ impl Connector {
     /// waiting for mqtt connect packet. Also this honours connection wait time as per config to prevent
     /// denial of service attacks (rogue clients which only does network connection without sending
     /// mqtt connection packet to make make the server reach its concurrent connection limit)
-    async fn new_connection(&self, network: Network) -> Result<(), Error> {
+    async fn new_connection(
+        &self,
+        network: Network,
+        certificate: Option<Vec<u8>>,
+    ) -> Result<(), Error> {
         let config = self.config.clone();
         let router_tx = self.router_tx.clone();
+        let authenticator = self.authenticator.clone();
 
         // Start the link
-        let (client_id, id, mut link) = RemoteLink::new(config, router_tx, network).await?;
+        let link = RemoteLink::new(config, router_tx, network, certificate, authenticator);
+        let (client_id, id, mut link) = link.await?;
         let (execute_will, pending) = match link.start().await {
             // Connection get close. This shouldn't usually happen
             Ok(_) => {
@@ -499,6 +737,14 @@ impl Connector {
                 info!("Disconnected!! Id = {} ({})", client_id, id);
                 (false, link.state.clean())
             }
+            // Router already disconnected this connection (ex. session takeover)
+            Err(remotelink::Error::RouterDisconnect(reason)) => {
+                info!(
+                    "Disconnected by router!! Id = {} ({}), {:?}",
+                    client_id, id, reason
+                );
+                (false, link.state.clean())
+            }
             // Any other error
             Err(e) => {
                 error!("Error!! Id = {} ({}), {}", client_id, id, e.to_string());
@@ -516,3 +762,67 @@ impl Connector {
 
 pub trait IO: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> IO for T {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mqttbytes::v4::ConnectReturnCode;
+
+    struct RejectAll;
+
+    impl Authenticator for RejectAll {
+        fn authenticate(&self, _client: &ClientInfo) -> Result<(), ConnectReturnCode> {
+            Err(ConnectReturnCode::NotAuthorized)
+        }
+    }
+
+    fn settings(login_credentials: Option<Vec<ConnectionLoginCredentials>>) -> ServerSettings {
+        let connections = ConnectionSettings {
+            connection_timeout_ms: 1000,
+            max_client_id_len: 256,
+            throttle_delay_ms: 0,
+            max_payload_size: 1024,
+            max_inflight_count: 100,
+            max_inflight_size: 1024,
+            login_credentials,
+            read_only: false,
+            slow_consumer: Default::default(),
+            ack_mode: Default::default(),
+        };
+
+        ServerSettings {
+            listen: "127.0.0.1:1883".parse().unwrap(),
+            cert: None,
+            next_connection_delay_ms: 1,
+            connections,
+        }
+    }
+
+    #[test]
+    fn servers_fall_back_to_login_credentials_without_an_authenticator() {
+        let (router_tx, _router_rx) = bounded(10);
+        let client = ClientInfo {
+            client_id: "device-1",
+            username: Some("user"),
+            password: Some("pass"),
+            certificate: None,
+        };
+
+        let server = Server::new("1".to_owned(), settings(None), router_tx.clone(), None);
+        assert!(server.authenticator.is_none());
+
+        let credentials = vec![ConnectionLoginCredentials {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        }];
+        let config = settings(Some(credentials));
+        let server = Server::new("1".to_owned(), config.clone(), router_tx.clone(), None);
+        assert!(server.authenticator.unwrap().authenticate(&client).is_ok());
+
+        // Authenticator of the broker replaces login credentials
+        let authenticator = Some(Arc::new(RejectAll) as Arc<dyn Authenticator>);
+        let server = Server::new("1".to_owned(), config, router_tx, authenticator);
+        let code = server.authenticator.unwrap().authenticate(&client);
+        assert_eq!(code, Err(ConnectReturnCode::NotAuthorized));
+    }
+}
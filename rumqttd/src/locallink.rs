@@ -142,6 +142,7 @@ impl LinkRx {
                 self.router_tx.send(message)?;
                 Ok(None)
             }
+            Notification::SubscribeProgress(_) => Ok(None),
             notification => {
                 warn!("{:?} not supported in local link", notification);
                 Ok(None)
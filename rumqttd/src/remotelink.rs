@@ -1,10 +1,12 @@
+use crate::auth::{Authenticator, ClientInfo};
 use crate::network::Network;
 use crate::state::{self, State};
 use crate::{network, ConnectionSettings, Id};
 use mqttbytes::v4::*;
 use mqttbytes::*;
 use rumqttlog::{
-    Connection, ConnectionAck, Event, Notification, Receiver, RecvError, SendError, Sender,
+    Connection, ConnectionAck, DisconnectReason, Event, Notification, Receiver, RecvError,
+    SendError, Sender,
 };
 
 use std::sync::Arc;
@@ -50,8 +52,14 @@ pub enum Error {
     InvalidClientId,
     #[error("Invalid username or password provided")]
     InvalidUsernameOrPassword,
+    #[error("Connection rejected by authenticator. Code = {0:?}")]
+    NotAuthenticated(ConnectReturnCode),
     #[error("Disconnect request")]
     Disconnect,
+    #[error("Disconnected by router. Reason = {0:?}")]
+    RouterDisconnect(DisconnectReason),
+    #[error("Publish on read only listener. Topic = {0}")]
+    ReadOnly(String),
 }
 
 impl RemoteLink {
@@ -59,6 +67,8 @@ impl RemoteLink {
         config: Arc<ConnectionSettings>,
         router_tx: Sender<(Id, Event)>,
         mut network: Network,
+        certificate: Option<Vec<u8>>,
+        authenticator: Option<Arc<dyn Authenticator>>,
     ) -> Result<(String, Id, RemoteLink), Error> {
         // Wait for MQTT connect packet and error out if it's not received in time to prevent
         // DOS attacks by filling total connections that the server can handle with idle open
@@ -67,28 +77,24 @@ impl RemoteLink {
         let mut connect = time::timeout(timeout, async {
             let connect = network.read_connect().await?;
 
-            // Validate credentials if they exist
-            if let Some(credentials) = &config.login_credentials {
-                let validated = match &connect.login {
-                    Some(l) => {
-                        let mut validated = false;
-
-                        // Iterate through all the potential credentials
-                        for entry in credentials {
-                            if l.validate(&entry.username, &entry.password) {
-                                validated = true;
-                                break;
-                            }
-                        }
-
-                        validated
-                    }
-                    None => false,
+            // Rejected clients are notified with the connack code of the authenticator
+            if let Some(authenticator) = &authenticator {
+                let login = connect.login.as_ref();
+                let client = ClientInfo {
+                    client_id: &connect.client_id,
+                    username: login.map(|l| l.username.as_str()),
+                    password: login.map(|l| l.password.as_str()),
+                    certificate: certificate.as_deref(),
                 };
 
-                // Return error if the username/password werenot found
-                if validated == false {
-                    return Err(Error::InvalidUsernameOrPassword);
+                if let Err(code) = authenticator.authenticate(&client) {
+                    network.connack(ConnAck::new(code, false)).await?;
+                    return match code {
+                        ConnectReturnCode::BadUserNamePassword => {
+                            Err(Error::InvalidUsernameOrPassword)
+                        }
+                        code => Err(Error::NotAuthenticated(code)),
+                    };
                 }
             }
 
@@ -106,11 +112,17 @@ impl RemoteLink {
 
         let (mut connection, link_rx) = Connection::new_remote(&client_id, clean_session, 10);
 
-        // Add last will to connection
+        // Add last will to connection. Read only connections can't publish wills either
         if let Some(will) = connect.last_will.take() {
-            connection.set_will(will);
+            match config.read_only {
+                true => warn!("Dropping will of {} on read only listener", client_id),
+                false => connection.set_will(will),
+            }
         }
 
+        connection.set_slow_consumer(config.slow_consumer);
+        connection.set_ack_mode(config.ack_mode);
+
         let message = (0, Event::Connect(connection));
         router_tx.send(message).unwrap();
 
@@ -120,7 +132,11 @@ impl RemoteLink {
         let (id, session, pending) = match link_rx.recv()? {
             Notification::ConnectionAck(ack) => match ack {
                 ConnectionAck::Success((id, session, pending)) => (id, session, pending),
-                ConnectionAck::Failure(reason) => return Err(Error::ConnAck(reason)),
+                ConnectionAck::Failure(reason) => {
+                    let connack = ConnAck::new(ConnectReturnCode::BadClientId, false);
+                    network.connack(connack).await?;
+                    return Err(Error::ConnAck(reason));
+                }
             },
             message => return Err(Error::RouterMessage(message)),
         };
@@ -199,6 +215,15 @@ impl RemoteLink {
         let data = self.state.take_incoming();
         self.network.flush(self.state.write_mut()).await?;
 
+        // Publishes on read only listeners are rejected before they reach the router
+        if self.config.read_only {
+            if let Some(Packet::Publish(publish)) =
+                data.iter().find(|p| matches!(p, Packet::Publish(_)))
+            {
+                return Err(Error::ReadOnly(publish.topic.clone()));
+            }
+        }
+
         if !data.is_empty() {
             debug!(
                 "{:11} {:14} Id = {}, Count = {}",
@@ -245,7 +270,7 @@ impl RemoteLink {
                     payload.len()
                 );
 
-                self.state.add_pending(topic, qos, vec![payload]);
+                self.state.add_pending(topic, qos, vec![payload], false);
                 self.state.write_pending()?;
             }
             Notification::Data(reply) => {
@@ -269,13 +294,25 @@ impl RemoteLink {
                 }
 
                 self.total += payload_count;
-                self.state.add_pending(topic, qos, payload);
+                self.state.add_pending(topic, qos, payload, reply.retained);
                 self.state.write_pending()?;
             }
             Notification::Pause => {
                 let message = (self.id, Event::Ready);
                 self.router_tx.send(message)?;
             }
+            Notification::Disconnect(reason) => return Err(Error::RouterDisconnect(reason)),
+            Notification::SubscribeProgress(progress) => {
+                trace!(
+                    "{:11} {:14} Id = {}, Pkid = {}, Applied = {}/{}",
+                    "subscribe",
+                    "progress",
+                    self.id,
+                    progress.pkid,
+                    progress.applied,
+                    progress.total
+                );
+            }
             notification => {
                 warn!("{:?} not supported in remote link", notification);
             }
@@ -285,3 +322,184 @@ impl RemoteLink {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::Credentials;
+    use crate::ConnectionLoginCredentials;
+    use bytes::BytesMut;
+    use rumqttlog::{Config, Router};
+    use std::thread;
+    use tokio::io::duplex;
+
+    /// Accepts only the client with this id
+    struct ClientId(&'static str);
+
+    impl Authenticator for ClientId {
+        fn authenticate(&self, client: &ClientInfo) -> Result<(), ConnectReturnCode> {
+            match client.client_id == self.0 {
+                true => Ok(()),
+                false => Err(ConnectReturnCode::NotAuthorized),
+            }
+        }
+    }
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            connection_timeout_ms: 1000,
+            max_client_id_len: 256,
+            throttle_delay_ms: 0,
+            max_payload_size: 1024,
+            max_inflight_count: 100,
+            max_inflight_size: 1024,
+            login_credentials: None,
+            read_only: false,
+            slow_consumer: Default::default(),
+            ack_mode: Default::default(),
+        }
+    }
+
+    /// Connects a client over an in memory stream. Returns the result of the
+    /// link and the client end of the stream
+    async fn start_link(
+        config: ConnectionSettings,
+        router_tx: Sender<(Id, Event)>,
+        connect: Connect,
+        authenticator: Arc<dyn Authenticator>,
+    ) -> (Result<(String, Id, RemoteLink), Error>, Network) {
+        let (client, server) = duplex(1024);
+        let mut client = Network::new(client, 1024);
+        client.connect(connect).await.unwrap();
+
+        let server = Network::new(server, 1024);
+        let config = Arc::new(config);
+        let link = RemoteLink::new(config, router_tx, server, None, Some(authenticator));
+        (link.await, client)
+    }
+
+    /// Starts a router behind a proxy which reports if connections have wills
+    fn start_router() -> (Sender<(Id, Event)>, Receiver<bool>) {
+        let (mut router, router_tx) = Router::new(Arc::new(Config::default())).unwrap();
+        thread::spawn(move || router.start());
+
+        let (proxy_tx, proxy_rx) = rumqttlog::bounded::<(Id, Event)>(10);
+        let (wills_tx, wills_rx) = rumqttlog::bounded(10);
+        thread::spawn(move || {
+            while let Ok((id, mut event)) = proxy_rx.recv() {
+                if let Event::Connect(connection) = &mut event {
+                    let will = connection.will();
+                    wills_tx.send(will.is_some()).unwrap();
+                    if let Some(will) = will {
+                        connection.set_will(will);
+                    }
+                }
+
+                router_tx.send((id, event)).unwrap();
+            }
+        });
+
+        (proxy_tx, wills_rx)
+    }
+
+    #[tokio::test]
+    async fn accepted_clients_are_registered_with_the_router() {
+        let (mut router, router_tx) = Router::new(Arc::new(Config::default())).unwrap();
+        thread::spawn(move || router.start());
+
+        let authenticator = Arc::new(ClientId("device-1"));
+        let connect = Connect::new("device-1");
+        let (link, mut client) = start_link(settings(), router_tx, connect, authenticator).await;
+        let (client_id, ..) = link.unwrap();
+        assert_eq!(client_id, "device-1");
+
+        let connack = client.read_connack().await.unwrap();
+        assert_eq!(connack.code, ConnectReturnCode::Success);
+    }
+
+    #[tokio::test]
+    async fn rejected_clients_receive_the_code_and_are_disconnected() {
+        let (router_tx, router_rx) = rumqttlog::bounded(10);
+        let authenticator = Arc::new(ClientId("device-1"));
+        let connect = Connect::new("device-2");
+        let (link, mut client) = start_link(settings(), router_tx, connect, authenticator).await;
+        match link {
+            Err(Error::NotAuthenticated(ConnectReturnCode::NotAuthorized)) => (),
+            Err(e) => panic!("Unexpected error = {:?}", e),
+            Ok(_) => panic!("Rejected client is connected"),
+        }
+
+        let connack = client.read_connack().await.unwrap();
+        assert_eq!(connack.code, ConnectReturnCode::NotAuthorized);
+        assert!(client.read().await.is_err());
+
+        // Router never hears of rejected clients
+        assert!(router_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_login_credentials_are_rejected() {
+        let (router_tx, _router_rx) = rumqttlog::bounded(10);
+        let credentials = vec![ConnectionLoginCredentials {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        }];
+
+        let authenticator = Arc::new(Credentials(credentials));
+        let mut connect = Connect::new("device-1");
+        connect.set_login("user", "wrong");
+        let (link, mut client) = start_link(settings(), router_tx, connect, authenticator).await;
+        assert!(matches!(link, Err(Error::InvalidUsernameOrPassword)));
+
+        let connack = client.read_connack().await.unwrap();
+        assert_eq!(connack.code, ConnectReturnCode::BadUserNamePassword);
+        assert!(client.read().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only_links_drop_wills_and_disconnect_publishers() {
+        let (router_tx, wills) = start_router();
+        let config = ConnectionSettings {
+            read_only: true,
+            ..settings()
+        };
+
+        let authenticator = Arc::new(ClientId("dashboard"));
+        let mut connect = Connect::new("dashboard");
+        connect.last_will = Some(LastWill::new("status", "offline", QoS::AtLeastOnce, false));
+        let (link, mut client) = start_link(config, router_tx, connect, authenticator).await;
+        let (_, _, mut link) = link.unwrap();
+        assert!(!wills.recv().unwrap());
+        client.read_connack().await.unwrap();
+        let link = tokio::spawn(async move { link.start().await });
+
+        // Subscriptions are allowed
+        let mut write = BytesMut::new();
+        let subscribe = Subscribe::new("devices/+/status", QoS::AtLeastOnce);
+        subscribe.write(&mut write).unwrap();
+        client.flush(&mut write).await.unwrap();
+        assert!(matches!(client.read().await.unwrap(), Packet::SubAck(_)));
+
+        let mut publish = Publish::new("devices/1/command", QoS::AtLeastOnce, "reboot");
+        publish.pkid = 1;
+        publish.write(&mut write).unwrap();
+        client.flush(&mut write).await.unwrap();
+        match link.await.unwrap() {
+            Err(Error::ReadOnly(topic)) => assert_eq!(topic, "devices/1/command"),
+            result => panic!("Unexpected result = {:?}", result.map(|_| ())),
+        }
+
+        assert!(client.read().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wills_of_writable_links_are_kept() {
+        let (router_tx, wills) = start_router();
+        let authenticator = Arc::new(ClientId("device-1"));
+        let mut connect = Connect::new("device-1");
+        connect.last_will = Some(LastWill::new("status", "offline", QoS::AtLeastOnce, false));
+        let (link, _client) = start_link(settings(), router_tx, connect, authenticator).await;
+        link.unwrap();
+        assert!(wills.recv().unwrap());
+    }
+}
@@ -0,0 +1,244 @@
+//! Replication of commitlogs between routers of a mesh. Links are mqtt
+//! connections. Data of the topics owned by a router is published to its
+//! peers as qos 1 publishes and pubacks of the peer, sent after the data is
+//! appended to its commitlog, free up the inflight window of the link
+use crate::network::Network;
+use crate::state::{self, State};
+use crate::{network, ConnectionSettings, Id};
+use mqttbytes::v4::*;
+use mqttbytes::*;
+use rumqttlog::{
+    replicator_id, Connection, ConnectionAck, Event, Notification, Receiver, RecvError, SendError,
+    Sender,
+};
+
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{error::Elapsed, Duration};
+use tokio::{select, time};
+
+pub struct ReplicaLink {
+    /// Id of the peer router. Also the id of its replicator connection
+    peer: Id,
+    network: Network,
+    router_tx: Sender<(Id, Event)>,
+    link_rx: Receiver<Notification>,
+    pub(crate) state: State,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O")]
+    Io(#[from] io::Error),
+    #[error("Network {0}")]
+    Network(#[from] network::Error),
+    #[error("Timeout")]
+    Timeout(#[from] Elapsed),
+    #[error("State error")]
+    State(#[from] state::Error),
+    #[error("Unexpected router message")]
+    RouterMessage(Notification),
+    #[error("Connack error {0}")]
+    ConnAck(String),
+    #[error("Channel send error")]
+    Send(#[from] SendError<(Id, Event)>),
+    #[error("Channel recv error")]
+    Recv(#[from] RecvError),
+    #[error("Invalid replicator id {0}")]
+    InvalidId(String),
+    #[error("Replicator disconnect request")]
+    Disconnect,
+}
+
+impl ReplicaLink {
+    /// Connects to a peer router. Routers connect to the peers with bigger ids
+    pub async fn connect(
+        config: Arc<ConnectionSettings>,
+        id: Id,
+        peer: Id,
+        address: &str,
+        topics: &[String],
+        router_tx: Sender<(Id, Event)>,
+    ) -> Result<ReplicaLink, Error> {
+        let timeout = Duration::from_millis(config.connection_timeout_ms.into());
+        let network = time::timeout(timeout, async {
+            let socket = TcpStream::connect(address).await?;
+            let mut network = Network::new(socket, config.max_payload_size);
+
+            // Links don't send pings. Dead peers are detected by the transport
+            let mut connect = Connect::new(replicator_id(id));
+            connect.keep_alive = 0;
+            connect.clean_session = false;
+            network.connect(connect).await?;
+
+            let connack = network.read_connack().await?;
+            if connack.code != ConnectReturnCode::Success {
+                return Err(Error::ConnAck(format!("{:?}", connack.code)));
+            }
+
+            Ok::<_, Error>(network)
+        })
+        .await??;
+
+        ReplicaLink::new(config, peer, network, topics, router_tx).await
+    }
+
+    /// Waits for a peer router with a smaller id to connect
+    pub async fn accept(
+        config: Arc<ConnectionSettings>,
+        mut network: Network,
+        topics: &[String],
+        router_tx: Sender<(Id, Event)>,
+    ) -> Result<ReplicaLink, Error> {
+        let timeout = Duration::from_millis(config.connection_timeout_ms.into());
+        let connect = time::timeout(timeout, network.read_connect()).await??;
+        let peer = connect
+            .client_id
+            .strip_prefix("replicator-")
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| Error::InvalidId(connect.client_id.clone()))?;
+
+        let mut link = ReplicaLink::new(config, peer, network, topics, router_tx).await?;
+        let connack = ConnAck::new(ConnectReturnCode::Success, false);
+        link.network.connack(connack).await?;
+        Ok(link)
+    }
+
+    /// Registers the replicator connection of the peer with the router and
+    /// subscribes to the topics which this router replicates
+    async fn new(
+        config: Arc<ConnectionSettings>,
+        peer: Id,
+        network: Network,
+        topics: &[String],
+        router_tx: Sender<(Id, Event)>,
+    ) -> Result<ReplicaLink, Error> {
+        let (connection, link_rx) = Connection::new_replica(peer, false, 10);
+        router_tx.send((0, Event::Connect(connection)))?;
+
+        let (session, pending) = match link_rx.async_recv().await? {
+            Notification::ConnectionAck(ConnectionAck::Success((_, session, pending))) => {
+                (session, pending)
+            }
+            Notification::ConnectionAck(ConnectionAck::Failure(reason)) => {
+                return Err(Error::ConnAck(reason))
+            }
+            message => return Err(Error::RouterMessage(message)),
+        };
+
+        // Resumed replicators continue from the cursors of their subscriptions
+        if !session {
+            let filters = topics
+                .iter()
+                .map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce))
+                .collect::<Vec<_>>();
+            let subscribe = Subscribe::new_many(filters);
+            router_tx.send((peer, Event::Data(vec![Packet::Subscribe(subscribe)])))?;
+        }
+
+        let mut link = ReplicaLink {
+            peer,
+            network,
+            router_tx,
+            link_rx,
+            state: State::new(config.max_inflight_count),
+        };
+
+        for notification in pending {
+            link.handle_router_response(notification).await?;
+        }
+
+        Ok(link)
+    }
+
+    pub fn peer(&self) -> Id {
+        self.peer
+    }
+
+    pub async fn start(&mut self) -> Result<(), Error> {
+        info!("{:11} {:14} Id = {}", "replication", "start", self.peer);
+        loop {
+            select! {
+                o = self.network.readb(&mut self.state) => {
+                    let disconnect = o?;
+                    self.handle_network_data().await?;
+
+                    if disconnect {
+                        return Err(Error::Disconnect)
+                    }
+                }
+                message = self.link_rx.async_recv(), if !self.state.pause_outgoing() => {
+                    let message = message?;
+                    self.handle_router_response(message).await?;
+                }
+            }
+        }
+    }
+
+    /// Appends data replicated by the peer to the commitlog. Acks of the data
+    /// are sent back once the router has appended it
+    async fn handle_network_data(&mut self) -> Result<(), Error> {
+        let data = self.state.take_incoming();
+        self.network.flush(self.state.write_mut()).await?;
+
+        if !data.is_empty() {
+            debug!(
+                "{:11} {:14} Id = {}, Count = {}",
+                "data",
+                "replica",
+                self.peer,
+                data.len()
+            );
+
+            let message = Event::Data(data);
+            self.router_tx.send((self.peer, message))?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_router_response(&mut self, message: Notification) -> Result<(), Error> {
+        match message {
+            Notification::ConnectionAck(_) => {}
+            Notification::Acks(reply) => {
+                for ack in reply.into_iter() {
+                    // Subscriptions are made by this link. Peer doesn't expect subacks
+                    if let Packet::SubAck(_) = ack {
+                        continue;
+                    }
+
+                    self.state.outgoing_ack(ack)?;
+                }
+            }
+            Notification::Data(reply) => {
+                let topic = reply.topic;
+                let qos = qos(reply.qos).unwrap();
+                let payload = reply.payload;
+
+                trace!(
+                    "{:11} {:14} Id = {}, Topic = {}, Offsets = {:?}, Count = {}",
+                    "replication",
+                    "reply",
+                    self.peer,
+                    topic,
+                    reply.cursor,
+                    payload.len()
+                );
+
+                self.state.add_pending(topic, qos, payload, reply.retained);
+                self.state.write_pending()?;
+            }
+            Notification::Pause => {
+                let message = (self.peer, Event::Ready);
+                self.router_tx.send(message)?;
+            }
+            notification => {
+                warn!("{:?} not supported in replica link", notification);
+            }
+        }
+
+        self.network.flush(self.state.write_mut()).await?;
+        Ok(())
+    }
+}
@@ -33,6 +33,8 @@ struct Pending {
     topic: String,
     qos: QoS,
     payload: IntoIter<Bytes>,
+    /// Last payload is a retained publish for a new subscription
+    retained: bool,
     collision: Option<Publish>
 }
 
@@ -42,15 +44,17 @@ impl Pending {
             topic: "".to_string(),
             qos: QoS::AtMostOnce,
             payload: vec![].into_iter(),
+            retained: false,
             collision: None
         }
     }
 
-    pub fn new(topic: String, qos: QoS, payload: IntoIter<Bytes>) -> Pending {
+    pub fn new(topic: String, qos: QoS, payload: IntoIter<Bytes>, retained: bool) -> Pending {
         Pending {
             topic,
             qos,
             payload,
+            retained,
             collision: None
         }
     }
@@ -60,8 +64,11 @@ impl Pending {
         len + self.payload.len()
     }
 
-    pub fn next(&mut self) -> Option<Bytes> {
-        self.payload.next()
+    /// Next payload and its retain flag
+    pub fn next(&mut self) -> Option<(Bytes, bool)> {
+        let payload = self.payload.next()?;
+        let retain = self.retained && self.payload.len() == 0;
+        Some((payload, retain))
     }
 }
 
@@ -83,8 +90,6 @@ pub struct State {
     outgoing_pub: Vec<Option<Publish>>,
     /// Packet ids of released QoS 2 publishes
     outgoing_rel: Vec<Option<u16>>,
-    /// Packet ids on incoming QoS 2 publishes
-    incoming_pub: Vec<Option<u16>>,
     /// Pending publishes due to collision
     pending: Pending,
     /// Collected incoming packets
@@ -105,7 +110,6 @@ impl State {
             // index 0 is wasted as 0 is not a valid packet id
             outgoing_pub: vec![None; max_inflight as usize + 1],
             outgoing_rel: vec![None; max_inflight as usize + 1],
-            incoming_pub: vec![None; std::u16::MAX as usize + 1],
             pending: Pending::empty(),
             incoming: Vec::with_capacity(10),
             write: BytesMut::with_capacity(10 * 1024),
@@ -150,8 +154,8 @@ impl State {
         pending
     }
 
-    pub fn add_pending(&mut self, topic: String, qos: QoS, data: Vec<Bytes>) {
-        self.pending = Pending::new(topic.clone(), qos, data.into_iter());
+    pub fn add_pending(&mut self, topic: String, qos: QoS, data: Vec<Bytes>, retained: bool) {
+        self.pending = Pending::new(topic.clone(), qos, data.into_iter(), retained);
     }
 
     /// Adds next packet identifier to QoS 1 and 2 publish packets.
@@ -160,8 +164,9 @@ impl State {
     /// waits for incoming acks to clear `pause_outgoing` flag and
     /// process more outgoing packets
     pub(crate) fn write_pending(&mut self) -> Result<(), Error> {
-        while let Some(payload) = self.pending.next() {
+        while let Some((payload, retain)) = self.pending.next() {
             let mut publish = Publish::from_bytes(&self.pending.topic, self.pending.qos, payload);
+            publish.retain = retain;
 
             if let QoS::AtMostOnce = publish.qos {
                 debug!("Publish. Qos 0. Payload size = {:?}", publish.payload.len());
@@ -200,12 +205,17 @@ impl State {
         match ack {
             Packet::PubAck(ack) => ack.write(&mut self.write),
             Packet::PubRec(ack) => ack.write(&mut self.write),
-            // Pending outgoing release given by router. Replay
-            // pubrec with these pkids. In normal flow, release
-            // id a response for incoming pubrec
+            // Pending outgoing release of the previous connection given
+            // by router. Replay pubrel and wait for pubcomp. In normal
+            // flow, release is a response for incoming pubrec
             Packet::PubRel(ack) => {
-                self.handle_incoming_pubrec(&PubRec::new(ack.pkid))?;
-                Ok(10)
+                match self.outgoing_rel.get_mut(ack.pkid as usize) {
+                    Some(rel) => *rel = Some(ack.pkid),
+                    None => return Err(Error::Unsolicited(ack.pkid)),
+                }
+
+                self.inflight += 1;
+                ack.write(&mut self.write)
             }
             Packet::PubComp(ack) => ack.write(&mut self.write),
             Packet::SubAck(ack) => ack.write(&mut self.write),
@@ -220,11 +230,12 @@ impl State {
         match packet {
             Packet::Connect(_) => return Err(Error::DuplicateConnect),
             Packet::ConnAck(_) => return Err(Error::ClientConnAck),
+            // Router deduplicates QoS 2 publishes and completes their releases
             Packet::Publish(publish) => {
-                let forward = self.handle_incoming_publish(&publish)?;
-                if forward {
-                    self.incoming.push(Packet::Publish(publish));
-                }
+                self.incoming.push(Packet::Publish(publish));
+            }
+            Packet::PubRel(ack) => {
+                self.incoming.push(Packet::PubRel(ack));
             }
             Packet::Subscribe(subscribe) => {
                 self.incoming.push(Packet::Subscribe(subscribe));
@@ -235,9 +246,6 @@ impl State {
             Packet::PubAck(ack) => {
                 self.handle_incoming_puback(&ack)?;
             }
-            Packet::PubRel(ack) => {
-                self.handle_incoming_pubrel(&ack)?;
-            }
             Packet::PubRec(ack) => {
                 self.handle_incoming_pubrec(&ack)?;
             }
@@ -257,23 +265,6 @@ impl State {
         Ok(false)
     }
 
-    /// Filters duplicate qos 2 publishes and returns true if publish should be forwarded
-    /// to the router.
-    pub fn handle_incoming_publish(&mut self, publish: &Publish) -> Result<bool, Error> {
-        if publish.qos == QoS::ExactlyOnce {
-            let pkid = publish.pkid;
-
-            // If publish packet is already recorded before, this is a duplicate
-            // qos 2 publish which should be filtered here.
-            if let Some(_) = mem::replace(&mut self.incoming_pub[pkid as usize], Some(pkid)) {
-                PubRec::new(pkid).write(&mut self.write)?;
-                return Ok(false);
-            }
-        }
-
-        Ok(true)
-    }
-
     pub fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<(), Error> {
         match mem::replace(&mut self.outgoing_pub[puback.pkid as usize], None) {
             Some(_) => self.inflight -= 1,
@@ -306,20 +297,6 @@ impl State {
         }
     }
 
-    pub fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<(), Error> {
-        match mem::replace(&mut self.incoming_pub[pubrel.pkid as usize], None) {
-            Some(_) => {
-                let response = PubComp::new(pubrel.pkid);
-                response.write(&mut self.write)?;
-                Ok(())
-            }
-            None => {
-                error!("Unsolicited pubrel packet: {:?}", pubrel.pkid);
-                Err(Error::Unsolicited(pubrel.pkid))
-            }
-        }
-    }
-
     pub fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<(), Error> {
         match mem::replace(&mut self.outgoing_rel[pubcomp.pkid as usize], None) {
             Some(_) => {
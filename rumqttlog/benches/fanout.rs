@@ -0,0 +1,116 @@
+//! Fanout of one publish to 10k subscribers of its topic. Subscribers are handed
+//! the buffer appended to the commitlog. So memory allocated during the fanout
+//! is bookkeeping of the subscribers and doesn't grow with the payload size
+use mqttbytes::v4::{Packet, Publish, Subscribe};
+use mqttbytes::QoS;
+use rumqttlog::logs::disk::DiskConfig;
+use rumqttlog::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SUBSCRIBERS: usize = 10_000;
+const PAYLOAD_SIZE: usize = 256 * 1024;
+
+/// Counts bytes allocated by all the threads
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    fanout("memory", Config::default());
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        dir: dir.path().to_owned(),
+        disk: Some(DiskConfig::default()),
+        ..Config::default()
+    };
+
+    fanout("disk", config);
+}
+
+fn fanout(name: &str, mut config: Config) {
+    config.max_connections = SUBSCRIBERS + 1;
+    let (router, router_tx) = Router::new(Arc::new(config)).unwrap();
+    thread::spawn(move || {
+        let mut router = router;
+        let _ = router.start();
+    });
+
+    let (publisher, _publisher_rx) = connect(&router_tx, "publisher");
+    let subscribers: Vec<Receiver<Notification>> = (0..SUBSCRIBERS)
+        .map(|i| {
+            let (id, rx) = connect(&router_tx, &format!("subscriber-{}", i));
+            let subscribe = Subscribe::new("hello/world", QoS::AtMostOnce);
+            let event = Event::Data(vec![Packet::Subscribe(subscribe)]);
+            router_tx.send((id, event)).unwrap();
+            rx
+        })
+        .collect();
+
+    // Subscriptions are applied before the publish
+    for rx in subscribers.iter() {
+        while !matches!(recv(rx), Notification::Acks(_)) {}
+    }
+
+    let publish = Publish::new("hello/world", QoS::AtMostOnce, vec![1; PAYLOAD_SIZE]);
+    let appended = publish.payload.as_ptr();
+
+    let start = Instant::now();
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let event = Event::Data(vec![Packet::Publish(publish)]);
+    router_tx.send((publisher, event)).unwrap();
+
+    for rx in subscribers.iter() {
+        let data = loop {
+            if let Notification::Data(data) = recv(rx) {
+                break data;
+            }
+        };
+
+        assert_eq!(data.payload[0].as_ptr(), appended);
+    }
+
+    let elapsed = start.elapsed();
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+    println!(
+        "{:6} fanout of {} bytes to {} subscribers. Time = {:?}, allocated = {} bytes",
+        name, PAYLOAD_SIZE, SUBSCRIBERS, elapsed, allocated
+    );
+
+    // A copy for every subscriber would allocate `PAYLOAD_SIZE * SUBSCRIBERS`
+    assert!(allocated < PAYLOAD_SIZE * SUBSCRIBERS / 100);
+}
+
+fn connect(
+    router_tx: &Sender<(ConnectionId, Event)>,
+    client_id: &str,
+) -> (ConnectionId, Receiver<Notification>) {
+    let (connection, rx) = Connection::new_remote(client_id, true, 10);
+    router_tx.send((0, Event::Connect(connection))).unwrap();
+    match recv(&rx) {
+        Notification::ConnectionAck(ConnectionAck::Success((id, ..))) => (id, rx),
+        notification => panic!("Unexpected connection ack = {:?}", notification),
+    }
+}
+
+fn recv(rx: &Receiver<Notification>) -> Notification {
+    rx.recv_timeout(Duration::from_secs(10)).unwrap()
+}
@@ -3,18 +3,23 @@ extern crate log;
 
 pub mod logs;
 pub mod router;
+pub mod storage;
 pub mod waiters;
 
 use std::path::PathBuf;
 
 pub use router::connection::Connection;
 pub use router::{
-    ConnectionAck, Data, DataRequest, Disconnection, Event, Message, MetricsReply, MetricsRequest,
-    Notification, Router,
+    replicator_id, ConnectionAck, Data, DataRequest, DisconnectReason, Disconnection, Event,
+    Message, MetricsReply, MetricsRequest, Notification, Router, ShardedRouter, SlowConsumer,
+    SubscribeProgress,
 };
 
 pub use jackiechan::{bounded, Receiver, RecvError, RecvTimeoutError, SendError, Sender};
+use logs::disk::{CompactionConfig, DiskConfig, RetentionConfig};
+use mqttbytes::matches;
 use serde::{Deserialize, Serialize};
+use storage::StorageConfig;
 
 pub type ConnectionId = usize;
 pub type RouterId = usize;
@@ -28,6 +33,88 @@ pub struct Config {
     pub max_segment_size: usize,
     pub max_segment_count: usize,
     pub max_connections: usize,
+    /// Replicator connections of other routers in the mesh. Connection ids below
+    /// this are reserved for them. 0 for single node deployments
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+    /// Groups of topics whose relative ingest order is preserved
+    /// while delivering to a subscriber
+    #[serde(default)]
+    pub ordered_groups: Vec<Vec<String>>,
+    /// Maximum subscription filters applied in one router iteration. Bigger
+    /// subscribes are applied over multiple iterations
+    #[serde(default = "default_max_subscription_batch")]
+    pub max_subscription_batch: usize,
+    /// Storage for sessions and retained publishes. Broker metadata
+    /// doesn't survive restarts when this isn't configured
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+    /// Commitlogs on disk under `dir`. Logs are in memory when this
+    /// isn't configured and queued data doesn't survive restarts
+    #[serde(default)]
+    pub disk: Option<DiskConfig>,
+    /// Retention of on disk commitlogs. First policy matching the topic wins.
+    /// In memory logs are bounded by segment size and count
+    #[serde(default)]
+    pub retention: Vec<RetentionConfig>,
+    /// Key based compaction of on disk commitlogs. First policy matching the
+    /// topic wins. Subscribers of compacted topics see the latest value of keys
+    #[serde(default)]
+    pub compaction: Vec<CompactionConfig>,
+    /// Persistent sessions of devices which stay disconnected longer than
+    /// this are dropped. Sessions never expire when this isn't configured
+    #[serde(default)]
+    pub session_expiry_secs: Option<u64>,
+    /// Persistent sessions lagging more than this many records behind a topic
+    /// they tracked at disconnection skip the backlog of that topic when they resume
+    #[serde(default)]
+    pub max_session_backlog: Option<u64>,
+    /// Broker statistics are published to `$SYS` topics at this interval.
+    /// Statistics aren't published when this isn't configured
+    #[serde(default)]
+    pub sys_interval_secs: Option<u64>,
+    /// Number of topics with the most records which get per topic statistics.
+    /// Only aggregate statistics are published by default
+    #[serde(default)]
+    pub sys_topic_stats: usize,
+    /// Handling of connections with the client id of a connected client
+    #[serde(default)]
+    pub duplicate_client_id: DuplicateClientId,
+    /// Records a connection is served in one turn of the ready queue before
+    /// the next connection is served
+    #[serde(default = "default_ready_quota")]
+    pub ready_quota: usize,
+    /// Replicators are served this many times the quota of devices in a turn
+    #[serde(default = "default_replicator_weight")]
+    pub replicator_weight: usize,
+    /// Maximum number of commitlogs. Connections publishing to new topics
+    /// beyond this are disconnected
+    #[serde(default)]
+    pub max_topics: Option<usize>,
+    /// Maximum payload size of a publish. Connections publishing bigger
+    /// payloads are disconnected
+    #[serde(default)]
+    pub max_payload_size: Option<usize>,
+    /// Maximum subscriptions of a connection. Filters beyond this are
+    /// rejected in the suback
+    #[serde(default)]
+    pub max_subscriptions: Option<usize>,
+    /// Router state is snapshotted under `dir` at this interval for
+    /// `Router::from_snapshot` to resume from after a restart
+    #[serde(default)]
+    pub snapshot_interval_secs: Option<u64>,
+    /// Routers which topics are partitioned across by `ShardedRouter`. Every
+    /// shard runs on its own thread
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+    /// Ack modes of matching topics. First policy matching the topic wins.
+    /// Publishes on other topics are acked as per the ack mode of their connection
+    #[serde(default)]
+    pub ack_modes: Vec<AckModeConfig>,
+    /// Replicas which have to pull a publish before it's acked in replicated
+    /// ack mode. Bounded by `replicas`
+    #[serde(default = "default_ack_quorum")]
+    pub ack_quorum: usize,
 }
 
 impl Default for Config {
@@ -38,6 +125,92 @@ impl Default for Config {
             max_segment_size: 5 * 1024 * 1024,
             max_segment_count: 1024,
             max_connections: 1010,
+            replicas: default_replicas(),
+            ordered_groups: Vec::new(),
+            max_subscription_batch: default_max_subscription_batch(),
+            storage: None,
+            disk: None,
+            retention: Vec::new(),
+            compaction: Vec::new(),
+            session_expiry_secs: None,
+            max_session_backlog: None,
+            sys_interval_secs: None,
+            sys_topic_stats: 0,
+            duplicate_client_id: DuplicateClientId::Takeover,
+            ready_quota: default_ready_quota(),
+            replicator_weight: default_replicator_weight(),
+            max_topics: None,
+            max_payload_size: None,
+            max_subscriptions: None,
+            snapshot_interval_secs: None,
+            shards: default_shards(),
+            ack_modes: Vec::new(),
+            ack_quorum: default_ack_quorum(),
         }
     }
 }
+
+/// Handling of a connection with the client id of a connected client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateClientId {
+    /// Disconnects the connected client. Its persistent session is resumed
+    /// by the new connection unless the new connection is clean
+    #[default]
+    Takeover,
+    /// Rejects the new connection
+    Reject,
+}
+
+/// When puback (or pubrec) of a publish is sent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckMode {
+    /// Acked once the publish is appended to the native commitlog. Subscribers
+    /// of this router read the write, but it's lost if the router dies before
+    /// replicas pull it
+    #[default]
+    Append,
+    /// Acked once `Config::ack_quorum` replicas pulled the publish
+    Replicated,
+}
+
+/// Ack mode of matching topics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckModeConfig {
+    /// Topic filters of this policy. All the topics when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub mode: AckMode,
+}
+
+impl AckModeConfig {
+    /// Checks if this policy applies to publishes on this topic
+    pub fn applies(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|filter| matches(topic, filter))
+    }
+}
+
+fn default_replicas() -> usize {
+    10
+}
+
+fn default_max_subscription_batch() -> usize {
+    1000
+}
+
+fn default_ready_quota() -> usize {
+    1000
+}
+
+fn default_replicator_weight() -> usize {
+    1
+}
+
+fn default_shards() -> usize {
+    1
+}
+
+fn default_ack_quorum() -> usize {
+    1
+}
@@ -1,19 +1,59 @@
 use mqttbytes::v4::*;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::mem;
 
+/// Committed ack. QoS 1 publishes of a client usually have contiguous
+/// packet ids. Their pubacks are compacted into a range until they are taken
+#[derive(Debug)]
+enum Ack {
+    /// Pubacks of packet ids start..=end
+    PubAcks(u16, u16),
+    Packet(Packet),
+}
+
+/// Ack of a publish which waits for replicas to pull the publish
+#[derive(Debug)]
+struct HeldAck {
+    pkid: u16,
+    qos: u8,
+    /// Log and offset of the publish. None for acks which are only held
+    /// behind earlier acks to keep acks in order
+    replicated: Option<(String, u64)>,
+}
+
 /// Watermarks for a given topic
 #[derive(Debug)]
 pub struct Acks {
     pending_acks_request: Option<()>,
-    /// Committed packet ids for acks
-    acks: Vec<Packet>,
+    /// Committed acks in order
+    acks: VecDeque<Ack>,
+    /// Count of committed acks with ranges expanded
+    count: usize,
+    /// Publish acks which wait for replication, in order
+    held: VecDeque<HeldAck>,
+    /// Packet ids of appended QoS 2 publishes which aren't released yet
+    unreleased: HashSet<u16>,
+    /// Cursors of a replicator on the topics it pulls. Map[topic](segment, offset)
+    cursors: HashMap<String, (u64, u64)>,
 }
 
 impl Acks {
     pub fn new() -> Acks {
         Acks {
             pending_acks_request: None,
-            acks: Vec::new(),
+            acks: VecDeque::new(),
+            count: 0,
+            held: VecDeque::new(),
+            unreleased: HashSet::new(),
+            cursors: HashMap::new(),
+        }
+    }
+
+    /// Watermarks of a resumed session with its unreleased QoS 2 packet ids
+    pub fn with_unreleased(unreleased: HashSet<u16>) -> Acks {
+        Acks {
+            unreleased,
+            ..Acks::new()
         }
     }
 
@@ -34,28 +74,248 @@ impl Acks {
         self.pending_acks_request.take()
     }
 
+    /// Commits puback or pubrec. Packet ids of QoS 2 publishes are held till release.
+    /// Acks are held behind the acks which wait for replication
     pub fn push_publish_ack(&mut self, pkid: u16, qos: u8) {
+        if qos == 2 {
+            self.unreleased.insert(pkid);
+        }
+
+        if !self.held.is_empty() {
+            let ack = HeldAck {
+                pkid,
+                qos,
+                replicated: None,
+            };
+            self.held.push_back(ack);
+            return;
+        }
+
+        self.commit_publish_ack(pkid, qos)
+    }
+
+    /// Holds puback or pubrec of the publish at `offset` of `log` till
+    /// replicas pull it. See `commit_replicated`
+    pub fn push_replicated_ack(&mut self, pkid: u16, qos: u8, log: String, offset: u64) {
+        if qos == 2 {
+            self.unreleased.insert(pkid);
+        }
+
+        let replicated = Some((log, offset));
+        self.held.push_back(HeldAck {
+            pkid,
+            qos,
+            replicated,
+        });
+    }
+
+    /// Commits held acks, in order, of publishes which are replicated. Publishes
+    /// before `replicated[log]` offset of a log are replicated. Returns true if
+    /// acks are committed
+    pub fn commit_replicated(&mut self, replicated: &HashMap<String, u64>) -> bool {
+        let mut committed = false;
+        while let Some(ack) = self.held.front() {
+            if let Some((log, offset)) = &ack.replicated {
+                let pulled = replicated.get(log).copied().unwrap_or(0);
+                if pulled <= *offset {
+                    break;
+                }
+            }
+
+            let ack = self.held.pop_front().unwrap();
+            self.commit_publish_ack(ack.pkid, ack.qos);
+            committed = true;
+        }
+
+        committed
+    }
+
+    /// Count of acks waiting for replication, including the acks held behind them
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    fn commit_publish_ack(&mut self, pkid: u16, qos: u8) {
         match qos {
-            1 => self.acks.push(Packet::PubAck(PubAck::new(pkid))),
-            2 => self.acks.push(Packet::PubRec(PubRec::new(pkid))),
-            _ => return,
+            1 => self.push_puback(pkid),
+            2 => self.push(Packet::PubRec(PubRec::new(pkid))),
+            _ => (),
         }
     }
 
+    /// Extends the last range of pubacks if this packet id is the next one
+    fn push_puback(&mut self, pkid: u16) {
+        if let Some(Ack::PubAcks(_, end)) = self.acks.back_mut() {
+            if *end < u16::MAX && pkid == *end + 1 {
+                *end = pkid;
+                self.count += 1;
+                return;
+            }
+        }
+
+        self.acks.push_back(Ack::PubAcks(pkid, pkid));
+        self.count += 1;
+    }
+
+    fn push(&mut self, ack: Packet) {
+        self.acks.push_back(Ack::Packet(ack));
+        self.count += 1;
+    }
+
+    /// Checks if a QoS 2 publish with this packet id is appended but not released.
+    /// Publishes with such ids are redeliveries
+    pub fn is_unreleased(&self, pkid: u16) -> bool {
+        self.unreleased.contains(&pkid)
+    }
+
+    /// Releases the packet id and commits pubcomp. Unknown packet ids are completed
+    /// as well as the pubcomp of a previous release might've been lost
+    pub fn push_release_ack(&mut self, pkid: u16) {
+        self.unreleased.remove(&pkid);
+        self.push(Packet::PubComp(PubComp::new(pkid)))
+    }
+
+    pub fn unreleased(&self) -> &HashSet<u16> {
+        &self.unreleased
+    }
+
+    pub fn take_unreleased(&mut self) -> HashSet<u16> {
+        mem::take(&mut self.unreleased)
+    }
+
+    /// Moves the cursor of the replicator on this topic after a pull
+    pub fn update_cursor(&mut self, topic: &str, cursor: (u64, u64)) {
+        match self.cursors.get_mut(topic) {
+            Some(current) => *current = cursor,
+            None => {
+                self.cursors.insert(topic.to_owned(), cursor);
+            }
+        }
+    }
+
+    /// Next offsets the replicator pulls from its topics. Map[topic](segment, offset)
+    pub fn cursors(&self) -> &HashMap<String, (u64, u64)> {
+        &self.cursors
+    }
+
+    /// Records the replicator is behind the head of every topic it pulls.
+    /// Topics which don't have a log anymore aren't reported
+    pub fn lag(&self, head: impl Fn(&str) -> Option<(u64, u64)>) -> BTreeMap<String, u64> {
+        self.cursors
+            .iter()
+            .filter_map(|(topic, cursor)| {
+                let head = head(topic)?;
+                Some((topic.clone(), head.1.saturating_sub(cursor.1)))
+            })
+            .collect()
+    }
+
     pub fn push_subscribe_ack(&mut self, pkid: u16, return_codes: Vec<SubscribeReasonCode>) {
         let suback = SubAck::new(pkid, return_codes);
         let suback = Packet::SubAck(suback);
-        self.acks.push(suback)
+        self.push(suback)
     }
 
     pub fn push_unsubscribe_ack(&mut self, pkid: u16) {
         let unsuback = UnsubAck::new(pkid);
         let unsuback = Packet::UnsubAck(unsuback);
-        self.acks.push(unsuback)
+        self.push(unsuback)
     }
 
-    /// Returns committed acks by take
+    /// Count of committed acks which are yet to be taken
+    pub fn pending(&self) -> usize {
+        self.count
+    }
+
+    /// Returns committed acks by take. All the acks are returned as one burst
+    /// with compacted pubacks expanded in order
     pub fn acks(&mut self) -> Vec<Packet> {
-        mem::take(&mut self.acks)
+        let mut acks = Vec::with_capacity(mem::take(&mut self.count));
+        for ack in self.acks.drain(..) {
+            match ack {
+                Ack::PubAcks(start, end) => {
+                    let pubacks = (start..=end).map(|pkid| Packet::PubAck(PubAck::new(pkid)));
+                    acks.extend(pubacks)
+                }
+                Ack::Packet(ack) => acks.push(ack),
+            }
+        }
+
+        // Release memory held by a burst of acks
+        self.acks.shrink_to(16);
+        acks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn contiguous_pubacks_are_compacted_and_expanded_in_order() {
+        let mut acks = Acks::new();
+        for pkid in 1..=100 {
+            acks.push_publish_ack(pkid, 1);
+        }
+
+        acks.push_subscribe_ack(101, vec![]);
+        acks.push_publish_ack(102, 1);
+        acks.push_publish_ack(103, 1);
+        acks.push_publish_ack(1, 1);
+
+        assert_eq!(acks.acks.len(), 4);
+        assert_eq!(acks.pending(), 104);
+
+        let expanded = acks.acks();
+        assert_eq!(expanded.len(), 104);
+        assert_eq!(expanded[0], Packet::PubAck(PubAck::new(1)));
+        assert_eq!(expanded[99], Packet::PubAck(PubAck::new(100)));
+        assert_eq!(expanded[100], Packet::SubAck(SubAck::new(101, vec![])));
+        assert_eq!(expanded[103], Packet::PubAck(PubAck::new(1)));
+        assert_eq!(acks.pending(), 0);
+    }
+
+    #[test]
+    fn replication_lag_is_head_minus_cursor() {
+        let mut acks = Acks::new();
+        acks.update_cursor("hello/1", (0, 10));
+        acks.update_cursor("hello/2", (0, 5));
+        acks.update_cursor("hello/1", (0, 40));
+
+        let lag = acks.lag(|topic| match topic {
+            "hello/1" => Some((0, 100)),
+            _ => None,
+        });
+
+        assert_eq!(
+            lag.into_iter().collect::<Vec<_>>(),
+            vec![("hello/1".to_owned(), 60)]
+        );
+        assert!(acks.handle_acks_request().is_none());
+    }
+
+    #[test]
+    fn acks_behind_unreplicated_publishes_are_held_in_order() {
+        let mut acks = Acks::new();
+        acks.push_publish_ack(1, 1);
+        acks.push_replicated_ack(2, 1, "hello/1".to_owned(), 10);
+        acks.push_publish_ack(3, 2);
+        acks.push_replicated_ack(4, 1, "hello/2".to_owned(), 0);
+        assert_eq!(acks.pending(), 1);
+        assert_eq!(acks.held(), 3);
+        assert!(acks.is_unreleased(3));
+
+        let mut replicated = HashMap::new();
+        replicated.insert("hello/1".to_owned(), 10);
+        assert!(!acks.commit_replicated(&replicated));
+
+        replicated.insert("hello/1".to_owned(), 11);
+        assert!(acks.commit_replicated(&replicated));
+        assert_eq!(acks.held(), 1);
+
+        let expanded = acks.acks();
+        assert_eq!(expanded.len(), 3);
+        assert_eq!(expanded[1], Packet::PubAck(PubAck::new(2)));
+        assert_eq!(expanded[2], Packet::PubRec(PubRec::new(3)));
     }
 }
@@ -1,11 +1,16 @@
 use crate::router::Tracker;
 use crate::{ConnectionId, Notification};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 struct SavedState {
-    id: ConnectionId,
+    /// Current connection id. None for sessions restored from storage
+    /// which didn't reconnect yet
+    id: Option<ConnectionId>,
     tracker: Option<Tracker>,
     pending: Option<Vec<Notification>>,
+    /// Time of disconnection of a saved session
+    disconnected: Option<Instant>,
 }
 
 pub struct ConnectionsLog {
@@ -20,7 +25,7 @@ impl ConnectionsLog {
     }
 
     pub fn id(&self, id: &str) -> Option<ConnectionId> {
-        self.connections.get(id).map(|v| v.id)
+        self.connections.get(id).and_then(|v| v.id)
     }
 
     pub fn add(
@@ -31,7 +36,8 @@ impl ConnectionsLog {
         match self.connections.get_mut(id) {
             // Return tracker of previous connection for persistent connection
             Some(savedstate) => {
-                savedstate.id = connection_id;
+                savedstate.id = Some(connection_id);
+                savedstate.disconnected = None;
                 (savedstate.tracker.take(), savedstate.pending.take())
             }
             // Add new connection if this is the first connection with this id
@@ -39,9 +45,10 @@ impl ConnectionsLog {
                 self.connections.insert(
                     id.to_owned(),
                     SavedState {
-                        id: connection_id,
+                        id: Some(connection_id),
                         tracker: None,
                         pending: None,
+                        disconnected: None,
                     },
                 );
 
@@ -50,6 +57,20 @@ impl ConnectionsLog {
         }
     }
 
+    /// Adds a persistent session restored from storage. Tracker is handed
+    /// over to the connection when a device with this id connects. Expiry
+    /// of restored sessions starts from the restart
+    pub fn restore(&mut self, id: &str, tracker: Tracker) {
+        let savedstate = SavedState {
+            id: None,
+            tracker: Some(tracker),
+            pending: None,
+            disconnected: Some(Instant::now()),
+        };
+
+        self.connections.insert(id.to_owned(), savedstate);
+    }
+
     pub fn save(&mut self, id: &str, mut tracker: Tracker, pending: Vec<Notification>) {
         tracker.set_busy_unschedule(false);
         tracker.set_empty_unschedule(false);
@@ -57,6 +78,31 @@ impl ConnectionsLog {
         if let Some(graveyard) = self.connections.get_mut(id) {
             graveyard.tracker = Some(tracker);
             graveyard.pending = Some(pending);
+            graveyard.disconnected = Some(Instant::now());
+        }
+    }
+
+    /// Saved sessions of disconnected devices
+    pub fn sessions(&self) -> impl Iterator<Item = (&String, &Tracker)> {
+        self.connections
+            .iter()
+            .filter_map(|(id, state)| state.tracker.as_ref().map(|tracker| (id, tracker)))
+    }
+
+    /// Removes saved sessions which are disconnected for longer than `expiry`
+    /// and returns their ids
+    pub fn expire(&mut self, expiry: Duration) -> Vec<String> {
+        let expired: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|(_, state)| state.disconnected.map_or(false, |t| t.elapsed() > expiry))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired.iter() {
+            self.connections.remove(id);
         }
+
+        expired
     }
 }
@@ -1,11 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::disk::{self, DiskLog};
+use crate::router::TopicMetrics;
 use crate::Config;
 use bytes::Bytes;
 use std::sync::Arc;
 use segments::MemoryLog;
 
+/// Directory of on disk logs under `Config::dir`
+const COMMITLOG_DIR: &str = "commitlog";
+
+/// Seconds covered by the time index of a log
+const MAX_INDEX_SECS: usize = 24 * 60 * 60;
+
 pub(crate) struct DataLog {
     config: Arc<Config>,
     logs: HashMap<String, Data>,
@@ -13,15 +22,215 @@ pub(crate) struct DataLog {
 
 struct Data {
     retained: Option<(u64, Bytes)>,
-    log: MemoryLog<Bytes>
+    log: Log,
+    /// Records and bytes appended since the router started
+    count: u64,
+    size: u64,
+    /// Offsets of the first record appended in every second since the router
+    /// started. List[(unix secs, offsets)]
+    index: VecDeque<(u64, (u64, u64))>,
+    /// Compacted records are left empty in the log and skipped by reads
+    compacted: bool,
+}
+
+impl Data {
+    fn append(&mut self, record: Bytes) -> io::Result<(u64, u64)> {
+        let size = record.len() as u64;
+        let offsets = self.log.append(record)?;
+        self.count += 1;
+        self.size += size;
+
+        let now = unix_secs(SystemTime::now());
+        match self.index.back() {
+            Some((secs, _)) if *secs == now => (),
+            _ => {
+                if self.index.len() >= MAX_INDEX_SECS {
+                    self.index.pop_front();
+                }
+
+                self.index.push_back((now, offsets));
+            }
+        }
+
+        Ok(offsets)
+    }
+
+    /// Offsets of the first record appended at or after the timestamp. Seeks
+    /// to the end if there are no such records
+    fn seek(&self, timestamp: u64) -> (u64, u64) {
+        let i = self.index.partition_point(|(secs, _)| *secs < timestamp);
+        match self.index.get(i) {
+            Some((_, offsets)) => *offsets,
+            None => self.log.next_offset(),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Commitlog of a topic. On disk when the topic matches `Config::disk`
+enum Log {
+    Memory(MemoryLog<Bytes>),
+    Disk(Box<DiskLog>),
+}
+
+impl Log {
+    /// Appends the record and returns its offsets. `MemoryLog` returns the
+    /// offset after the record instead
+    fn append(&mut self, record: Bytes) -> io::Result<(u64, u64)> {
+        match self {
+            Log::Memory(log) => {
+                let (segment, next) = log.append(record.len(), record);
+                Ok((segment, next - 1))
+            }
+            Log::Disk(log) => log.append(record),
+        }
+    }
+
+    fn next_offset(&self) -> (u64, u64) {
+        match self {
+            Log::Memory(log) => log.next_offset(),
+            Log::Disk(log) => log.next_offset(),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn readv(
+        &mut self,
+        segment: u64,
+        offset: u64,
+    ) -> io::Result<(Option<u64>, u64, u64, Vec<Bytes>)> {
+        match self {
+            Log::Memory(log) => Ok(log.readv(segment, offset)),
+            Log::Disk(log) => log.readv(segment, offset),
+        }
+    }
 }
 
 impl DataLog {
-    pub fn new(config: Arc<Config>) -> DataLog {
-        DataLog {
+    /// Creates the logs and opens on disk logs of previous runs
+    pub fn new(config: Arc<Config>) -> io::Result<DataLog> {
+        let mut datalog = DataLog {
             config,
             logs: HashMap::new(),
+        };
+
+        if datalog.config.disk.is_some() {
+            let dir = datalog.config.dir.join(COMMITLOG_DIR);
+            for topic in disk::topics(&dir)? {
+                match datalog.data(&topic) {
+                    Ok(data) => {
+                        datalog.logs.insert(topic, data);
+                    }
+                    Err(e) => error!("Failed to open commitlog of {}. Error = {:?}", topic, e),
+                }
+            }
         }
+
+        Ok(datalog)
+    }
+
+    /// Topics of logs opened from disk
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.logs.keys()
+    }
+
+    /// Drops old segments of on disk logs as per their retention policies and
+    /// compacts them as per their compaction policies. Returns the number of
+    /// dropped segments
+    pub fn clean(&mut self) -> usize {
+        let now = SystemTime::now();
+        let mut dropped = 0;
+        for (topic, data) in self.logs.iter_mut() {
+            let log = match &mut data.log {
+                Log::Disk(log) => log,
+                Log::Memory(_) => continue,
+            };
+
+            let compaction = self.config.compaction.iter().find(|c| c.applies(topic));
+            if let Some(compaction) = compaction {
+                match log.compact(compaction.delimiter.as_bytes()) {
+                    Ok(0) => (),
+                    Ok(count) => debug!("Compacted {} records of {}", count, topic),
+                    Err(e) => error!("Failed to compact commitlog of {}. Error = {:?}", topic, e),
+                }
+            }
+
+            let retention = self.config.retention.iter().find(|r| r.applies(topic));
+            let retention = match retention {
+                Some(retention) => retention,
+                None => continue,
+            };
+
+            match log.clean(retention, now) {
+                Ok(0) => (),
+                Ok(count) => {
+                    let start = log.start_offset();
+                    debug!("Dropped {} segments of {}. Start = {}", count, topic, start);
+                    dropped += count;
+                }
+                Err(e) => error!("Failed to clean commitlog of {}. Error = {:?}", topic, e),
+            }
+        }
+
+        dropped
+    }
+
+    /// Removes the log of the topic along with its retained publish. On disk
+    /// logs are deleted. Returns false if there is no such topic
+    pub fn delete(&mut self, topic: &str) -> io::Result<bool> {
+        let data = match self.logs.remove(topic) {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+
+        if let Log::Disk(log) = data.log {
+            log.delete()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Drops segments of the on disk log of the topic whose records are all
+    /// before the offset. Returns the number of dropped segments. In memory
+    /// logs are already bounded by segment count and aren't purged
+    pub fn purge(&mut self, topic: &str, offset: u64) -> io::Result<usize> {
+        match self.logs.get_mut(topic).map(|data| &mut data.log) {
+            Some(Log::Disk(log)) => log.purge(offset),
+            _ => Ok(0),
+        }
+    }
+
+    /// Empty log of a new topic
+    fn data(&self, topic: &str) -> io::Result<Data> {
+        let max_segment_size = self.config.max_segment_size;
+        let max_segment_count = self.config.max_segment_count;
+        let (log, compacted) = match &self.config.disk {
+            Some(config) if config.applies(topic) => {
+                let dir = self.config.dir.join(COMMITLOG_DIR);
+                let dir = dir.join(disk::dir_name(topic));
+                let log = DiskLog::open(dir, max_segment_size, max_segment_count, config.fsync)?;
+                let compacted = self.config.compaction.iter().any(|c| c.applies(topic));
+                (Log::Disk(Box::new(log)), compacted)
+            }
+            _ => {
+                let log = MemoryLog::new(max_segment_size, max_segment_count);
+                (Log::Memory(log), false)
+            }
+        };
+
+        Ok(Data {
+            retained: None,
+            log,
+            count: 0,
+            size: 0,
+            index: VecDeque::new(),
+            compacted,
+        })
     }
 
     /// Appends the record to correct commitlog and returns a boolean to indicate
@@ -29,16 +238,11 @@ impl DataLog {
     pub fn append(&mut self, topic: &str, record: Bytes) -> io::Result<(bool, (u64, u64))> {
         // Entry instead of if/else?
         if let Some(data) = self.logs.get_mut(topic) {
-            let offsets = data.log.append(record.len(), record);
+            let offsets = data.append(record)?;
             Ok((false, offsets))
         } else {
-            let max_segment_size = self.config.max_segment_size;
-            let max_segment_count = self.config.max_segment_count;
-            let mut data = Data {
-                retained: None,
-                log: MemoryLog::new(max_segment_size, max_segment_count)
-            };
-            let offsets = data.log.append(record.len(), record);
+            let mut data = self.data(topic)?;
+            let offsets = data.append(record)?;
             self.logs.insert(topic.to_owned(), data);
             Ok((true, offsets))
         }
@@ -58,13 +262,7 @@ impl DataLog {
 
             Ok(false)
         } else {
-            let max_segment_size = self.config.max_segment_size;
-            let max_segment_count = self.config.max_segment_count;
-            let mut data = Data {
-                retained: None,
-                log: MemoryLog::new(max_segment_size, max_segment_count)
-            };
-
+            let mut data = self.data(topic)?;
             if record.is_empty() {
                 data.retained = None;
             } else {
@@ -78,7 +276,16 @@ impl DataLog {
         }
     }
 
-    fn next_offset(&self, topic: &str) -> Option<(u64, u64)> {
+    /// Number of logs
+    pub fn count(&self) -> usize {
+        self.logs.len()
+    }
+
+    pub fn contains(&self, topic: &str) -> bool {
+        self.logs.contains_key(topic)
+    }
+
+    pub fn next_offset(&self, topic: &str) -> Option<(u64, u64)> {
         let data = match self.logs.get(topic) {
             Some(log) => log,
             None => return None,
@@ -87,18 +294,40 @@ impl DataLog {
         Some(data.log.next_offset())
     }
 
+    /// Offsets of the first record of the topic appended at or after the
+    /// timestamp (unix secs). Records appended before a restart aren't indexed
+    pub fn seek(&self, topic: &str, timestamp: u64) -> Option<(u64, u64)> {
+        self.logs.get(topic).map(|data| data.seek(timestamp))
+    }
+
+    /// Live state of the log of the topic
+    pub fn metrics(&self, topic: &str) -> Option<TopicMetrics> {
+        let data = self.logs.get(topic)?;
+        Some(TopicMetrics {
+            topic: topic.to_owned(),
+            next_offset: data.log.next_offset(),
+            appended_count: data.count,
+            appended_size: data.size,
+            retained: data.retained.is_some(),
+        })
+    }
+
     pub fn seek_offsets_to_end(&self, topic: &mut (String, u8, (u64, u64))) {
         if let Some(last_offset) = self.next_offset(&topic.0) {
             topic.2 = last_offset;
         }
     }
 
+    /// Reads records from the cursor. Reads are bounded by count and size of
+    /// records. Retained publish follows once the log is read till the end
     pub fn readv(
         &mut self,
         topic: &str,
         in_segment: u64,
         in_offset: u64,
         last_retain: u64,
+        max_count: usize,
+        max_bytes: usize,
     ) -> io::Result<Option<(Option<u64>, u64, u64, u64, Vec<Bytes>)>> {
         // Router during data request and notifications will check both
         // native and replica commitlog where this topic doesn't exist
@@ -107,17 +336,55 @@ impl DataLog {
             None => return Ok(None),
         };
 
-        let (jump, segment, offset, mut out) = data.log.readv(in_segment, in_offset);
+        let (mut segment, mut offset) = (in_segment, in_offset);
+        let (jump, bounded, mut out) = loop {
+            let (mut jump, next_segment, next_offset, mut out) = data.log.readv(segment, offset)?;
+            segment = next_segment;
+            offset = next_offset;
+
+            // Cursor of a bounded read continues from the first record left out
+            let mut size = 0;
+            let count = out
+                .iter()
+                .take(max_count)
+                .take_while(|record| {
+                    size += record.len();
+                    size <= max_bytes
+                })
+                .count()
+                .max(1);
+
+            let bounded = count < out.len();
+            if bounded {
+                offset -= (out.len() - count) as u64;
+                out.truncate(count);
+                jump = None;
+            }
+
+            // Reads continue past ranges which are completely compacted
+            let read = out.len();
+            if data.compacted {
+                out.retain(|record| !record.is_empty());
+            }
+
+            if read == 0 || !out.is_empty() {
+                break (jump, bounded, out);
+            }
+
+            if let Some(next) = jump {
+                segment = next;
+                offset = next;
+            }
+        };
 
         let mut last_retain = last_retain;
         if let Some((id, publish)) = &mut data.retained {
-            if *id != last_retain {
+            if *id != last_retain && !bounded {
                 out.push(publish.clone());
                 last_retain = *id;
             }
         }
 
-
         // For debugging. Will be removed later
         // println!(
         //     "In: segment {} offset {}, Out: segment {} offset {}, Count {}",
@@ -0,0 +1,910 @@
+//! Commitlog with segments on disk. Records survive broker restarts while
+//! memory holds only the metadata of segments. A segment is a data file
+//! preallocated to `max_segment_size` and an index file of record end positions
+use bytes::Bytes;
+use mqttbytes::matches;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// Maximum records returned by one read
+const MAX_READ_RECORDS: u64 = 100;
+
+/// Size of an index entry
+const ENTRY_LEN: u64 = 8;
+
+/// When appends are flushed to the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Left to the os. Recent appends are lost on power failures
+    #[default]
+    Never,
+    /// After every append
+    Always,
+    /// After every n appends
+    Every(usize),
+}
+
+/// Topics whose commitlogs are on disk. Segments are under `Config::dir`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskConfig {
+    /// Topic filters of on disk logs. All the topics when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+impl DiskConfig {
+    /// Checks if the log of this topic is on disk
+    pub fn applies(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|filter| matches(topic, filter))
+    }
+}
+
+/// Retention of logs of matching topics. Limits are enforced by dropping
+/// whole segments, oldest first. Active segment is never dropped
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Topic filters of this policy. All the topics when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Maximum bytes of a log
+    pub max_bytes: Option<u64>,
+    /// Maximum age of records in seconds
+    pub max_age_secs: Option<u64>,
+    /// Maximum records of a log
+    pub max_messages: Option<u64>,
+}
+
+impl RetentionConfig {
+    /// Checks if this policy applies to the log of this topic
+    pub fn applies(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|filter| matches(topic, filter))
+    }
+}
+
+/// Key based compaction of logs of matching topics. Records are
+/// `<key><delimiter><value>` and closed segments keep only the latest record
+/// of every key. Records without the delimiter are never compacted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionConfig {
+    /// Topic filters of this policy. All the topics when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: String,
+}
+
+impl CompactionConfig {
+    /// Checks if this policy applies to the log of this topic
+    pub fn applies(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|filter| matches(topic, filter))
+    }
+}
+
+fn default_delimiter() -> String {
+    ":".to_owned()
+}
+
+struct Segment {
+    /// Offset of the first record
+    base: u64,
+    /// Number of records
+    len: u64,
+    /// Bytes of data written
+    size: u64,
+    /// Time of the last append
+    modified: SystemTime,
+    index: File,
+    data: File,
+    /// Records appended to the active segment in this run, last `tail.len()`
+    /// records of the segment. Reads of them share the appended buffers instead
+    /// of copying records from the disk for every subscriber
+    tail: Vec<Bytes>,
+}
+
+impl Segment {
+    fn paths(dir: &Path, base: u64) -> (PathBuf, PathBuf) {
+        let index = dir.join(format!("{:020}.index", base));
+        let data = dir.join(format!("{:020}.segment", base));
+        (index, data)
+    }
+
+    /// Files of a rewrite. Index being written, committed index and data
+    fn rewrite_paths(dir: &Path, base: u64) -> (PathBuf, PathBuf, PathBuf) {
+        let partial = dir.join(format!("{:020}.index.partial", base));
+        let index = dir.join(format!("{:020}.index.compact", base));
+        let data = dir.join(format!("{:020}.segment.compact", base));
+        (partial, index, data)
+    }
+
+    fn create(dir: &Path, base: u64, preallocate: u64) -> io::Result<Segment> {
+        let (index, data) = Segment::paths(dir, base);
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(index)?;
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(data)?;
+        data.set_len(preallocate)?;
+
+        Ok(Segment {
+            base,
+            len: 0,
+            size: 0,
+            modified: SystemTime::now(),
+            index,
+            data,
+            tail: Vec::new(),
+        })
+    }
+
+    /// Opens a segment of a previous run. Index entry of an interrupted
+    /// append is dropped
+    fn open(dir: &Path, base: u64) -> io::Result<Segment> {
+        Segment::recover(dir, base)?;
+        let (index, data) = Segment::paths(dir, base);
+        let index = OpenOptions::new().read(true).write(true).open(index)?;
+        let data = OpenOptions::new().read(true).write(true).open(data)?;
+
+        let len = index.metadata()?.len() / ENTRY_LEN;
+        index.set_len(len * ENTRY_LEN)?;
+
+        let mut segment = Segment {
+            base,
+            len,
+            size: 0,
+            modified: data.metadata()?.modified()?,
+            index,
+            data,
+            tail: Vec::new(),
+        };
+
+        if len > 0 {
+            segment.size = segment.entries(len - 1, 1)?[0];
+        }
+
+        Ok(segment)
+    }
+
+    /// End positions of `count` records starting at `from`
+    fn entries(&mut self, from: u64, count: u64) -> io::Result<Vec<u64>> {
+        let mut buf = vec![0; (count * ENTRY_LEN) as usize];
+        self.index.seek(SeekFrom::Start(from * ENTRY_LEN))?;
+        self.index.read_exact(&mut buf)?;
+
+        let entries = buf
+            .chunks_exact(ENTRY_LEN as usize)
+            .map(|entry| {
+                let mut end = [0; ENTRY_LEN as usize];
+                end.copy_from_slice(entry);
+                u64::from_be_bytes(end)
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    fn append(&mut self, record: Bytes) -> io::Result<()> {
+        self.data.seek(SeekFrom::Start(self.size))?;
+        self.data.write_all(&record)?;
+
+        // Index is written after the data. Entries always point to full records
+        let end = self.size + record.len() as u64;
+        self.index.seek(SeekFrom::Start(self.len * ENTRY_LEN))?;
+        self.index.write_all(&end.to_be_bytes())?;
+
+        self.size = end;
+        self.len += 1;
+        self.modified = SystemTime::now();
+        self.tail.push(record);
+        Ok(())
+    }
+
+    /// Reads `count` records starting at relative offset `from`
+    fn read(&mut self, from: u64, count: u64) -> io::Result<Vec<Bytes>> {
+        let cached = self.len - self.tail.len() as u64;
+        if from >= cached {
+            let start = (from - cached) as usize;
+            return Ok(self.tail[start..start + count as usize].to_vec());
+        }
+
+        let (start, ends) = match from {
+            0 => (0, self.entries(0, count)?),
+            from => {
+                let mut ends = self.entries(from - 1, count + 1)?;
+                (ends.remove(0), ends)
+            }
+        };
+
+        let end = *ends.last().unwrap_or(&start);
+        let mut buf = vec![0; (end - start) as usize];
+        self.data.seek(SeekFrom::Start(start))?;
+        self.data.read_exact(&mut buf)?;
+
+        let mut buf = Bytes::from(buf);
+        let mut position = start;
+        let mut records = Vec::with_capacity(ends.len());
+        for end in ends {
+            records.push(buf.split_to((end - position) as usize));
+            position = end;
+        }
+
+        Ok(records)
+    }
+
+    /// Empties the records at relative offsets of a closed segment. Runs off
+    /// the router thread. Rewrite is committed by renaming its index once both
+    /// the files are synced. Returns the size of the rewritten segment
+    fn rewrite(dir: &Path, base: u64, stale: &[u64]) -> io::Result<u64> {
+        let mut segment = Segment::open(dir, base)?;
+        let mut records = segment.read(0, segment.len)?;
+        for offset in stale {
+            records[*offset as usize] = Bytes::new();
+        }
+
+        let (partial, index, data) = Segment::rewrite_paths(dir, base);
+        let mut entries = Vec::with_capacity(records.len() * ENTRY_LEN as usize);
+        let mut file = File::create(&data)?;
+        let mut end = 0;
+        for record in records.iter() {
+            file.write_all(record)?;
+            end += record.len() as u64;
+            entries.extend_from_slice(&end.to_be_bytes());
+        }
+        file.sync_data()?;
+
+        let mut file = File::create(&partial)?;
+        file.write_all(&entries)?;
+        file.sync_data()?;
+        fs::rename(&partial, &index)?;
+        Segment::recover(dir, base)?;
+        Ok(end)
+    }
+
+    /// Switches to the files of a rewrite. Reads until then are served by
+    /// the files of before the rewrite
+    fn reopen(&mut self, dir: &Path, size: u64) -> io::Result<()> {
+        let (index, data) = Segment::paths(dir, self.base);
+        self.index = OpenOptions::new().read(true).write(true).open(index)?;
+        self.data = OpenOptions::new().read(true).write(true).open(data)?;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Completes a committed rewrite and drops the files of an interrupted one
+    fn recover(dir: &Path, base: u64) -> io::Result<()> {
+        let (partial, compacted_index, compacted_data) = Segment::rewrite_paths(dir, base);
+        let (index, data) = Segment::paths(dir, base);
+        if compacted_index.exists() {
+            if compacted_data.exists() {
+                fs::rename(compacted_data, data)?;
+            }
+
+            return fs::rename(compacted_index, index);
+        }
+
+        for path in [partial, compacted_data].iter() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.data.sync_data()?;
+        self.index.sync_data()
+    }
+
+    fn remove(self, dir: &Path) -> io::Result<()> {
+        let (index, data) = Segment::paths(dir, self.base);
+        fs::remove_file(index)?;
+        fs::remove_file(data)
+    }
+}
+
+/// Incremental state of key based compaction. Every record is indexed once
+/// and a closed segment is rewritten only when its records become stale
+#[derive(Default)]
+struct Compaction {
+    /// Latest offset of every key
+    keys: HashMap<Vec<u8>, u64>,
+    /// Next offset to index
+    indexed: u64,
+    /// Offsets of records superseded by a later record of their key
+    stale: BTreeSet<u64>,
+    rewrite: Option<Rewrite>,
+}
+
+/// Rewrite of closed segments running in the background
+struct Rewrite {
+    /// Bases of the segments being rewritten
+    bases: Vec<u64>,
+    /// Number of records being compacted
+    count: u64,
+    /// Sizes of the rewritten segments. List[(base, size)]
+    handle: JoinHandle<io::Result<Vec<(u64, u64)>>>,
+}
+
+/// On disk equivalent of the in memory commitlog. Offsets are absolute and
+/// segments are identified by the offset of their first record
+pub(crate) struct DiskLog {
+    dir: PathBuf,
+    max_segment_size: usize,
+    max_segment_count: usize,
+    fsync: FsyncPolicy,
+    /// Appends since the last fsync
+    unsynced: usize,
+    /// Oldest segment first
+    segments: VecDeque<Segment>,
+    compaction: Compaction,
+}
+
+impl DiskLog {
+    /// Opens the log in `dir` with the segments of previous runs
+    pub fn open(
+        dir: PathBuf,
+        max_segment_size: usize,
+        max_segment_count: usize,
+        fsync: FsyncPolicy,
+    ) -> io::Result<DiskLog> {
+        fs::create_dir_all(&dir)?;
+
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |e| e == "index") {
+                let stem = path.file_stem().unwrap().to_string_lossy();
+                match stem.parse::<u64>() {
+                    Ok(base) => bases.push(base),
+                    Err(_) => warn!("Unknown file in commitlog. Path = {:?}", path),
+                }
+            }
+        }
+
+        bases.sort_unstable();
+        let mut segments = VecDeque::with_capacity(bases.len());
+        for base in bases {
+            segments.push_back(Segment::open(&dir, base)?);
+        }
+
+        if segments.is_empty() {
+            segments.push_back(Segment::create(&dir, 0, max_segment_size as u64)?);
+        }
+
+        Ok(DiskLog {
+            dir,
+            max_segment_size,
+            max_segment_count,
+            fsync,
+            unsynced: 0,
+            segments,
+            compaction: Compaction::default(),
+        })
+    }
+
+    /// Appends the record and returns its segment and offset. Segment is
+    /// rolled when the record doesn't fit and oldest segments are deleted
+    /// beyond `max_segment_count`
+    pub fn append(&mut self, record: Bytes) -> io::Result<(u64, u64)> {
+        let active = self.segments.back_mut().unwrap();
+        let full = active.size + record.len() as u64 > self.max_segment_size as u64;
+        if full && active.len > 0 {
+            // Closed segments are read from the disk
+            active.tail = Vec::new();
+            active.sync()?;
+            let base = active.base + active.len;
+            let segment = Segment::create(&self.dir, base, self.max_segment_size as u64)?;
+            self.segments.push_back(segment);
+
+            if self.segments.len() > self.max_segment_count {
+                self.remove_oldest()?;
+            }
+        }
+
+        let active = self.segments.back_mut().unwrap();
+        active.append(record)?;
+        self.unsynced += 1;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Never => false,
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+        };
+
+        if sync {
+            active.sync()?;
+            self.unsynced = 0;
+        }
+
+        Ok((active.base, active.base + active.len - 1))
+    }
+
+    /// Drops oldest segments beyond the limits of the policy. Returns the
+    /// number of dropped segments. Reads of dropped segments continue from the
+    /// oldest remaining record
+    pub fn clean(&mut self, retention: &RetentionConfig, now: SystemTime) -> io::Result<usize> {
+        let mut bytes: u64 = self.segments.iter().map(|s| s.size).sum();
+        let mut messages: u64 = self.segments.iter().map(|s| s.len).sum();
+        let max_age = retention.max_age_secs.map(Duration::from_secs);
+
+        let mut dropped = 0;
+        while self.segments.len() > 1 {
+            let oldest = self.segments.front().unwrap();
+            let age = now.duration_since(oldest.modified).unwrap_or_default();
+            let expired = max_age.map_or(false, |max| age > max);
+            let oversized = retention.max_bytes.map_or(false, |max| bytes > max);
+            let overfull = retention.max_messages.map_or(false, |max| messages > max);
+            if !expired && !oversized && !overfull {
+                break;
+            }
+
+            bytes -= oldest.size;
+            messages -= oldest.len;
+            self.remove_oldest()?;
+            dropped += 1;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Keeps only the latest record of every key in the closed segments.
+    /// Compacted records are left empty so that offsets don't change. Records
+    /// appended since the last call are indexed and closed segments with stale
+    /// records are rewritten in the background. Returns the number of records
+    /// compacted by rewrites which finished since the last call
+    pub fn compact(&mut self, delimiter: &[u8]) -> io::Result<u64> {
+        let mut compacted = 0;
+        let finished = self
+            .compaction
+            .rewrite
+            .as_ref()
+            .map_or(false, |rewrite| rewrite.handle.is_finished());
+
+        if finished {
+            compacted = self.finish_rewrite()?;
+        }
+
+        self.index(delimiter)?;
+        if self.compaction.rewrite.is_some() {
+            return Ok(compacted);
+        }
+
+        // Records of the active segment wait for the segment to close
+        let mut jobs = Vec::new();
+        let closed = self.segments.len() - 1;
+        for segment in self.segments.iter().take(closed) {
+            let end = segment.base + segment.len;
+            let stale: Vec<u64> = self
+                .compaction
+                .stale
+                .range(segment.base..end)
+                .map(|offset| offset - segment.base)
+                .collect();
+
+            if !stale.is_empty() {
+                jobs.push((segment.base, stale));
+            }
+        }
+
+        let active = self.segments.back().unwrap().base;
+        self.compaction.stale = self.compaction.stale.split_off(&active);
+        if jobs.is_empty() {
+            return Ok(compacted);
+        }
+
+        let dir = self.dir.clone();
+        let bases = jobs.iter().map(|(base, _)| *base).collect();
+        let count = jobs.iter().map(|(_, stale)| stale.len() as u64).sum();
+        let handle = thread::spawn(move || {
+            jobs.into_iter()
+                .map(|(base, stale)| Segment::rewrite(&dir, base, &stale).map(|size| (base, size)))
+                .collect()
+        });
+
+        self.compaction.rewrite = Some(Rewrite {
+            bases,
+            count,
+            handle,
+        });
+
+        Ok(compacted)
+    }
+
+    /// Updates the latest offset of keys with the records appended since the
+    /// last call. Superseded records are marked stale
+    fn index(&mut self, delimiter: &[u8]) -> io::Result<()> {
+        let compaction = &mut self.compaction;
+        let start = self.segments.front().unwrap().base;
+        compaction.indexed = compaction.indexed.max(start);
+        for segment in self.segments.iter_mut() {
+            let end = segment.base + segment.len;
+            if end <= compaction.indexed {
+                continue;
+            }
+
+            let from = compaction.indexed - segment.base;
+            let records = segment.read(from, segment.len - from)?;
+            for (i, record) in records.iter().enumerate() {
+                let offset = compaction.indexed + i as u64;
+                if let Some(key) = key(record, delimiter) {
+                    if let Some(previous) = compaction.keys.insert(key.to_vec(), offset) {
+                        compaction.stale.insert(previous);
+                    }
+                }
+            }
+
+            compaction.indexed = end;
+        }
+
+        // Records of dropped segments are gone already
+        compaction.stale = compaction.stale.split_off(&start);
+        Ok(())
+    }
+
+    /// Waits for the background rewrite and switches its segments to the
+    /// rewritten files. Returns the number of compacted records
+    fn finish_rewrite(&mut self) -> io::Result<u64> {
+        let rewrite = match self.compaction.rewrite.take() {
+            Some(rewrite) => rewrite,
+            None => return Ok(0),
+        };
+
+        let sizes = match rewrite.handle.join() {
+            Ok(sizes) => sizes?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Rewrite panicked")),
+        };
+
+        for (base, size) in sizes {
+            if let Some(segment) = self.segments.iter_mut().find(|s| s.base == base) {
+                segment.reopen(&self.dir, size)?;
+            }
+        }
+
+        Ok(rewrite.count)
+    }
+
+    /// Deletes the oldest segment. A rewrite of the segment is waited for so
+    /// that its files don't reappear after the delete
+    fn remove_oldest(&mut self) -> io::Result<()> {
+        let base = self.segments.front().unwrap().base;
+        let rewriting = self
+            .compaction
+            .rewrite
+            .as_ref()
+            .map_or(false, |rewrite| rewrite.bases.contains(&base));
+
+        if rewriting {
+            self.finish_rewrite()?;
+        }
+
+        let oldest = self.segments.pop_front().unwrap();
+        oldest.remove(&self.dir)
+    }
+
+    /// Drops closed segments whose records are all before the offset. Returns
+    /// the number of dropped segments
+    pub fn purge(&mut self, offset: u64) -> io::Result<usize> {
+        let mut dropped = 0;
+        while self.segments.len() > 1 {
+            let oldest = self.segments.front().unwrap();
+            if oldest.base + oldest.len > offset {
+                break;
+            }
+
+            self.remove_oldest()?;
+            dropped += 1;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Deletes all the segments of the log along with its directory
+    pub fn delete(mut self) -> io::Result<()> {
+        // Files of a failed rewrite are deleted along with the log
+        let _ = self.finish_rewrite();
+        fs::remove_dir_all(&self.dir)
+    }
+
+    /// Offset of the oldest record
+    pub fn start_offset(&self) -> u64 {
+        self.segments.front().unwrap().base
+    }
+
+    /// Segment and offset of the next append
+    pub fn next_offset(&self) -> (u64, u64) {
+        let active = self.segments.back().unwrap();
+        (active.base, active.base + active.len)
+    }
+
+    /// Reads records from the offset in the segment. Returns the next segment
+    /// to jump to when the segment is read till its end, along with the
+    /// segment and offset to continue from. Cursors of deleted segments
+    /// continue from the oldest record
+    #[allow(clippy::type_complexity)]
+    pub fn readv(
+        &mut self,
+        segment: u64,
+        offset: u64,
+    ) -> io::Result<(Option<u64>, u64, u64, Vec<Bytes>)> {
+        let mut i = self
+            .segments
+            .iter()
+            .position(|s| s.base == segment)
+            .unwrap_or(0);
+
+        let mut offset = offset;
+        loop {
+            let last = i + 1 == self.segments.len();
+            let current = &mut self.segments[i];
+            let from = offset.max(current.base) - current.base;
+
+            // Skip segments which are completely read
+            if from >= current.len && !last {
+                i += 1;
+                offset = 0;
+                continue;
+            }
+
+            let count = current.len.saturating_sub(from).min(MAX_READ_RECORDS);
+            let records = current.read(from, count)?;
+            let base = current.base;
+            let next = base + from + count;
+
+            let end = next == base + current.len;
+            let jump = match end && !last {
+                true => Some(self.segments[i + 1].base),
+                false => None,
+            };
+
+            return Ok((jump, base, next, records));
+        }
+    }
+}
+
+/// Key of a record. None if the record doesn't have the delimiter
+fn key<'a>(record: &'a [u8], delimiter: &[u8]) -> Option<&'a [u8]> {
+    if delimiter.is_empty() {
+        return None;
+    }
+
+    record
+        .windows(delimiter.len())
+        .position(|window| window == delimiter)
+        .map(|position| &record[..position])
+}
+
+/// Directory name of the log of a topic. Topics are hex encoded as they can
+/// have characters which aren't valid in paths
+pub(crate) fn dir_name(topic: &str) -> String {
+    topic.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Topics of the logs in `dir`
+pub(crate) fn topics(dir: &Path) -> io::Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut topics = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let bytes = (0..name.len())
+            .step_by(2)
+            .map(|i| {
+                name.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>();
+
+        match bytes.and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(topic) => topics.push(topic),
+            None => warn!("Unknown directory in commitlog. Name = {}", name),
+        }
+    }
+
+    Ok(topics)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_survive_restarts_and_old_segments_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("hello/world"));
+        let mut log = DiskLog::open(path.clone(), 20, 2, FsyncPolicy::Always).unwrap();
+
+        for i in 0..5u8 {
+            let offsets = log.append(Bytes::from(vec![i; 8])).unwrap();
+            assert_eq!(offsets.1, i as u64);
+        }
+
+        // 2 records per segment. Oldest segment is deleted
+        assert_eq!(log.next_offset(), (4, 5));
+        let (jump, segment, offset, records) = log.readv(0, 0).unwrap();
+        assert_eq!((jump, segment, offset), (Some(4), 2, 4));
+        assert_eq!(
+            records,
+            vec![Bytes::from(vec![2; 8]), Bytes::from(vec![3; 8])]
+        );
+
+        drop(log);
+        let mut log = DiskLog::open(path, 20, 2, FsyncPolicy::Always).unwrap();
+        let (jump, segment, offset, records) = log.readv(4, 4).unwrap();
+        assert_eq!((jump, segment, offset), (None, 4, 5));
+        assert_eq!(records, vec![Bytes::from(vec![4; 8])]);
+        assert_eq!(log.append(Bytes::from(vec![5; 8])).unwrap(), (4, 5));
+
+        // records of segment 2 are beyond the limit of 3
+        let retention = RetentionConfig {
+            max_messages: Some(3),
+            ..RetentionConfig::default()
+        };
+        assert_eq!(log.clean(&retention, SystemTime::now()).unwrap(), 1);
+        assert_eq!(log.start_offset(), 4);
+
+        // active segment stays
+        let retention = RetentionConfig {
+            max_age_secs: Some(0),
+            ..RetentionConfig::default()
+        };
+        let later = SystemTime::now() + Duration::from_secs(10);
+        assert_eq!(log.clean(&retention, later).unwrap(), 0);
+
+        let topics = topics(dir.path()).unwrap();
+        assert_eq!(topics, vec!["hello/world".to_owned()]);
+    }
+
+    #[test]
+    fn compaction_keeps_latest_record_of_every_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("devices/state"));
+        let mut log = DiskLog::open(path.clone(), 6, 10, FsyncPolicy::Always).unwrap();
+
+        // 2 records per segment
+        for record in ["a:1", "b:1", "a:2", "c", "b:2"].iter() {
+            log.append(Bytes::from(*record)).unwrap();
+        }
+
+        assert_eq!(compact(&mut log, b":"), 2);
+        assert_eq!(compact(&mut log, b":"), 0);
+
+        // Offsets don't change. Compacted records are empty
+        drop(log);
+        let mut log = DiskLog::open(path, 6, 10, FsyncPolicy::Always).unwrap();
+        let (jump, _, _, records) = log.readv(0, 0).unwrap();
+        assert_eq!(jump, Some(2));
+        assert_eq!(records, vec![Bytes::new(), Bytes::new()]);
+
+        let (_, _, _, records) = log.readv(2, 2).unwrap();
+        assert_eq!(records, vec![Bytes::from("a:2"), Bytes::from("c")]);
+        assert_eq!(log.append(Bytes::from("a:3")).unwrap(), (4, 5));
+    }
+
+    #[test]
+    fn compaction_indexes_new_records_and_rewrites_closed_segments_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("devices/state"));
+        let mut log = DiskLog::open(path, 6, 10, FsyncPolicy::Always).unwrap();
+        for record in ["a:1", "b:1", "a:2"].iter() {
+            log.append(Bytes::from(*record)).unwrap();
+        }
+
+        // Stale record of segment 0 is rewritten off the router thread. Reads
+        // see the old files until the rewrite finishes
+        assert_eq!(log.compact(b":").unwrap(), 0);
+        assert_eq!(log.compaction.indexed, 3);
+        assert!(log.compaction.rewrite.is_some());
+        assert_eq!(compact(&mut log, b":"), 1);
+
+        // Nothing new to index or rewrite
+        assert_eq!(log.compact(b":").unwrap(), 0);
+        assert!(log.compaction.rewrite.is_none());
+
+        // Stale records of the active segment wait for it to close
+        log.append(Bytes::from("a:3")).unwrap();
+        assert_eq!(compact(&mut log, b":"), 0);
+        assert_eq!(log.compaction.stale.iter().collect::<Vec<_>>(), vec![&2]);
+
+        log.append(Bytes::from("b:2")).unwrap();
+        assert_eq!(compact(&mut log, b":"), 2);
+        assert_eq!(log.compaction.indexed, 5);
+        assert!(log.compaction.stale.is_empty());
+
+        let (_, _, _, records) = log.readv(0, 0).unwrap();
+        assert_eq!(records, vec![Bytes::new(), Bytes::new()]);
+        let (_, _, _, records) = log.readv(2, 2).unwrap();
+        assert_eq!(records, vec![Bytes::new(), Bytes::from("a:3")]);
+    }
+
+    #[test]
+    fn segments_being_rewritten_stay_deleted_when_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("devices/state"));
+        let mut log = DiskLog::open(path.clone(), 6, 10, FsyncPolicy::Always).unwrap();
+        for record in ["a:1", "b:1", "a:2"].iter() {
+            log.append(Bytes::from(*record)).unwrap();
+        }
+
+        log.compact(b":").unwrap();
+        assert!(log.compaction.rewrite.is_some());
+        assert_eq!(log.purge(2).unwrap(), 1);
+        assert!(log.compaction.rewrite.is_none());
+
+        let (index, data) = Segment::paths(&path, 0);
+        assert!(!index.exists() && !data.exists());
+
+        drop(log);
+        let log = DiskLog::open(path, 6, 10, FsyncPolicy::Always).unwrap();
+        assert_eq!(log.start_offset(), 2);
+    }
+
+    #[test]
+    fn purge_drops_segments_before_offset_and_delete_removes_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("hello/world"));
+        let mut log = DiskLog::open(path.clone(), 20, 10, FsyncPolicy::Always).unwrap();
+        for i in 0..7u8 {
+            log.append(Bytes::from(vec![i; 8])).unwrap();
+        }
+
+        // Segment 2 has records beyond offset 3
+        assert_eq!(log.purge(3).unwrap(), 1);
+        assert_eq!(log.start_offset(), 2);
+
+        // Active segment stays
+        assert_eq!(log.purge(100).unwrap(), 2);
+        assert_eq!(log.start_offset(), 6);
+
+        log.delete().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reads_of_the_active_segment_share_appended_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(dir_name("hello/world"));
+        let mut log = DiskLog::open(path, 20, 10, FsyncPolicy::Never).unwrap();
+        let records: Vec<Bytes> = (0..3u8).map(|i| Bytes::from(vec![i; 8])).collect();
+        for record in records.iter() {
+            log.append(record.clone()).unwrap();
+        }
+
+        // Closed segment is read from the disk
+        let (_, _, _, closed) = log.readv(0, 0).unwrap();
+        assert_eq!(closed, records[..2]);
+        assert_ne!(closed[0].as_ptr(), records[0].as_ptr());
+
+        let (_, _, _, active) = log.readv(2, 2).unwrap();
+        assert_eq!(active, records[2..]);
+        assert_eq!(active[0].as_ptr(), records[2].as_ptr());
+    }
+
+    /// Compacts until the background rewrites are finished
+    fn compact(log: &mut DiskLog, delimiter: &[u8]) -> u64 {
+        let mut compacted = log.compact(delimiter).unwrap();
+        while log.compaction.rewrite.is_some() {
+            thread::sleep(Duration::from_millis(1));
+            compacted += log.compact(delimiter).unwrap();
+        }
+
+        compacted
+    }
+}
@@ -2,33 +2,155 @@ mod connections;
 mod data;
 mod topics;
 pub mod acks;
+pub mod disk;
+pub mod ordered;
 
+use crate::router::TopicMetrics;
 use crate::{Config, Data, DataRequest};
 use bytes::Bytes;
+use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
 
 pub use connections::ConnectionsLog;
+pub use ordered::OrderedGroups;
 pub use topics::TopicsLog;
 
 pub struct DataLog {
     commitlog: data::DataLog,
+    ordered: OrderedGroups,
 }
 
 impl DataLog {
-    pub fn new(config: Arc<Config>) -> DataLog {
-        let commitlog = data::DataLog::new(config.clone());
-        DataLog { commitlog }
+    pub fn new(config: Arc<Config>) -> io::Result<DataLog> {
+        let ordered = OrderedGroups::new(&config.ordered_groups);
+        let commitlog = data::DataLog::new(config.clone())?;
+        Ok(DataLog { commitlog, ordered })
     }
 
-    /// Update matched topic offsets to current offset of this topic's commitlog
+    /// Topics of logs opened from disk. Group logs are excluded
+    pub fn topics(&self) -> Vec<String> {
+        self.commitlog
+            .topics()
+            .filter(|topic| !ordered::is_group_log(topic))
+            .cloned()
+            .collect()
+    }
+
+    /// Applies retention policies. Returns the number of dropped segments
+    pub fn clean(&mut self) -> usize {
+        self.commitlog.clean()
+    }
+
+    /// Deletes the log of the topic. Returns false if there is no such topic
+    pub fn delete(&mut self, topic: &str) -> bool {
+        match self.commitlog.delete(topic) {
+            Ok(deleted) => deleted,
+            Err(e) => {
+                error!("Failed to delete commitlog of {}. Error = {:?}", topic, e);
+                false
+            }
+        }
+    }
+
+    /// Drops data of the topic before the offset. Returns the number of dropped segments
+    pub fn purge(&mut self, topic: &str, offset: u64) -> usize {
+        match self.commitlog.purge(topic, offset) {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                error!("Failed to purge commitlog of {}. Error = {:?}", topic, e);
+                0
+            }
+        }
+    }
+
+    /// Live state of the log of this topic. Topics of ordered groups
+    /// report their group log
+    pub fn metrics(&self, topic: &str) -> Option<TopicMetrics> {
+        match self.ordered.group(topic) {
+            Some(group) => self.commitlog.metrics(group),
+            None => self.commitlog.metrics(topic),
+        }
+    }
+
+    /// Number of commitlogs. Topics of an ordered group share one
+    pub fn count(&self) -> usize {
+        self.commitlog.count()
+    }
+
+    /// Next offset of the log. Group logs are addressed by their group log name
+    pub fn next_offset(&self, log: &str) -> Option<(u64, u64)> {
+        self.commitlog.next_offset(log)
+    }
+
+    /// Next offsets of all the logs including group logs. Map[log](segment, offset)
+    pub fn next_offsets(&self) -> HashMap<String, (u64, u64)> {
+        self.commitlog
+            .topics()
+            .filter_map(|log| Some((log.clone(), self.commitlog.next_offset(log)?)))
+            .collect()
+    }
+
+    /// Checks if a publish on this topic creates a new commitlog
+    pub fn is_new(&self, topic: &str) -> bool {
+        let log = self.ordered.group(topic).unwrap_or(topic);
+        !self.commitlog.contains(log)
+    }
+
+    /// Group log of this topic if the topic is part of an ordered group
+    pub fn group(&self, topic: &str) -> Option<&str> {
+        self.ordered.group(topic)
+    }
+
+    /// Update matched topic offsets to current offset of this topic's commitlog.
+    /// Topics of ordered groups are seeked to the end of their group log
     pub fn seek_offsets_to_end(&self, topic: &mut (String, u8, (u64, u64))) {
+        if let Some(group) = self.ordered.group(&topic.0) {
+            let mut log = (group.to_owned(), topic.1, topic.2);
+            self.commitlog.seek_offsets_to_end(&mut log);
+            topic.2 = log.2;
+            return;
+        }
+
         self.commitlog.seek_offsets_to_end(topic);
     }
 
+    /// Seeks the request to the end of its log if it lags more than `max`
+    /// records behind. Returns true if the request is seeked
+    pub fn skip_backlog(&self, request: &mut DataRequest, max: u64) -> bool {
+        let next = match self.commitlog.next_offset(&request.topic) {
+            Some(next) => next,
+            None => return false,
+        };
+
+        if next.1.saturating_sub(request.cursor.1) <= max {
+            return false;
+        }
+
+        request.cursor = next;
+        true
+    }
+
     /// Connections pull logs from both replication and connections where as replicator
     /// only pull logs from connections.
     /// Data from replicator and data from connection are separated for this reason
     pub fn append(&mut self, topic: &str, bytes: Bytes) -> Option<(bool, (u64, u64))> {
+        // Topics of an ordered group share the group log. New topic status
+        // is still tracked per topic for subscriptions to match them
+        if let Some(group) = self.ordered.group(topic) {
+            let group = group.to_owned();
+            let record = ordered::encode(topic, bytes);
+            let (_, offsets) = match self.commitlog.append(&group, record) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Commitlog append failed. Error = {:?}", e);
+                    return None;
+                }
+            };
+
+            return Some((self.ordered.first_publish(topic), offsets));
+        }
+
         match self.commitlog.append(&topic, bytes) {
             Ok(v) => Some(v),
             Err(e) => {
@@ -39,7 +161,11 @@ impl DataLog {
     }
 
     pub fn retain(&mut self, topic: &str, bytes: Bytes) -> Option<bool> {
-        // id 0-10 are reserved for replications which are linked to other routers in the mesh
+        // Group logs don't hold retained publishes. They are appended in order
+        if self.ordered.group(topic).is_some() {
+            return self.append(topic, bytes).map(|(is_new_topic, _)| is_new_topic);
+        }
+
         match self.commitlog.retain(&topic, bytes) {
             Ok(v) => Some(v),
             Err(e) => {
@@ -55,36 +181,49 @@ impl DataLog {
         let topic = &request.topic;
         let mut last_retain = request.last_retain;
 
+        // Requests from a timestamp are seeked with the time index of the log
+        let (segment, offset) = match request.timestamp {
+            Some(timestamp) => self.commitlog.seek(topic, timestamp)?,
+            None => request.cursor,
+        };
+
         // Iterate through native and replica commitlogs to collect data (of a topic)
-            let (segment, offset) = request.cursor;
-            match self.commitlog.readv(topic, segment, offset, last_retain) {
-                Ok(Some(v)) => {
-                    let (jump, base_offset, record_offset, retain, data) = v;
-                    let cursor = match jump {
-                        Some(next) => (next, next),
-                        None => (base_offset, record_offset),
-                    };
-
-                    // Update retain id (incase readv has retained publish to consider)
-                    last_retain = retain;
-                    if data.is_empty() {
-                        return None;
-                    }
-
-                    Some(Data::new(
-                        request.topic.clone(),
-                        request.qos,
-                        cursor,
-                        last_retain,
-                        0,
-                        data,
-                    ))
-                }
-                Ok(None) => None,
-                Err(e) => {
-                    error!("Failed to extract data from commitlog. Error = {:?}", e);
-                    None
+        let (max_count, max_bytes) = (request.max_count, request.max_bytes);
+        match self.commitlog.readv(topic, segment, offset, last_retain, max_count, max_bytes) {
+            Ok(Some(v)) => {
+                let (jump, base_offset, record_offset, retain, data) = v;
+                let cursor = match jump {
+                    Some(next) => (next, next),
+                    None => (base_offset, record_offset),
+                };
+
+                // Only the retained publish read by the first request of a new
+                // subscription is flagged. Live retains are forwarded as is
+                let retained = request.subscribed && retain != request.last_retain;
+
+                // Update retain id (incase readv has retained publish to consider)
+                last_retain = retain;
+                if data.is_empty() {
+                    return None;
                 }
+
+                let mut data = Data::new(
+                    request.topic.clone(),
+                    request.qos,
+                    cursor,
+                    last_retain,
+                    0,
+                    data,
+                );
+
+                data.retained = retained;
+                Some(data)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to extract data from commitlog. Error = {:?}", e);
+                None
             }
+        }
     }
 }
@@ -0,0 +1,175 @@
+use crate::Data;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+
+/// Prefix of group log names
+const GROUP_PREFIX: &str = "$ordered/";
+
+/// Topics whose relative ingest order is preserved while delivering to a
+/// subscriber. Publishes on all the topics of a group are appended to a single
+/// group log as topic tagged records. Subscribers read the group log and
+/// receive the records split back into per topic data in the ingest order
+pub struct OrderedGroups {
+    /// Map[topic]group log
+    groups: HashMap<String, String>,
+    /// Grouped topics which received at least one publish
+    seen: HashSet<String>,
+}
+
+impl OrderedGroups {
+    pub fn new(groups: &[Vec<String>]) -> OrderedGroups {
+        let mut map = HashMap::new();
+        for (i, topics) in groups.iter().enumerate() {
+            // '$' prefix keeps group logs out of reach of device subscriptions
+            let log = format!("{}{}", GROUP_PREFIX, i);
+            for topic in topics {
+                match map.get(topic) {
+                    Some(previous) => warn!("Topic {} already ordered in {}", topic, previous),
+                    None => {
+                        map.insert(topic.clone(), log.clone());
+                    }
+                }
+            }
+        }
+
+        OrderedGroups {
+            groups: map,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Group log of this topic if the topic is part of an ordered group
+    pub fn group(&self, topic: &str) -> Option<&str> {
+        self.groups.get(topic).map(|log| log.as_str())
+    }
+
+    /// Marks the topic as seen and returns true if this is the first publish on it
+    pub fn first_publish(&mut self, topic: &str) -> bool {
+        if self.seen.contains(topic) {
+            return false;
+        }
+
+        self.seen.insert(topic.to_owned());
+        true
+    }
+}
+
+/// Checks if the log is the log of an ordered group
+pub fn is_group_log(log: &str) -> bool {
+    log.starts_with(GROUP_PREFIX)
+}
+
+/// Tags the payload with its topic. Record = [topic len: u16][topic][payload]
+pub fn encode(topic: &str, payload: Bytes) -> Bytes {
+    let mut record = BytesMut::with_capacity(2 + topic.len() + payload.len());
+    record.put_u16(topic.len() as u16);
+    record.put_slice(topic.as_bytes());
+    record.put_slice(&payload);
+    record.freeze()
+}
+
+/// Extracts topic and payload from a topic tagged record
+pub fn decode(mut record: Bytes) -> Option<(String, Bytes)> {
+    if record.len() < 2 {
+        return None;
+    }
+
+    let len = record.get_u16() as usize;
+    if record.len() < len {
+        return None;
+    }
+
+    let topic = record.split_to(len);
+    let topic = String::from_utf8(topic.to_vec()).ok()?;
+    Some((topic, record))
+}
+
+/// Splits data of a group log into per topic data of consecutive records. Only
+/// records of `topics` are retained. Order of records across topics is preserved
+pub fn split(data: Data, topics: &HashSet<String>) -> Vec<Data> {
+    let mut out: Vec<Data> = Vec::new();
+    for record in data.payload {
+        let (topic, payload) = match decode(record) {
+            Some(v) => v,
+            None => {
+                error!("Malformed record in group log {}", data.topic);
+                continue;
+            }
+        };
+
+        if !topics.contains(&topic) {
+            continue;
+        }
+
+        // Append to current run if the topic didn't change
+        if let Some(last) = out.last_mut() {
+            if last.topic == topic {
+                last.size += payload.len();
+                last.payload.push(payload);
+                continue;
+            }
+        }
+
+        let size = payload.len();
+        out.push(Data::new(
+            topic,
+            data.qos,
+            data.cursor,
+            data.last_retain,
+            size,
+            vec![payload],
+        ));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_preserves_ingest_order_across_topics() {
+        let records = vec![
+            encode("device/x/cmd", Bytes::from(vec![1])),
+            encode("device/x/cmd", Bytes::from(vec![2])),
+            encode("device/x/config", Bytes::from(vec![3])),
+            encode("device/x/status", Bytes::from(vec![4])),
+            encode("device/x/cmd", Bytes::from(vec![5])),
+        ];
+
+        let data = Data::new("$ordered/0".to_owned(), 1, (0, 5), 0, 0, records);
+        let mut topics = HashSet::new();
+        topics.insert("device/x/cmd".to_owned());
+        topics.insert("device/x/config".to_owned());
+
+        let out = split(data, &topics);
+        assert_eq!(out.len(), 3);
+
+        assert_eq!(out[0].topic, "device/x/cmd");
+        assert_eq!(
+            out[0].payload,
+            vec![Bytes::from(vec![1]), Bytes::from(vec![2])]
+        );
+        assert_eq!(out[1].topic, "device/x/config");
+        assert_eq!(out[1].payload, vec![Bytes::from(vec![3])]);
+        assert_eq!(out[2].topic, "device/x/cmd");
+        assert_eq!(out[2].payload, vec![Bytes::from(vec![5])]);
+    }
+
+    #[test]
+    fn topics_are_mapped_to_their_group_logs() {
+        let groups = vec![
+            vec!["a/cmd".to_owned(), "a/config".to_owned()],
+            vec!["b/cmd".to_owned()],
+        ];
+
+        let mut ordered = OrderedGroups::new(&groups);
+        assert_eq!(ordered.group("a/config"), Some("$ordered/0"));
+        assert_eq!(ordered.group("b/cmd"), Some("$ordered/1"));
+        assert_eq!(ordered.group("c/cmd"), None);
+
+        assert!(ordered.first_publish("a/cmd"));
+        assert!(!ordered.first_publish("a/cmd"));
+    }
+}
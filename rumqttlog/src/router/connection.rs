@@ -1,6 +1,8 @@
-use crate::Notification;
+use crate::router::DisconnectReason;
+use crate::{AckMode, ConnectionId, Notification};
 use jackiechan::{bounded, Receiver, Sender, TrySendError};
 use mqttbytes::v4::LastWill;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Debug, Clone)]
@@ -9,6 +11,12 @@ pub enum ConnectionType {
     Replicator(usize),
 }
 
+/// Session id of the replicator connection of a peer router. Links disconnect
+/// replicators with this id to resume from the same cursors on reconnection
+pub fn replicator_id(id: usize) -> String {
+    format!("replicator-{}", id)
+}
+
 /// Used to register a new connection with the router
 /// Connection messages encompasses a handle for router to
 /// communicate with this connection
@@ -30,6 +38,27 @@ pub struct Connection {
     capacity: usize,
     /// Last seen remaining space in the channel
     remaining_space: usize,
+    /// Handling of the connection when it can't drain its channel
+    slow_consumer: SlowConsumer,
+    /// Id assigned by a sharded router. Every shard registers the connection at this id
+    id: Option<ConnectionId>,
+    /// Ack mode of publishes on topics without an ack mode policy
+    ack_mode: AckMode,
+}
+
+/// Handling of a connection which can't drain its channel as fast as
+/// the router fills it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumer {
+    /// Router stops serving the connection till it drains the channel
+    #[default]
+    Wait,
+    /// Like `Wait`, but QoS 0 data published while the connection was
+    /// paused is dropped when the connection resumes
+    DropQos0,
+    /// Connection is disconnected
+    Disconnect,
 }
 
 impl Connection {
@@ -48,6 +77,9 @@ impl Connection {
             handle: this_tx,
             capacity,
             remaining_space: capacity,
+            slow_consumer: SlowConsumer::Wait,
+            id: None,
+            ack_mode: AckMode::Append,
         };
 
         (connection, this_rx)
@@ -68,6 +100,9 @@ impl Connection {
             handle: this_tx,
             capacity,
             remaining_space: capacity,
+            slow_consumer: SlowConsumer::Wait,
+            id: None,
+            ack_mode: AckMode::Append,
         };
 
         (connection, this_rx)
@@ -85,6 +120,69 @@ impl Connection {
         self.will = Some(will);
     }
 
+    pub fn slow_consumer(&self) -> SlowConsumer {
+        self.slow_consumer
+    }
+
+    pub fn set_slow_consumer(&mut self, slow_consumer: SlowConsumer) {
+        self.slow_consumer = slow_consumer;
+    }
+
+    pub fn ack_mode(&self) -> AckMode {
+        self.ack_mode
+    }
+
+    pub fn set_ack_mode(&mut self, ack_mode: AckMode) {
+        self.ack_mode = ack_mode;
+    }
+
+    pub(crate) fn id(&self) -> Option<ConnectionId> {
+        self.id
+    }
+
+    pub(crate) fn set_id(&mut self, id: ConnectionId) {
+        self.id = Some(id);
+    }
+
+    /// Splits the connection across router shards. Shards share the channel of
+    /// the link and each shard fills at most its share of the capacity. Will of
+    /// the connection isn't handed to any of the shards
+    pub(crate) fn split(self, count: usize) -> Vec<Connection> {
+        let capacity = (self.capacity / count).max(2);
+        (0..count)
+            .map(|_| Connection {
+                conn: self.conn.clone(),
+                clean: self.clean,
+                will: None,
+                last_failed: None,
+                handle: self.handle.clone(),
+                capacity,
+                remaining_space: capacity,
+                slow_consumer: self.slow_consumer,
+                id: self.id,
+                ack_mode: self.ack_mode,
+            })
+            .collect()
+    }
+
+    /// Link of the connection is closed
+    pub fn closed(&self) -> bool {
+        self.last_failed.is_some()
+    }
+
+    /// Notifications in the channel which the link is yet to read
+    pub fn queued(&self) -> usize {
+        self.handle.len()
+    }
+
+    /// Asks the link to disconnect. Links of connections which are dropped by the
+    /// router close anyway once they drain the channel. So this is best effort
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        if let Err(e) = self.handle.try_send(Notification::Disconnect(reason)) {
+            debug!("Failed to notify disconnection. Error = {:?}", e);
+        }
+    }
+
     /// Sends notification and returns status to unschedule this connection
     pub fn notify(&mut self, notification: Notification) -> bool {
         if let Err(e) = self.handle.try_send(notification) {
@@ -97,11 +195,12 @@ impl Connection {
             }
         }
 
-        self.remaining_space -= 1;
+        // Channels shared by shards are filled by other shards as well
+        self.remaining_space = self.remaining_space.saturating_sub(1);
 
         // Update remaining space if there is room for only one notification.
         if self.remaining_space <= 1 {
-            self.remaining_space = self.capacity - self.handle.len();
+            self.remaining_space = self.capacity.saturating_sub(self.handle.len());
 
             // If remaining space is still 1 after refresh, send pause notification
             // to the connection and return unschedule true
@@ -1,6 +1,7 @@
 use crate::router::Tracker;
 use crate::{Config, RouterId};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
@@ -8,13 +9,18 @@ pub enum MetricsRequest {
     Config,
     Router,
     Connection(String),
+    Topic(String),
+    /// Lag of the replicators of peer routers
+    Replication,
 }
 
 #[derive(Debug, Clone)]
 pub enum MetricsReply {
     Config(Arc<Config>),
     Router(RouterMetrics),
-    Connection(ConnectionMetrics),
+    Connection(Box<ConnectionMetrics>),
+    Topic(Option<TopicMetrics>),
+    Replication(Vec<ReplicaMetrics>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +29,19 @@ pub struct RouterMetrics {
     pub total_connections: usize,
     pub total_topics: usize,
     pub total_subscriptions: usize,
+    /// Connections scheduled for their next requests
+    pub readyqueue_len: usize,
+    /// Publishes appended to the commitlogs since the router started
+    pub total_publishes: u64,
+    pub total_publish_size: u64,
+    /// Publishes delivered to connections since the router started
+    pub total_deliveries: u64,
+    /// Times connections couldn't drain their notifications and were paused
+    pub slow_consumer_pauses: u64,
+    /// QoS 0 records skipped by slow connections with `SlowConsumer::DropQos0`
+    pub slow_consumer_drops: u64,
+    /// Slow connections disconnected with `SlowConsumer::Disconnect`
+    pub slow_consumer_disconnections: u64,
 }
 
 impl RouterMetrics {
@@ -32,6 +51,13 @@ impl RouterMetrics {
             total_connections: 0,
             total_topics: 0,
             total_subscriptions: 0,
+            readyqueue_len: 0,
+            total_publishes: 0,
+            total_publish_size: 0,
+            total_deliveries: 0,
+            slow_consumer_pauses: 0,
+            slow_consumer_drops: 0,
+            slow_consumer_disconnections: 0,
         }
     }
 }
@@ -40,10 +66,60 @@ impl RouterMetrics {
 pub struct ConnectionMetrics {
     id: String,
     tracker: Option<Tracker>,
+    /// None when the connection isn't connected
+    inflight: Option<InflightMetrics>,
 }
 
 impl ConnectionMetrics {
-    pub fn new(id: String, tracker: Option<Tracker>) -> ConnectionMetrics {
-        ConnectionMetrics { id, tracker }
+    pub fn new(
+        id: String,
+        tracker: Option<Tracker>,
+        inflight: Option<InflightMetrics>,
+    ) -> ConnectionMetrics {
+        ConnectionMetrics {
+            id,
+            tracker,
+            inflight,
+        }
+    }
+
+    pub fn inflight(&self) -> Option<&InflightMetrics> {
+        self.inflight.as_ref()
     }
 }
+
+/// Data of a connection which is on its way to the link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InflightMetrics {
+    /// Notifications which the link is yet to read
+    pub queued: usize,
+    /// Acks of the publishes of the connection which are yet to be sent
+    pub pending_acks: usize,
+    /// Acks which wait for replicas to pull the publishes of the connection
+    pub held_acks: usize,
+    /// Ordered data waiting for room in the link
+    pub backlog: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMetrics {
+    pub topic: String,
+    /// (segment, offset) of the next append
+    pub next_offset: (u64, u64),
+    /// Records and bytes appended since the router started
+    pub appended_count: u64,
+    pub appended_size: u64,
+    /// Topic holds a retained publish
+    pub retained: bool,
+}
+
+/// Lag of the replicator connection of a peer router
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaMetrics {
+    /// Id of the peer router
+    pub replica: usize,
+    /// Disconnected replicas don't report lag
+    pub connected: bool,
+    /// Records the replica is behind the head of the topics it pulls. Map[topic]lag
+    pub lag: BTreeMap<String, u64>,
+}
@@ -4,15 +4,24 @@ pub(crate) mod connection;
 mod metrics;
 mod readyqueue;
 mod router;
+mod sharded;
+mod shared;
 mod slab;
+mod snapshot;
+mod sys;
 mod tracker;
 
+pub use connection::replicator_id;
 use connection::Connection;
+pub use connection::SlowConsumer;
 pub use router::Router;
+pub use sharded::ShardedRouter;
 pub use tracker::Tracker;
 
 use self::bytes::Bytes;
-pub use crate::router::metrics::{ConnectionMetrics, MetricsReply, MetricsRequest};
+pub use crate::router::metrics::{
+    ConnectionMetrics, InflightMetrics, MetricsReply, MetricsRequest, ReplicaMetrics, TopicMetrics,
+};
 use mqttbytes::v4::Packet;
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -30,6 +39,10 @@ pub enum Event {
     Disconnect(Disconnection),
     /// Get metrics of a connection or all connections
     Metrics(MetricsRequest),
+    /// Delete a topic along with its commitlog. Replied with topic metrics
+    DeleteTopic(String),
+    /// Drop data of a topic before an offset. Replied with topic metrics
+    PurgeTopic(String, u64),
 }
 
 /// Requests for pull operations
@@ -56,8 +69,34 @@ pub enum Notification {
     Acks(Vec<Packet>),
     /// Connection paused by router
     Pause,
+    /// Progress of a bulk subscription which is applied over multiple iterations
+    SubscribeProgress(SubscribeProgress),
     /// All metrics
     Metrics(MetricsReply),
+    /// Connection is disconnected by the router
+    Disconnect(DisconnectReason),
+}
+
+/// Reason of a disconnection by the router
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// New connection with the same client id took over the session
+    TakenOver,
+    /// Connection couldn't drain its notifications as per its `SlowConsumer` policy
+    SlowConsumer,
+    /// Publish on a new topic beyond `Config::max_topics`
+    TopicLimit,
+    /// Publish bigger than `Config::max_payload_size`
+    PayloadLimit,
+}
+
+/// Subscriptions applied so far of a subscribe packet. Suback follows
+/// once all the filters are applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscribeProgress {
+    pub pkid: u16,
+    pub applied: usize,
+    pub total: usize,
 }
 
 /// Request that connection/linker makes to extract data from commitlog
@@ -70,12 +109,24 @@ pub struct DataRequest {
     pub(crate) topic: String,
     /// QoS of the request
     pub(crate) qos: u8,
-    /// (segment, offset) tuples per replica (1 native and 2 replicas)
+    /// (segment, offset) cursor in the commitlog
     pub(crate) cursor: (u64, u64),
     /// Last retain id
     pub(crate) last_retain: u64,
-    /// Maximum count of payload buffer per replica
-    max_count: usize,
+    /// Maximum count of payload buffer
+    pub(crate) max_count: usize,
+    /// Maximum size of payload buffer. A record bigger than this is still
+    /// returned alone so that the request makes progress
+    #[serde(default = "default_max_bytes")]
+    pub(crate) max_bytes: usize,
+    /// Read from the first record appended at or after this time (unix secs)
+    /// instead of the cursor
+    #[serde(default)]
+    pub(crate) timestamp: Option<u64>,
+    /// First read of a new subscription. Retained publish of this read is
+    /// flagged. Requests registered after a reply have it cleared
+    #[serde(default)]
+    pub(crate) subscribed: bool,
 }
 
 impl DataRequest {
@@ -87,6 +138,18 @@ impl DataRequest {
             cursor: (0, 0),
             last_retain: 0,
             max_count: 100,
+            max_bytes: default_max_bytes(),
+            timestamp: None,
+            subscribed: false,
+        }
+    }
+
+    /// New data request which starts from the records appended at or after
+    /// the timestamp (unix secs). E.g. to consume the data of the last hour
+    pub fn from_timestamp(topic: String, qos: u8, timestamp: u64) -> DataRequest {
+        DataRequest {
+            timestamp: Some(timestamp),
+            ..DataRequest::new(topic, qos)
         }
     }
 
@@ -103,16 +166,28 @@ impl DataRequest {
             cursor,
             last_retain,
             max_count: 100,
+            max_bytes: default_max_bytes(),
+            timestamp: None,
+            subscribed: false,
         }
     }
+
+    /// Bounds size of the data reply
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+    }
+}
+
+fn default_max_bytes() -> usize {
+    1024 * 1024
 }
 
 impl fmt::Debug for DataRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Topic = {}, cursors = {:?}, max_count = {}",
-            self.topic, self.cursor, self.max_count
+            "Topic = {}, cursors = {:?}, max_count = {}, max_bytes = {}",
+            self.topic, self.cursor, self.max_count, self.max_bytes
         )
     }
 }
@@ -152,7 +227,7 @@ pub struct Data {
     pub topic: String,
     /// Qos of the topic
     pub qos: u8,
-    /// (segment, offset) tuples per replica (1 native and 2 replicas)
+    /// (segment, offset) cursor in the commitlog
     pub cursor: (u64, u64),
     /// Next retain publish id
     pub last_retain: u64,
@@ -160,6 +235,9 @@ pub struct Data {
     pub size: usize,
     /// Reply data chain
     pub payload: Vec<Bytes>,
+    /// Last payload is the retained publish of the topic, delivered because
+    /// of a new subscription. Delivered with the retain flag
+    pub retained: bool,
 }
 
 impl Data {
@@ -178,6 +256,7 @@ impl Data {
             size,
             payload,
             qos,
+            retained: false,
         }
     }
 }
@@ -1,15 +1,32 @@
 use crate::ConnectionId;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 
+/// Connections which are ready to make progress. Connections are served in
+/// turns with deficit round robin. Every turn adds `quantum * weight` records
+/// to the quota of a connection and records served in the turn are taken out
+/// of it. Connections which overshoot their quota with big replies skip turns
+/// till they are back in credit. So chatty connections can't starve the rest
 #[derive(Debug)]
 pub struct ReadyQueue {
     queue: VecDeque<ConnectionId>,
+    /// Connections in the queue. A connection is queued only once
+    queued: HashSet<ConnectionId>,
+    /// Records a connection of weight 1 is served in a turn
+    quantum: usize,
+    /// Weights of connections other than 1. Map[id]weight
+    weights: HashMap<ConnectionId, usize>,
+    /// Records left (or owed) from previous turns. Map[id]deficit
+    deficits: HashMap<ConnectionId, i64>,
 }
 
 impl ReadyQueue {
-    pub fn new() -> ReadyQueue {
+    pub fn new(quantum: usize) -> ReadyQueue {
         ReadyQueue {
             queue: VecDeque::with_capacity(100),
+            queued: HashSet::with_capacity(100),
+            quantum,
+            weights: HashMap::new(),
+            deficits: HashMap::new(),
         }
     }
 
@@ -17,18 +34,92 @@ impl ReadyQueue {
         self.queue.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
     pub fn pop_front(&mut self) -> Option<ConnectionId> {
-        self.queue.pop_front()
+        let id = self.queue.pop_front()?;
+        self.queued.remove(&id);
+        Some(id)
     }
 
     pub fn push_back(&mut self, id: ConnectionId) {
-        self.queue.push_back(id)
+        if self.queued.insert(id) {
+            self.queue.push_back(id)
+        }
+    }
+
+    /// Connections with weight `n` are served `n` times the records of others in a turn
+    pub fn set_weight(&mut self, id: ConnectionId, weight: usize) {
+        self.weights.insert(id, weight);
+    }
+
+    /// Starts a turn of the connection and returns the records it can be served.
+    /// Zero or less when the connection should skip this turn
+    pub fn quota(&mut self, id: ConnectionId) -> i64 {
+        let weight = self.weights.get(&id).copied().unwrap_or(1);
+        let deficit = self.deficits.entry(id).or_insert(0);
+        *deficit += (self.quantum * weight) as i64;
+        *deficit
+    }
+
+    /// Ends the turn of the connection with the records served in it. Connections
+    /// which are out of work don't carry their remaining quota to the next turn
+    pub fn charge(&mut self, id: ConnectionId, work: usize) {
+        let queued = self.queued.contains(&id);
+        if let Some(deficit) = self.deficits.get_mut(&id) {
+            *deficit -= work as i64;
+            if !queued && *deficit > 0 {
+                *deficit = 0;
+            }
+        }
     }
 
     /// Remove a connection from waiters
     pub fn remove(&mut self, id: ConnectionId) {
+        self.weights.remove(&id);
+        self.deficits.remove(&id);
+        if !self.queued.remove(&id) {
+            return;
+        }
+
         if let Some(index) = self.queue.iter().position(|x| *x == id) {
             self.queue.swap_remove_back(index);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::ReadyQueue;
+
+    #[test]
+    fn connections_over_their_quota_skip_turns() {
+        let mut readyqueue = ReadyQueue::new(10);
+        readyqueue.set_weight(0, 2);
+        readyqueue.push_back(0);
+        readyqueue.push_back(1);
+        readyqueue.push_back(1);
+        assert_eq!(readyqueue.len(), 2);
+
+        // Weighted connection overshoots with a big reply
+        let id = readyqueue.pop_front().unwrap();
+        assert_eq!(readyqueue.quota(id), 20);
+        readyqueue.push_back(id);
+        readyqueue.charge(id, 50);
+
+        let id = readyqueue.pop_front().unwrap();
+        assert_eq!(readyqueue.quota(id), 10);
+        readyqueue.charge(id, 5);
+
+        // Idle connections don't hoard quota
+        assert_eq!(readyqueue.quota(1), 10);
+        readyqueue.charge(1, 0);
+
+        let id = readyqueue.pop_front().unwrap();
+        assert_eq!(id, 0);
+        assert_eq!(readyqueue.quota(id), -10);
+        assert_eq!(readyqueue.quota(id), 10);
+    }
+}
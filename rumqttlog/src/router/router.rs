@@ -1,19 +1,34 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
-
-use jackiechan::{bounded, Receiver, RecvError, Sender, TryRecvError};
-use mqttbytes::v4::{Packet, Publish, Subscribe, SubscribeReasonCode, Unsubscribe};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use jackiechan::{bounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+use mqttbytes::v4::{
+    Packet, PubRel, Publish, Subscribe, SubscribeFilter, SubscribeReasonCode, Unsubscribe,
+};
+use mqttbytes::{qos, QoS};
 use thiserror::Error;
 
 use super::connection::ConnectionType;
 use super::readyqueue::ReadyQueue;
+use super::shared::{self, SharedGroups};
 use super::slab::Slab;
+use super::snapshot::Snapshot;
+use super::sys;
 use super::*;
 use crate::logs::acks::Acks;
+use crate::logs::ordered;
 
 use crate::logs::{ConnectionsLog, DataLog, TopicsLog};
 use crate::router::metrics::RouterMetrics;
+use crate::storage::{self, Session, Storage, StorageError, Table};
 use crate::waiters::{DataWaiters, TopicsWaiters};
-use crate::{Config, ConnectionId, DataRequest, Disconnection, RouterId};
+use crate::{
+    AckMode, Config, ConnectionId, DataRequest, DisconnectReason, Disconnection, DuplicateClientId,
+    RouterId, SlowConsumer,
+};
 
 #[derive(Error, Debug)]
 #[error("...")]
@@ -22,6 +37,12 @@ pub enum RouterError {
     Disconnected,
 }
 
+/// Interval between retention cleanups of commitlogs and session expiry checks
+const CLEAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval between checks of replication lag against the alert threshold
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Router {
     /// Router configuration
     config: Arc<Config>,
@@ -41,6 +62,12 @@ pub struct Router {
     trackers: Slab<Tracker>,
     /// Watermarks of a connection
     watermarks: Slab<Acks>,
+    /// Ordered group data which is yet to be delivered to a connection
+    backlogs: Slab<VecDeque<Data>>,
+    /// Subscribes which are yet to be applied to the tracker of a connection
+    subscriptions: Slab<VecDeque<PendingSubscribe>>,
+    /// Cursors of shared subscription groups
+    shared: SharedGroups,
     /// Connections with more pending requests and ready to make progress
     readyqueue: ReadyQueue,
     /// Waiter on a topic. These are used to wake connections/replicators
@@ -54,31 +81,74 @@ pub struct Router {
     router_rx: Receiver<(ConnectionId, Event)>,
     /// Aggregates for all connections
     metrics: RouterMetrics,
+    /// Storage for persistent sessions and retained publishes
+    storage: Option<Box<dyn Storage>>,
+    /// Persistent sessions with subscription changes which aren't in the
+    /// storage yet. Saved in one batch after every round of events
+    unsaved_sessions: HashMap<String, Session>,
+    /// Next cleanup. None without retention policies and session expiry
+    next_clean: Option<Instant>,
+    /// Next publish of statistics. None when `$SYS` topics are disabled
+    next_sys: Option<Instant>,
+    /// Start time of the router. Used for uptime
+    started: Instant,
+    /// Time, publish count and publish size during the last statistics
+    /// publish. Used to compute load
+    sys_snapshot: (Instant, u64, u64),
+    /// Topics with per topic statistics in the last statistics publish
+    sys_topics: HashSet<String>,
+    /// Time of the next snapshot of router state
+    next_snapshot: Option<Instant>,
+    /// Alert on replicas lagging behind topics
+    lag_alert: Option<LagAlert>,
+    /// Time of the next replication lag check
+    next_lag_check: Option<Instant>,
+    /// Connections with acks which wait for replicas to pull their publishes
+    replicating: HashSet<ConnectionId>,
+    /// Shards of a sharded router other than the first don't ack connections,
+    /// subscribes and unsubscribes. Links expect one ack for each of them
+    primary: bool,
 }
 
 impl Router {
-    pub fn new(config: Arc<Config>) -> (Self, Sender<(ConnectionId, Event)>) {
+    /// Router with retained publishes and persistent sessions of the storage.
+    /// Fails if the storage or on disk logs can't be opened
+    pub fn new(config: Arc<Config>) -> Result<(Self, Sender<(ConnectionId, Event)>), StorageError> {
         let (router_tx, router_rx) = bounded(1000);
         let id = config.id;
         let max_connections = config.max_connections;
+        let replicas = config.replicas;
 
-        // Connection level information
-        let connections = Slab::with_capacity(max_connections);
-        let trackers = Slab::with_capacity(max_connections);
-        let watermarks = Slab::with_capacity(max_connections);
+        // Connection level information. Ids below `replicas` are for replicators
+        let connections = Slab::with_capacity(max_connections, replicas);
+        let trackers = Slab::with_capacity(max_connections, replicas);
+        let watermarks = Slab::with_capacity(max_connections, replicas);
+        let backlogs = Slab::with_capacity(max_connections, replicas);
+        let subscriptions = Slab::with_capacity(max_connections, replicas);
 
         // Global data
         let connectionslog = ConnectionsLog::new();
-        let datalog: DataLog = DataLog::new(config.clone());
-        let topicslog = TopicsLog::new();
+        let datalog: DataLog = DataLog::new(config.clone())?;
+        let mut topicslog = TopicsLog::new();
+
+        // Topics of on disk logs are matched by subscriptions like new topics
+        for topic in datalog.topics() {
+            topicslog.append(&topic);
+        }
 
         // Waiters to notify new data or topics
         let data_waiters = DataWaiters::new();
         let topics_waiters = TopicsWaiters::new();
-        let readyqueue = ReadyQueue::new();
+        let shared = SharedGroups::new();
+        let readyqueue = ReadyQueue::new(config.ready_quota);
         let metrics = RouterMetrics::new(id);
 
-        let router = Router {
+        let storage = match config.storage.as_ref() {
+            Some(config) => Some(storage::open(config)?),
+            None => None,
+        };
+
+        let mut router = Router {
             config,
             _id: id,
             connectionslog,
@@ -87,25 +157,311 @@ impl Router {
             connections,
             trackers,
             watermarks,
+            backlogs,
+            subscriptions,
+            shared,
             readyqueue,
             data_waiters,
             topics_waiters,
             router_rx,
             metrics,
+            storage,
+            unsaved_sessions: HashMap::new(),
+            next_clean: None,
+            next_sys: None,
+            started: Instant::now(),
+            sys_snapshot: (Instant::now(), 0, 0),
+            sys_topics: HashSet::new(),
+            next_snapshot: None,
+            lag_alert: None,
+            next_lag_check: None,
+            replicating: HashSet::new(),
+            primary: true,
+        };
+
+        if !router.config.retention.is_empty() || router.config.session_expiry_secs.is_some() {
+            router.next_clean = Some(Instant::now() + CLEAN_INTERVAL);
+        }
+
+        if let Some(interval) = router.config.sys_interval_secs {
+            router.next_sys = Some(Instant::now() + Duration::from_secs(interval));
+        }
+
+        if let Some(interval) = router.config.snapshot_interval_secs {
+            router.next_snapshot = Some(Instant::now() + Duration::from_secs(interval));
+        }
+
+        router.restore()?;
+        Ok((router, router_tx))
+    }
+
+    /// Restores retained publishes and persistent sessions from storage
+    fn restore(&mut self) -> Result<(), StorageError> {
+        let storage = match self.storage.as_mut() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+
+        let retained = storage.scan(Table::Retained)?;
+        let sessions = storage.scan(Table::Sessions)?;
+        info!(
+            "{:11} {:14} Retained = {} Sessions = {}",
+            "storage",
+            "restore",
+            retained.len(),
+            sessions.len()
+        );
+
+        for (topic, payload) in retained {
+            if let Some(true) = self.datalog.retain(&topic, Bytes::from(payload)) {
+                self.topicslog.append(&topic);
+            }
+        }
+
+        // Sessions resume with their subscriptions. Matching topics are tracked
+        // from the start of the logs as the logs don't survive restarts
+        for (client_id, session) in sessions {
+            let session = Session::decode(&session)?;
+            let mut filters = Vec::new();
+            for (filter, q) in session.subscriptions {
+                match qos(q) {
+                    Ok(q) => filters.push(SubscribeFilter::new(filter, q)),
+                    Err(e) => error!("Invalid qos in session {}. Error = {:?}", client_id, e),
+                }
+            }
+
+            let mut tracker = Tracker::new();
+            if tracker.add_subscription_and_match(filters, &[]) {
+                tracker.register_topics_request(TopicsRequest::offset(0));
+            }
+
+            self.connectionslog.restore(&client_id, tracker);
+        }
+
+        Ok(())
+    }
+
+    /// Router which resumes persistent sessions and shared group cursors from
+    /// the snapshot under `Config::dir`. Same as `Router::new` when there is no
+    /// snapshot. Cursors on logs which didn't survive the restart start over
+    pub fn from_snapshot(
+        config: Arc<Config>,
+    ) -> Result<(Self, Sender<(ConnectionId, Event)>), StorageError> {
+        let (mut router, router_tx) = Router::new(config)?;
+        let snapshot = match Snapshot::read(&router.config.dir)? {
+            Some(snapshot) => snapshot,
+            None => return Ok((router, router_tx)),
+        };
+
+        let lost = snapshot.lost(|log| router.datalog.next_offset(log));
+        info!(
+            "{:11} {:14} Sessions = {} Lost logs = {}",
+            "snapshot",
+            "restore",
+            snapshot.sessions.len(),
+            lost.len()
+        );
+
+        // Sessions in the snapshot supersede the ones restored from storage
+        for (client_id, mut tracker) in snapshot.sessions {
+            for request in tracker.data_requests_mut() {
+                if lost.contains(&request.topic) {
+                    request.cursor = (0, 0);
+                }
+            }
+
+            tracker.rewind_topics_request();
+            router.connectionslog.restore(&client_id, tracker);
+        }
+
+        router.shared = snapshot.shared;
+        for log in lost.iter() {
+            router.shared.remove_topic(log);
+        }
+
+        Ok((router, router_tx))
+    }
+
+    /// Writes offsets of commitlogs, trackers of persistent sessions and cursors
+    /// of shared groups under `Config::dir` for `Router::from_snapshot`
+    pub fn snapshot(&self) -> Result<(), StorageError> {
+        let mut sessions: HashMap<String, Tracker> = self
+            .connectionslog
+            .sessions()
+            .map(|(client_id, tracker)| (client_id.clone(), tracker.clone()))
+            .collect();
+
+        // Connected persistent sessions are saved like on a disconnection
+        for (id, connection) in self.connections.iter() {
+            let client_id = match &connection.conn {
+                ConnectionType::Device(did) if !connection.clean() => did,
+                _ => continue,
+            };
+
+            let mut tracker = self.trackers.get(id).unwrap().clone();
+            for request in self.data_waiters.requests(id) {
+                tracker.register_data_request(request);
+            }
+
+            if let Some(request) = self.topics_waiters.get(id) {
+                tracker.register_topics_request(request.clone());
+            }
+
+            tracker.register_acks_request();
+            tracker.set_busy_unschedule(false);
+            tracker.set_empty_unschedule(false);
+            if let Some(watermarks) = self.watermarks.get(id) {
+                tracker.set_unreleased(watermarks.unreleased().clone());
+            }
+
+            sessions.insert(client_id.clone(), tracker);
+        }
+
+        let snapshot = Snapshot {
+            offsets: self.datalog.next_offsets(),
+            sessions,
+            shared: self.shared.clone(),
+        };
+
+        snapshot.write(&self.config.dir)?;
+        debug!(
+            "{:11} {:14} Sessions = {}",
+            "snapshot",
+            "write",
+            snapshot.sessions.len()
+        );
+        Ok(())
+    }
+
+    /// Calls `alert` with the replica, topic and lag when a replica falls behind a
+    /// topic by more than `threshold` records. Replicas are alerted again for the
+    /// topic only after they catch up
+    pub fn set_lag_alert(
+        &mut self,
+        threshold: u64,
+        alert: impl FnMut(usize, &str, u64) + Send + 'static,
+    ) {
+        self.lag_alert = Some(LagAlert {
+            threshold,
+            alert: Box::new(alert),
+            lagging: HashSet::new(),
+        });
+
+        self.next_lag_check = Some(Instant::now() + LAG_CHECK_INTERVAL);
+    }
+
+    pub(crate) fn set_primary(&mut self, primary: bool) {
+        self.primary = primary;
+    }
+
+    /// Marks subscriptions of a persistent session to be saved to storage
+    /// with the next batch
+    fn persist_session(&mut self, id: ConnectionId) {
+        if self.storage.is_none() {
+            return;
+        }
+
+        let connection = self.connections.get(id).unwrap();
+        let client_id = match &connection.conn {
+            ConnectionType::Device(did) if !connection.clean() => did,
+            _ => return,
+        };
+
+        let tracker = self.trackers.get(id).unwrap();
+        let session = Session {
+            subscriptions: tracker.subscriptions(),
+        };
+
+        self.unsaved_sessions.insert(client_id.to_owned(), session);
+    }
+
+    /// Saves sessions marked by `persist_session` to storage in one batch
+    fn save_sessions(&mut self) {
+        let storage = match self.storage.as_mut() {
+            Some(storage) if !self.unsaved_sessions.is_empty() => storage,
+            _ => return,
         };
 
-        (router, router_tx)
+        let mut pairs = Vec::with_capacity(self.unsaved_sessions.len());
+        for (client_id, session) in self.unsaved_sessions.drain() {
+            match session.encode() {
+                Ok(session) => pairs.push((client_id, session)),
+                Err(e) => error!("Failed to encode session {}. Error = {:?}", client_id, e),
+            }
+        }
+
+        trace!("{:11} {:14} Count = {}", "storage", "sessions", pairs.len());
+        if let Err(e) = storage.put_batch(Table::Sessions, &pairs) {
+            error!("Failed to persist sessions. Error = {:?}", e);
+        }
+    }
+
+    /// Runs the router till all the senders of events are dropped
+    pub fn start(&mut self) -> Result<(), RouterError> {
+        let o = self.run();
+
+        // Sessions changed by the last round of events
+        self.save_sessions();
+        o
     }
 
     /// Waits on incoming events when ready queue is empty.
     /// After pulling 1 event, tries to pull 500 more events
     /// before polling ready queue 100 times (connections)
-    pub fn start(&mut self) -> Result<(), RouterError> {
+    fn run(&mut self) -> Result<(), RouterError> {
         loop {
-            // Block on incoming events if there are no connections
-            // in ready queue.
+            if let Some(deadline) = self.next_clean {
+                if Instant::now() >= deadline {
+                    self.clean();
+                }
+            }
+
+            if let Some(deadline) = self.next_sys {
+                if Instant::now() >= deadline {
+                    self.publish_sys();
+                }
+            }
+
+            if let Some(deadline) = self.next_lag_check {
+                if Instant::now() >= deadline {
+                    self.check_lag();
+                }
+            }
+
+            if let Some(deadline) = self.next_snapshot {
+                if Instant::now() >= deadline {
+                    if let Err(e) = self.snapshot() {
+                        error!("Failed to snapshot router state. Error = {:?}", e);
+                    }
+
+                    let interval = self.config.snapshot_interval_secs.unwrap_or_default();
+                    self.next_snapshot = Some(Instant::now() + Duration::from_secs(interval));
+                }
+            }
+
+            // Block on incoming events if there are no connections in ready
+            // queue. Wakes up for cleanups, statistics, lag checks and snapshots
             if self.readyqueue.is_empty() {
-                let (id, data) = self.router_rx.recv()?;
+                let deadline = [
+                    self.next_clean,
+                    self.next_sys,
+                    self.next_lag_check,
+                    self.next_snapshot,
+                ]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+
+                let (id, data) = match deadline {
+                    Some(deadline) => match self.router_rx.recv_deadline(deadline) {
+                        Ok(v) => v,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Closed) => return Err(RouterError::Disconnected),
+                    },
+                    None => self.router_rx.recv()?,
+                };
+
                 self.route(id, data);
             }
 
@@ -122,6 +478,9 @@ impl Router {
                 }
             }
 
+            // Subscription changes of the events above are saved together
+            self.save_sessions();
+
             // Poll 100 connections which are ready in ready queue
             for _ in 0..100 {
                 match self.readyqueue.pop_front() {
@@ -132,14 +491,228 @@ impl Router {
         }
     }
 
+    /// Drops old segments of commitlogs as per retention policies and expired
+    /// sessions. Cursors of dropped segments continue from the oldest remaining records
+    fn clean(&mut self) {
+        let dropped = self.datalog.clean();
+        if dropped > 0 {
+            info!("{:11} {:14} Segments = {}", "retention", "clean", dropped);
+        }
+
+        if let Some(expiry) = self.config.session_expiry_secs {
+            for id in self.connectionslog.expire(Duration::from_secs(expiry)) {
+                info!("{:11} {:14} Id = {}", "session", "expired", id);
+                self.unsaved_sessions.remove(&id);
+                if let Some(storage) = self.storage.as_mut() {
+                    if let Err(e) = storage.delete(Table::Sessions, &id) {
+                        error!("Failed to delete session {}. Error = {:?}", id, e);
+                    }
+                }
+            }
+        }
+
+        self.next_clean = Some(Instant::now() + CLEAN_INTERVAL);
+    }
+
+    /// Publishes broker statistics to `$SYS` topics as retained publishes
+    fn publish_sys(&mut self) {
+        let now = Instant::now();
+        let (last, last_publishes, last_size) = self.sys_snapshot;
+        let elapsed = now.duration_since(last).as_secs_f64().max(1.0);
+        let publishes = self.metrics.total_publishes;
+        let size = self.metrics.total_publish_size;
+        let subscriptions: usize = self
+            .trackers
+            .iter()
+            .map(|(_, tracker)| tracker.subscription_count())
+            .sum();
+
+        let mut stats = vec![
+            ("broker/uptime".to_owned(), self.started.elapsed().as_secs()),
+            (
+                "broker/clients/connected".to_owned(),
+                self.connections.iter().count() as u64,
+            ),
+            (
+                "broker/subscriptions/count".to_owned(),
+                subscriptions as u64,
+            ),
+            ("broker/messages/received".to_owned(), publishes),
+            (
+                "broker/messages/sent".to_owned(),
+                self.metrics.total_deliveries,
+            ),
+            ("broker/bytes/received".to_owned(), size),
+            (
+                "broker/load/messages/received".to_owned(),
+                ((publishes - last_publishes) as f64 / elapsed) as u64,
+            ),
+            (
+                "broker/load/bytes/received".to_owned(),
+                ((size - last_size) as f64 / elapsed) as u64,
+            ),
+        ];
+
+        let mut topics: Vec<(String, u64)> = self
+            .datalog
+            .topics()
+            .into_iter()
+            .filter(|topic| !sys::is_sys(topic))
+            .filter_map(|topic| {
+                let appended = self.datalog.metrics(&topic)?.appended_count;
+                Some((topic, appended))
+            })
+            .collect();
+
+        stats.push(("broker/topics/count".to_owned(), topics.len() as u64));
+
+        // Records appended since the router started, only for the busiest
+        // topics so that statistics don't grow with the number of topics
+        topics.sort_by_key(|(_, appended)| Reverse(*appended));
+        topics.truncate(self.config.sys_topic_stats);
+        let mut sys_topics = HashSet::new();
+        for (topic, appended) in topics {
+            let name = format!("broker/topics/{}/messages", topic);
+            stats.push((name, appended));
+            sys_topics.insert(topic);
+        }
+
+        trace!("{:11} {:14} Count = {}", "sys", "publish", stats.len());
+        for (name, value) in stats {
+            let mut publish = Publish::new(sys::topic(&name), QoS::AtMostOnce, value.to_string());
+            publish.retain = true;
+            self.append_publish(publish);
+        }
+
+        // Statistics of topics which aren't among the busiest anymore are cleared
+        let dropped: Vec<String> = self.sys_topics.difference(&sys_topics).cloned().collect();
+        for topic in dropped {
+            let name = sys::topic(&format!("broker/topics/{}/messages", topic));
+            let mut publish = Publish::new(name, QoS::AtMostOnce, vec![]);
+            publish.retain = true;
+            self.append_publish(publish);
+        }
+
+        self.sys_topics = sys_topics;
+
+        self.sys_snapshot = (now, publishes, size);
+        if let Some(interval) = self.config.sys_interval_secs {
+            self.next_sys = Some(now + Duration::from_secs(interval));
+        }
+    }
+
+    /// Lag of replicas on the topics they pull. Lag is tracked by the
+    /// watermarks of replicator connections
+    fn replication_lag(&self) -> Vec<ReplicaMetrics> {
+        (0..self.config.replicas)
+            .map(|replica| {
+                let lag = self
+                    .watermarks
+                    .get(replica)
+                    .map(|watermarks| watermarks.lag(|topic| self.datalog.next_offset(topic)));
+
+                ReplicaMetrics {
+                    replica,
+                    connected: lag.is_some(),
+                    lag: lag.unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Alerts replicas which fell behind a topic by more than the threshold
+    fn check_lag(&mut self) {
+        let replicas = self.replication_lag();
+        let alert = match self.lag_alert.as_mut() {
+            Some(alert) => alert,
+            None => return,
+        };
+
+        for replica in replicas {
+            for (topic, lag) in replica.lag {
+                let key = (replica.replica, topic);
+                if lag <= alert.threshold {
+                    alert.lagging.remove(&key);
+                    continue;
+                }
+
+                if alert.lagging.insert(key.clone()) {
+                    warn!(
+                        "{:11} {:14} Replica = {}, Topic = {}, Lag = {}",
+                        "replication", "lag", key.0, key.1, lag
+                    );
+                    (alert.alert)(key.0, &key.1, lag);
+                }
+            }
+        }
+
+        self.next_lag_check = Some(Instant::now() + LAG_CHECK_INTERVAL);
+    }
+
     fn route(&mut self, id: usize, data: Event) {
         match data {
             Event::Connect(connection) => self.handle_new_connection(connection),
+            // Links of connections which are taken over might send data till they close
+            Event::Data(_) | Event::Ready if self.connections.get(id).is_none() => {
+                debug!("Event from disconnected connection. Id = {}", id);
+            }
             Event::Data(data) => self.handle_connection_data(id, data),
             Event::Disconnect(request) => self.handle_disconnection(id, request),
             Event::Ready => self.connection_ready(id, 100),
             Event::Metrics(metrics) => self.retrieve_metrics(id, metrics),
+            Event::DeleteTopic(topic) => self.delete_topic(id, topic),
+            Event::PurgeTopic(topic, offset) => self.purge_topic(id, topic, offset),
+        }
+    }
+
+    /// Deletes a topic along with its commitlog and retained publish. Trackers
+    /// forget the topic and match it again if it's published again
+    fn delete_topic(&mut self, id: ConnectionId, topic: String) {
+        if self.datalog.group(&topic).is_some() {
+            warn!(
+                "Topics of ordered groups share their group log. Topic = {}",
+                topic
+            );
+        } else if self.datalog.delete(&topic) {
+            info!("{:11} {:14} Topic = {}", "topic", "delete", topic);
+            for (_, tracker) in self.trackers.iter_mut() {
+                tracker.remove_topic(&topic);
+            }
+
+            self.data_waiters.remove_topic(&topic);
+            self.shared.remove_topic(&topic);
+            if let Some(storage) = self.storage.as_mut() {
+                if let Err(e) = storage.delete(Table::Retained, &topic) {
+                    error!(
+                        "Failed to delete retained publish {}. Error = {:?}",
+                        topic, e
+                    );
+                }
+            }
+        }
+
+        let message = Notification::Metrics(MetricsReply::Topic(self.datalog.metrics(&topic)));
+        notify(&mut self.connections, id, message);
+    }
+
+    /// Drops data of a topic before the offset to reclaim space. Cursors
+    /// of dropped data continue from the oldest remaining record
+    fn purge_topic(&mut self, id: ConnectionId, topic: String, offset: u64) {
+        if self.datalog.group(&topic).is_some() {
+            warn!(
+                "Topics of ordered groups share their group log. Topic = {}",
+                topic
+            );
+        } else {
+            let dropped = self.datalog.purge(&topic, offset);
+            info!(
+                "{:11} {:14} Topic = {}, Offset = {}, Segments = {}",
+                "topic", "purge", topic, offset, dropped
+            );
         }
+
+        let message = Notification::Metrics(MetricsReply::Topic(self.datalog.metrics(&topic)));
+        notify(&mut self.connections, id, message);
     }
 
     fn retrieve_metrics(&mut self, id: ConnectionId, metrics: MetricsRequest) {
@@ -149,35 +722,103 @@ impl Router {
                 Notification::Metrics(MetricsReply::Config(self.config.clone()))
             }
             MetricsRequest::Router => {
-                Notification::Metrics(MetricsReply::Router(self.metrics.clone()))
+                let mut metrics = self.metrics.clone();
+                metrics.total_connections = self.connections.iter().count();
+                metrics.total_topics = self.datalog.topics().len();
+                metrics.total_subscriptions = self
+                    .trackers
+                    .iter()
+                    .map(|(_, tracker)| tracker.subscription_count())
+                    .sum();
+                metrics.readyqueue_len = self.readyqueue.len();
+                Notification::Metrics(MetricsReply::Router(metrics))
             }
             MetricsRequest::Connection(device_id) => {
-                let tracker = match self.connectionslog.id(&device_id) {
-                    Some(id) => self.trackers.get_mut(id).cloned(),
-                    None => None,
+                let (tracker, inflight) = match self.connectionslog.id(&device_id) {
+                    Some(id) => {
+                        let inflight = self.connections.get(id).map(|connection| InflightMetrics {
+                            queued: connection.queued(),
+                            pending_acks: self.watermarks.get(id).map_or(0, |w| w.pending()),
+                            held_acks: self.watermarks.get(id).map_or(0, |w| w.held()),
+                            backlog: self.backlogs.get(id).map_or(0, |b| b.len()),
+                        });
+
+                        (self.trackers.get(id).cloned(), inflight)
+                    }
+                    None => (None, None),
                 };
 
-                Notification::Metrics(MetricsReply::Connection(ConnectionMetrics::new(
-                    device_id, tracker,
-                )))
+                let metrics = ConnectionMetrics::new(device_id, tracker, inflight);
+                Notification::Metrics(MetricsReply::Connection(Box::new(metrics)))
+            }
+            MetricsRequest::Topic(topic) => {
+                Notification::Metrics(MetricsReply::Topic(self.datalog.metrics(&topic)))
+            }
+            MetricsRequest::Replication => {
+                Notification::Metrics(MetricsReply::Replication(self.replication_lag()))
             }
         };
 
         notify(&mut self.connections, id, message);
     }
 
-    fn handle_new_connection(&mut self, connection: Connection) {
+    fn handle_new_connection(&mut self, mut connection: Connection) {
         let clean = connection.clean();
 
+        // Client id of a connected device is either taken over or rejected.
+        // Anonymous clients share the empty id but are separate clients
+        if let ConnectionType::Device(did) = &connection.conn {
+            let previous = self.connectionslog.id(did).filter(|_| !did.is_empty());
+            if let Some(previous) = previous.filter(|&id| self.is_connected(id, did)) {
+                if self.config.duplicate_client_id == DuplicateClientId::Reject {
+                    warn!("{:11} {:14} Id = {}", "connection", "duplicate", did);
+                    let reason = format!("Client id {} is already connected", did);
+                    if self.primary {
+                        let ack = ConnectionAck::Failure(reason);
+                        connection.notify(Notification::ConnectionAck(ack));
+                    }
+                    return;
+                }
+
+                info!(
+                    "{:11} {:14} Id = {}:{}",
+                    "connection", "takeover", did, previous
+                );
+                self.force_disconnect(previous, DisconnectReason::TakenOver);
+            }
+        }
+
         let (id, mut tracker, mut pending) = match connection.conn.clone() {
             ConnectionType::Replicator(id) => {
+                if id >= self.config.replicas {
+                    error!(
+                        "Replicator id {} beyond {} replicas",
+                        id, self.config.replicas
+                    );
+                    return;
+                }
+
                 info!("{:11} {:14} Id = {}", "connection", "replicator", id,);
                 self.connections.insert_at(connection, id);
-                (id, None, None)
+                self.readyqueue
+                    .set_weight(id, self.config.replicator_weight);
+
+                // Replicators resume from the cursors of the previous link to the peer
+                let (tracker, pending) = self.connectionslog.add(&replicator_id(id), id);
+                (id, tracker, pending)
             }
-            ConnectionType::Device(did) => match self.connections.insert(connection) {
+            ConnectionType::Device(did) => match self.insert_device(connection) {
                 Some(id) => {
                     info!("{:11} {:14} Id = {}:{}", "connection", "remote", did, id);
+                    if clean {
+                        self.unsaved_sessions.remove(&did);
+                        if let Some(storage) = self.storage.as_mut() {
+                            if let Err(e) = storage.delete(Table::Sessions, &did) {
+                                error!("Failed to delete session {}. Error = {:?}", did, e);
+                            }
+                        }
+                    }
+
                     let (tracker, pending) = self.connectionslog.add(&did, id);
                     (id, tracker, pending)
                 }
@@ -192,7 +833,21 @@ impl Router {
 
         // Add a new tracker or resume from previous topics and offsets
         match tracker.take() {
-            Some(tracker) if !clean => self.trackers.insert_at(tracker, id),
+            Some(mut tracker) if !clean => {
+                if let Some(max) = self.config.max_session_backlog {
+                    for request in tracker.data_requests_mut() {
+                        if self.datalog.skip_backlog(request, max) {
+                            let topic = &request.topic;
+                            warn!(
+                                "{:11} {:14} Id = {}, Topic = {}",
+                                "session", "skip", id, topic
+                            );
+                        }
+                    }
+                }
+
+                self.trackers.insert_at(tracker, id)
+            }
             _ => self.trackers.insert_at(Tracker::new(), id),
         }
 
@@ -201,11 +856,62 @@ impl Router {
             None => ConnectionAck::Success((id, previous_session, Vec::new())),
         };
 
-        self.watermarks.insert_at(Acks::new(), id);
+        let unreleased = self.trackers.get_mut(id).unwrap().take_unreleased();
+        self.watermarks
+            .insert_at(Acks::with_unreleased(unreleased), id);
+        self.backlogs.insert_at(VecDeque::new(), id);
+        self.subscriptions.insert_at(VecDeque::new(), id);
         self.readyqueue.push_back(id);
 
-        let message = Notification::ConnectionAck(ack);
-        notify(&mut self.connections, id, message);
+        if self.primary {
+            let message = Notification::ConnectionAck(ack);
+            notify(&mut self.connections, id, message);
+        }
+    }
+
+    /// Registers a device connection. Shards of a sharded router register
+    /// the connection at the id assigned by the sharded router
+    fn insert_device(&mut self, connection: Connection) -> Option<ConnectionId> {
+        match connection.id() {
+            Some(id) if self.connections.get(id).is_none() => {
+                self.connections.insert_at(connection, id);
+                Some(id)
+            }
+            Some(id) => {
+                error!("Connection id {} is already in use", id);
+                None
+            }
+            None => self.connections.insert(connection),
+        }
+    }
+
+    /// Checks if the connection with this id is of the client
+    fn is_connected(&self, id: ConnectionId, did: &str) -> bool {
+        match self.connections.get(id).map(|connection| &connection.conn) {
+            Some(ConnectionType::Device(device)) => device == did,
+            Some(ConnectionType::Replicator(replicator)) => replicator_id(*replicator) == did,
+            None => false,
+        }
+    }
+
+    /// Disconnects a connection from the router's side. Session of the
+    /// connection is saved like on an ungraceful disconnection
+    fn force_disconnect(&mut self, id: ConnectionId, reason: DisconnectReason) {
+        let did = match self.connections.get_mut(id) {
+            Some(connection) => {
+                connection.disconnect(reason);
+                match &connection.conn {
+                    ConnectionType::Device(did) => did.clone(),
+                    ConnectionType::Replicator(id) => replicator_id(*id),
+                }
+            }
+            None => return,
+        };
+
+        // Publishes inflight on the link aren't known to the router and
+        // aren't redelivered when the session resumes
+        let disconnect = Disconnection::new(did, true, Vec::new());
+        self.handle_disconnection(id, disconnect);
     }
 
     fn handle_disconnection(&mut self, id: ConnectionId, disconnect: Disconnection) {
@@ -213,23 +919,36 @@ impl Router {
         let execute_will = disconnect.execute_will;
         let pending = disconnect.pending;
 
+        // Disconnections of connections which are already taken over
+        if !self.is_connected(id, &did) {
+            debug!("{:11} {:14} Id = {}:{}", "disconnect", "stale", did, id);
+            return;
+        }
+
         info!("{:11} {:14} Id = {}:{}", "disconnect", "", did, id);
 
-        // Forward connection will
+        // Forward connection will. Wills aren't acked as there is no one to ack
         let mut connection = self.connections.remove(id).unwrap();
         let clean = connection.clean();
 
         if execute_will {
             if let Some(will) = connection.will() {
-                let publish = Publish::from_bytes(will.topic, will.qos, will.message);
-                self.handle_connection_publish(id, publish);
+                info!(
+                    "{:11} {:14} Id = {}, Topic = {}",
+                    "disconnect", "will", did, will.topic
+                );
+                let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+                publish.retain = will.retain;
+                self.append_publish(publish);
             }
         }
 
         let mut tracker = self.trackers.remove(id);
         let inflight_data_requests = self.data_waiters.remove(id);
         let mut inflight_topics_request = self.topics_waiters.remove(id);
-        self.watermarks.remove(id);
+        let watermarks = self.watermarks.remove(id);
+        self.backlogs.remove(id);
+        self.subscriptions.remove(id);
         self.readyqueue.remove(id);
 
         if !clean {
@@ -248,32 +967,142 @@ impl Router {
                 // TODO Get this from 'watermarks.remove'
                 tracker.register_acks_request();
 
+                // QoS 2 publishes redelivered by the resumed session are deduplicated
+                if let Some(mut watermarks) = watermarks {
+                    tracker.set_unreleased(watermarks.take_unreleased());
+                }
+
                 // Save tracker
                 self.connectionslog.save(&did, tracker, pending);
             }
         }
     }
 
+    /// Serves requests of a connection and applies its slow consumer policy
+    /// if the connection can't take all the notifications
     fn connection_ready(&mut self, id: ConnectionId, max_iterations: usize) {
+        let slow_consumer = self.connections.get(id).unwrap().slow_consumer();
+        let resumed = self.trackers.get(id).unwrap().busy_unschedule();
+        if resumed && slow_consumer == SlowConsumer::DropQos0 {
+            self.drop_qos0(id);
+        }
+
+        // Connections which overshot their quota in previous turns skip this turn
+        let quota = self.readyqueue.quota(id);
+        if quota <= 0 {
+            self.readyqueue.push_back(id);
+            return;
+        }
+
+        let work = self.serve_requests(id, max_iterations, quota as usize);
+        self.readyqueue.charge(id, work);
+
+        // Replicators pulling data might complete the replication of held publishes
+        if id < self.config.replicas {
+            self.commit_replicated();
+        }
+
+        let connection = self.connections.get(id).unwrap();
+        if !self.trackers.get(id).unwrap().busy_unschedule() || connection.closed() {
+            return;
+        }
+
+        self.metrics.slow_consumer_pauses += 1;
+        if slow_consumer == SlowConsumer::Disconnect {
+            warn!("{:11} {:14} Id = {}", "connection", "slow", id);
+            self.metrics.slow_consumer_disconnections += 1;
+            self.force_disconnect(id, DisconnectReason::SlowConsumer);
+        }
+    }
+
+    /// Seeks QoS 0 data requests of a connection to the end of their logs
+    fn drop_qos0(&mut self, id: ConnectionId) {
+        let tracker = self.trackers.get_mut(id).unwrap();
+        let mut dropped = 0;
+        for request in tracker.data_requests_mut().filter(|r| r.qos == 0) {
+            let offset = request.cursor.1;
+            if self.datalog.skip_backlog(request, 0) {
+                dropped += request.cursor.1.saturating_sub(offset);
+            }
+        }
+
+        if dropped > 0 {
+            debug!(
+                "{:11} {:14} Id = {}, Count = {}",
+                "data", "drop", id, dropped
+            );
+            self.metrics.slow_consumer_drops += dropped;
+        }
+    }
+
+    fn serve_requests(&mut self, id: ConnectionId, max_iterations: usize, quota: usize) -> usize {
         trace!("{:11} {:14} Id = {}", "requests", "start", id,);
         let tracker = self.trackers.get_mut(id).unwrap();
         if tracker.busy_unschedule() {
             tracker.set_busy_unschedule(false);
         }
 
+        // Deliver pending ordered data before serving new requests
+        let backlog = self.backlogs.get_mut(id).unwrap();
+        if notify_backlog(&mut self.connections, id, backlog) {
+            info!("Connection busy. Unschedule. Id = {}", id);
+            tracker.set_busy_unschedule(true);
+            return 0;
+        }
+
+        // Apply next batch of a bulk subscribe
+        if self.apply_subscriptions(id) {
+            info!("Connection busy. Unschedule. Id = {}", id);
+            let tracker = self.trackers.get_mut(id).unwrap();
+            tracker.set_busy_unschedule(true);
+            return 0;
+        }
+
+        let pending_subscriptions = !self.subscriptions.get(id).unwrap().is_empty();
+        let tracker = self.trackers.get_mut(id).unwrap();
+
         // Iterate through a max of 'max_iterations' requests everytime a connection.
         // if polled. This prevents a connection from unfairly taking up router's time
         // preventing other connections from making progress.
+        let mut work = 0;
         for _ in 0..max_iterations {
+            // Connections which used up their quota continue in their next turn
+            if work >= quota {
+                break;
+            }
+
             match tracker.pop_request() {
                 Some(request) => match request {
-                    Request::Data(request) => {
+                    Request::Data(mut request) => {
+                        // Requests on topics of an ordered group are served by the group log
+                        if let Some(group) = self.datalog.group(&request.topic) {
+                            tracker.track_ordered(group, request);
+                            continue;
+                        }
+
+                        // Members of a shared group pull with the cursor of the group
+                        let group = tracker.shared_group(&request.topic).map(str::to_owned);
+                        if let Some(group) = &group {
+                            let cursor = request.cursor;
+                            request.cursor = self.shared.cursor(group, &request.topic, cursor);
+                        }
+
+                        // Replicators have pulled everything before the cursor
+                        let replicator = id < self.config.replicas;
+                        if replicator {
+                            let watermarks = self.watermarks.get_mut(id).unwrap();
+                            watermarks.update_cursor(&request.topic, request.cursor);
+                        }
+
                         let datalog = &mut self.datalog;
                         let waiters = &mut self.data_waiters;
 
                         // Get data from commitlog and register for notification if
                         // all the data is caught up.
                         if let Some(data) = handle_data_request(id, request, datalog, waiters) {
+                            self.metrics.total_deliveries += data.payload.len() as u64;
+                            work += data.payload.len();
+
                             // If data is yielded by commitlog, register a new data request
                             // in the tracker with next offset and send data notification to
                             // the connection
@@ -282,16 +1111,36 @@ impl Router {
                             let cursors = data.cursor;
                             let last_retain = data.last_retain;
 
+                            if let Some(group) = &group {
+                                self.shared.update(id, group, &topic, cursors);
+                            }
+
+                            if replicator {
+                                let watermarks = self.watermarks.get_mut(id).unwrap();
+                                watermarks.update_cursor(&topic, cursors);
+                            }
+
+                            // Group log data is split back into per topic data and delivered in order
+                            let pause = match tracker.ordered_topics(&topic) {
+                                Some(topics) => {
+                                    let backlog = self.backlogs.get_mut(id).unwrap();
+                                    backlog.extend(ordered::split(data, topics));
+                                    notify_backlog(&mut self.connections, id, backlog)
+                                }
+                                None => {
+                                    let notification = Notification::Data(data);
+                                    notify(&mut self.connections, id, notification)
+                                }
+                            };
+
                             let request = DataRequest::offsets(topic, qos, cursors, last_retain);
                             tracker.register_data_request(request);
-                            let notification = Notification::Data(data);
-                            let pause = notify(&mut self.connections, id, notification);
 
                             // This connection might not be able to process next request. Don't schedule
                             if pause {
                                 info!("Connection busy. Unschedule. Id = {}", id);
                                 tracker.set_busy_unschedule(true);
-                                return;
+                                return work;
                             }
                         }
                     }
@@ -315,6 +1164,7 @@ impl Router {
                         // If acks are yielded, register a new acks request
                         // and send acks notification to the connection
                         if let Some(acks) = handle_acks_request(id, acks) {
+                            work += acks.len();
                             tracker.register_acks_request();
                             let notification = Notification::Acks(acks);
                             let pause = notify(&mut self.connections, id, notification);
@@ -323,11 +1173,13 @@ impl Router {
                             if pause {
                                 info!("Connection busy/closed. Unschedule. Id = {}", id);
                                 tracker.set_busy_unschedule(true);
-                                return;
+                                return work;
                             }
                         }
                     }
                 },
+                // Connection stays scheduled to apply rest of the subscriptions
+                None if pending_subscriptions => break,
                 None => {
                     // At this point, pending requests in tracker is 0. Early return
                     // here prevents connection from being added to ready queue again.
@@ -336,7 +1188,7 @@ impl Router {
                     // again, it's scheduled back on ready queue again.
                     trace!("{:11} {:14} Id = {}", "requests", "done", id);
                     tracker.set_empty_unschedule(true);
-                    return;
+                    return work;
                 }
             }
         }
@@ -345,6 +1197,7 @@ impl Router {
         // to ready queue.
         trace!("{:11} {:14} Id = {}", "requests", "pause", id,);
         self.readyqueue.push_back(id);
+        work
     }
 
     /// Handles new incoming data on a topic
@@ -358,8 +1211,16 @@ impl Router {
         );
 
         for publish in data {
+            // Rest of the batch is dropped once the connection is disconnected
+            // (e.g. a publish beyond limits)
+            if self.connections.get(id).is_none() {
+                warn!("{:11} {:14} Id = {}", "data", "disconnected", id);
+                break;
+            }
+
             match publish {
                 Packet::Publish(publish) => self.handle_connection_publish(id, publish),
+                Packet::PubRel(pubrel) => self.handle_connection_pubrel(id, pubrel),
                 Packet::Subscribe(subscribe) => self.handle_connection_subscribe(id, subscribe),
                 Packet::Unsubscribe(unsubscribe) => {
                     self.handle_connection_unsubscribe(id, unsubscribe)
@@ -373,6 +1234,20 @@ impl Router {
         trace!("{:11} {:14} Id = {}", "data", "committed", id,);
     }
 
+    /// Releases the packet id of a QoS 2 publish and completes it
+    fn handle_connection_pubrel(&mut self, id: ConnectionId, pubrel: PubRel) {
+        trace!(
+            "{:11} {:14} Id = {} Pkid = {}",
+            "data",
+            "pubrel",
+            id,
+            pubrel.pkid
+        );
+        let watermarks = self.watermarks.get_mut(id).unwrap();
+        watermarks.push_release_ack(pubrel.pkid);
+        self.fresh_acks_notification(id);
+    }
+
     fn handle_connection_subscribe(&mut self, id: ConnectionId, subscribe: Subscribe) {
         trace!(
             "{:11} {:14} Id = {} Filters = {:?}",
@@ -382,18 +1257,127 @@ impl Router {
             subscribe.filters
         );
 
-        let topics = self.topicslog.readv(0, 0);
-        let tracker = self.trackers.get_mut(id).unwrap();
+        // Subscriptions of the connection including the ones yet to be applied
+        let tracker = self.trackers.get(id).unwrap();
+        let pending = self.subscriptions.get(id).unwrap();
+        let mut count = tracker.subscription_count();
+        count += pending.iter().map(|p| p.filters.len()).sum::<usize>();
 
         let mut return_codes = Vec::new();
-        for filter in subscribe.filters.iter() {
-            if filter.path.starts_with("test") || filter.path.starts_with("$") {
+        let mut filters = VecDeque::new();
+        for filter in subscribe.filters.into_iter() {
+            // New filters beyond the limit are rejected and not applied
+            let new = !tracker.has_subscription(&filter.path);
+            let max = self.config.max_subscriptions;
+            if new && max.map_or(false, |max| count >= max) {
+                warn!(
+                    "Subscription limit. ID = {:?}, filter = {:?}",
+                    id, filter.path
+                );
                 return_codes.push(SubscribeReasonCode::Failure);
-            } else {
+                continue;
+            }
+
+            let path = match shared::split(&filter.path) {
+                Some((_, path)) => path,
+                None => &filter.path,
+            };
+
+            if path.starts_with("test") || (path.starts_with('$') && !sys::is_sys(path)) {
+                return_codes.push(SubscribeReasonCode::Failure);
+            } else {
                 return_codes.push(SubscribeReasonCode::Success(filter.qos));
             }
+
+            count += new as usize;
+            filters.push_back(filter);
+        }
+
+        // Subscribes are applied in order. Big subscribes (e.g. bridges subscribing to
+        // thousands of filters) are applied in batches across router iterations to not
+        // stall other connections
+        let pending = PendingSubscribe {
+            pkid: subscribe.pkid,
+            total: filters.len(),
+            filters,
+            return_codes,
+        };
+
+        self.subscriptions.get_mut(id).unwrap().push_back(pending);
+        if self.apply_subscriptions(id) {
+            let tracker = self.trackers.get_mut(id).unwrap();
+            tracker.set_busy_unschedule(true);
+            return;
+        }
+
+        // Schedule the connection to apply rest of the filters
+        let tracker = self.trackers.get_mut(id).unwrap();
+        if !self.subscriptions.get(id).unwrap().is_empty() && tracker.empty_unschedule() {
+            self.readyqueue.push_back(id);
+            tracker.set_empty_unschedule(false);
+        }
+    }
+
+    /// Applies a batch of pending subscription filters of this connection. Suback is
+    /// sent when all the filters of a subscribe are applied. Progress is notified
+    /// for partially applied subscribes. Returns true if the connection is busy
+    fn apply_subscriptions(&mut self, id: ConnectionId) -> bool {
+        let mut budget = self.config.max_subscription_batch.max(1);
+        while budget > 0 {
+            let pending = match self.subscriptions.get_mut(id).unwrap().front_mut() {
+                Some(pending) => pending,
+                None => return false,
+            };
+
+            let count = budget.min(pending.filters.len());
+            let filters: Vec<SubscribeFilter> = pending.filters.drain(..count).collect();
+            let progress = SubscribeProgress {
+                pkid: pending.pkid,
+                applied: pending.total - pending.filters.len(),
+                total: pending.total,
+            };
+
+            budget -= count;
+            self.add_subscriptions(id, filters);
+
+            if progress.applied < progress.total {
+                trace!(
+                    "{:11} {:14} Id = {} Applied = {}/{}",
+                    "data",
+                    "subscribe",
+                    id,
+                    progress.applied,
+                    progress.total
+                );
+
+                if !self.primary {
+                    return false;
+                }
+
+                let notification = Notification::SubscribeProgress(progress);
+                return notify(&mut self.connections, id, notification);
+            }
+
+            let pending = self.subscriptions.get_mut(id).unwrap().pop_front().unwrap();
+            self.persist_session(id);
+            if !self.primary {
+                continue;
+            }
+
+            // Update acks and triggers acks notification for suback
+            let watermarks = self.watermarks.get_mut(id).unwrap();
+            watermarks.push_subscribe_ack(pending.pkid, pending.return_codes);
+            self.fresh_acks_notification(id);
         }
 
+        false
+    }
+
+    /// Adds subscriptions to the tracker and tracks matching topics
+    fn add_subscriptions(&mut self, id: ConnectionId, filters: Vec<SubscribeFilter>) {
+        let topics = self.topicslog.readv(0, 0);
+        let tracker = self.trackers.get_mut(id).unwrap();
+
         // A new subscription should match with all the existing topics and take a snapshot of current
         // offset of all the matched topics. Subscribers will receive data from the next offset
         match topics {
@@ -402,7 +1386,7 @@ impl Router {
                 // in the (topics) commitlog ant seek them to next offset. Add subscriptions
                 // and store matched topics interna. If this is the first subscription,
                 // register topics request
-                if tracker.add_subscription_and_match(subscribe.filters, topics) {
+                if tracker.add_subscription_and_match(filters, topics) {
                     tracker.register_topics_request(TopicsRequest::offset(topics.len()));
 
                     // If connection is removed from ready queue because of 0 requests,
@@ -420,7 +1404,8 @@ impl Router {
                 while let Some(mut topic) = tracker.next_matched() {
                     self.datalog.seek_offsets_to_end(&mut topic);
                     let (topic, qos, cursors) = (topic.0, topic.1, topic.2);
-                    let request = DataRequest::offsets(topic, qos, cursors, 0);
+                    let mut request = DataRequest::offsets(topic, qos, cursors, 0);
+                    request.subscribed = true;
                     tracker.register_data_request(request);
 
                     // If connection is removed from ready queue because of 0 requests,
@@ -435,7 +1420,7 @@ impl Router {
             None => {
                 // Router did not receive data from any topics yet. Add subscription and
                 // register topics request from offset 0
-                if tracker.add_subscription_and_match(subscribe.filters, &[]) {
+                if tracker.add_subscription_and_match(filters, &[]) {
                     tracker.register_topics_request(TopicsRequest::offset(0));
 
                     // If connection is removed from ready queue because of 0 requests,
@@ -448,11 +1433,6 @@ impl Router {
                 }
             }
         };
-
-        // Update acks and triggers acks notification for suback
-        let watermarks = self.watermarks.get_mut(id).unwrap();
-        watermarks.push_subscribe_ack(subscribe.pkid, return_codes);
-        self.fresh_acks_notification(id);
     }
 
     fn handle_connection_unsubscribe(&mut self, id: ConnectionId, unsubscribe: Unsubscribe) {
@@ -464,6 +1444,14 @@ impl Router {
             unsubscribe.topics
         );
 
+        // Filters which are yet to be applied shouldn't be applied anymore
+        for pending in self.subscriptions.get_mut(id).unwrap().iter_mut() {
+            let topics = &unsubscribe.topics;
+            pending
+                .filters
+                .retain(|filter| !topics.contains(&filter.path));
+        }
+
         let tracker = self.trackers.get_mut(id).unwrap();
         let inflight = tracker.remove_subscription_and_unmatch(unsubscribe.topics);
 
@@ -473,6 +1461,11 @@ impl Router {
             }
         }
 
+        self.persist_session(id);
+        if !self.primary {
+            return;
+        }
+
         // Update acks and triggers acks notification for suback
         let watermarks = self.watermarks.get_mut(id).unwrap();
         watermarks.push_unsubscribe_ack(unsubscribe.pkid);
@@ -480,48 +1473,133 @@ impl Router {
     }
 
     fn handle_connection_publish(&mut self, id: ConnectionId, publish: Publish) {
+        let (pkid, qos) = (publish.pkid, publish.qos);
+        if sys::is_sys(&publish.topic) {
+            warn!(
+                "Publish on statistics topic. ID = {:?}, topic = {:?}",
+                id, publish.topic
+            );
+            return;
+        }
+
+        if publish.payload.is_empty() && !publish.retain {
+            warn!("Empty publish. ID = {:?}, topic = {:?}", id, publish.topic);
+            // Some tests in paho test suite are sending empty publishes.
+            // Disabling this filter for the time being
+            // return;
+        }
+
+        if let Some(reason) = self.publish_limit(&publish) {
+            warn!(
+                "Publish beyond limits. ID = {:?}, topic = {:?}, reason = {:?}",
+                id, publish.topic, reason
+            );
+            self.force_disconnect(id, reason);
+            return;
+        }
+
+        // QoS 0 publishes aren't acked. Replicas and persistent sessions pull them from
+        // the commitlog like everyone else. So there is no watermark bookkeeping for them
+        if qos == QoS::AtMostOnce {
+            self.append_publish(publish);
+            return;
+        }
+
+        // Redeliveries of QoS 2 publishes which aren't released yet are only acked
+        let watermarks = self.watermarks.get_mut(id).unwrap();
+        if qos == QoS::ExactlyOnce && watermarks.is_unreleased(pkid) {
+            debug!(
+                "{:11} {:14} Id = {}, Pkid = {}",
+                "data", "duplicate", id, pkid
+            );
+            watermarks.push_publish_ack(pkid, 2);
+            self.fresh_acks_notification(id);
+            return;
+        }
+
+        let mode = self.ack_mode(id, &publish.topic);
+        let (log, offset) = match self.append_publish(publish) {
+            Some(appended) => appended,
+            None => return,
+        };
+
+        let watermarks = self.watermarks.get_mut(id).unwrap();
+        match offset {
+            Some(offset) if mode == AckMode::Replicated => {
+                watermarks.push_replicated_ack(pkid, qos as u8, log, offset);
+                self.replicating.insert(id);
+            }
+            _ => watermarks.push_publish_ack(pkid, qos as u8),
+        }
+
+        // Data from topics with replication factor = 0 should be acked immediately if there are
+        // waiters registered. We shouldn't rely on replication acks for data acks in this case
+        self.fresh_acks_notification(id);
+    }
+
+    /// Checks the publish against payload size and topic count limits
+    fn publish_limit(&self, publish: &Publish) -> Option<DisconnectReason> {
+        let payload_size = publish.payload.len();
+        if self
+            .config
+            .max_payload_size
+            .map_or(false, |max| payload_size > max)
+        {
+            return Some(DisconnectReason::PayloadLimit);
+        }
+
+        let full = self
+            .config
+            .max_topics
+            .map_or(false, |max| self.datalog.count() >= max);
+        if full && self.datalog.is_new(&publish.topic) {
+            return Some(DisconnectReason::TopicLimit);
+        }
+
+        None
+    }
+
+    /// Appends the publish to its commitlog and notifies waiters. Retained publishes
+    /// replace the retained publish of the topic. Returns the log of the publish and
+    /// its offset in the log. Retained publishes have no offset. None if the append failed
+    fn append_publish(&mut self, publish: Publish) -> Option<(String, Option<u64>)> {
         let Publish {
-            pkid,
             topic,
             payload,
-            qos,
             retain,
             ..
         } = publish;
 
-        let is_new_topic = if retain {
-            let is_new_topic = match self.datalog.retain(&topic, payload) {
-                Some(v) => v,
-                None => return,
-            };
+        // Statistics are neither persisted nor counted as publishes
+        let stats = sys::is_sys(&topic);
+        let size = payload.len() as u64;
+        let (is_new_topic, offset) = if retain {
+            // Empty retained publish clears the retained message
+            if let Some(storage) = self.storage.as_mut().filter(|_| !stats) {
+                let result = match payload.is_empty() {
+                    true => storage.delete(Table::Retained, &topic),
+                    false => storage.put(Table::Retained, &topic, &payload),
+                };
 
-            if qos as u8 > 0 {
-                let watermarks = self.watermarks.get_mut(id).unwrap();
-                watermarks.push_publish_ack(pkid, qos as u8);
+                if let Err(e) = result {
+                    error!(
+                        "Failed to persist retained publish {}. Error = {:?}",
+                        topic, e
+                    );
+                }
             }
 
-            is_new_topic
+            (self.datalog.retain(&topic, payload)?, None)
         } else {
-            if payload.is_empty() {
-                warn!("Empty publish. ID = {:?}, topic = {:?}", id, topic);
-                // Some tests in paho test suite are sending empty publishes.
-                // Disabling this filter for the time being
-                // return;
-            }
-
-            let (is_new_topic, _) = match self.datalog.append(&topic, payload) {
-                Some(v) => v,
-                None => return,
-            };
-
-            if qos as u8 > 0 {
-                let watermarks = self.watermarks.get_mut(id).unwrap();
-                watermarks.push_publish_ack(pkid, qos as u8);
-            }
-
-            is_new_topic
+            let (is_new_topic, (_, offset)) = self.datalog.append(&topic, payload)?;
+            (is_new_topic, Some(offset))
         };
 
+        if !stats {
+            self.metrics.total_publishes += 1;
+            self.metrics.total_publish_size += size;
+        }
+
         // If there is a new unique append, send it to connection waiting on it
         // This is equivalent to hybrid of block and poll and we don't need timers.
         // Connections/Replicator will make a request and request might fail as
@@ -535,12 +1613,80 @@ impl Router {
             self.fresh_topics_notification();
         }
 
-        // Notify waiters on this topic of new data
-        self.fresh_data_notification(&topic);
+        // Notify waiters on this topic of new data. Waiters on topics of ordered
+        // groups wait on the group log
+        let log = match self.datalog.group(&topic) {
+            Some(group) => group.to_owned(),
+            None => topic,
+        };
 
-        // Data from topics with replication factor = 0 should be acked immediately if there are
-        // waiters registered. We shouldn't rely on replication acks for data acks in this case
-        self.fresh_acks_notification(id);
+        self.fresh_data_notification(&log);
+        Some((log, offset))
+    }
+
+    /// Ack mode of a publish by the connection on the topic. Publishes are
+    /// acked on append when there are no replicas to wait for
+    fn ack_mode(&self, id: ConnectionId, topic: &str) -> AckMode {
+        if self.ack_quorum() == 0 {
+            return AckMode::Append;
+        }
+
+        if let Some(policy) = self.config.ack_modes.iter().find(|p| p.applies(topic)) {
+            return policy.mode;
+        }
+
+        match self.connections.get(id) {
+            Some(connection) => connection.ack_mode(),
+            None => AckMode::Append,
+        }
+    }
+
+    fn ack_quorum(&self) -> usize {
+        self.config.ack_quorum.min(self.config.replicas)
+    }
+
+    /// Commits held acks of publishes which are pulled by a quorum of replicas
+    fn commit_replicated(&mut self) {
+        if self.replicating.is_empty() {
+            return;
+        }
+
+        // Cursors of replicas on every log, furthest first
+        let mut cursors: HashMap<String, Vec<u64>> = HashMap::new();
+        for replica in 0..self.config.replicas {
+            if let Some(watermarks) = self.watermarks.get(replica) {
+                for (log, cursor) in watermarks.cursors() {
+                    cursors.entry(log.clone()).or_default().push(cursor.1);
+                }
+            }
+        }
+
+        // Offset of every log before which `quorum` replicas have pulled
+        let quorum = self.ack_quorum();
+        let replicated: HashMap<String, u64> = cursors
+            .into_iter()
+            .filter_map(|(log, mut offsets)| {
+                offsets.sort_unstable_by(|a, b| b.cmp(a));
+                let offset = *offsets.get(quorum.checked_sub(1)?)?;
+                Some((log, offset))
+            })
+            .collect();
+
+        let replicating: Vec<ConnectionId> = self.replicating.drain().collect();
+        for id in replicating {
+            let (committed, held) = match self.watermarks.get_mut(id) {
+                Some(watermarks) => (watermarks.commit_replicated(&replicated), watermarks.held()),
+                None => continue,
+            };
+
+            if held > 0 {
+                self.replicating.insert(id);
+            }
+
+            if committed {
+                self.fresh_acks_notification(id);
+            }
+        }
     }
 
     /// Send notifications to links which registered them. Id is only used to
@@ -579,7 +1725,9 @@ impl Router {
         waiters.prepare_next();
     }
 
-    /// Send data to links which registered them
+    /// Send data to links which registered them. Only one member of a shared
+    /// group is woken, preferring the longest waiting one which didn't receive
+    /// the last data of the group. Rest of the group keeps waiting
     fn fresh_data_notification(&mut self, topic: &str) {
         // There might not be any waiters on this topic
         // FIXME: Every notification trigger is a hashmap lookup
@@ -588,9 +1736,40 @@ impl Router {
             None => return,
         };
 
+        let mut woken = HashSet::new();
+        let mut wake = Vec::new();
+        let mut last = Vec::new();
         while let Some((link_id, request)) = waiters.pop_front() {
-            let tracker = self.trackers.get_mut(link_id).unwrap();
+            let tracker = self.trackers.get(link_id).unwrap();
+            if let Some(group) = tracker.shared_group(&request.topic) {
+                if self.shared.last(group) == Some(link_id) {
+                    last.push((link_id, request));
+                    continue;
+                }
 
+                if !woken.insert(group.to_owned()) {
+                    waiters.push_back(link_id, request);
+                    continue;
+                }
+            }
+
+            wake.push((link_id, request));
+        }
+
+        // Last receiver of a group is woken only when no other member is waiting
+        for (link_id, request) in last {
+            let tracker = self.trackers.get(link_id).unwrap();
+            let group = tracker.shared_group(&request.topic).unwrap();
+            match woken.insert(group.to_owned()) {
+                true => wake.push((link_id, request)),
+                false => waiters.push_back(link_id, request),
+            }
+        }
+
+        waiters.prepare_next();
+
+        for (link_id, request) in wake {
+            let tracker = self.trackers.get_mut(link_id).unwrap();
             let topic = request.topic;
             let qos = request.qos;
             let cursors = request.cursor;
@@ -607,8 +1786,6 @@ impl Router {
                 tracker.set_empty_unschedule(false);
             }
         }
-
-        waiters.prepare_next();
     }
 
     fn fresh_acks_notification(&mut self, id: ConnectionId) {
@@ -743,6 +1920,43 @@ fn handle_acks_request(id: ConnectionId, acks: &mut Acks) -> Option<Vec<Packet>>
     Some(acks)
 }
 
+/// Callback with the replica, topic and lag of a lagging replica
+type Alert = Box<dyn FnMut(usize, &str, u64) + Send>;
+
+/// Alert on replicas lagging behind a topic by more than `threshold` records
+struct LagAlert {
+    threshold: u64,
+    alert: Alert,
+    /// Replica and topic pairs which are alerted. Pairs are alerted
+    /// again after they catch up
+    lagging: HashSet<(usize, String)>,
+}
+
+/// Subscribe which is applied to the tracker over multiple router iterations
+struct PendingSubscribe {
+    pkid: u16,
+    filters: VecDeque<SubscribeFilter>,
+    return_codes: Vec<SubscribeReasonCode>,
+    total: usize,
+}
+
+/// Notifies pending ordered data one after another and returns unschedule
+/// status if the connection is busy
+fn notify_backlog(
+    connections: &mut Slab<Connection>,
+    id: ConnectionId,
+    backlog: &mut VecDeque<Data>,
+) -> bool {
+    while let Some(data) = backlog.pop_front() {
+        let notification = Notification::Data(data);
+        if notify(connections, id, notification) {
+            return true;
+        }
+    }
+
+    false
+}
+
 /// Notifies and returns unschedule status if the connection is busy
 fn notify(connections: &mut Slab<Connection>, id: ConnectionId, reply: Notification) -> bool {
     let connection = match connections.get_mut(id) {
@@ -759,11 +1973,13 @@ fn notify(connections: &mut Slab<Connection>, id: ConnectionId, reply: Notificat
 #[cfg(test)]
 mod test {
     use super::*;
-    use mqttbytes::*;
+    use crate::logs::disk::{self, RetentionConfig};
+    use crate::AckModeConfig;
+    use mqttbytes::v4::{LastWill, PubAck, PubComp, PubRec};
 
     #[test]
     fn topics_notifications_does_not_create_infinite_loops() {
-        let (mut router, _tx) = Router::new(Arc::new(Config::default()));
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
 
         // Instantiate replica connections with subscriptions and topic request notifications
         for i in 10..20 {
@@ -784,7 +2000,7 @@ mod test {
 
     #[test]
     fn data_notifications_does_not_create_infinite_loops() {
-        let (mut router, _tx) = Router::new(Arc::new(Config::default()));
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
 
         // Instantiate replica connections with subscriptions and topic request notifications
         for i in 10..20 {
@@ -814,7 +2030,7 @@ mod test {
         let mut config = Config::default();
         config.id = 0;
 
-        let (mut router, _tx) = Router::new(Arc::new(config));
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
         let _rx = add_new_remote_connection(&mut router, "10");
 
         // Register 20 data requests in notifications
@@ -847,6 +2063,1157 @@ mod test {
         assert!(router.readyqueue.is_empty());
     }
 
+    #[test]
+    fn sessions_and_retained_publishes_are_restored_from_storage() {
+        let config = Config {
+            storage: Some(storage::StorageConfig {
+                backend: storage::Backend::Memory,
+                path: Default::default(),
+            }),
+            ..Config::default()
+        };
+
+        let config = Arc::new(config);
+        let (mut router, _tx) = Router::new(config.clone()).unwrap();
+        let (connection, _rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        add_new_subscription(&mut router, 10, "hello/+/world");
+
+        let mut publish = Publish::new("hello/1/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        publish.retain = true;
+        router.handle_connection_data(10, vec![Packet::Publish(publish)]);
+        router.save_sessions();
+
+        // New router with storage of the previous router. Simulates a restart
+        let (mut restarted, _tx) = Router::new(config).unwrap();
+        restarted.storage = router.storage.take();
+        restarted.restore().unwrap();
+
+        let (_, topics) = restarted.topicslog.readv(0, 0).unwrap();
+        assert_eq!(topics, ["hello/1/world".to_owned()]);
+
+        let (connection, rx) = Connection::new_remote("device-1", false, 10);
+        restarted.handle_new_connection(connection);
+        match rx.recv().unwrap() {
+            Notification::ConnectionAck(ConnectionAck::Success((_, session, _))) => {
+                assert!(session)
+            }
+            notification => panic!("Unexpected notification = {:?}", notification),
+        }
+
+        let tracker = restarted.trackers.get(10).unwrap();
+        assert_eq!(
+            tracker.subscriptions(),
+            vec![("hello/+/world".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn routers_fail_when_logs_or_storage_can_not_be_opened() {
+        // Commitlog directory is a file
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("commitlog"), b"").unwrap();
+        let config = Config {
+            dir: dir.path().to_owned(),
+            disk: Some(Default::default()),
+            ..Config::default()
+        };
+        let router = Router::new(Arc::new(config));
+        assert!(matches!(router, Err(StorageError::Io(_))));
+
+        let config = Config {
+            storage: Some(storage::StorageConfig {
+                backend: storage::Backend::Sqlite,
+                path: dir.path().to_owned(),
+            }),
+            ..Config::default()
+        };
+        assert!(Router::new(Arc::new(config)).is_err());
+    }
+
+    #[test]
+    fn session_changes_are_saved_in_batches() {
+        let config = Config {
+            storage: Some(storage::StorageConfig {
+                backend: storage::Backend::Memory,
+                path: Default::default(),
+            }),
+            ..Config::default()
+        };
+
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        for client_id in ["device-1", "device-2"].iter() {
+            let (connection, _rx) = Connection::new_remote(client_id, false, 10);
+            router.handle_new_connection(connection);
+            let id = router.connectionslog.id(client_id).unwrap();
+            add_new_subscription(&mut router, id, "hello/1");
+            add_new_subscription(&mut router, id, "hello/2");
+        }
+
+        let storage = router.storage.as_mut().unwrap();
+        assert!(storage.scan(Table::Sessions).unwrap().is_empty());
+
+        // Clean session of a client discards its unsaved changes
+        let (connection, _rx) = Connection::new_remote("device-2", true, 10);
+        router.handle_new_connection(connection);
+        router.save_sessions();
+
+        let storage = router.storage.as_mut().unwrap();
+        let sessions = storage.scan(Table::Sessions).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].0, "device-1");
+        let session = Session::decode(&sessions[0].1).unwrap();
+        assert_eq!(session.subscriptions.len(), 2);
+    }
+
+    #[test]
+    fn retained_publishes_are_delivered_to_new_subscriptions() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "10");
+        let mut publish = Publish::new("hello/1/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        publish.retain = true;
+        router.handle_connection_data(10, vec![Packet::Publish(publish)]);
+
+        let (connection, rx) = Connection::new_remote("20", true, 10);
+        router.handle_new_connection(connection);
+        add_new_subscription(&mut router, 11, "hello/+/world");
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        let mut data = None;
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Data(reply) = notification {
+                data = Some(reply);
+            }
+        }
+
+        let data = data.unwrap();
+        assert!(data.retained);
+        assert_eq!(data.payload, vec![Bytes::from(vec![1, 2, 3])]);
+
+        // Empty retained publish deletes the retained publish
+        let mut publish = Publish::new("hello/1/world", QoS::AtLeastOnce, vec![]);
+        publish.retain = true;
+        router.handle_connection_data(10, vec![Packet::Publish(publish)]);
+        let request = DataRequest::offsets("hello/1/world".to_owned(), 1, (0, 0), 0);
+        assert!(router.datalog.extract_data(&request).is_none());
+    }
+
+    #[test]
+    fn retained_publishes_after_a_subscription_are_not_flagged() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "10");
+        let publish = Publish::new("hello/1/world", QoS::AtLeastOnce, vec![1]);
+        router.handle_connection_data(10, vec![Packet::Publish(publish)]);
+
+        let rx = add_new_remote_connection(&mut router, "20");
+        add_new_subscription(&mut router, 11, "hello/+/world");
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        // Retains of an existing topic and of a new topic arrive after the subscription
+        for topic in ["hello/1/world", "hello/2/world"].iter() {
+            let mut publish = Publish::new(*topic, QoS::AtLeastOnce, vec![2]);
+            publish.retain = true;
+            router.handle_connection_data(10, vec![Packet::Publish(publish)]);
+        }
+
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        let mut topics = Vec::new();
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Data(data) = notification {
+                assert!(
+                    !data.retained,
+                    "Live retain flagged. Topic = {}",
+                    data.topic
+                );
+                assert_eq!(data.payload.last(), Some(&Bytes::from(vec![2])));
+                topics.push(data.topic);
+            }
+        }
+
+        topics.sort();
+        assert_eq!(topics, vec!["hello/1/world", "hello/2/world"]);
+    }
+
+    #[test]
+    fn wills_are_published_only_on_ungraceful_disconnections() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        for (client_id, execute_will) in [("10", true), ("11", false)].iter() {
+            let (mut connection, _rx) = Connection::new_remote(client_id, true, 10);
+            let topic = format!("will/{}", client_id);
+            connection.set_will(LastWill::new(topic, vec![1], QoS::AtLeastOnce, true));
+            router.handle_new_connection(connection);
+
+            let id = router.connectionslog.id(client_id).unwrap();
+            let disconnect = Disconnection::new(client_id.to_string(), *execute_will, vec![]);
+            router.handle_disconnection(id, disconnect);
+        }
+
+        // will of the ungraceful disconnection is retained
+        let mut request = DataRequest::offsets("will/10".to_owned(), 1, (0, 0), 0);
+        request.subscribed = true;
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert!(data.retained);
+        assert_eq!(data.payload, vec![Bytes::from(vec![1])]);
+
+        let request = DataRequest::offsets("will/11".to_owned(), 1, (0, 0), 0);
+        assert!(router.datalog.extract_data(&request).is_none());
+    }
+
+    #[test]
+    fn lagging_sessions_skip_backlog_and_expire() {
+        let config = Config {
+            max_session_backlog: Some(2),
+            session_expiry_secs: Some(0),
+            ..Config::default()
+        };
+
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device-2");
+        let publisher = router.connectionslog.id("device-2").unwrap();
+        let publish = Publish::new("hello/world", QoS::AtMostOnce, vec![1, 2, 3]);
+        router.handle_connection_data(publisher, vec![Packet::Publish(publish)]);
+
+        let (connection, _rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device-1").unwrap();
+        add_new_subscription(&mut router, id, "hello/world");
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        let disconnect = Disconnection::new("device-1".to_owned(), false, vec![]);
+        router.handle_disconnection(id, disconnect);
+        for _ in 0..5 {
+            let publish = Publish::new("hello/world", QoS::AtMostOnce, vec![1, 2, 3]);
+            router.handle_connection_data(publisher, vec![Packet::Publish(publish)]);
+        }
+
+        // resumed session skips the 5 records published while it was away
+        let (connection, _rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device-1").unwrap();
+        let tracker = router.trackers.get_mut(id).unwrap();
+        let request = tracker.data_requests_mut().next().unwrap();
+        assert_eq!(request.cursor.1, 6);
+
+        let disconnect = Disconnection::new("device-1".to_owned(), false, vec![]);
+        router.handle_disconnection(id, disconnect);
+        std::thread::sleep(Duration::from_millis(10));
+        router.clean();
+
+        let (connection, rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        match rx.recv().unwrap() {
+            Notification::ConnectionAck(ConnectionAck::Success((_, session, _))) => {
+                assert!(!session)
+            }
+            notification => panic!("Unexpected notification = {:?}", notification),
+        }
+    }
+
+    #[test]
+    fn bulk_subscriptions_are_applied_incrementally() {
+        let config = Config {
+            max_subscription_batch: 2,
+            ..Config::default()
+        };
+
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let rx = add_new_remote_connection(&mut router, "10");
+        assert!(matches!(rx.recv().unwrap(), Notification::ConnectionAck(_)));
+
+        let filters =
+            (0..5).map(|i| SubscribeFilter::new(format!("hello/{}", i), QoS::AtLeastOnce));
+        let mut subscribe = Subscribe::empty_subscribe();
+        subscribe.pkid = 1;
+        subscribe.filters.extend(filters);
+        router.handle_connection_subscribe(10, subscribe);
+
+        // First batch is applied right away
+        let tracker = router.trackers.get(10).unwrap();
+        assert_eq!(tracker.subscription_count(), 2);
+        match rx.recv().unwrap() {
+            Notification::SubscribeProgress(progress) => assert_eq!(progress.applied, 2),
+            notification => panic!("Unexpected notification = {:?}", notification),
+        }
+
+        // Rest of the batches are applied over next iterations
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 1);
+        }
+
+        let tracker = router.trackers.get(10).unwrap();
+        assert_eq!(tracker.subscription_count(), 5);
+
+        let mut applied = Vec::new();
+        let mut suback = None;
+        while let Ok(notification) = rx.try_recv() {
+            match notification {
+                Notification::SubscribeProgress(progress) => applied.push(progress.applied),
+                Notification::Acks(acks) => suback = acks.into_iter().next(),
+                _ => continue,
+            }
+        }
+
+        assert_eq!(applied, vec![4]);
+        match suback {
+            Some(Packet::SubAck(suback)) => assert_eq!(suback.return_codes.len(), 5),
+            packet => panic!("Expecting suback. Received = {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn replicator_slots_are_configurable() {
+        let config = Config {
+            replicas: 0,
+            ..Config::default()
+        };
+
+        // single node routers hand out ids from 0
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device-1");
+        assert_eq!(router.connectionslog.id("device-1"), Some(0));
+
+        let (connection, _rx) = Connection::new_replica(0, true, 10);
+        router.handle_new_connection(connection);
+        let connection = router.connections.get(0).unwrap();
+        assert!(matches!(connection.conn, ConnectionType::Device(_)));
+
+        let config = Config {
+            replicas: 2,
+            ..Config::default()
+        };
+
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let (connection, _rx) = Connection::new_replica(1, true, 10);
+        router.handle_new_connection(connection);
+        assert!(router.connections.get(1).is_some());
+
+        let _rx = add_new_remote_connection(&mut router, "device-1");
+        assert_eq!(router.connectionslog.id("device-1"), Some(2));
+    }
+
+    #[test]
+    fn replicators_resume_in_their_reserved_slots() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (connection, rx) = Connection::new_replica(1, false, 10);
+        router.handle_new_connection(connection);
+        add_new_subscription(&mut router, 1, "hello/world");
+        match rx.try_recv() {
+            Ok(Notification::ConnectionAck(ConnectionAck::Success((1, false, _)))) => {}
+            v => panic!("{:?}", v),
+        }
+
+        let disconnect = Disconnection::new(replicator_id(1), false, vec![]);
+        router.handle_disconnection(1, disconnect);
+
+        // slot of the replicator isn't handed out to devices
+        let _rx = add_new_remote_connection(&mut router, "device-1");
+        assert_eq!(router.connectionslog.id("device-1"), Some(10));
+
+        let (connection, rx) = Connection::new_replica(1, false, 10);
+        router.handle_new_connection(connection);
+        match rx.try_recv() {
+            Ok(Notification::ConnectionAck(ConnectionAck::Success((1, true, _)))) => {}
+            v => panic!("{:?}", v),
+        }
+
+        let tracker = router.trackers.get(1).unwrap();
+        assert_eq!(tracker.subscription_count(), 1);
+    }
+
+    #[test]
+    fn metrics_report_live_router_connection_and_topic_state() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device-1");
+        let id = router.connectionslog.id("device-1").unwrap();
+        let (console, rx) = Connection::new_remote("console", true, 10);
+        router.handle_new_connection(console);
+        let console = router.connectionslog.id("console").unwrap();
+        while rx.try_recv().is_ok() {}
+
+        add_new_subscription(&mut router, id, "hello/+");
+        let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        publish.pkid = 1;
+        router.handle_connection_data(id, vec![Packet::Publish(publish)]);
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        router.retrieve_metrics(console, MetricsRequest::Router);
+        match rx.try_recv() {
+            Ok(Notification::Metrics(MetricsReply::Router(metrics))) => {
+                assert_eq!(metrics.total_connections, 2);
+                assert_eq!(metrics.total_topics, 1);
+                assert_eq!(metrics.total_subscriptions, 1);
+                assert_eq!(metrics.total_publishes, 1);
+                assert_eq!(metrics.total_publish_size, 3);
+            }
+            v => panic!("{:?}", v),
+        }
+
+        router.retrieve_metrics(console, MetricsRequest::Topic("hello/world".to_owned()));
+        match rx.try_recv() {
+            Ok(Notification::Metrics(MetricsReply::Topic(Some(metrics)))) => {
+                assert_eq!(metrics.appended_count, 1);
+                assert!(!metrics.retained);
+            }
+            v => panic!("{:?}", v),
+        }
+
+        let request = MetricsRequest::Connection("device-1".to_owned());
+        router.retrieve_metrics(console, request);
+        match rx.try_recv() {
+            Ok(Notification::Metrics(MetricsReply::Connection(metrics))) => {
+                let inflight = metrics.inflight().unwrap();
+                assert!(inflight.queued > 0);
+                assert_eq!(inflight.backlog, 0);
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn qos2_publishes_are_appended_once_till_release() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (connection, rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device-1").unwrap();
+
+        let publish = |pkid| {
+            let mut publish = Publish::new("hello/world", QoS::ExactlyOnce, vec![1, 2, 3]);
+            publish.pkid = pkid;
+            Packet::Publish(publish)
+        };
+
+        // redelivery before release is only acked
+        router.handle_connection_data(id, vec![publish(1), publish(1)]);
+        let expected = vec![
+            Packet::PubRec(PubRec::new(1)),
+            Packet::PubRec(PubRec::new(1)),
+        ];
+        assert_eq!(collect_acks(&mut router, &rx), expected);
+        let appended = |router: &Router| {
+            let metrics = router.datalog.metrics("hello/world").unwrap();
+            metrics.appended_count
+        };
+        assert_eq!(appended(&router), 1);
+
+        // unreleased packet ids survive persistent reconnections
+        let disconnect = Disconnection::new("device-1".to_owned(), false, vec![]);
+        router.handle_disconnection(id, disconnect);
+        let (connection, rx) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device-1").unwrap();
+        router.handle_connection_data(id, vec![publish(1)]);
+        assert_eq!(appended(&router), 1);
+
+        let pubrel = Packet::PubRel(PubRel::new(1));
+        router.handle_connection_data(id, vec![pubrel, publish(1)]);
+        let expected = vec![
+            Packet::PubRec(PubRec::new(1)),
+            Packet::PubComp(PubComp::new(1)),
+            Packet::PubRec(PubRec::new(1)),
+        ];
+        assert_eq!(collect_acks(&mut router, &rx), expected);
+        assert_eq!(appended(&router), 2);
+    }
+
+    #[test]
+    fn shared_subscriptions_spread_publishes_across_the_group() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device-0");
+        let publisher = router.connectionslog.id("device-0").unwrap();
+
+        let mut members = Vec::new();
+        for client_id in ["backend-1", "backend-2"].iter() {
+            let rx = add_new_remote_connection(&mut router, client_id);
+            let id = router.connectionslog.id(client_id).unwrap();
+            add_new_subscription(&mut router, id, "$share/backend/hello/+");
+            members.push(rx);
+        }
+
+        let rx = add_new_remote_connection(&mut router, "monitor");
+        let id = router.connectionslog.id("monitor").unwrap();
+        add_new_subscription(&mut router, id, "hello/+");
+        members.push(rx);
+
+        let mut counts = vec![0; members.len()];
+        for pkid in 1..=4 {
+            let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+            publish.pkid = pkid;
+            router.handle_connection_data(publisher, vec![Packet::Publish(publish)]);
+            while let Some(id) = router.readyqueue.pop_front() {
+                router.connection_ready(id, 10);
+            }
+
+            for (rx, count) in members.iter().zip(counts.iter_mut()) {
+                while let Ok(notification) = rx.try_recv() {
+                    if let Notification::Data(data) = notification {
+                        *count += data.payload.len();
+                    }
+                }
+            }
+        }
+
+        // Every publish reaches one member of the group and all the regular subscribers
+        assert_eq!(counts, vec![2, 2, 4]);
+    }
+
+    #[test]
+    fn data_replies_are_bounded_by_size() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        for i in 0..5 {
+            router
+                .datalog
+                .append("hello/world", Bytes::from(vec![i; 4]));
+        }
+        router
+            .datalog
+            .append("hello/world", Bytes::from(vec![5; 20]));
+
+        let mut request = DataRequest::new("hello/world".to_owned(), 1);
+        request.set_max_bytes(10);
+
+        let mut counts = Vec::new();
+        while let Some(data) = router.datalog.extract_data(&request) {
+            counts.push(data.payload.len());
+            request.cursor = data.cursor;
+        }
+
+        // Records bigger than the bound are read alone
+        assert_eq!(counts, vec![2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn data_requests_seek_to_timestamps() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        for i in 0..5 {
+            router
+                .datalog
+                .append("hello/world", Bytes::from(vec![i; 4]));
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Records of the last hour
+        let request = DataRequest::from_timestamp("hello/world".to_owned(), 1, now - 3600);
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert_eq!(data.payload.len(), 5);
+
+        // Nothing is appended in the future yet
+        let request = DataRequest::from_timestamp("hello/world".to_owned(), 1, now + 3600);
+        assert!(router.datalog.extract_data(&request).is_none());
+    }
+
+    #[test]
+    fn statistics_are_published_only_to_sys_subscriptions() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (connection, rx1) = Connection::new_remote("dashboard", true, 100);
+        router.handle_new_connection(connection);
+        let rx2 = add_new_remote_connection(&mut router, "device");
+        let dashboard = router.connectionslog.id("dashboard").unwrap();
+        let device = router.connectionslog.id("device").unwrap();
+        add_new_subscription(&mut router, dashboard, "$SYS/broker/#");
+        add_new_subscription(&mut router, device, "#");
+
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]);
+        router.handle_connection_data(device, vec![Packet::Publish(publish)]);
+
+        // Clients can't publish statistics
+        let publish = Publish::new("$SYS/broker/uptime", QoS::AtLeastOnce, vec![1]);
+        router.handle_connection_data(device, vec![Packet::Publish(publish)]);
+
+        router.publish_sys();
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        let mut topics = Vec::new();
+        while let Ok(notification) = rx1.try_recv() {
+            if let Notification::Data(reply) = notification {
+                topics.push(reply.topic);
+            }
+        }
+
+        assert!(topics.contains(&"$SYS/broker/clients/connected".to_owned()));
+        assert!(!topics.contains(&"$SYS/broker/topics/hello/world/messages".to_owned()));
+        assert!(!topics.contains(&"hello/world".to_owned()));
+
+        while let Ok(notification) = rx2.try_recv() {
+            if let Notification::Data(reply) = notification {
+                assert_eq!(reply.topic, "hello/world");
+            }
+        }
+
+        let request = DataRequest::new("$SYS/broker/messages/received".to_owned(), 0);
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert_eq!(data.payload, vec![Bytes::from("1")]);
+    }
+
+    #[test]
+    fn per_topic_statistics_are_limited_to_the_busiest_topics() {
+        let config = Config {
+            sys_topic_stats: 1,
+            ..Config::default()
+        };
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device");
+        let device = router.connectionslog.id("device").unwrap();
+        let stats = |topic: &str| format!("$SYS/broker/topics/{}/messages", topic);
+        let publish = |topic: &str| {
+            let publish = Publish::new(topic, QoS::AtMostOnce, vec![1]);
+            Packet::Publish(publish)
+        };
+
+        let packets = vec![publish("a"), publish("b"), publish("b")];
+        router.handle_connection_data(device, packets);
+        router.publish_sys();
+
+        let request = DataRequest::new(stats("b"), 0);
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert_eq!(data.payload, vec![Bytes::from("2")]);
+        assert!(router.datalog.is_new(&stats("a")));
+
+        // Statistics of a topic which isn't the busiest anymore are cleared
+        let packets = vec![publish("a"), publish("a"), publish("a")];
+        router.handle_connection_data(device, packets);
+        router.publish_sys();
+
+        let request = DataRequest::new(stats("a"), 0);
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert_eq!(data.payload, vec![Bytes::from("4")]);
+        let request = DataRequest::new(stats("b"), 0);
+        assert!(router.datalog.extract_data(&request).is_none());
+
+        let request = DataRequest::new("$SYS/broker/topics/count".to_owned(), 0);
+        let data = router.datalog.extract_data(&request).unwrap();
+        assert_eq!(data.payload, vec![Bytes::from("2")]);
+    }
+
+    #[test]
+    fn duplicate_client_ids_take_over_connected_sessions() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (connection, rx1) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let old = router.connectionslog.id("device-1").unwrap();
+        add_new_subscription(&mut router, old, "hello/world");
+        while rx1.try_recv().is_ok() {}
+
+        let (connection, rx2) = Connection::new_remote("device-1", false, 10);
+        router.handle_new_connection(connection);
+        let new = router.connectionslog.id("device-1").unwrap();
+        assert_ne!(old, new);
+        assert!(matches!(
+            rx1.try_recv(),
+            Ok(Notification::Disconnect(DisconnectReason::TakenOver))
+        ));
+        match rx2.try_recv() {
+            Ok(Notification::ConnectionAck(ConnectionAck::Success((id, session, _)))) => {
+                assert_eq!(id, new);
+                assert!(session);
+            }
+            v => panic!("{:?}", v),
+        }
+
+        // Subscriptions are resumed and late disconnection of the old link is ignored
+        assert_eq!(router.trackers.get(new).unwrap().subscription_count(), 1);
+        let disconnect = Disconnection::new("device-1".to_owned(), true, Vec::new());
+        router.handle_disconnection(old, disconnect);
+        assert!(router.connections.get(new).is_some());
+    }
+
+    #[test]
+    fn duplicate_client_ids_are_rejected_when_configured() {
+        let config = Config {
+            duplicate_client_id: DuplicateClientId::Reject,
+            ..Config::default()
+        };
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let _rx1 = add_new_remote_connection(&mut router, "device-1");
+        let id = router.connectionslog.id("device-1").unwrap();
+
+        let rx2 = add_new_remote_connection(&mut router, "device-1");
+        assert!(matches!(
+            rx2.try_recv(),
+            Ok(Notification::ConnectionAck(ConnectionAck::Failure(_)))
+        ));
+        assert_eq!(router.connectionslog.id("device-1"), Some(id));
+        assert_eq!(router.connections.iter().count(), 1);
+    }
+
+    #[test]
+    fn anonymous_clients_are_not_duplicates() {
+        for mode in [DuplicateClientId::Takeover, DuplicateClientId::Reject].iter() {
+            let config = Config {
+                duplicate_client_id: *mode,
+                ..Config::default()
+            };
+
+            let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+            let rx1 = add_new_remote_connection(&mut router, "");
+            let rx2 = add_new_remote_connection(&mut router, "");
+            for rx in [rx1, rx2].iter() {
+                assert!(matches!(
+                    rx.try_recv(),
+                    Ok(Notification::ConnectionAck(ConnectionAck::Success(_)))
+                ));
+                assert!(rx.try_recv().is_err());
+            }
+
+            assert_eq!(router.connections.iter().count(), 2);
+        }
+    }
+
+    #[test]
+    fn slow_consumers_drop_qos0_data_published_while_paused() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (mut connection, rx) = Connection::new_remote("device", true, 4);
+        connection.set_slow_consumer(SlowConsumer::DropQos0);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device").unwrap();
+        let subscribe = Subscribe::new("hello/world", QoS::AtMostOnce);
+        router.handle_connection_subscribe(id, subscribe);
+        serve_ready(&mut router);
+        while rx.try_recv().is_ok() {}
+
+        // Fill the channel without reading it
+        while !router.trackers.get(id).unwrap().busy_unschedule() {
+            router.append_publish(Publish::new("hello/world", QoS::AtMostOnce, vec![1]));
+            serve_ready(&mut router);
+        }
+
+        for _ in 0..5 {
+            router.append_publish(Publish::new("hello/world", QoS::AtMostOnce, vec![2]));
+        }
+
+        // Resumed connection skips the publishes of the pause
+        while rx.try_recv().is_ok() {}
+        router.connection_ready(id, 10);
+        router.append_publish(Publish::new("hello/world", QoS::AtMostOnce, vec![3]));
+        serve_ready(&mut router);
+
+        let mut payloads = Vec::new();
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Data(reply) = notification {
+                payloads.extend(reply.payload);
+            }
+        }
+
+        assert_eq!(payloads, vec![Bytes::from(vec![3])]);
+        assert_eq!(router.metrics.slow_consumer_pauses, 1);
+        assert_eq!(router.metrics.slow_consumer_drops, 5);
+    }
+
+    #[test]
+    fn slow_consumers_are_disconnected_when_configured() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (mut connection, _rx) = Connection::new_remote("device", true, 4);
+        connection.set_slow_consumer(SlowConsumer::Disconnect);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device").unwrap();
+        add_new_subscription(&mut router, id, "hello/world");
+
+        for _ in 0..10 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![1]));
+            serve_ready(&mut router);
+        }
+
+        assert!(router.connections.get(id).is_none());
+        assert_eq!(router.metrics.slow_consumer_disconnections, 1);
+    }
+
+    fn serve_ready(router: &mut Router) {
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+    }
+
+    #[test]
+    fn qos0_publishes_skip_acks() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let rx1 = add_new_remote_connection(&mut router, "publisher");
+        let rx2 = add_new_remote_connection(&mut router, "subscriber");
+        let publisher = router.connectionslog.id("publisher").unwrap();
+        let subscriber = router.connectionslog.id("subscriber").unwrap();
+        add_new_subscription(&mut router, subscriber, "hello/world");
+        collect_acks(&mut router, &rx1);
+        while rx2.try_recv().is_ok() {}
+
+        let publishes = (0..5)
+            .map(|_| Packet::Publish(Publish::new("hello/world", QoS::AtMostOnce, vec![1])))
+            .collect();
+        router.handle_connection_data(publisher, publishes);
+        assert_eq!(router.watermarks.get(publisher).unwrap().pending(), 0);
+        assert!(collect_acks(&mut router, &rx1).is_empty());
+
+        let mut count = 0;
+        while let Ok(notification) = rx2.try_recv() {
+            if let Notification::Data(reply) = notification {
+                count += reply.payload.len();
+            }
+        }
+
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn deleted_topics_are_matched_again_when_recreated() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let (connection, rx) = Connection::new_remote("device", true, 100);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device").unwrap();
+        add_new_subscription(&mut router, id, "hello/+");
+
+        for i in 0..3 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![i]));
+        }
+
+        serve_ready(&mut router);
+        while rx.try_recv().is_ok() {}
+
+        router.delete_topic(id, "hello/world".to_owned());
+        match rx.try_recv() {
+            Ok(Notification::Metrics(MetricsReply::Topic(metrics))) => assert!(metrics.is_none()),
+            v => panic!("{:?}", v),
+        }
+
+        // Recreated topic is delivered from its start
+        router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![3]));
+        serve_ready(&mut router);
+
+        let mut payloads = Vec::new();
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Data(reply) = notification {
+                payloads.extend(reply.payload);
+            }
+        }
+
+        assert_eq!(payloads, vec![Bytes::from(vec![3])]);
+    }
+
+    #[test]
+    fn publishes_and_subscriptions_beyond_limits_are_rejected() {
+        let config = Config {
+            max_topics: Some(1),
+            max_payload_size: Some(2),
+            max_subscriptions: Some(2),
+            ..Config::default()
+        };
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+
+        // Subscriptions beyond the limit fail in the suback
+        let rx = add_new_remote_connection(&mut router, "device-1");
+        let id = router.connectionslog.id("device-1").unwrap();
+        let mut subscribe = Subscribe::new("hello/1", QoS::AtLeastOnce);
+        subscribe.add("hello/2".to_owned(), QoS::AtLeastOnce);
+        subscribe.add("hello/3".to_owned(), QoS::AtLeastOnce);
+        subscribe.pkid = 1;
+        router.handle_connection_subscribe(id, subscribe);
+        match collect_acks(&mut router, &rx).pop() {
+            Some(Packet::SubAck(suback)) => assert_eq!(
+                suback.return_codes,
+                vec![
+                    SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                    SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                    SubscribeReasonCode::Failure,
+                ]
+            ),
+            v => panic!("{:?}", v),
+        }
+        assert_eq!(router.trackers.get(id).unwrap().subscription_count(), 2);
+
+        // Publishes beyond payload or topic limits disconnect the connection
+        let publish = Publish::new("hello/1", QoS::AtMostOnce, vec![1, 2, 3]);
+        router.handle_connection_data(id, vec![Packet::Publish(publish)]);
+        assert!(router.connections.get(id).is_none());
+
+        let _rx = add_new_remote_connection(&mut router, "device-2");
+        let id = router.connectionslog.id("device-2").unwrap();
+        let publish = Publish::new("hello/1", QoS::AtMostOnce, vec![1]);
+        router.handle_connection_data(id, vec![Packet::Publish(publish)]);
+        assert!(router.connections.get(id).is_some());
+
+        let publish = Publish::new("hello/2", QoS::AtMostOnce, vec![1]);
+        router.handle_connection_data(id, vec![Packet::Publish(publish)]);
+        assert!(router.connections.get(id).is_none());
+        assert!(!router.datalog.topics().contains(&"hello/2".to_owned()));
+    }
+
+    #[test]
+    fn packets_after_a_publish_beyond_limits_are_dropped() {
+        let config = Config {
+            max_payload_size: Some(2),
+            ..Config::default()
+        };
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let _rx = add_new_remote_connection(&mut router, "device-1");
+        let id = router.connectionslog.id("device-1").unwrap();
+
+        let oversized = Publish::new("hello/1", QoS::AtMostOnce, vec![1, 2, 3]);
+        let mut publish = Publish::new("hello/1", QoS::AtLeastOnce, vec![1]);
+        publish.pkid = 1;
+        let mut subscribe = Subscribe::new("hello/+", QoS::AtLeastOnce);
+        subscribe.pkid = 2;
+        let data = vec![
+            Packet::Publish(oversized),
+            Packet::Publish(publish),
+            Packet::PubRel(PubRel::new(1)),
+            Packet::Subscribe(subscribe),
+        ];
+
+        router.handle_connection_data(id, data);
+        assert!(router.connections.get(id).is_none());
+        assert!(router.datalog.topics().is_empty());
+    }
+
+    #[test]
+    fn persistent_sessions_resume_from_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_owned(),
+            disk: Some(Default::default()),
+            ..Config::default()
+        };
+        let config = Arc::new(config);
+
+        let (mut router, _tx) = Router::new(config.clone()).unwrap();
+        let (connection, rx) = Connection::new_remote("device", false, 100);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device").unwrap();
+        add_new_subscription(&mut router, id, "hello/+");
+        for i in 0..2 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![i]));
+        }
+
+        serve_ready(&mut router);
+        while rx.try_recv().is_ok() {}
+        router.snapshot().unwrap();
+        drop(router);
+
+        // Restarted router delivers only the data published after the snapshot
+        let (mut router, _tx) = Router::from_snapshot(config).unwrap();
+        router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![2]));
+        let (connection, rx) = Connection::new_remote("device", false, 100);
+        router.handle_new_connection(connection);
+        serve_ready(&mut router);
+
+        let mut session = false;
+        let mut payloads = Vec::new();
+        while let Ok(notification) = rx.try_recv() {
+            match notification {
+                Notification::ConnectionAck(ConnectionAck::Success((_, s, _))) => session = s,
+                Notification::Data(reply) => payloads.extend(reply.payload),
+                _ => (),
+            }
+        }
+
+        assert!(session);
+        assert_eq!(payloads, vec![Bytes::from(vec![2])]);
+    }
+
+    #[test]
+    fn lagging_replicas_are_reported_and_alerted() {
+        let (mut router, _tx) = Router::new(Arc::new(Config::default())).unwrap();
+        let alerts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let alerted = alerts.clone();
+        router.set_lag_alert(2, move |replica, topic: &str, lag| {
+            alerted
+                .lock()
+                .unwrap()
+                .push((replica, topic.to_owned(), lag))
+        });
+
+        let (connection, rx) = Connection::new_replica(1, false, 100);
+        router.handle_new_connection(connection);
+        add_new_subscription(&mut router, 1, "hello/world");
+        for i in 0..3 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![i]));
+        }
+
+        serve_ready(&mut router);
+        while rx.try_recv().is_ok() {}
+
+        // Replica falls behind the topic till it's served again
+        for i in 3..8 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![i]));
+        }
+
+        let replica = &router.replication_lag()[1];
+        assert!(replica.connected);
+        assert_eq!(replica.lag.get("hello/world"), Some(&5));
+
+        router.check_lag();
+        router.check_lag();
+        serve_ready(&mut router);
+        router.check_lag();
+        assert_eq!(router.replication_lag()[1].lag.get("hello/world"), Some(&0));
+        assert!(!router.replication_lag()[0].connected);
+
+        // Replicas are alerted again once they catch up and fall behind again
+        for i in 8..11 {
+            router.append_publish(Publish::new("hello/world", QoS::AtLeastOnce, vec![i]));
+        }
+
+        router.check_lag();
+        let alerts = alerts.lock().unwrap();
+        assert_eq!(
+            *alerts,
+            vec![
+                (1, "hello/world".to_owned(), 5),
+                (1, "hello/world".to_owned(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn replicated_acks_wait_for_a_quorum_of_replicas() {
+        let config = Config {
+            ack_modes: vec![AckModeConfig {
+                topics: vec!["slow/+".to_owned()],
+                mode: AckMode::Replicated,
+            }],
+            ..Config::default()
+        };
+
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let (connection, replica_rx) = Connection::new_replica(1, false, 100);
+        router.handle_new_connection(connection);
+        add_new_subscription(&mut router, 1, "slow/+");
+        serve_ready(&mut router);
+
+        let rx = add_new_remote_connection(&mut router, "publisher");
+        let publisher = router.connectionslog.id("publisher").unwrap();
+        collect_acks(&mut router, &rx);
+        while replica_rx.try_recv().is_ok() {}
+
+        let publish = |topic, pkid| {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, vec![1]);
+            publish.pkid = pkid;
+            Packet::Publish(publish)
+        };
+
+        // Ack of a publish on a topic acked on append waits behind the replicated one
+        let publishes = vec![publish("slow/1", 1), publish("fast/1", 2)];
+        router.handle_connection_data(publisher, publishes);
+        assert_eq!(router.watermarks.get(publisher).unwrap().held(), 2);
+        assert!(rx.try_recv().is_err());
+
+        let acks = collect_acks(&mut router, &rx);
+        assert!(replica_rx.try_recv().is_ok());
+        assert_eq!(
+            acks,
+            vec![
+                Packet::PubAck(PubAck::new(1)),
+                Packet::PubAck(PubAck::new(2))
+            ]
+        );
+
+        // Topic policies override the ack mode of the connection
+        assert_eq!(router.ack_mode(publisher, "fast/1"), AckMode::Append);
+        let connection = router.connections.get_mut(publisher).unwrap();
+        connection.set_ack_mode(AckMode::Replicated);
+        assert_eq!(router.ack_mode(publisher, "fast/1"), AckMode::Replicated);
+    }
+
+    fn collect_acks(router: &mut Router, rx: &Receiver<Notification>) -> Vec<Packet> {
+        while let Some(id) = router.readyqueue.pop_front() {
+            router.connection_ready(id, 10);
+        }
+
+        let mut acks = Vec::new();
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Acks(v) = notification {
+                acks.extend(v);
+            }
+        }
+
+        acks
+    }
+
+    /// Config of on disk logs with a record per segment. Logs of `logs/+`
+    /// keep 2 records
+    fn retention_config(dir: &std::path::Path) -> Config {
+        Config {
+            dir: dir.to_owned(),
+            disk: Some(Default::default()),
+            max_segment_size: 1,
+            retention: vec![RetentionConfig {
+                topics: vec!["logs/+".to_owned()],
+                max_messages: Some(2),
+                ..RetentionConfig::default()
+            }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn retention_drops_old_records_of_matching_topics_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = retention_config(dir.path());
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        let (connection, rx) = Connection::new_remote("device", true, 100);
+        router.handle_new_connection(connection);
+        let id = router.connectionslog.id("device").unwrap();
+        add_new_subscription(&mut router, id, "logs/1");
+        add_new_subscription(&mut router, id, "metrics/1");
+        for i in 0..5 {
+            router.append_publish(Publish::new("logs/1", QoS::AtLeastOnce, vec![i]));
+            router.append_publish(Publish::new("metrics/1", QoS::AtLeastOnce, vec![i]));
+        }
+
+        // Cursors in dropped segments continue from the oldest remaining record
+        router.clean();
+        serve_ready(&mut router);
+        let mut payloads = HashMap::new();
+        while let Ok(notification) = rx.try_recv() {
+            if let Notification::Data(reply) = notification {
+                let payloads = payloads.entry(reply.topic).or_insert_with(Vec::new);
+                payloads.extend(reply.payload.iter().map(|p| p[0]));
+            }
+        }
+
+        assert_eq!(payloads["logs/1"], vec![3, 4]);
+        assert_eq!(payloads["metrics/1"], vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn idle_routers_clean_logs_periodically() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = retention_config(dir.path());
+        let (mut router, _tx) = Router::new(Arc::new(config)).unwrap();
+        for i in 0..5 {
+            router.append_publish(Publish::new("logs/1", QoS::AtLeastOnce, vec![i]));
+        }
+
+        let path = dir.path().join("commitlog").join(disk::dir_name("logs/1"));
+        let segments = || {
+            let entries = std::fs::read_dir(&path).unwrap();
+            let paths = entries.map(|entry| entry.unwrap().path());
+            paths.filter(|p| p.extension().unwrap() == "index").count()
+        };
+
+        assert_eq!(segments(), 5);
+        std::thread::spawn(move || router.start());
+
+        // No events wake the router up. Retention runs on its own timer
+        let start = Instant::now();
+        while segments() > 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
     fn add_new_remote_connection(router: &mut Router, client_id: &str) -> Receiver<Notification> {
         let (connection, rx) = Connection::new_remote(client_id, true, 10);
         router.handle_new_connection(connection);
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use jackiechan::{bounded, Receiver, Sender};
+use mqttbytes::v4::Packet;
+use mqttbytes::QoS;
+
+use super::connection::ConnectionType;
+use super::router::RouterError;
+use super::slab::Slab;
+use super::*;
+use crate::logs::OrderedGroups;
+use crate::storage::StorageError;
+use crate::{Config, ConnectionId, DuplicateClientId};
+
+/// Router which partitions topics across `Config::shards` routers, each running
+/// its event loop on its own thread. Topics are hashed to shards, so all the
+/// publishes of a topic (or of an ordered group) are appended by one shard in
+/// the order they are received. Links talk to the sharded router like they talk
+/// to a router. Connections are registered with every shard at the same id and
+/// subscriptions are applied on every shard. Data of different topics arrives
+/// from different shards and hence isn't ordered across topics
+pub struct ShardedRouter {
+    config: Arc<Config>,
+    /// Shards which are yet to be started
+    routers: Vec<Router>,
+    /// Event senders of shards
+    shards: Vec<Sender<(ConnectionId, Event)>>,
+    /// Channel receiver to receive data from all the active connections
+    router_rx: Receiver<(ConnectionId, Event)>,
+    /// Client ids of connections by the id assigned to them
+    clients: Slab<String>,
+    /// Ids of connected clients. Map[client id]id
+    connected: HashMap<String, ConnectionId>,
+    /// Ordered groups whose topics have to be on the same shard
+    ordered: OrderedGroups,
+    /// Shards of QoS 2 publishes which are yet to be released. Map[(client id, pkid)]shard
+    unreleased: HashMap<(String, u16), usize>,
+}
+
+impl ShardedRouter {
+    pub fn new(config: Arc<Config>) -> Result<(Self, Sender<(ConnectionId, Event)>), StorageError> {
+        let (router_tx, router_rx) = bounded(1000);
+        let count = config.shards.max(1);
+        let mut routers = Vec::with_capacity(count);
+        let mut shards = Vec::with_capacity(count);
+        for index in 0..count {
+            let (mut router, shard_tx) = shard(&config, index)?;
+            router.set_primary(index == 0);
+            routers.push(router);
+            shards.push(shard_tx);
+        }
+
+        let router = ShardedRouter {
+            clients: Slab::with_capacity(config.max_connections, config.replicas),
+            connected: HashMap::new(),
+            ordered: OrderedGroups::new(&config.ordered_groups),
+            unreleased: HashMap::new(),
+            config,
+            routers,
+            shards,
+            router_rx,
+        };
+
+        Ok((router, router_tx))
+    }
+
+    /// Starts every shard on its own thread and dispatches events of
+    /// connections to the shards
+    pub fn start(&mut self) -> Result<(), RouterError> {
+        for (index, mut router) in self.routers.drain(..).enumerate() {
+            thread::spawn(move || {
+                if let Err(e) = router.start() {
+                    error!("Router shard {} stopped. Error = {:?}", index, e);
+                }
+            });
+        }
+
+        loop {
+            let (id, event) = self.router_rx.recv()?;
+            self.dispatch(id, event)?;
+        }
+    }
+
+    fn dispatch(&mut self, id: ConnectionId, event: Event) -> Result<(), RouterError> {
+        match event {
+            Event::Connect(connection) => self.connect(connection),
+            Event::Data(data) => self.data(id, data),
+            Event::Disconnect(disconnect) => self.disconnect(id, disconnect),
+            Event::Ready => self.broadcast(id, || Event::Ready),
+            Event::Metrics(MetricsRequest::Topic(topic)) => {
+                let shard = self.shard(&topic);
+                let metrics = MetricsRequest::Topic(topic);
+                self.send(shard, id, Event::Metrics(metrics))
+            }
+            Event::Metrics(metrics) => self.send(0, id, Event::Metrics(metrics)),
+            Event::DeleteTopic(topic) => {
+                self.send(self.shard(&topic), id, Event::DeleteTopic(topic))
+            }
+            Event::PurgeTopic(topic, offset) => {
+                let shard = self.shard(&topic);
+                self.send(shard, id, Event::PurgeTopic(topic, offset))
+            }
+        }
+    }
+
+    /// Assigns an id to the connection and registers it with every shard. Will
+    /// of the connection is handed to the shard of the will topic
+    fn connect(&mut self, mut connection: Connection) -> Result<(), RouterError> {
+        if let ConnectionType::Device(did) = connection.conn.clone() {
+            let reject = self.config.duplicate_client_id == DuplicateClientId::Reject;
+            if reject && self.connected.contains_key(&did) {
+                warn!("{:11} {:14} Id = {}", "connection", "duplicate", did);
+                let reason = format!("Client id {} is already connected", did);
+                connection.notify(Notification::ConnectionAck(ConnectionAck::Failure(reason)));
+                return Ok(());
+            }
+
+            let id = match self.clients.insert(did.clone()) {
+                Some(id) => id,
+                None => {
+                    error!("No space for new connection!!");
+                    return Ok(());
+                }
+            };
+
+            if connection.clean() {
+                self.unreleased
+                    .retain(|(client_id, _), _| client_id != &did);
+            }
+
+            self.connected.insert(did, id);
+            connection.set_id(id);
+        }
+
+        let will = connection.will();
+        let mut connections = connection.split(self.shards.len());
+        if let Some(will) = will {
+            let shard = self.shard(&will.topic);
+            connections[shard].set_will(will);
+        }
+
+        for (shard, connection) in connections.into_iter().enumerate() {
+            self.send(shard, 0, Event::Connect(connection))?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes go to the shard of their topic. Subscribes and unsubscribes
+    /// go to all the shards. Packets to a shard keep their relative order
+    fn data(&mut self, id: ConnectionId, data: Vec<Packet>) -> Result<(), RouterError> {
+        let mut batches = vec![Vec::new(); self.shards.len()];
+        for packet in data {
+            match packet {
+                Packet::Publish(publish) => {
+                    let shard = self.shard(&publish.topic);
+                    if publish.qos == QoS::ExactlyOnce {
+                        if let Some(client_id) = self.clients.get(id) {
+                            let key = (client_id.clone(), publish.pkid);
+                            self.unreleased.insert(key, shard);
+                        }
+                    }
+
+                    batches[shard].push(Packet::Publish(publish));
+                }
+                // Releases of unknown packet ids are completed by the first shard
+                Packet::PubRel(pubrel) => {
+                    let shard = match self.clients.get(id) {
+                        Some(client_id) => {
+                            let key = (client_id.clone(), pubrel.pkid);
+                            self.unreleased.remove(&key).unwrap_or(0)
+                        }
+                        None => 0,
+                    };
+
+                    batches[shard].push(Packet::PubRel(pubrel));
+                }
+                packet @ Packet::Subscribe(_) | packet @ Packet::Unsubscribe(_) => {
+                    for batch in batches.iter_mut() {
+                        batch.push(packet.clone());
+                    }
+                }
+                packet => batches[0].push(packet),
+            }
+        }
+
+        for (shard, batch) in batches.into_iter().enumerate() {
+            if !batch.is_empty() {
+                self.send(shard, id, Event::Data(batch))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Disconnects the connection from every shard. Pending notifications of
+    /// the link are handed to the first shard
+    fn disconnect(
+        &mut self,
+        id: ConnectionId,
+        disconnect: Disconnection,
+    ) -> Result<(), RouterError> {
+        // Disconnections of connections which are taken over release only their id
+        if self.clients.get(id) == Some(&disconnect.id) {
+            self.clients.remove(id);
+            if self.connected.get(&disconnect.id) == Some(&id) {
+                self.connected.remove(&disconnect.id);
+            }
+        }
+
+        let (did, execute_will) = (disconnect.id.clone(), disconnect.execute_will);
+        for shard in 1..self.shards.len() {
+            let disconnect = Disconnection::new(did.clone(), execute_will, Vec::new());
+            self.send(shard, id, Event::Disconnect(disconnect))?;
+        }
+
+        self.send(0, id, Event::Disconnect(disconnect))
+    }
+
+    fn broadcast(&self, id: ConnectionId, event: impl Fn() -> Event) -> Result<(), RouterError> {
+        for shard in 0..self.shards.len() {
+            self.send(shard, id, event())?;
+        }
+
+        Ok(())
+    }
+
+    fn send(&self, shard: usize, id: ConnectionId, event: Event) -> Result<(), RouterError> {
+        self.shards[shard]
+            .send((id, event))
+            .map_err(|_| RouterError::Disconnected)
+    }
+
+    /// Shard of the topic. Topics of an ordered group are on the shard of the group
+    fn shard(&self, topic: &str) -> usize {
+        let key = self.ordered.group(topic).unwrap_or(topic);
+        (fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize
+    }
+}
+
+/// Router of a shard. Shards keep their commitlogs, storage and snapshots apart
+/// as topics are hashed to shards. The number of shards shouldn't change across
+/// restarts for shards to find their topics on disk
+fn shard(
+    config: &Config,
+    index: usize,
+) -> Result<(Router, Sender<(ConnectionId, Event)>), StorageError> {
+    let mut config = config.clone();
+    config.dir = config.dir.join(format!("shard-{}", index));
+    if let Some(storage) = config.storage.as_mut() {
+        let mut path = storage.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        storage.path = path.into();
+    }
+
+    let config = Arc::new(config);
+    match config.snapshot_interval_secs {
+        Some(_) => Router::from_snapshot(config),
+        None => Router::new(config),
+    }
+}
+
+/// Hash which is stable across builds. Topics map to the same shards after restarts
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
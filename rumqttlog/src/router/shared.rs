@@ -0,0 +1,68 @@
+use crate::ConnectionId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Prefix of shared subscription filters
+const SHARE_PREFIX: &str = "$share/";
+
+/// Cursors of shared subscription groups. Connections subscribing with
+/// `$share/<group>/<filter>` pull the matched topics with the cursor of their
+/// group instead of their own. Every publish is hence delivered to only one
+/// connection of the group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SharedGroups {
+    /// Map[group]Map[topic]cursor
+    cursors: HashMap<String, HashMap<String, (u64, u64)>>,
+    /// Member which received the last data of the group. Map[group]id
+    #[serde(skip)]
+    last: HashMap<String, ConnectionId>,
+}
+
+impl SharedGroups {
+    pub fn new() -> SharedGroups {
+        SharedGroups {
+            cursors: HashMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    /// Cursor of the group on this topic. First request of the group on a
+    /// topic decides where the group starts from
+    pub fn cursor(&mut self, group: &str, topic: &str, cursor: (u64, u64)) -> (u64, u64) {
+        let cursors = self.cursors.entry(group.to_owned()).or_default();
+        *cursors.entry(topic.to_owned()).or_insert(cursor)
+    }
+
+    /// Moves the cursor of the group on this topic forward after data is
+    /// delivered to a member
+    pub fn update(&mut self, id: ConnectionId, group: &str, topic: &str, cursor: (u64, u64)) {
+        self.last.insert(group.to_owned(), id);
+        let cursors = self.cursors.entry(group.to_owned()).or_default();
+        let current = cursors.entry(topic.to_owned()).or_insert(cursor);
+        if cursor > *current {
+            *current = cursor;
+        }
+    }
+
+    /// Removes cursors of all the groups on a deleted topic
+    pub fn remove_topic(&mut self, topic: &str) {
+        for cursors in self.cursors.values_mut() {
+            cursors.remove(topic);
+        }
+    }
+
+    /// Member which received the last data of the group
+    pub fn last(&self, group: &str) -> Option<ConnectionId> {
+        self.last.get(group).copied()
+    }
+}
+
+/// Splits a shared subscription filter into its group and topic filter
+pub fn split(filter: &str) -> Option<(&str, &str)> {
+    let (group, filter) = filter.strip_prefix(SHARE_PREFIX)?.split_once('/')?;
+    if group.is_empty() || group.contains(['+', '#'].as_ref()) || filter.is_empty() {
+        return None;
+    }
+
+    Some((group, filter))
+}
@@ -9,25 +9,32 @@ pub struct Slab<T> {
     // Offset of the next available slot in the slab. Set to the slab's
     // capacity when the slab is full.
     next: VecDeque<usize>,
+
+    // Slots which are only filled with `insert_at`
+    reserved: usize,
 }
 
 impl<T> Slab<T> {
-    /// Constructs a new initialized slab
-    pub fn with_capacity(mut capacity: usize) -> Slab<T> {
-        // slots for replicators
-        capacity += 10;
+    /// Constructs a new initialized slab. First `reserved` slots are
+    /// only filled with `insert_at`
+    pub fn with_capacity(mut capacity: usize, reserved: usize) -> Slab<T> {
+        capacity += reserved;
         let mut entries = Vec::with_capacity(capacity);
         entries.resize_with(capacity, || None::<T>);
 
-        let next: VecDeque<usize> = (10..capacity).collect();
-        Slab { entries, next }
+        let next: VecDeque<usize> = (reserved..capacity).collect();
+        Slab {
+            entries,
+            next,
+            reserved,
+        }
     }
 
     /// Return a reference to the value associated with the given key.
     ///
     /// If the given key is not associated with a value, then `None` is
     /// returned.
-    pub fn _get(&self, key: usize) -> Option<&T> {
+    pub fn get(&self, key: usize) -> Option<&T> {
         match self.entries.get(key) {
             Some(v) => v.as_ref(),
             None => None,
@@ -69,12 +76,38 @@ impl<T> Slab<T> {
             panic!("Inserting in a non vacant space")
         };
 
+        // Free slots can be filled with `insert_at` as well
+        if at >= self.reserved {
+            if let Some(index) = self.next.iter().position(|key| *key == at) {
+                self.next.remove(index);
+            }
+        }
+
         self.entries[at] = Some(val);
     }
 
+    /// Iterates through filled slots along with their keys
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(key, v)| v.as_ref().map(|v| (key, v)))
+    }
+
+    /// Iterates mutably through filled slots along with their keys
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.entries
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(key, v)| v.as_mut().map(|v| (key, v)))
+    }
+
     pub fn remove(&mut self, key: usize) -> Option<T> {
         let data = mem::replace(&mut self.entries[key], None);
-        self.next.push_back(key);
+        if key >= self.reserved {
+            self.next.push_back(key);
+        }
+
         data
     }
 }
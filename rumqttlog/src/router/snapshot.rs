@@ -0,0 +1,68 @@
+//! Router state written to disk so that a restarted router resumes persistent
+//! sessions where they left off. With on disk commitlogs, sessions continue from
+//! their cursors instead of the start of the logs
+use super::shared::SharedGroups;
+use super::Tracker;
+use crate::storage::StorageError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the snapshot file under `Config::dir`
+const SNAPSHOT: &str = "snapshot";
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Next offsets of commitlogs when the snapshot is taken. Map[log](segment, offset)
+    pub offsets: HashMap<String, (u64, u64)>,
+    /// Trackers of persistent sessions, connected or not. Map[client id]tracker
+    pub sessions: HashMap<String, Tracker>,
+    /// Cursors of shared subscription groups
+    pub shared: SharedGroups,
+}
+
+impl Snapshot {
+    /// Writes the snapshot next to the previous one and replaces it once synced.
+    /// A crash while writing leaves the previous snapshot intact
+    pub fn write(&self, dir: &Path) -> Result<(), StorageError> {
+        let (partial, path) = paths(dir);
+        let bytes = bincode::serialize(self)?;
+
+        fs::create_dir_all(dir)?;
+        let mut file = File::create(&partial)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    /// Reads the snapshot under `dir`. None if no snapshot is taken yet
+    pub fn read(dir: &Path) -> Result<Option<Snapshot>, StorageError> {
+        let (_, path) = paths(dir);
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    /// Logs which are behind their offsets in the snapshot. These are lost
+    /// (e.g in memory logs) or recreated after the snapshot. Cursors on them are stale
+    pub fn lost(&self, next_offset: impl Fn(&str) -> Option<(u64, u64)>) -> HashSet<String> {
+        self.offsets
+            .iter()
+            .filter(|(log, offset)| next_offset(log).map_or(true, |next| next < **offset))
+            .map(|(log, _)| log.clone())
+            .collect()
+    }
+}
+
+fn paths(dir: &Path) -> (PathBuf, PathBuf) {
+    let partial = dir.join(format!("{}.partial", SNAPSHOT));
+    let path = dir.join(SNAPSHOT);
+    (partial, path)
+}
@@ -0,0 +1,27 @@
+//! Broker statistics which the router publishes to `$SYS` topics. Statistics
+//! are retained publishes so that dashboards receive them as soon as they subscribe
+
+/// Prefix of statistics topics
+const SYS_PREFIX: &str = "$SYS/";
+
+/// Checks if the topic or filter is of broker statistics
+pub fn is_sys(topic: &str) -> bool {
+    topic.starts_with(SYS_PREFIX)
+}
+
+/// Topic of a statistic
+pub fn topic(name: &str) -> String {
+    format!("{}{}", SYS_PREFIX, name)
+}
+
+/// Topics starting with '$' aren't matched by wildcards. Statistics topics
+/// are matched by filters which start with `$SYS/` as well
+pub fn matches(topic: &str, filter: &str) -> bool {
+    match (
+        topic.strip_prefix(SYS_PREFIX),
+        filter.strip_prefix(SYS_PREFIX),
+    ) {
+        (Some(topic), Some(filter)) => mqttbytes::matches(topic, filter),
+        _ => mqttbytes::matches(topic, filter),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::router::{shared, sys};
 use crate::router::{AcksRequest, Request, TopicsRequest};
 use crate::DataRequest;
 use mqttbytes::v4::*;
@@ -22,10 +23,18 @@ pub struct Tracker {
     topics_index: HashSet<String>,
     /// Concrete subscriptions on this topic
     concrete_subscriptions: HashMap<String, u8>,
-    /// Wildcard subscriptions on this topic
+    /// Wildcard and shared subscriptions on this topic
     wild_subscriptions: Vec<(String, u8)>,
     /// Topics Matches on new subscription waiting for offset updates
     matched: VecDeque<(String, u8, (u64, u64))>,
+    /// Matched topics of ordered groups. Map[group log]Set[topic]
+    ordered: HashMap<String, HashSet<String>>,
+    /// Topics matched by shared subscriptions. Map[topic]group
+    #[serde(default)]
+    shared: HashMap<String, String>,
+    /// Unreleased QoS 2 packet ids of a disconnected session
+    #[serde(default)]
+    unreleased: HashSet<u16>,
 }
 
 impl Tracker {}
@@ -43,6 +52,9 @@ impl Tracker {
             concrete_subscriptions: HashMap::new(),
             wild_subscriptions: Vec::new(),
             matched: VecDeque::with_capacity(100),
+            ordered: HashMap::new(),
+            shared: HashMap::new(),
+            unreleased: HashSet::new(),
         }
     }
 
@@ -67,6 +79,22 @@ impl Tracker {
         self.concrete_subscriptions.len() + self.wild_subscriptions.len()
     }
 
+    /// Checks if the connection is already subscribed with this filter
+    pub fn has_subscription(&self, filter: &str) -> bool {
+        self.concrete_subscriptions.contains_key(filter)
+            || self.wild_subscriptions.iter().any(|(f, _)| f == filter)
+    }
+
+    /// Subscription filters and their qos
+    pub fn subscriptions(&self) -> Vec<(String, u8)> {
+        let concrete = self
+            .concrete_subscriptions
+            .iter()
+            .map(|(f, q)| (f.clone(), *q));
+        let wild = self.wild_subscriptions.iter().cloned();
+        concrete.chain(wild).collect()
+    }
+
     pub fn pop_request(&mut self) -> Option<Request> {
         self.requests.pop_front()
     }
@@ -76,12 +104,40 @@ impl Tracker {
         self.requests.push_back(request);
     }
 
+    /// Registered data requests
+    pub fn data_requests_mut(&mut self) -> impl Iterator<Item = &mut DataRequest> {
+        self.requests
+            .iter_mut()
+            .filter_map(|request| match request {
+                Request::Data(request) => Some(request),
+                _ => None,
+            })
+    }
+
     pub fn register_topics_request(&mut self, request: TopicsRequest) {
         // let request = TopicsRequest::offset(next_offset);
         let request = Request::Topics(request);
         self.requests.push_back(request);
     }
 
+    /// Restarts topics requests from the start of the topics log. Used when
+    /// the topics log is rebuilt. Already matched topics aren't tracked again
+    pub fn rewind_topics_request(&mut self) {
+        for request in self.requests.iter_mut() {
+            if let Request::Topics(request) = request {
+                *request = TopicsRequest::offset(0);
+            }
+        }
+    }
+
+    pub fn set_unreleased(&mut self, unreleased: HashSet<u16>) {
+        self.unreleased = unreleased;
+    }
+
+    pub fn take_unreleased(&mut self) -> HashSet<u16> {
+        std::mem::take(&mut self.unreleased)
+    }
+
     pub fn register_acks_request(&mut self) {
         let request = Request::Acks(AcksRequest);
         self.requests.push_back(request);
@@ -101,6 +157,33 @@ impl Tracker {
         matched_count
     }
 
+    /// Folds data request of a topic in an ordered group into a data request
+    /// on the group log. Group log is requested only once for all the matched
+    /// topics of the group so that data of all the topics is pulled in order
+    pub fn track_ordered(&mut self, group: &str, request: DataRequest) {
+        if let Some(topics) = self.ordered.get_mut(group) {
+            topics.insert(request.topic);
+            return;
+        }
+
+        let mut topics = HashSet::new();
+        topics.insert(request.topic);
+        self.ordered.insert(group.to_owned(), topics);
+
+        let request = DataRequest::offsets(group.to_owned(), request.qos, request.cursor, 0);
+        self.register_data_request(request);
+    }
+
+    /// Matched topics of an ordered group. None if the log isn't a group log
+    pub fn ordered_topics(&self, group: &str) -> Option<&HashSet<String>> {
+        self.ordered.get(group)
+    }
+
+    /// Group of the shared subscription which matched this topic
+    pub fn shared_group(&self, topic: &str) -> Option<&str> {
+        self.shared.get(topic).map(|group| group.as_str())
+    }
+
     /// Updates offsets and moves matches to the tracker
     pub fn next_matched(&mut self) -> Option<(String, u8, (u64, u64))> {
         self.matched.pop_front()
@@ -122,7 +205,9 @@ impl Tracker {
         }
 
         for filter in filters {
-            if has_wildcards(&filter.path) {
+            // Shared filters are matched by stripping the group
+            let (path, group) = split(&filter.path);
+            if group.is_some() || has_wildcards(path) {
                 let subscription = filter.path.clone();
                 let qos = filter.qos as u8;
                 self.wild_subscriptions.push((subscription, qos));
@@ -139,7 +224,11 @@ impl Tracker {
                     continue;
                 }
 
-                if matches(&topic, &filter.path) {
+                if sys::matches(topic, path) {
+                    if let Some(group) = group {
+                        self.shared.insert(topic.clone(), group.to_owned());
+                    }
+
                     self.topics_index.insert(topic.clone());
                     let qos = filter.qos as u8;
                     self.matched.push_back((topic.clone(), qos, (0, 0)));
@@ -170,7 +259,12 @@ impl Tracker {
 
         // Wildcard subscription match. We return after first match
         for (filter, qos) in self.wild_subscriptions.iter() {
-            if matches(&topic, filter) {
+            let (filter, group) = split(filter);
+            if sys::matches(topic, filter) {
+                if let Some(group) = group {
+                    self.shared.insert(topic.to_owned(), group.to_owned());
+                }
+
                 self.topics_index.insert(topic.to_owned());
                 let request = DataRequest::offsets(topic.to_owned(), *qos, (0, 0), 0);
                 return Some(request);
@@ -180,6 +274,23 @@ impl Tracker {
         None
     }
 
+    /// Forgets a deleted topic. Topic is matched again when it's recreated.
+    /// Returns false if the topic isn't tracked
+    pub fn remove_topic(&mut self, topic: &str) -> bool {
+        if !self.topics_index.remove(topic) {
+            return false;
+        }
+
+        self.shared.remove(topic);
+        self.matched.retain(|(matched, _, _)| matched != topic);
+        self.requests.retain(|request| match request {
+            Request::Data(data) => data.topic != topic,
+            _ => true,
+        });
+
+        true
+    }
+
     /// Removes a subscription and removes matched topics in tracker
     pub fn remove_subscription_and_unmatch(&mut self, filters: Vec<String>) -> VecDeque<String> {
         let mut matching = VecDeque::new();
@@ -187,14 +298,15 @@ impl Tracker {
         // Remove subscriptions and collect topics that match filters
         for filter in filters.iter() {
             // Collect topics matching current filter
+            let (path, group) = split(filter);
             for topic in self.topics_index.iter() {
-                if matches(topic, filter) {
+                if sys::matches(topic, path) {
                     matching.push_back(topic.clone());
                 }
             }
 
             // Remove the subscription
-            if has_wildcards(filter) {
+            if group.is_some() || has_wildcards(path) {
                 if let Some(index) = self.wild_subscriptions.iter().position(|v| v.0 == *filter) {
                     self.wild_subscriptions.swap_remove(index);
                 }
@@ -207,6 +319,20 @@ impl Tracker {
         while let Some(topic) = matching.pop_front() {
             // Remove this tracked topic from index
             self.topics_index.remove(&topic);
+            self.shared.remove(&topic);
+
+            // Ordered group request is removed along with last topic of the group
+            let group = self.ordered.iter_mut().find_map(|(group, topics)| {
+                match topics.remove(&topic) && topics.is_empty() {
+                    true => Some(group.clone()),
+                    false => None,
+                }
+            });
+
+            if let Some(group) = group {
+                self.ordered.remove(&group);
+                matching.push_back(group);
+            }
 
             // Find the topic in request queue
             let position = self.requests.iter().position(|request| match request {
@@ -226,6 +352,14 @@ impl Tracker {
     }
 }
 
+/// Topic filter of a subscription and its group if the subscription is shared
+fn split(subscription: &str) -> (&str, Option<&str>) {
+    match shared::split(subscription) {
+        Some((group, filter)) => (filter, Some(group)),
+        None => (subscription, None),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -281,4 +415,31 @@ mod test {
         assert!(!tracker.topics_index.contains("a/b"));
         assert!(!tracker.topics_index.contains("c/d"));
     }
+
+    #[test]
+    fn ordered_topics_share_one_group_request() {
+        let mut tracker = Tracker::new();
+        let group = "$ordered/0";
+
+        tracker.track_ordered(group, DataRequest::new("a/cmd".to_owned(), 1));
+        tracker.track_ordered(group, DataRequest::new("a/config".to_owned(), 1));
+        tracker.topics_index.insert("a/cmd".to_owned());
+        tracker.topics_index.insert("a/config".to_owned());
+        assert_eq!(tracker.ordered_topics(group).unwrap().len(), 2);
+
+        let mut t = tracker.requests.iter().skip(1);
+        assert_eq!(
+            *t.next().unwrap(),
+            Request::Data(DataRequest::new(group.to_owned(), 1))
+        );
+        assert!(t.next().is_none());
+
+        // Group request stays until all the topics of the group are unsubscribed
+        tracker.remove_subscription_and_unmatch(vec!["a/cmd".to_owned()]);
+        assert_eq!(tracker.requests.len(), 2);
+
+        tracker.remove_subscription_and_unmatch(vec!["a/config".to_owned()]);
+        assert_eq!(tracker.requests.len(), 1);
+        assert!(tracker.ordered_topics(group).is_none());
+    }
 }
@@ -0,0 +1,207 @@
+//! Storage for broker metadata (sessions, retained publishes, acl cache).
+//! This is independent of the commitlog which holds the actual data. Backends
+//! other than memory are enabled with `sled` and `sqlite` features
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("I/O = {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization = {0}")]
+    Serialization(#[from] bincode::Error),
+    #[cfg(feature = "sled")]
+    #[error("Sled = {0}")]
+    Sled(#[from] ::sled::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("Sqlite = {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Storage backend {0} not enabled")]
+    BackendNotEnabled(&'static str),
+}
+
+/// Kinds of metadata held by the storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    /// Subscriptions of persistent sessions. Key = client id
+    Sessions,
+    /// Retained publishes. Key = topic
+    Retained,
+    /// Cached authorization decisions. Key = client id
+    Acl,
+}
+
+impl Table {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Table::Sessions => "sessions",
+            Table::Retained => "retained",
+            Table::Acl => "acl",
+        }
+    }
+}
+
+/// Key value store for broker metadata. Writes are expected to be durable
+/// when the call returns
+pub trait Storage: Send {
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> Result<(), StorageError>;
+    /// Puts all the pairs. Backends override this to write them together
+    fn put_batch(&mut self, table: Table, pairs: &[(String, Vec<u8>)]) -> Result<(), StorageError> {
+        for (key, value) in pairs {
+            self.put(table, key, value)?;
+        }
+
+        Ok(())
+    }
+    fn delete(&mut self, table: Table, key: &str) -> Result<(), StorageError>;
+    /// All the key value pairs of a table
+    fn scan(&mut self, table: Table) -> Result<Vec<(String, Vec<u8>)>, StorageError>;
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Memory,
+    Sled,
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// Database path. Not used by memory backend
+    pub path: PathBuf,
+}
+
+/// Opens storage backend selected in the config
+pub fn open(config: &StorageConfig) -> Result<Box<dyn Storage>, StorageError> {
+    let storage: Box<dyn Storage> = match config.backend {
+        Backend::Memory => Box::new(MemoryStorage::new()),
+        #[cfg(feature = "sled")]
+        Backend::Sled => Box::new(self::sled::SledStorage::open(&config.path)?),
+        #[cfg(not(feature = "sled"))]
+        Backend::Sled => return Err(StorageError::BackendNotEnabled("sled")),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Box::new(self::sqlite::SqliteStorage::open(&config.path)?),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => return Err(StorageError::BackendNotEnabled("sqlite")),
+    };
+
+    Ok(storage)
+}
+
+/// Persisted state of a persistent session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// Subscription filters and their qos
+    pub subscriptions: Vec<(String, u8)>,
+}
+
+impl Session {
+    pub fn encode(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Session, StorageError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Storage which doesn't survive restarts. Useful for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: HashMap<Table, BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let table = self.tables.entry(table).or_default();
+        table.insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> Result<(), StorageError> {
+        if let Some(table) = self.tables.get_mut(&table) {
+            table.remove(key);
+        }
+
+        Ok(())
+    }
+
+    fn scan(&mut self, table: Table) -> Result<Vec<(String, Vec<u8>)>, StorageError> {
+        let pairs = match self.tables.get(&table) {
+            Some(table) => table.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs the same checks against every backend
+    pub(crate) fn check_backend(storage: &mut dyn Storage) {
+        let session = Session {
+            subscriptions: vec![("hello/+/world".to_owned(), 1)],
+        };
+
+        storage
+            .put(Table::Sessions, "device-1", &session.encode().unwrap())
+            .unwrap();
+        storage
+            .put(Table::Retained, "hello/1/world", &[1, 2, 3])
+            .unwrap();
+        storage
+            .put(Table::Retained, "hello/2/world", &[4, 5, 6])
+            .unwrap();
+        storage.put(Table::Retained, "hello/2/world", &[7]).unwrap();
+
+        let pairs = vec![
+            ("device-2".to_owned(), session.encode().unwrap()),
+            ("device-3".to_owned(), session.encode().unwrap()),
+        ];
+        storage.put_batch(Table::Sessions, &pairs).unwrap();
+        storage.delete(Table::Sessions, "device-3").unwrap();
+
+        let sessions = storage.scan(Table::Sessions).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[1].0, "device-2");
+        assert_eq!(Session::decode(&sessions[0].1).unwrap(), session);
+
+        let retained = storage.scan(Table::Retained).unwrap();
+        assert_eq!(
+            retained,
+            vec![
+                ("hello/1/world".to_owned(), vec![1, 2, 3]),
+                ("hello/2/world".to_owned(), vec![7])
+            ]
+        );
+
+        storage.delete(Table::Retained, "hello/1/world").unwrap();
+        assert_eq!(storage.scan(Table::Retained).unwrap().len(), 1);
+        assert!(storage.scan(Table::Acl).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_storage_puts_scans_and_deletes() {
+        let mut storage = MemoryStorage::new();
+        check_backend(&mut storage);
+    }
+}
@@ -0,0 +1,66 @@
+use super::{Storage, StorageError, Table};
+use std::path::Path;
+
+/// Storage backed by sled. Each table is a sled tree
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<SledStorage, StorageError> {
+        let db = sled::open(path)?;
+        Ok(SledStorage { db })
+    }
+}
+
+impl Storage for SledStorage {
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let tree = self.db.open_tree(table.name())?;
+        tree.insert(key, value)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn put_batch(&mut self, table: Table, pairs: &[(String, Vec<u8>)]) -> Result<(), StorageError> {
+        let tree = self.db.open_tree(table.name())?;
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_str(), value.as_slice());
+        }
+
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> Result<(), StorageError> {
+        let tree = self.db.open_tree(table.name())?;
+        tree.remove(key)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn scan(&mut self, table: Table) -> Result<Vec<(String, Vec<u8>)>, StorageError> {
+        let tree = self.db.open_tree(table.name())?;
+        let mut pairs = Vec::new();
+        for pair in tree.iter() {
+            let (key, value) = pair?;
+            let key = String::from_utf8_lossy(&key).to_string();
+            pairs.push((key, value.to_vec()));
+        }
+
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sled_storage_puts_scans_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SledStorage::open(dir.path()).unwrap();
+        crate::storage::test::check_backend(&mut storage);
+    }
+}
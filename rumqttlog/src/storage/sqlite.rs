@@ -0,0 +1,84 @@
+use super::{Storage, StorageError, Table};
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+const TABLES: [Table; 3] = [Table::Sessions, Table::Retained, Table::Acl];
+
+/// Storage backed by sqlite. Each table is a sqlite table of key value pairs
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, StorageError> {
+        let connection = Connection::open(path)?;
+        for table in TABLES.iter() {
+            let query = format!(
+                "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value BLOB NOT NULL)",
+                table.name()
+            );
+
+            connection.execute(&query, params![])?;
+        }
+
+        Ok(SqliteStorage { connection })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let query = format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+            table.name()
+        );
+
+        self.connection.execute(&query, params![key, value])?;
+        Ok(())
+    }
+
+    fn put_batch(&mut self, table: Table, pairs: &[(String, Vec<u8>)]) -> Result<(), StorageError> {
+        let query = format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+            table.name()
+        );
+
+        let transaction = self.connection.transaction()?;
+        for (key, value) in pairs {
+            transaction.execute(&query, params![key, value])?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> Result<(), StorageError> {
+        let query = format!("DELETE FROM {} WHERE key = ?1", table.name());
+        self.connection.execute(&query, params![key])?;
+        Ok(())
+    }
+
+    fn scan(&mut self, table: Table) -> Result<Vec<(String, Vec<u8>)>, StorageError> {
+        let query = format!("SELECT key, value FROM {} ORDER BY key", table.name());
+        let mut statement = self.connection.prepare(&query)?;
+        let rows = statement.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut pairs = Vec::new();
+        for row in rows {
+            pairs.push(row?);
+        }
+
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sqlite_storage_puts_scans_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(&dir.path().join("rumqttd.db")).unwrap();
+        crate::storage::test::check_backend(&mut storage);
+    }
+}
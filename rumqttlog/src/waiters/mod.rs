@@ -26,10 +26,15 @@ impl DataWaiters {
     /// Register data waiter
     pub fn register(&mut self, id: ConnectionId, request: DataRequest) {
         let topic = request.topic.clone();
-        let waiters = self.waiters.entry(topic).or_insert(Waiters::new());
+        let waiters = self.waiters.entry(topic).or_insert_with(Waiters::new);
         waiters.register(id, request);
     }
 
+    /// Removes all the waiters of a topic
+    pub fn remove_topic(&mut self, topic: &str) {
+        self.waiters.remove(topic);
+    }
+
     /// Remove a connection from waiters
     pub fn remove(&mut self, id: ConnectionId) -> VecDeque<DataRequest> {
         let mut pending = VecDeque::new();
@@ -41,6 +46,15 @@ impl DataWaiters {
 
         pending
     }
+
+    /// Requests of a connection which are waiting for new data
+    pub fn requests(&self, id: ConnectionId) -> Vec<DataRequest> {
+        self.waiters
+            .values()
+            .filter_map(|waiters| waiters.get(id))
+            .cloned()
+            .collect()
+    }
 }
 
 pub type TopicsWaiters = Waiters<TopicsRequest>;
@@ -89,6 +103,11 @@ impl<T> Waiters<T> {
         std::mem::swap(&mut self.current, &mut self.next);
     }
 
+    /// Request of a connection in current wait queue
+    pub fn get(&self, id: ConnectionId) -> Option<&T> {
+        self.current.iter().find(|x| x.0 == id).map(|v| &v.1)
+    }
+
     /// Remove a connection from waiters
     pub fn remove(&mut self, id: ConnectionId) -> Option<T> {
         match self.current.iter().position(|x| x.0 == id) {
@@ -111,6 +111,58 @@ fn failed_notifications_are_retried_after_connection_ready() {
     }
 }
 
+#[test]
+fn sharded_routers_preserve_order_of_every_topic() {
+    let connections = Connections::sharded(4);
+    let (publisher_id, publisher_rx) = connections.connection("publisher", 100);
+    let (subscriber_id, subscriber_rx) = connections.connection("subscriber", 100);
+    connections.subscribe(subscriber_id, "hello/+/world", 1);
+
+    // Subscribe is applied on every shard but acked once
+    let acks = wait_for_acks(&subscriber_rx).unwrap();
+    assert!(matches!(acks.as_slice(), [Packet::SubAck(_)]));
+    assert!(subscriber_rx.try_recv().is_err());
+
+    // Publishes of different topics are interleaved and land on different shards
+    for i in 0..100u8 {
+        for topic in 0..8u8 {
+            let topic_name = format!("hello/{}/world", topic);
+            let pkid = i as u16 * 8 + topic as u16 + 1;
+            connections.data(publisher_id, &topic_name, vec![topic, i], pkid);
+        }
+    }
+
+    let mut acks = 0;
+    while acks < 800 {
+        match publisher_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Notification::Acks(v) => acks += v.len(),
+            Notification::Pause => connections.ready(publisher_id),
+            v => panic!("Expecting acks or pause. Received {:?}", v),
+        }
+    }
+
+    let mut next = [0u8; 8];
+    let mut count = 0;
+    while count < 800 {
+        match subscriber_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Notification::Data(data) => {
+                for payload in data.payload {
+                    let (topic, i) = (payload[0] as usize, payload[1]);
+                    assert_eq!(data.topic, format!("hello/{}/world", topic));
+                    assert_eq!(i, next[topic]);
+                    next[topic] += 1;
+                    count += 1;
+                }
+            }
+            Notification::Pause => connections.ready(subscriber_id),
+            Notification::Acks(_) => continue,
+            v => panic!("Expecting data or pause. Received {:?}", v),
+        }
+    }
+
+    assert_eq!(next, [100; 8]);
+}
+
 fn wait_for_data(rx: &Receiver<Notification>) -> Option<Data> {
     thread::sleep(Duration::from_secs(1));
 
@@ -165,7 +217,23 @@ impl Connections {
         let mut config = Config::default();
         config.id = 0;
 
-        let (router, router_tx) = Router::new(Arc::new(config));
+        let (router, router_tx) = Router::new(Arc::new(config)).unwrap();
+        thread::spawn(move || {
+            let mut router = router;
+            let _ = router.start();
+        });
+
+        Connections { router_tx }
+    }
+
+    pub fn sharded(shards: usize) -> Connections {
+        let config = Config {
+            id: 0,
+            shards,
+            ..Config::default()
+        };
+
+        let (router, router_tx) = ShardedRouter::new(Arc::new(config)).unwrap();
         thread::spawn(move || {
             let mut router = router;
             let _ = router.start();